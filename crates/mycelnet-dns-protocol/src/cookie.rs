@@ -0,0 +1,225 @@
+//! DNS Cookies as specified in RFC 7873 with interoperable server cookies from RFC 9018.
+
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+
+use siphasher::sip::SipHasher24;
+
+use crate::edns::{DnsOptRecord, EdnsOption};
+
+/// Version of the server cookie format defined by RFC 9018
+const SERVER_COOKIE_VERSION: u8 = 1;
+
+/// Seconds a server cookie stays valid after it was issued
+pub const SERVER_COOKIE_LIFETIME: u32 = 3600;
+
+/// Seconds a server cookie timestamp may lie in the future to allow for clock skew
+const SERVER_COOKIE_SKEW: u32 = 300;
+
+fn siphash(secret: &[u8; 16], parts: &[&[u8]]) -> [u8; 8] {
+    let mut hasher = SipHasher24::new_with_key(secret);
+    for part in parts {
+        hasher.write(part);
+    }
+
+    hasher.finish().to_le_bytes()
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Secrets used to issue and validate server cookies. The previous secret keeps cookies issued
+/// before a rotation valid until they expire.
+#[derive(Debug, Clone)]
+pub struct ServerCookieSecrets {
+    current: [u8; 16],
+    previous: Option<[u8; 16]>,
+}
+
+impl ServerCookieSecrets {
+    pub fn new(secret: [u8; 16]) -> ServerCookieSecrets {
+        ServerCookieSecrets {
+            current: secret,
+            previous: None,
+        }
+    }
+
+    /// Start issuing cookies with a new secret while still accepting the current one
+    pub fn rotate(&mut self, secret: [u8; 16]) {
+        self.previous = Some(self.current);
+        self.current = secret;
+    }
+
+    fn hash(secret: &[u8; 16], client: &[u8; 8], header: &[u8], client_ip: IpAddr) -> [u8; 8] {
+        siphash(secret, &[client, header, &ip_bytes(client_ip)])
+    }
+
+    /// Generate a server cookie of version, reserved, timestamp and hash, RFC 9018 section 4
+    pub fn generate(&self, client: &[u8; 8], client_ip: IpAddr, now: u32) -> Vec<u8> {
+        let mut cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+        cookie.extend_from_slice(&now.to_be_bytes());
+        let hash = ServerCookieSecrets::hash(&self.current, client, &cookie, client_ip);
+        cookie.extend_from_slice(&hash);
+        cookie
+    }
+
+    /// Check a server cookie was issued by this server to the client and has not expired
+    pub fn validate(&self, client: &[u8; 8], server: &[u8], client_ip: IpAddr, now: u32) -> bool {
+        if server.len() != 16 || server[0] != SERVER_COOKIE_VERSION {
+            return false;
+        }
+
+        let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        // Serial number arithmetic since the timestamp wraps, RFC 9018 section 4.3
+        let age = now.wrapping_sub(timestamp) as i32;
+        if age > SERVER_COOKIE_LIFETIME as i32 || age < -(SERVER_COOKIE_SKEW as i32) {
+            return false;
+        }
+
+        [Some(self.current), self.previous]
+            .iter()
+            .flatten()
+            .any(|secret| {
+                ServerCookieSecrets::hash(secret, client, &server[..8], client_ip) == server[8..]
+            })
+    }
+}
+
+/// Client side cookie state, a client cookie per server and the server cookies learnt from them
+#[derive(Debug, Clone)]
+pub struct ClientCookieJar {
+    secret: [u8; 16],
+    servers: HashMap<SocketAddr, Vec<u8>>,
+}
+
+impl ClientCookieJar {
+    pub fn new(secret: [u8; 16]) -> ClientCookieJar {
+        ClientCookieJar {
+            secret,
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Client cookie for a server, derived from the server address so servers cannot track the
+    /// client across each other, RFC 7873 section 4.1
+    pub fn client_cookie(&self, server: IpAddr) -> [u8; 8] {
+        siphash(&self.secret, &[&ip_bytes(server)])
+    }
+
+    /// Cookie option to send to a server including its last known server cookie
+    pub fn option(&self, server: SocketAddr) -> EdnsOption {
+        EdnsOption::Cookie {
+            client: self.client_cookie(server.ip()),
+            server: self.servers.get(&server).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Remember the server cookie of a response. Responses that do not echo our client cookie
+    /// are ignored and false is returned as they may be spoofed.
+    pub fn update(&mut self, server: SocketAddr, opt: &DnsOptRecord) -> bool {
+        match opt.cookie() {
+            Some((client, cookie)) if *client == self.client_cookie(server.ip()) => {
+                if !cookie.is_empty() {
+                    self.servers.insert(server, cookie.to_vec());
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn server_cookie(&self, server: SocketAddr) -> Option<&[u8]> {
+        self.servers.get(&server).map(|cookie| cookie.as_slice())
+    }
+
+    /// Forget the cookie of a server, for instance after it changed its secret
+    pub fn forget(&mut self, server: SocketAddr) {
+        self.servers.remove(&server);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector from RFC 9018 appendix A.1
+    const CLIENT: [u8; 8] = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+    const SECRET: [u8; 16] = [
+        0xe5, 0xe9, 0x73, 0xe5, 0xa6, 0xb2, 0xa4, 0x3f, 0x48, 0xe7, 0xdc, 0x84, 0x9e, 0x37, 0xbf,
+        0xcf,
+    ];
+
+    fn client_ip() -> IpAddr {
+        "198.51.100.100".parse().unwrap()
+    }
+
+    #[test]
+    fn server_cookie_round_trip() {
+        let secrets = ServerCookieSecrets::new(SECRET);
+        let now = 1_559_731_985;
+        let cookie = secrets.generate(&CLIENT, client_ip(), now);
+
+        assert_eq!(
+            cookie,
+            vec![
+                0x01, 0x00, 0x00, 0x00, 0x5c, 0xf7, 0x9f, 0x11, 0x1f, 0x81, 0x30, 0xc3, 0xee, 0xe2,
+                0x94, 0x80
+            ]
+        );
+        assert!(secrets.validate(&CLIENT, &cookie, client_ip(), now));
+        assert!(secrets.validate(&CLIENT, &cookie, client_ip(), now + 3600));
+        assert!(!secrets.validate(&CLIENT, &cookie, client_ip(), now + 3601));
+        assert!(!secrets.validate(&CLIENT, &cookie, client_ip(), now - 301));
+        assert!(!secrets.validate(&CLIENT, &cookie, "198.51.100.101".parse().unwrap(), now));
+        assert!(!secrets.validate(&[0; 8], &cookie, client_ip(), now));
+    }
+
+    #[test]
+    fn server_cookie_rotation() {
+        let mut secrets = ServerCookieSecrets::new(SECRET);
+        let now = 1_559_731_985;
+        let cookie = secrets.generate(&CLIENT, client_ip(), now);
+
+        secrets.rotate([0x43; 16]);
+        assert!(secrets.validate(&CLIENT, &cookie, client_ip(), now));
+        assert_ne!(secrets.generate(&CLIENT, client_ip(), now), cookie);
+
+        secrets.rotate([0x44; 16]);
+        assert!(!secrets.validate(&CLIENT, &cookie, client_ip(), now));
+    }
+
+    #[test]
+    fn client_cookie_jar() {
+        let server: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let other: SocketAddr = "192.0.2.54:53".parse().unwrap();
+        let mut jar = ClientCookieJar::new([0x07; 16]);
+
+        let client = jar.client_cookie(server.ip());
+        assert_ne!(client, jar.client_cookie(other.ip()));
+        assert_eq!(
+            jar.option(server),
+            EdnsOption::Cookie {
+                client,
+                server: Vec::new()
+            }
+        );
+
+        let mut opt = DnsOptRecord::new();
+        opt.set_option(EdnsOption::Cookie {
+            client,
+            server: vec![0x01; 16],
+        });
+        assert!(jar.update(server, &opt));
+        assert_eq!(jar.server_cookie(server), Some(&[0x01; 16][..]));
+        assert!(!jar.update(other, &opt));
+        assert_eq!(jar.server_cookie(other), None);
+
+        jar.forget(server);
+        assert_eq!(jar.server_cookie(server), None);
+    }
+}
@@ -0,0 +1,706 @@
+//! EDNS(0) OPT pseudo-record and its options as specified in RFC 6891.
+//!
+//! The OPT record reuses the CLASS and TTL fields of a resource record for the requestor's UDP
+//! payload size, the upper bits of the extended RCODE, the EDNS version and flags. A parsed
+//! [`DnsResourceRecord`] of type OPT keeps these fixed fields at the start of its rdata, the
+//! conversions here hide that layout.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{DnsName, DnsPacketData, DnsQType, DnsRcode, DnsResourceRecord};
+
+/// UDP payload size advertised by default as recommended by DNS Flag Day 2020.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Option code of EDNS Client Subnet, RFC 7871
+pub const OPTION_CLIENT_SUBNET: u16 = 8;
+
+/// Option code of a DNS Cookie, RFC 7873
+pub const OPTION_COOKIE: u16 = 10;
+
+/// Option code of an Extended DNS Error, RFC 8914
+pub const OPTION_EXTENDED_ERROR: u16 = 15;
+
+/// Address family numbers assigned by IANA used by the client subnet option
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EdnsOption {
+    /// EDNS Client Subnet, RFC 7871. The address is zeroed beyond the source prefix length.
+    ClientSubnet {
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: IpAddr,
+    },
+    /// DNS Cookie, RFC 7873. The server cookie is empty when only the client cookie is sent.
+    Cookie { client: [u8; 8], server: Vec<u8> },
+    /// Extended DNS Error, RFC 8914. A message may carry several of them.
+    ExtendedError {
+        code: ExtendedErrorCode,
+        text: String,
+    },
+    /// Any option not understood by this crate
+    Unknown { code: u16, data: Vec<u8> },
+}
+
+impl EdnsOption {
+    /// Client subnet option for an address truncated to the source prefix length
+    pub fn client_subnet(address: IpAddr, source_prefix: u8) -> EdnsOption {
+        let source_prefix = source_prefix.min(max_prefix(&address));
+        EdnsOption::ClientSubnet {
+            source_prefix,
+            scope_prefix: 0,
+            address: truncate_address(address, source_prefix),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::ClientSubnet { .. } => OPTION_CLIENT_SUBNET,
+            EdnsOption::Cookie { .. } => OPTION_COOKIE,
+            EdnsOption::ExtendedError { .. } => OPTION_EXTENDED_ERROR,
+            EdnsOption::Unknown { code, .. } => *code,
+        }
+    }
+
+    pub fn from_data(code: u16, data: &[u8]) -> Result<EdnsOption> {
+        let option = match code {
+            OPTION_CLIENT_SUBNET => {
+                if data.len() < 4 {
+                    Err(anyhow!(
+                        "Client subnet option of {} bytes is too short",
+                        data.len()
+                    ))?;
+                }
+
+                let family = u16::from_be_bytes([data[0], data[1]]);
+                let source_prefix = data[2];
+                let scope_prefix = data[3];
+                let address_bytes = &data[4..];
+
+                let mut octets = [0; 16];
+                let address = match family {
+                    FAMILY_IPV4 if address_bytes.len() <= 4 => {
+                        octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                        IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+                    }
+                    FAMILY_IPV6 if address_bytes.len() <= 16 => {
+                        octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                        IpAddr::V6(Ipv6Addr::from(octets))
+                    }
+                    _ => Err(anyhow!(
+                        "Invalid client subnet family {} with {} address bytes",
+                        family,
+                        address_bytes.len()
+                    ))?,
+                };
+
+                let max = max_prefix(&address);
+                if source_prefix > max || scope_prefix > max {
+                    Err(anyhow!(
+                        "Client subnet prefix lengths {}/{} exceed {}",
+                        source_prefix,
+                        scope_prefix,
+                        max
+                    ))?;
+                }
+
+                // The address must be exactly as long as the source prefix with the bits beyond
+                // it set to zero, RFC 7871 section 6
+                if address_bytes.len() != (source_prefix as usize).div_ceil(8)
+                    || truncate_address(address, source_prefix) != address
+                {
+                    Err(anyhow!(
+                        "Client subnet address {} does not match source prefix {}",
+                        address,
+                        source_prefix
+                    ))?;
+                }
+
+                EdnsOption::ClientSubnet {
+                    source_prefix,
+                    scope_prefix,
+                    address,
+                }
+            }
+            OPTION_COOKIE => {
+                // A client cookie is 8 octets and an optional server cookie 8 to 32 octets
+                if data.len() != 8 && !(16..=40).contains(&data.len()) {
+                    Err(anyhow!("Invalid cookie option length {}", data.len()))?;
+                }
+
+                let mut client = [0; 8];
+                client.copy_from_slice(&data[..8]);
+                EdnsOption::Cookie {
+                    client,
+                    server: data[8..].to_vec(),
+                }
+            }
+            OPTION_EXTENDED_ERROR => {
+                if data.len() < 2 {
+                    Err(anyhow!(
+                        "Extended error option of {} bytes is too short",
+                        data.len()
+                    ))?;
+                }
+
+                // The text is not meant for machines, tolerate invalid UTF-8 and a trailing NUL
+                let text = String::from_utf8_lossy(&data[2..]);
+                EdnsOption::ExtendedError {
+                    code: ExtendedErrorCode::from_u16(u16::from_be_bytes([data[0], data[1]])),
+                    text: text.trim_end_matches('\0').to_string(),
+                }
+            }
+            _ => EdnsOption::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        };
+
+        Ok(option)
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            EdnsOption::ClientSubnet {
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let (family, octets) = match address {
+                    IpAddr::V4(address) => (FAMILY_IPV4, address.octets().to_vec()),
+                    IpAddr::V6(address) => (FAMILY_IPV6, address.octets().to_vec()),
+                };
+
+                let mut data = family.to_be_bytes().to_vec();
+                data.push(*source_prefix);
+                data.push(*scope_prefix);
+                data.extend_from_slice(&octets[..(*source_prefix as usize).div_ceil(8)]);
+                data
+            }
+            EdnsOption::Cookie { client, server } => {
+                let mut data = client.to_vec();
+                data.extend_from_slice(server);
+                data
+            }
+            EdnsOption::ExtendedError { code, text } => {
+                let mut data = code.to_u16().to_be_bytes().to_vec();
+                data.extend_from_slice(text.as_bytes());
+                data
+            }
+            EdnsOption::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    /// Serialize as option code, option length and option data
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let data = self.data();
+        let length = u16::try_from(data.len())
+            .map_err(|_| anyhow!("EDNS option {} is too long", self.code()))?;
+
+        let mut bytes = self.code().to_be_bytes().to_vec();
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend(data);
+
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsOptRecord {
+    /// Largest UDP payload the sender is able to reassemble.
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the 12 bit extended RCODE, the lower 4 bits are in the header.
+    pub extended_rcode: u8,
+    /// Version of EDNS implemented by the sender, only version 0 is defined.
+    pub version: u8,
+    /// DNSSEC OK - the sender is able to accept DNSSEC security records.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for DnsOptRecord {
+    fn default() -> DnsOptRecord {
+        DnsOptRecord {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl DnsOptRecord {
+    pub fn new() -> DnsOptRecord {
+        DnsOptRecord::default()
+    }
+
+    pub fn from_record(record: &DnsResourceRecord) -> Result<DnsOptRecord> {
+        if record.rtype != DnsQType::OPT {
+            Err(anyhow!(
+                "Record of type {:?} is not an OPT record",
+                record.rtype
+            ))?;
+        }
+
+        let data = &record.rdata;
+        if data.len() < 8 {
+            Err(anyhow!(
+                "OPT record data of {} bytes is too short",
+                data.len()
+            ))?;
+        }
+
+        let mut opt = DnsOptRecord {
+            udp_payload_size: u16::from_be_bytes([data[0], data[1]]),
+            extended_rcode: data[2],
+            version: data[3],
+            dnssec_ok: data[4] & 0b10000000 != 0,
+            options: Vec::new(),
+        };
+
+        let mut index = 8;
+        while index < data.len() {
+            let header = data
+                .get(index..index + 4)
+                .ok_or_else(|| anyhow!("EDNS option at offset {} is truncated", index))?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let option_data = data
+                .get(index + 4..index + 4 + length)
+                .ok_or_else(|| anyhow!("EDNS option {} exceeds record data", code))?;
+
+            opt.options.push(
+                EdnsOption::from_data(code, option_data)
+                    .with_context(|| format!("Failed to parse EDNS option {}", code))?,
+            );
+            index += 4 + length;
+        }
+
+        Ok(opt)
+    }
+
+    pub fn to_record(&self) -> Result<DnsResourceRecord> {
+        let mut options = Vec::new();
+        for option in &self.options {
+            options.extend(option.to_bytes()?);
+        }
+
+        let rdlength = u16::try_from(options.len())
+            .map_err(|_| anyhow!("EDNS options of {} bytes are too long", options.len()))?;
+
+        let mut rdata = self.udp_payload_size.to_be_bytes().to_vec();
+        rdata.push(self.extended_rcode);
+        rdata.push(self.version);
+        rdata.push(if self.dnssec_ok { 0b10000000 } else { 0 });
+        rdata.push(0);
+        rdata.extend_from_slice(&rdlength.to_be_bytes());
+        rdata.extend(options);
+
+        Ok(DnsResourceRecord {
+            name: DnsName::default(),
+            rtype: DnsQType::OPT,
+            ttl: 0,
+            rdlength,
+            rdata,
+            ..DnsResourceRecord::default()
+        })
+    }
+
+    /// Combine the lower 4 bits of the header response code with the extended bits
+    pub fn rcode(&self, header_rcode: DnsRcode) -> DnsRcode {
+        DnsRcode::from_u16((self.extended_rcode as u16) << 4 | header_rcode.to_u8() as u16)
+    }
+
+    /// Store the upper bits of a response code, the header must carry the lower 4 bits
+    pub fn set_rcode(&mut self, rcode: DnsRcode) {
+        self.extended_rcode = (rcode.to_u16() >> 4) as u8;
+    }
+
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|option| option.code() == code)
+    }
+
+    /// Replace any option with the same code
+    pub fn set_option(&mut self, option: EdnsOption) {
+        self.options
+            .retain(|existing| existing.code() != option.code());
+        self.options.push(option);
+    }
+
+    /// Add an extended error, unlike other options there may be more than one
+    pub fn add_extended_error(&mut self, code: ExtendedErrorCode, text: &str) {
+        self.options.push(EdnsOption::ExtendedError {
+            code,
+            text: text.to_string(),
+        });
+    }
+
+    /// Info codes and texts of all extended error options
+    pub fn extended_errors(&self) -> Vec<(ExtendedErrorCode, &str)> {
+        self.options
+            .iter()
+            .filter_map(|option| match option {
+                EdnsOption::ExtendedError { code, text } => Some((*code, text.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Address, source prefix length and scope prefix length of the client subnet option
+    pub fn client_subnet(&self) -> Option<(IpAddr, u8, u8)> {
+        match self.option(OPTION_CLIENT_SUBNET) {
+            Some(EdnsOption::ClientSubnet {
+                source_prefix,
+                scope_prefix,
+                address,
+            }) => Some((*address, *source_prefix, *scope_prefix)),
+            _ => None,
+        }
+    }
+
+    /// Client and server cookie of the cookie option
+    pub fn cookie(&self) -> Option<(&[u8; 8], &[u8])> {
+        match self.option(OPTION_COOKIE) {
+            Some(EdnsOption::Cookie { client, server }) => Some((client, server)),
+            _ => None,
+        }
+    }
+}
+
+/// Info codes of Extended DNS Errors registered with IANA, RFC 8914 section 5.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedErrorCode {
+    /// The error does not match any other code, the text explains it
+    Other,
+    /// A DNSKEY RRset uses an algorithm not supported by the validator
+    UnsupportedDnskeyAlgorithm,
+    /// A DS RRset uses a digest type not supported by the validator
+    UnsupportedDsDigestType,
+    /// The answer was served from a cache after its TTL expired, RFC 8767
+    StaleAnswer,
+    /// The answer was forged by a policy
+    ForgedAnswer,
+    /// DNSSEC validation ended in the indeterminate state
+    DnssecIndeterminate,
+    /// DNSSEC validation ended in the bogus state
+    DnssecBogus,
+    /// A signature has expired
+    SignatureExpired,
+    /// A signature is not yet valid
+    SignatureNotYetValid,
+    /// No DNSKEY matches the DS record of the zone
+    DnskeyMissing,
+    /// No RRSIGs were found for a signed zone
+    RrsigsMissing,
+    /// No DNSKEY has the Zone Key bit set
+    NoZoneKeyBitSet,
+    /// The NSEC or NSEC3 records needed to prove non-existence are missing
+    NsecMissing,
+    /// A SERVFAIL was answered from the cache
+    CachedError,
+    /// The server is not ready to answer queries
+    NotReady,
+    /// The query was blocked by an operator policy
+    Blocked,
+    /// The query was blocked by a policy of a third party
+    Censored,
+    /// The query was blocked at the request of the client
+    Filtered,
+    /// The client is not allowed to send this query
+    Prohibited,
+    /// The answer was served from a cache after the upstream resolver failed
+    StaleNxdomainAnswer,
+    /// The server is not authoritative for the name and does not recurse
+    NotAuthoritative,
+    /// The requested operation or query is not supported
+    NotSupported,
+    /// No authoritative server could be reached
+    NoReachableAuthority,
+    /// The query failed because of an unrecoverable network error
+    NetworkError,
+    /// The zone data is invalid or expired
+    InvalidData,
+    /// A code without an assigned meaning
+    Unassigned(u16),
+}
+
+impl ExtendedErrorCode {
+    pub fn from_u16(code: u16) -> ExtendedErrorCode {
+        match code {
+            0 => ExtendedErrorCode::Other,
+            1 => ExtendedErrorCode::UnsupportedDnskeyAlgorithm,
+            2 => ExtendedErrorCode::UnsupportedDsDigestType,
+            3 => ExtendedErrorCode::StaleAnswer,
+            4 => ExtendedErrorCode::ForgedAnswer,
+            5 => ExtendedErrorCode::DnssecIndeterminate,
+            6 => ExtendedErrorCode::DnssecBogus,
+            7 => ExtendedErrorCode::SignatureExpired,
+            8 => ExtendedErrorCode::SignatureNotYetValid,
+            9 => ExtendedErrorCode::DnskeyMissing,
+            10 => ExtendedErrorCode::RrsigsMissing,
+            11 => ExtendedErrorCode::NoZoneKeyBitSet,
+            12 => ExtendedErrorCode::NsecMissing,
+            13 => ExtendedErrorCode::CachedError,
+            14 => ExtendedErrorCode::NotReady,
+            15 => ExtendedErrorCode::Blocked,
+            16 => ExtendedErrorCode::Censored,
+            17 => ExtendedErrorCode::Filtered,
+            18 => ExtendedErrorCode::Prohibited,
+            19 => ExtendedErrorCode::StaleNxdomainAnswer,
+            20 => ExtendedErrorCode::NotAuthoritative,
+            21 => ExtendedErrorCode::NotSupported,
+            22 => ExtendedErrorCode::NoReachableAuthority,
+            23 => ExtendedErrorCode::NetworkError,
+            24 => ExtendedErrorCode::InvalidData,
+            _ => ExtendedErrorCode::Unassigned(code),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            ExtendedErrorCode::Other => 0,
+            ExtendedErrorCode::UnsupportedDnskeyAlgorithm => 1,
+            ExtendedErrorCode::UnsupportedDsDigestType => 2,
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::ForgedAnswer => 4,
+            ExtendedErrorCode::DnssecIndeterminate => 5,
+            ExtendedErrorCode::DnssecBogus => 6,
+            ExtendedErrorCode::SignatureExpired => 7,
+            ExtendedErrorCode::SignatureNotYetValid => 8,
+            ExtendedErrorCode::DnskeyMissing => 9,
+            ExtendedErrorCode::RrsigsMissing => 10,
+            ExtendedErrorCode::NoZoneKeyBitSet => 11,
+            ExtendedErrorCode::NsecMissing => 12,
+            ExtendedErrorCode::CachedError => 13,
+            ExtendedErrorCode::NotReady => 14,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Censored => 16,
+            ExtendedErrorCode::Filtered => 17,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::StaleNxdomainAnswer => 19,
+            ExtendedErrorCode::NotAuthoritative => 20,
+            ExtendedErrorCode::NotSupported => 21,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::InvalidData => 24,
+            ExtendedErrorCode::Unassigned(code) => *code,
+        }
+    }
+}
+
+impl DnsPacketData for DnsOptRecord {
+    fn from_bytes(data: &[u8], offset: usize) -> Result<DnsOptRecord> {
+        let record = DnsResourceRecord::from_bytes(data, offset)
+            .with_context(|| format!("Failed to parse OPT record at offset {}", offset))?;
+
+        DnsOptRecord::from_record(&record)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.to_record()?.to_bytes()
+    }
+}
+
+/// Number of bits in an address of the same family
+pub fn max_prefix(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Zero all bits of an address beyond the prefix length
+pub fn truncate_address(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    }
+}
+
+/// Find and parse the OPT record of an additional section, more than one is an error
+pub fn find_opt(additional: &[DnsResourceRecord]) -> Result<Option<DnsOptRecord>> {
+    let mut records = additional
+        .iter()
+        .filter(|record| record.rtype == DnsQType::OPT);
+
+    let opt = match records.next() {
+        Some(record) => DnsOptRecord::from_record(record)?,
+        None => return Ok(None),
+    };
+
+    if records.next().is_some() {
+        Err(anyhow!("Message contains more than one OPT record"))?;
+    }
+
+    Ok(Some(opt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_opt_record() -> Result<()> {
+        let data = vec![
+            0x00, // NAME
+            0x00, 0x29, // TYPE
+            0x04, 0xd0, // UDP payload size
+            0x00, 0x00, 0x80, 0x00, // Extended RCODE, version and flags
+            0x00, 0x0c, // RDLENGTH
+            0x00, 0x0a, 0x00, 0x08, 0x31, 0xb9, 0xb2, 0x38, 0x01, 0xba, 0x1a, 0xfe, // Cookie
+        ];
+
+        let opt = DnsOptRecord::from_bytes(&data, 0)?;
+
+        assert_eq!(opt.udp_payload_size, 1232);
+        assert_eq!(opt.extended_rcode, 0);
+        assert_eq!(opt.version, 0);
+        assert!(opt.dnssec_ok);
+        assert_eq!(
+            opt.cookie(),
+            Some((&[0x31, 0xb9, 0xb2, 0x38, 0x01, 0xba, 0x1a, 0xfe], &[][..]))
+        );
+
+        assert_eq!(opt.to_bytes()?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn decode_unknown_option() -> Result<()> {
+        let data = vec![
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, // OPT
+            0xff, 0x01, 0x00, 0x02, 0xab, 0xcd, // Option
+        ];
+
+        let opt = DnsOptRecord::from_bytes(&data, 0)?;
+        assert_eq!(
+            opt.options,
+            vec![EdnsOption::Unknown {
+                code: 0xff01,
+                data: vec![0xab, 0xcd]
+            }]
+        );
+        assert_eq!(opt.to_bytes()?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_options() {
+        // Cookie of invalid length
+        let data = [
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x0a, 0x00,
+            0x03, 0x01, 0x02, 0x03,
+        ];
+        assert!(DnsOptRecord::from_bytes(&data, 0).is_err());
+
+        // Option exceeding the record data
+        let data = [
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xff, 0x01, 0x00,
+            0x04, 0x01,
+        ];
+        assert!(DnsOptRecord::from_bytes(&data, 0).is_err());
+    }
+
+    #[test]
+    fn decode_client_subnet() -> Result<()> {
+        let data = vec![
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b, // OPT
+            0x00, 0x08, 0x00, 0x07, 0x00, 0x01, 0x18, 0x10, 0xc6, 0x33, 0x64, // ECS
+        ];
+
+        let opt = DnsOptRecord::from_bytes(&data, 0)?;
+        assert_eq!(opt.client_subnet(), Some(("198.51.100.0".parse()?, 24, 16)));
+        assert_eq!(opt.to_bytes()?, data);
+
+        // Bits beyond the source prefix must be zero
+        let data = [
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x08, 0x00,
+            0x07, 0x00, 0x01, 0x14, 0x00, 0xc6, 0x33, 0x64,
+        ];
+        assert!(DnsOptRecord::from_bytes(&data, 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_client_subnet() -> Result<()> {
+        let option = EdnsOption::client_subnet("2001:db8:aa:bbcc::1".parse()?, 56);
+        assert_eq!(
+            option,
+            EdnsOption::ClientSubnet {
+                source_prefix: 56,
+                scope_prefix: 0,
+                address: "2001:db8:aa:bb00::".parse()?,
+            }
+        );
+        assert_eq!(
+            option.to_bytes()?,
+            vec![
+                0x00, 0x08, 0x00, 0x0b, 0x00, 0x02, 0x38, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0xaa,
+                0xbb
+            ]
+        );
+
+        let option = EdnsOption::client_subnet("192.0.2.1".parse()?, 0);
+        assert_eq!(option.data(), vec![0x00, 0x01, 0x00, 0x00]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn decode_extended_errors() -> Result<()> {
+        let data = vec![
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, // OPT
+            0x00, 0x0f, 0x00, 0x02, 0x00, 0x06, // DNSSEC Bogus without text
+            0x00, 0x0f, 0x00, 0x09, 0x00, 0x16, 0x74, 0x69, 0x6d, 0x65, 0x6f, 0x75,
+            0x74, // No Reachable Authority with text
+        ];
+
+        let opt = DnsOptRecord::from_bytes(&data, 0)?;
+        assert_eq!(
+            opt.extended_errors(),
+            vec![
+                (ExtendedErrorCode::DnssecBogus, ""),
+                (ExtendedErrorCode::NoReachableAuthority, "timeout"),
+            ]
+        );
+        assert_eq!(opt.to_bytes()?, data);
+
+        let mut opt = DnsOptRecord::new();
+        opt.add_extended_error(ExtendedErrorCode::Unassigned(500), "new\0");
+        let opt = DnsOptRecord::from_bytes(&opt.to_bytes()?, 0)?;
+        assert_eq!(
+            opt.extended_errors(),
+            vec![(ExtendedErrorCode::Unassigned(500), "new")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_rcode() {
+        let mut opt = DnsOptRecord::new();
+        opt.set_rcode(DnsRcode::BadCookie);
+
+        assert_eq!(opt.extended_rcode, 1);
+        assert_eq!(DnsRcode::BadCookie.to_u8(), 7);
+        assert_eq!(opt.rcode(DnsRcode::from_u8(7)), DnsRcode::BadCookie);
+    }
+}
@@ -1,19 +1,56 @@
-use std::fmt::{Debug, Display, Formatter};
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter, Write};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
 use anyhow::{anyhow, Context, Result};
 
+use edns::{find_opt, DnsOptRecord};
+use message::DnsMessage;
+use writer::{to_vec, write_records, DnsWriter};
+
+pub mod cookie;
+pub mod edns;
+pub mod message;
+pub mod tsig;
+pub mod update;
+pub mod view;
+pub mod writer;
+
 pub trait DnsPacketData: Sized {
     fn from_bytes(data: &[u8], offset: usize) -> Result<Self>;
     fn to_bytes(&self) -> Result<Vec<u8>>;
+
+    /// Append the wire format to a writer, by default by copying the result of `to_bytes`
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        writer.write_bytes(&self.to_bytes()?)?;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Default)]
+/// Offsets of the section counts in the header
+const ANCOUNT_OFFSET: usize = 6;
+const NSCOUNT_OFFSET: usize = 8;
+const ARCOUNT_OFFSET: usize = 10;
+
+#[derive(Debug, Default, Clone)]
 pub struct DnsRequest {
     pub header: DnsHeader,
     pub question: DnsQuestion,
+    /// Records of the answer section, the SOA of a NOTIFY or the prerequisites of an UPDATE
+    pub answers: Option<Vec<DnsResourceRecord>>,
+    /// Records of the authority section, the SOA of an IXFR or the changes of an UPDATE
+    pub authority: Option<Vec<DnsResourceRecord>>,
     pub additional: Option<Vec<DnsResourceRecord>>,
 }
 
+impl DnsRequest {
+    /// Parse the OPT record of the additional section if the request uses EDNS
+    pub fn edns(&self) -> Result<Option<DnsOptRecord>> {
+        find_opt(self.additional.as_deref().unwrap_or_default())
+    }
+}
+
 impl DnsPacketData for DnsRequest {
     fn from_bytes(data: &[u8], offset: usize) -> Result<DnsRequest> {
         let mut request = DnsRequest {
@@ -22,55 +59,65 @@ impl DnsPacketData for DnsRequest {
             question: DnsQuestion::from_bytes(data, offset + 12).with_context(|| {
                 format!("Failed to parse DNS question at offset {}", offset + 12)
             })?,
-            additional: None,
+            ..DnsRequest::default()
         };
 
-        // Parse additional records if they exist
-        if request.header.arcount > 0 {
-            let mut additional = Vec::new();
-            let mut index = offset + 12 + request.question.qname.length() + 4;
-
-            for _ in 0..request.header.arcount {
+        let mut index = offset + 12 + DnsName::wire_length(data, offset + 12)? + 4;
+        let sections = [
+            (request.header.ancount, &mut request.answers, "answer"),
+            (request.header.nscount, &mut request.authority, "authority"),
+            (
+                request.header.arcount,
+                &mut request.additional,
+                "additional",
+            ),
+        ];
+        for (count, section, name) in sections {
+            for _ in 0..count {
                 let record = DnsResourceRecord::from_bytes(data, index).with_context(|| {
-                    format!("Failed to parse DNS additional record at offset {}", index)
+                    format!("Failed to parse DNS {} record at offset {}", name, index)
                 })?;
 
-                index += record.name.length() + 10 + record.rdlength as usize;
-                additional.push(record);
+                index += DnsResourceRecord::wire_length(data, index)?;
+                section.get_or_insert_with(Vec::new).push(record);
             }
-
-            request.additional = Some(additional);
         }
 
         Ok(request)
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-
-        data.extend_from_slice(
-            &self
-                .header
-                .to_bytes()
-                .with_context(|| format!("Failed to serialize DNS header {:?}", self.header))?,
-        );
-
-        data.extend_from_slice(
-            &self
-                .question
-                .to_bytes()
-                .with_context(|| format!("Failed to serialize DNS question {:?}", self.question))?,
-        );
+        to_vec(self)
+    }
 
-        if let Some(additional) = &self.additional {
-            for record in additional {
-                data.extend_from_slice(&record.to_bytes().with_context(|| {
-                    format!("Failed to serialize DNS additional record {:?}", record)
-                })?);
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        let start = writer.len();
+
+        self.header
+            .write_to(writer)
+            .with_context(|| format!("Failed to serialize DNS header {:?}", self.header))?;
+        self.question
+            .write_to(writer)
+            .with_context(|| format!("Failed to serialize DNS question {:?}", self.question))?;
+
+        // The counts follow the sections written, whatever the header says
+        let sections = [
+            (&self.answers, ANCOUNT_OFFSET, "answer"),
+            (&self.authority, NSCOUNT_OFFSET, "authority"),
+            (&self.additional, ARCOUNT_OFFSET, "additional"),
+        ];
+        for (section, count_offset, name) in sections {
+            let mut count = 0;
+            for record in section.iter().flatten() {
+                record.write_to(writer).with_context(|| {
+                    format!("Failed to serialize DNS {} record {:?}", name, record)
+                })?;
+                count += 1;
             }
+            writer.patch_u16(start + count_offset, count);
         }
 
-        Ok(data)
+        Ok(())
     }
 }
 
@@ -79,6 +126,8 @@ pub struct DnsResponse {
     pub header: DnsHeader,
     pub question: DnsQuestion,
     pub answers: Option<Vec<DnsResourceRecord>>,
+    pub authority: Option<Vec<DnsResourceRecord>>,
+    pub additional: Option<Vec<DnsResourceRecord>>,
 }
 
 impl DnsResponse {
@@ -87,36 +136,47 @@ impl DnsResponse {
             header: DnsHeader::default(),
             question: DnsQuestion::default(),
             answers: None,
+            authority: None,
+            additional: None,
         }
     }
 
-    pub fn from_request(request: &DnsRequest) -> Result<DnsResponse> {
-        let mut response = DnsResponse::new();
+    /// Parse the OPT record of the additional section if the response uses EDNS
+    pub fn edns(&self) -> Result<Option<DnsOptRecord>> {
+        find_opt(self.additional.as_deref().unwrap_or_default())
+    }
 
-        response.header.id = request.header.id;
-        response.header.flags.qr = 1;
-        response.header.flags.rd = request.header.flags.rd;
-        response.header.flags.ra = 1;
-        response.header.qdcount = request.header.qdcount;
-        response.header.ancount = 1;
-        response.header.nscount = 0;
-        response.header.arcount = 0;
-        response.question = request.question.clone();
-        response.answers = Some(vec![DnsResourceRecord {
-            name: DnsName::from_question(&request.question).with_context(|| {
-                format!(
-                    "Failed to create DNS resource record from question {:?}",
-                    request.question
-                )
-            })?,
-            rtype: request.question.qtype,
-            rclass: request.question.qclass,
-            ttl: 300,
-            rdlength: 4,
-            rdata: vec![127, 0, 0, 1],
-        }]);
+    /// Append an OPT record to the additional section carrying the extended bits of the rcode
+    pub fn set_edns(&mut self, mut opt: DnsOptRecord) -> Result<()> {
+        opt.set_rcode(self.header.flags.rcode);
+        let additional = self.additional.get_or_insert_with(Vec::new);
+        additional.retain(|record| record.rtype != DnsQType::OPT);
+        additional.push(opt.to_record()?);
+        self.header.arcount = additional.len() as u16;
 
-        Ok(response)
+        Ok(())
+    }
+
+    /// Response answering the question of a request with 127.0.0.1
+    pub fn from_request(request: &DnsRequest) -> Result<DnsResponse> {
+        let name = DnsName::from_question(&request.question).with_context(|| {
+            format!(
+                "Failed to create DNS resource record from question {:?}",
+                request.question
+            )
+        })?;
+
+        DnsMessage::response_to(request)
+            .recursion_available(true)
+            .answer(DnsResourceRecord {
+                name,
+                rtype: request.question.qtype,
+                rclass: request.question.qclass,
+                ttl: 300,
+                rdlength: 4,
+                rdata: vec![127, 0, 0, 1],
+            })
+            .build()
     }
 }
 
@@ -126,54 +186,117 @@ impl DnsPacketData for DnsResponse {
 
         response.header = DnsHeader::from_bytes(data, offset)
             .with_context(|| "Failed to parse DNS header".to_string())?;
-        response.question = DnsQuestion::from_bytes(data, offset + 12)
-            .with_context(|| format!("Failed to parse DNS question at offset {}", offset + 12))?;
+        let mut index = offset + 12;
+        // Errors about requests that could not be read carry no question
+        if response.header.qdcount > 0 {
+            response.question = DnsQuestion::from_bytes(data, index)
+                .with_context(|| format!("Failed to parse DNS question at offset {index}"))?;
+            index += DnsName::wire_length(data, index)? + 4;
+        }
 
-        let mut index = offset + 12 + response.question.qname.length() + 4;
         for _ in 0..response.header.ancount {
             let record = DnsResourceRecord::from_bytes(data, index).with_context(|| {
                 format!("Failed to parse DNS resource record at offset {}", index)
             })?;
-            index += record.name.length() + 10 + record.rdlength as usize;
+            index += DnsResourceRecord::wire_length(data, index)?;
             if response.answers.is_none() {
                 response.answers = Some(Vec::new());
             }
             response.answers.as_mut().unwrap().push(record);
         }
 
+        for _ in 0..response.header.nscount {
+            let record = DnsResourceRecord::from_bytes(data, index).with_context(|| {
+                format!("Failed to parse DNS authority record at offset {}", index)
+            })?;
+            index += DnsResourceRecord::wire_length(data, index)?;
+            response.authority.get_or_insert_with(Vec::new).push(record);
+        }
+
+        for _ in 0..response.header.arcount {
+            let record = DnsResourceRecord::from_bytes(data, index).with_context(|| {
+                format!("Failed to parse DNS additional record at offset {}", index)
+            })?;
+            index += DnsResourceRecord::wire_length(data, index)?;
+            response
+                .additional
+                .get_or_insert_with(Vec::new)
+                .push(record);
+        }
+
         Ok(response)
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
+        to_vec(self)
+    }
 
-        data.extend_from_slice(
-            &self
-                .header
-                .to_bytes()
-                .with_context(|| format!("Failed to serialize DNS header {:?}", self.header))?,
-        );
+    /// Write the response truncated to the limit of the writer. Records that do not fit are
+    /// left out and the TC bit is set if any answer or authority record is missing, RFC 2181
+    /// section 9. The OPT and TSIG records are always kept.
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        let start = writer.len();
+
+        self.header
+            .write_to(writer)
+            .with_context(|| format!("Failed to serialize DNS header {:?}", self.header))?;
+        if self.header.qdcount > 0 {
+            self.question
+                .write_to(writer)
+                .with_context(|| format!("Failed to serialize DNS question {:?}", self.question))?;
+        }
 
-        data.extend_from_slice(
-            &self
-                .question
-                .to_bytes()
-                .with_context(|| format!("Failed to serialize DNS question {:?}", self.question))?,
-        );
+        let is_message_record =
+            |record: &&DnsResourceRecord| matches!(record.rtype, DnsQType::OPT | DnsQType::TSIG);
+        let additional = self.additional.as_deref().unwrap_or_default();
+        let reserved: usize = additional
+            .iter()
+            .filter(is_message_record)
+            .map(DnsResourceRecord::size)
+            .sum();
+
+        let limit = writer.limit();
+        writer.set_limit(limit.saturating_sub(reserved));
+
+        let (ancount, mut truncated) = write_records(writer, self.answers.iter().flatten())
+            .with_context(|| "Failed to serialize DNS answer records".to_string())?;
+        let mut nscount = 0;
+        if !truncated {
+            (nscount, truncated) = write_records(writer, self.authority.iter().flatten())
+                .with_context(|| "Failed to serialize DNS authority records".to_string())?;
+        }
+        let mut arcount = 0;
+        if !truncated {
+            (arcount, _) = write_records(
+                writer,
+                additional
+                    .iter()
+                    .filter(|record| !is_message_record(record)),
+            )
+            .with_context(|| "Failed to serialize DNS additional records".to_string())?;
+        }
 
-        if let Some(answers) = &self.answers {
-            for record in answers {
-                data.extend_from_slice(&record.to_bytes().with_context(|| {
-                    format!("Failed to serialize DNS resource record {:?}", record)
-                })?);
-            }
+        writer.set_limit(limit);
+        for record in additional.iter().filter(is_message_record) {
+            record.write_to(writer).with_context(|| {
+                format!("Failed to serialize DNS additional record {:?}", record)
+            })?;
+            arcount += 1;
+        }
+
+        writer.patch_u16(start + ANCOUNT_OFFSET, ancount);
+        writer.patch_u16(start + NSCOUNT_OFFSET, nscount);
+        writer.patch_u16(start + ARCOUNT_OFFSET, arcount);
+        if truncated {
+            let flags = writer.as_bytes()[start + 2];
+            writer.patch_u8(start + 2, flags | 0b00000010);
         }
 
-        Ok(data)
+        Ok(())
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct DnsHeader {
     /// A 16 bit identifier assigned by the program that generates any kind of query.
     pub id: u16,
@@ -190,6 +313,14 @@ pub struct DnsHeader {
 
 impl DnsPacketData for DnsHeader {
     fn from_bytes(data: &[u8], offset: usize) -> Result<DnsHeader> {
+        if data.len() < offset + 12 {
+            Err(anyhow!(
+                "Message of {} bytes is too short for a header at offset {}",
+                data.len(),
+                offset
+            ))?;
+        }
+
         let header = DnsHeader {
             id: ((data[offset] as u16) << 8) | data[offset + 1] as u16,
             flags: DnsFlags::from_bytes(data, 2)
@@ -204,29 +335,24 @@ impl DnsPacketData for DnsHeader {
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-        data.push((self.id >> 8) as u8);
-        data.push(self.id as u8);
+        to_vec(self)
+    }
 
-        let flags = self
-            .flags
-            .to_bytes()
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        writer.write_u16(self.id)?;
+        self.flags
+            .write_to(writer)
             .with_context(|| "Failed to serialize DNS flags".to_string())?;
-        data.push(flags[0]);
-        data.push(flags[1]);
-        data.push((self.qdcount >> 8) as u8);
-        data.push(self.qdcount as u8);
-        data.push((self.ancount >> 8) as u8);
-        data.push(self.ancount as u8);
-        data.push((self.nscount >> 8) as u8);
-        data.push(self.nscount as u8);
-        data.push((self.arcount >> 8) as u8);
-        data.push(self.arcount as u8);
-
-        Ok(data)
+        writer.write_u16(self.qdcount)?;
+        writer.write_u16(self.ancount)?;
+        writer.write_u16(self.nscount)?;
+        writer.write_u16(self.arcount)?;
+
+        Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct DnsFlags {
     /// A one bit field that specifies whether this message is a query (0), or a response (1).
     pub qr: u8,
@@ -286,13 +412,18 @@ impl DnsPacketData for DnsFlags {
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
+        to_vec(self)
+    }
+
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
         let mut flags = [0; 2];
         flags[0] =
             (self.qr << 7) | (self.opcode.to_u8() << 3) | (self.aa << 2) | (self.tc << 1) | self.rd;
         flags[1] =
             (self.ra << 7) | (self.z << 6) | (self.ad << 5) | (self.cd << 4) | self.rcode.to_u8();
 
-        Ok(flags.to_vec())
+        writer.write_bytes(&flags)?;
+        Ok(())
     }
 }
 
@@ -415,7 +546,7 @@ pub enum DnsOpcode {
     Status,
     /// Reserved for future use
     Reserved,
-    /// A request for a transfer of an entire zone (NOTIFY)
+    /// A notification that a zone changed (NOTIFY), RFC 1996
     Notify,
     /// Dynamic update request (UPDATE)
     Update,
@@ -494,6 +625,8 @@ pub enum DnsRcode {
     BadAlg,
     /// Bad Truncation - Bad/missing Server Cookie
     BadTruncation,
+    /// Bad Cookie - Bad/missing Server Cookie
+    BadCookie,
     /// Unassigned
     Unassigned,
     /// Reserved
@@ -545,7 +678,8 @@ impl DnsRcode {
             20 => DnsRcode::BadName,
             21 => DnsRcode::BadAlg,
             22 => DnsRcode::BadTruncation,
-            23..=3840 => DnsRcode::Unassigned,
+            23 => DnsRcode::BadCookie,
+            24..=3840 => DnsRcode::Unassigned,
             3841..=4095 => DnsRcode::Reserved,
             4096..=65534 => DnsRcode::Unassigned,
             65535 => DnsRcode::Reserved,
@@ -566,7 +700,8 @@ impl DnsRcode {
             DnsRcode::NotAuth => 9,
             DnsRcode::NotZone => 10,
             DnsRcode::BadOptVersion => 16,
-            _ => 0,
+            // Extended response codes carry their lower 4 bits in the header
+            _ => (self.to_u16() & 0b00001111) as u8,
         }
     }
 
@@ -590,6 +725,7 @@ impl DnsRcode {
             DnsRcode::BadName => 20,
             DnsRcode::BadAlg => 21,
             DnsRcode::BadTruncation => 22,
+            DnsRcode::BadCookie => 23,
             _ => 0,
         }
     }
@@ -610,7 +746,11 @@ impl DnsPacketData for DnsQuestion {
         let name = DnsName::from_bytes(data, offset)
             .with_context(|| format!("Failed to parse DNS question name at offset {}", offset))?;
 
-        let index = offset + name.length();
+        let index = offset + DnsName::wire_length(data, offset)?;
+        if data.len() < index + 4 {
+            Err(anyhow!("DNS question at offset {} is truncated", offset))?;
+        }
+
         let qtype = ((data[index] as u16) << 8) | data[index + 1] as u16;
         let qclass = ((data[index + 2] as u16) << 8) | data[index + 3] as u16;
 
@@ -624,32 +764,21 @@ impl DnsPacketData for DnsQuestion {
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-
-        data.extend_from_slice(
-            &self.qname.to_bytes().with_context(|| {
-                format!("Failed to serialize DNS question name {:?}", self.qname)
-            })?,
-        );
-
-        // data.push(0); // TODO: do we still need this?
-        // data.push((self.qtype >> 8) as u8);
-        data.extend(
-            self.qtype.to_bytes().with_context(|| {
-                format!("Failed to serialize DNS question type {:?}", self.qtype)
-            })?,
-        );
+        to_vec(self)
+    }
 
-        // data.push((self.qclass >> 8) as u8);
-        data.extend(self.qclass.to_bytes().with_context(|| {
-            format!("Failed to serialize DNS question class {:?}", self.qclass)
-        })?);
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        self.qname
+            .write_to(writer)
+            .with_context(|| format!("Failed to serialize DNS question name {:?}", self.qname))?;
+        writer.write_u16(self.qtype.to_u16())?;
+        writer.write_u16(self.qclass.to_u16())?;
 
-        Ok(data)
+        Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DnsResourceRecord {
     /// A domain name to which this resource record pertains.
     pub name: DnsName,
@@ -687,20 +816,36 @@ impl DnsPacketData for DnsResourceRecord {
             )
         })?;
 
-        let index = offset + name.length();
+        let index = offset + DnsName::wire_length(data, offset)?;
+        if data.len() < index + 10 {
+            Err(anyhow!(
+                "DNS resource record at offset {} is truncated",
+                offset
+            ))?;
+        }
+
         let rtype = ((data[index] as u16) << 8) | data[index + 1] as u16;
+        let rdlength = ((data[index + 8] as u16) << 8) | data[index + 9] as u16;
+        if data.len() < index + 10 + rdlength as usize {
+            Err(anyhow!(
+                "DNS resource record data at offset {} exceeds message",
+                offset
+            ))?;
+        }
 
         // If rtype is 41 then this is an OPT extension request not a standard resource record
         // We need to additional information to properly process the request
         if rtype == 41 {
             // TODO: parse OPT extension request
+            // Keep the fixed fields in rdata but bound it by the real rdlength so records
+            // following the OPT record, such as TSIG, can still be parsed
             let rr = DnsResourceRecord {
                 name,
                 rtype: DnsQType::from_u16(rtype),
                 rclass: DnsClass::IN,
                 ttl: 0,
-                rdlength: data.len() as u16 - index as u16 - 2,
-                rdata: data[index + 2..].to_vec(),
+                rdlength,
+                rdata: data[index + 2..index + 10 + rdlength as usize].to_vec(),
             };
 
             return Ok(rr);
@@ -711,15 +856,16 @@ impl DnsPacketData for DnsResourceRecord {
             | ((data[index + 5] as u32) << 16)
             | ((data[index + 6] as u32) << 8)
             | data[index + 7] as u32;
-        let rdlength = ((data[index + 8] as u16) << 8) | data[index + 9] as u16;
-        let rdata = data[index + 10..index + 10 + rdlength as usize].to_vec();
+        let rtype = DnsQType::from_u16(rtype);
+        let rdata = decompress_rdata(rtype, data, index + 10, rdlength as usize)
+            .with_context(|| format!("Failed to decompress DNS record data of type {:?}", rtype))?;
 
         let rr = DnsResourceRecord {
             name,
-            rtype: DnsQType::from_u16(rtype),
+            rtype,
             rclass: DnsClass::from_u16(rclass),
             ttl,
-            rdlength,
+            rdlength: rdata.len() as u16,
             rdata,
         };
 
@@ -727,43 +873,127 @@ impl DnsPacketData for DnsResourceRecord {
     }
 
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-        data.extend_from_slice(&self.name.to_bytes().with_context(|| {
+        to_vec(self)
+    }
+
+    /// Write the record with its RDLENGTH taken from the length of its data
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
+        self.name.write_to(writer).with_context(|| {
             format!(
                 "Failed to serialize DNS resource record name {:?}",
                 self.name
             )
-        })?);
+        })?;
+        writer.write_u16(self.rtype.to_u16())?;
 
-        data.extend(self.rtype.to_bytes().with_context(|| {
-            format!(
-                "Failed to serialize DNS resource record type {:?}",
-                self.rtype
+        // The fixed fields of an OPT record are kept in its data
+        if self.rtype == DnsQType::OPT {
+            writer.write_bytes(&self.rdata)?;
+            return Ok(());
+        }
+
+        let rdlength = u16::try_from(self.rdata.len()).map_err(|_| {
+            anyhow!(
+                "DNS resource record data of {} bytes is too long",
+                self.rdata.len()
             )
-        })?);
+        })?;
+        writer.write_u16(self.rclass.to_u16())?;
+        writer.write_u32(self.ttl)?;
+        writer.write_u16(rdlength)?;
+        writer.write_bytes(&self.rdata)?;
 
-        if self.rtype == DnsQType::OPT {
-            data.extend_from_slice(&self.rdata);
-        } else {
-            data.extend(self.rclass.to_bytes().with_context(|| {
-                format!(
-                    "Failed to serialize DNS resource record class {:?}",
-                    self.rclass
-                )
-            })?);
-            data.push((self.ttl >> 24) as u8);
-            data.push((self.ttl >> 16) as u8);
-            data.push((self.ttl >> 8) as u8);
-            data.push(self.ttl as u8);
-            data.push((self.rdlength >> 8) as u8);
-            data.push(self.rdlength as u8);
-            data.extend_from_slice(&self.rdata);
+        Ok(())
+    }
+}
+
+impl DnsResourceRecord {
+    /// Number of bytes the record occupies when written
+    pub fn size(&self) -> usize {
+        match self.rtype {
+            DnsQType::OPT => self.name.length() + 2 + self.rdata.len(),
+            _ => self.name.length() + 10 + self.rdata.len(),
         }
+    }
+
+    /// Number of bytes the record at offset occupies in a message
+    pub fn wire_length(data: &[u8], offset: usize) -> Result<usize> {
+        let index = offset + DnsName::wire_length(data, offset)?;
+        let rdlength = data
+            .get(index + 8..index + 10)
+            .map(|bytes| ((bytes[0] as u16) << 8) | bytes[1] as u16)
+            .ok_or_else(|| anyhow!("DNS resource record at offset {} is truncated", offset))?;
 
-        Ok(data)
+        Ok(index - offset + 10 + rdlength as usize)
     }
 }
 
+/// Layout of record data containing domain names
+enum RDataField {
+    Name,
+    Fixed(usize),
+}
+
+/// Expand compressed names in the data of the types listed in RFC 3597 section 4 so the record
+/// no longer depends on the message it was read from
+fn decompress_rdata(
+    rtype: DnsQType,
+    data: &[u8],
+    offset: usize,
+    rdlength: usize,
+) -> Result<Vec<u8>> {
+    // Prerequisites and deletions of dynamic updates stand for whole RRsets without any data,
+    // RFC 2136 section 2.4
+    if rdlength == 0 {
+        return Ok(Vec::new());
+    }
+
+    let fields: &[RDataField] = match rtype {
+        DnsQType::NS
+        | DnsQType::MD
+        | DnsQType::MF
+        | DnsQType::CNAME
+        | DnsQType::MB
+        | DnsQType::MG
+        | DnsQType::MR
+        | DnsQType::PTR
+        | DnsQType::DNAME => &[RDataField::Name],
+        DnsQType::SOA | DnsQType::MINFO | DnsQType::RP => &[RDataField::Name, RDataField::Name],
+        DnsQType::MX | DnsQType::AFSDB | DnsQType::RT | DnsQType::KX => {
+            &[RDataField::Fixed(2), RDataField::Name]
+        }
+        DnsQType::PX => &[RDataField::Fixed(2), RDataField::Name, RDataField::Name],
+        DnsQType::SRV => &[RDataField::Fixed(6), RDataField::Name],
+        _ => return Ok(data[offset..offset + rdlength].to_vec()),
+    };
+
+    let end = offset + rdlength;
+    let mut rdata = Vec::with_capacity(rdlength);
+    let mut index = offset;
+    for field in fields {
+        let length = match field {
+            RDataField::Name => {
+                let name = DnsName::from_bytes(data, index)?;
+                rdata.extend(DnsName::from_labels(name.labels).to_bytes()?);
+                DnsName::wire_length(data, index)?
+            }
+            RDataField::Fixed(length) => {
+                rdata.extend_from_slice(data.get(index..index + length).unwrap_or_default());
+                *length
+            }
+        };
+
+        index += length;
+        if index > end {
+            Err(anyhow!("Record data at offset {} is truncated", offset))?;
+        }
+    }
+
+    rdata.extend_from_slice(&data[index..end]);
+
+    Ok(rdata)
+}
+
 #[derive(Debug, Default)]
 pub struct DnsRDataCname {
     pub cname: DnsName,
@@ -771,12 +1001,199 @@ pub struct DnsRDataCname {
 
 #[derive(Debug, Default, Clone)]
 pub struct DnsName {
-    pub labels: Vec<String>,
+    /// Labels as they appear on the wire, any byte is allowed in a label
+    pub labels: Vec<Vec<u8>>,
     pub offset: u16,
     pub pointer: u16,
 }
 
+/// Offset of the question name in a message, names compressed to it are kept compressed
+const QUESTION_NAME_OFFSET: u16 = 12;
+
+/// Maximum number of compression pointers followed while reading a name
+pub(crate) const MAX_POINTER_HOPS: usize = 64;
+
+/// Maximum length of a label, RFC 1035 section 2.3.4
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+/// Maximum length of a name on the wire including length octets, RFC 1035 section 2.3.4
+pub const MAX_NAME_LENGTH: usize = 255;
+
 impl DnsName {
+    pub fn from_labels<L: Into<Vec<u8>>>(labels: impl IntoIterator<Item = L>) -> DnsName {
+        DnsName {
+            labels: labels.into_iter().map(Into::into).collect(),
+            ..DnsName::default()
+        }
+    }
+
+    /// Parse an internationalized domain name, mapping it with UTS #46 and encoding labels
+    /// outside ASCII with punycode as specified by IDNA 2008
+    pub fn from_unicode(name: &str) -> Result<DnsName> {
+        let ascii = idna::domain_to_ascii(name)
+            .map_err(|e| anyhow!("Invalid internationalized domain name {}: {}", name, e))?;
+
+        // A trailing dot only marks the name as fully qualified
+        let ascii = ascii.strip_suffix('.').unwrap_or(&ascii);
+        if ascii.is_empty() {
+            return Ok(DnsName::default());
+        }
+
+        let name = DnsName::from_labels(ascii.split('.'));
+        for label in &name.labels {
+            if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+                Err(anyhow!(
+                    "Label of {} bytes in domain name {} is invalid",
+                    label.len(),
+                    ascii
+                ))?;
+            }
+        }
+
+        if name.wire_size() > MAX_NAME_LENGTH {
+            Err(anyhow!(
+                "Domain name {} exceeds {} bytes",
+                ascii,
+                MAX_NAME_LENGTH
+            ))?;
+        }
+
+        Ok(name)
+    }
+
+    /// Parse a name in master file format, RFC 1035 section 5.1. Names ending in a dot are
+    /// absolute, others are relative to the origin or to the root if there is no origin.
+    /// Special characters in labels are escaped as `\X` or as a decimal byte `\DDD`.
+    pub fn parse(name: &str, origin: Option<&DnsName>) -> Result<DnsName> {
+        if name.is_empty() {
+            Err(anyhow!("Domain name is empty"))?;
+        }
+
+        if name == "." {
+            return Ok(DnsName::default());
+        }
+
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut absolute = false;
+        let mut chars = name.char_indices().peekable();
+
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '.' => {
+                    if label.is_empty() {
+                        Err(anyhow!(
+                            "Empty label at position {} of domain name {}",
+                            position,
+                            name
+                        ))?;
+                    }
+                    labels.push(std::mem::take(&mut label));
+                    absolute = chars.peek().is_none();
+                }
+                '\\' => {
+                    let digits: String =
+                        std::iter::from_fn(|| chars.next_if(|(_, digit)| digit.is_ascii_digit()))
+                            .take(3)
+                            .map(|(_, digit)| digit)
+                            .collect();
+
+                    match digits.len() {
+                        0 => {
+                            let (_, escaped) = chars.next().ok_or_else(|| {
+                                anyhow!("Domain name {} ends with an incomplete escape", name)
+                            })?;
+                            let mut buf = [0; 4];
+                            label.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                        }
+                        3 => {
+                            let byte = digits.parse::<u8>().map_err(|_| {
+                                anyhow!(
+                                    "Escape \\{} at position {} of domain name {} exceeds 255",
+                                    digits,
+                                    position,
+                                    name
+                                )
+                            })?;
+                            label.push(byte);
+                        }
+                        _ => Err(anyhow!(
+                            "Escape at position {} of domain name {} needs three digits",
+                            position,
+                            name
+                        ))?,
+                    }
+                }
+                _ => {
+                    let mut buf = [0; 4];
+                    label.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+
+            if label.len() > MAX_LABEL_LENGTH {
+                Err(anyhow!(
+                    "Label at position {} of domain name {} exceeds {} bytes",
+                    position,
+                    name,
+                    MAX_LABEL_LENGTH
+                ))?;
+            }
+        }
+
+        if !label.is_empty() {
+            labels.push(label);
+        }
+
+        if !absolute {
+            if let Some(origin) = origin {
+                labels.extend(origin.labels.iter().cloned());
+            }
+        }
+
+        let name_length = DnsName::from_labels(labels.clone()).wire_size();
+        if name_length > MAX_NAME_LENGTH {
+            Err(anyhow!(
+                "Domain name {} is {} bytes long, at most {} are allowed",
+                name,
+                name_length,
+                MAX_NAME_LENGTH
+            ))?;
+        }
+
+        Ok(DnsName::from_labels(labels))
+    }
+
+    /// Unicode form of the name with punycode labels decoded. Labels that are not valid IDNA
+    /// are kept as they are on the wire.
+    pub fn to_unicode(&self) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|label| {
+                let ascii = String::from_utf8_lossy(label);
+                if !label.is_ascii() || !ascii.to_ascii_lowercase().starts_with("xn--") {
+                    return ascii.into_owned();
+                }
+
+                match idna::domain_to_unicode(&ascii) {
+                    (unicode, Ok(())) => unicode,
+                    (_, Err(_)) => ascii.into_owned(),
+                }
+            })
+            .collect();
+
+        labels.join(".")
+    }
+
+    /// Number of bytes of the uncompressed name on the wire
+    fn wire_size(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
     pub fn from_question(question: &DnsQuestion) -> Result<DnsName> {
         Ok(DnsName {
             labels: question.qname.labels.clone(),
@@ -786,23 +1203,137 @@ impl DnsName {
     }
 
     pub fn count(&self) -> usize {
+        self.label_count()
+    }
+
+    /// Number of labels not counting the root
+    pub fn label_count(&self) -> usize {
         self.labels.len()
     }
 
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Whether the first label is an asterisk, RFC 4592 section 2.1.1
+    pub fn is_wildcard(&self) -> bool {
+        self.labels.first().is_some_and(|label| label == b"*")
+    }
+
+    /// Whether the name equals or is below another name, compared without regard to case
+    pub fn is_subdomain_of(&self, other: &DnsName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self.labels[self.labels.len() - other.labels.len()..]
+                .iter()
+                .zip(&other.labels)
+                .all(|(label, other)| label.eq_ignore_ascii_case(other))
+    }
+
+    /// Name with the first label removed, None for the root
+    pub fn parent(&self) -> Option<DnsName> {
+        self.labels
+            .split_first()
+            .map(|(_, labels)| DnsName::from_labels(labels.to_vec()))
+    }
+
+    /// The name itself followed by each of its ancestors up to and including the root
+    pub fn ancestors(&self) -> impl Iterator<Item = DnsName> + '_ {
+        (0..=self.labels.len()).map(|skip| DnsName::from_labels(self.labels[skip..].to_vec()))
+    }
+
+    /// Longest name both names are equal to or below, the root if they share no labels
+    pub fn common_ancestor(&self, other: &DnsName) -> DnsName {
+        let shared = self
+            .labels
+            .iter()
+            .rev()
+            .zip(other.labels.iter().rev())
+            .take_while(|(label, other)| label.eq_ignore_ascii_case(other))
+            .count();
+
+        DnsName::from_labels(self.labels[self.labels.len() - shared..].to_vec())
+    }
+
+    /// Name with a label added in front, checked against the label and name length limits
+    pub fn prepend_label(&self, label: impl Into<Vec<u8>>) -> Result<DnsName> {
+        let label = label.into();
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            Err(anyhow!(
+                "Label of {} bytes can not be added to {}",
+                label.len(),
+                self
+            ))?;
+        }
+
+        let mut labels = vec![label];
+        labels.extend(self.labels.iter().cloned());
+        DnsName::from_labels(labels).checked()
+    }
+
+    /// Name with the labels of a suffix appended, typically to qualify a relative name
+    pub fn append(&self, suffix: &DnsName) -> Result<DnsName> {
+        let labels = self.labels.iter().chain(&suffix.labels).cloned();
+        DnsName::from_labels(labels).checked()
+    }
+
+    /// Wildcard name directly below this name, the source of synthesis for names this name is
+    /// the closest encloser of, RFC 4592 section 3.3.1
+    pub fn wildcard_of(&self) -> Result<DnsName> {
+        self.prepend_label("*")
+    }
+
+    /// Name with the suffix owner replaced by target, the substitution of a DNAME record owned
+    /// by owner, RFC 6672 section 2.2. Fails when the name is not below owner or the result
+    /// exceeds the name length limit.
+    pub fn substitute(&self, owner: &DnsName, target: &DnsName) -> Result<DnsName> {
+        if self.labels.len() == owner.labels.len() || !self.is_subdomain_of(owner) {
+            Err(anyhow!("Domain name {} is not below {}", self, owner))?;
+        }
+
+        let prefix = &self.labels[..self.labels.len() - owner.labels.len()];
+        let labels = prefix.iter().chain(&target.labels).cloned();
+        DnsName::from_labels(labels).checked()
+    }
+
+    fn checked(self) -> Result<DnsName> {
+        if self.wire_size() > MAX_NAME_LENGTH {
+            Err(anyhow!(
+                "Domain name {} exceeds {} bytes",
+                self,
+                MAX_NAME_LENGTH
+            ))?;
+        }
+
+        Ok(self)
+    }
+
     pub fn length(&self) -> usize {
         // If pointer is set then return 2 bytes for pointer
         if self.pointer != 0 {
             return 2;
         }
 
-        // Loop through labels and add length of each label
-        let mut length = 0;
-        for label in &self.labels {
-            // Add 1 byte for label length
-            length += label.len() + 1; // Add 1 byte for label length
-        }
+        self.wire_size()
+    }
+
+    /// Number of bytes the name at offset occupies in a message, a compression pointer ends it
+    pub fn wire_length(data: &[u8], offset: usize) -> Result<usize> {
+        let mut index = offset;
+        loop {
+            let label_length = *data
+                .get(index)
+                .ok_or_else(|| anyhow!("DNS name at offset {} exceeds message", offset))?;
 
-        length + 1 // Add 1 byte for null byte
+            if label_length == 0 {
+                return Ok(index + 1 - offset);
+            }
+
+            if label_length & 0b11000000 == 0b11000000 {
+                return Ok(index + 2 - offset);
+            }
+
+            index += label_length as usize + 1;
+        }
     }
 }
 
@@ -812,9 +1343,12 @@ impl DnsPacketData for DnsName {
 
         // Loop through bytes reading label length and then label then add to qname
         let mut index = offset;
+        let mut hops = 0;
 
         loop {
-            let label_length = data[index];
+            let label_length = *data
+                .get(index)
+                .ok_or_else(|| anyhow!("DNS name at offset {} exceeds message", offset))?;
 
             // Check if label is null byte and break loop
             if label_length == 0 {
@@ -823,36 +1357,39 @@ impl DnsPacketData for DnsName {
 
             // Check if label is a pointer to another label
             if label_length & 0b11000000 == 0b11000000 {
-                let pointer = ((label_length & 0b00111111) as u16) << 8 | data[index + 1] as u16;
+                let low = *data
+                    .get(index + 1)
+                    .ok_or_else(|| anyhow!("DNS name pointer at offset {} is truncated", index))?;
+                let pointer = ((label_length & 0b00111111) as u16) << 8 | low as u16;
 
-                // Else reference start of data to get label from question section
-                // Dirty hackery to get this to work
-                let question = DnsQuestion::from_bytes(data, 12).with_context(|| {
-                    format!("Failed to parse DNS question at offset {}", offset + 12)
-                })?;
-
-                // Check if pointer matches question section
-                if question.qname.offset == pointer {
-                    name.labels = question.qname.labels;
+                // A name that is only a pointer to the question name is serialized the same way
+                if index == offset && pointer == QUESTION_NAME_OFFSET {
                     name.pointer = pointer;
+                }
 
-                    return Ok(name);
+                hops += 1;
+                if hops > MAX_POINTER_HOPS {
+                    Err(anyhow!(
+                        "Too many compression pointers in DNS name at offset {}",
+                        offset
+                    ))?;
                 }
 
-                // Pointer found byt no matching question section
-                Err(anyhow!(
-                    "Failed to locate name pointer reference at offset {}",
-                    offset
-                ))?;
+                // Continue reading the labels the pointer references
+                index = pointer as usize;
+                continue;
             }
 
             let label_index = index + 1;
-            let label_bytes = &data[label_index..label_index + label_length as usize];
-            let label = String::from_utf8(label_bytes.to_vec()).unwrap_or_else(|_| "".to_string());
+            let label_bytes = data
+                .get(label_index..label_index + label_length as usize)
+                .ok_or_else(|| anyhow!("DNS label at offset {} exceeds message", index))?;
 
             // Add label to name
-            name.labels.push(label.to_owned());
-            name.offset = offset as u16;
+            name.labels.push(label_bytes.to_vec());
+            if hops == 0 {
+                name.offset = offset as u16;
+            }
 
             // Update index to end of label
             index = label_index + label_length as usize;
@@ -861,40 +1398,121 @@ impl DnsPacketData for DnsName {
         Ok(name)
     }
 
-    /// Convert a domain name to bytes using the format specified in RFC 1035 section 4.1.4
-    /// Use name compression if possible
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = Vec::<u8>::new();
+        to_vec(self)
+    }
 
+    /// Write a domain name using the format specified in RFC 1035 section 4.1.4
+    /// Use name compression if possible
+    fn write_to(&self, writer: &mut DnsWriter) -> Result<()> {
         // Add pointer reference if label has already been added
         if self.pointer != 0 {
-            data.push(0b11000000 | (self.pointer >> 8) as u8);
-            data.push(self.pointer as u8);
-
-            return Ok(data);
+            writer.write_u16(0b11000000 << 8 | self.pointer)?;
+            return Ok(());
         }
 
         // Loop through labels and add to data
         for label in &self.labels {
             // Add label length and label to data
-            data.push(label.len() as u8);
-            data.extend_from_slice(label.as_bytes());
+            writer.write_u8(label.len() as u8)?;
+            writer.write_bytes(label)?;
         }
 
         // Add null byte to end of name
-        data.push(0);
+        writer.write_u8(0)?;
+
+        Ok(())
+    }
+}
+
+/// Names are equal if their labels are equal ignoring ASCII case, RFC 4343
+impl PartialEq for DnsName {
+    fn eq(&self, other: &DnsName) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(label, other)| label.eq_ignore_ascii_case(other))
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for byte in label {
+                state.write_u8(byte.to_ascii_lowercase());
+            }
+        }
+    }
+}
 
-        Ok(data)
+/// Names are ordered canonically, RFC 4034 section 6.1, comparing labels from the root with
+/// uppercase ASCII letters treated as lowercase
+impl Ord for DnsName {
+    fn cmp(&self, other: &DnsName) -> Ordering {
+        for (label, other) in self.labels.iter().rev().zip(other.labels.iter().rev()) {
+            let ordering = label
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(other.iter().map(u8::to_ascii_lowercase));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        self.labels.len().cmp(&other.labels.len())
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &DnsName) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
+impl FromStr for DnsName {
+    type Err = anyhow::Error;
+
+    /// Parse a name in master file format, relative names are taken relative to the root
+    fn from_str(name: &str) -> Result<DnsName> {
+        DnsName::parse(name, None)
+    }
+}
+
+/// Display the name in master file format with special characters escaped so that it parses
+/// back to the same labels. The root is displayed as a single dot.
 impl Display for DnsName {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.labels.join("."))
+        if self.labels.is_empty() {
+            return f.write_char('.');
+        }
+
+        for (index, label) in self.labels.iter().enumerate() {
+            if index > 0 {
+                f.write_char('.')?;
+            }
+
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", byte as char)?
+                    }
+                    0x21..=0x7e => f.write_char(byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum DnsQType {
     #[default]
     A,
@@ -1179,6 +1797,130 @@ impl DnsQType {
             DnsQType::Unassigned => 0,
         }
     }
+
+    /// Mnemonic used for the type in master files, RFC 1035 section 5.1
+    pub fn name(&self) -> &'static str {
+        match self {
+            DnsQType::A => "A",
+            DnsQType::NS => "NS",
+            DnsQType::MD => "MD",
+            DnsQType::MF => "MF",
+            DnsQType::CNAME => "CNAME",
+            DnsQType::SOA => "SOA",
+            DnsQType::MB => "MB",
+            DnsQType::MG => "MG",
+            DnsQType::MR => "MR",
+            DnsQType::NULL => "NULL",
+            DnsQType::WKS => "WKS",
+            DnsQType::PTR => "PTR",
+            DnsQType::HINFO => "HINFO",
+            DnsQType::MINFO => "MINFO",
+            DnsQType::MX => "MX",
+            DnsQType::TXT => "TXT",
+            DnsQType::RP => "RP",
+            DnsQType::AFSDB => "AFSDB",
+            DnsQType::X25 => "X25",
+            DnsQType::ISDN => "ISDN",
+            DnsQType::RT => "RT",
+            DnsQType::NSAP => "NSAP",
+            DnsQType::NsapPtr => "NSAP-PTR",
+            DnsQType::SIG => "SIG",
+            DnsQType::KEY => "KEY",
+            DnsQType::PX => "PX",
+            DnsQType::GPOS => "GPOS",
+            DnsQType::AAAA => "AAAA",
+            DnsQType::LOC => "LOC",
+            DnsQType::NXT => "NXT",
+            DnsQType::EID => "EID",
+            DnsQType::NIMLOC => "NIMLOC",
+            DnsQType::SRV => "SRV",
+            DnsQType::ATMA => "ATMA",
+            DnsQType::NAPTR => "NAPTR",
+            DnsQType::KX => "KX",
+            DnsQType::CERT => "CERT",
+            DnsQType::A6 => "A6",
+            DnsQType::DNAME => "DNAME",
+            DnsQType::SINK => "SINK",
+            DnsQType::OPT => "OPT",
+            DnsQType::APL => "APL",
+            DnsQType::DS => "DS",
+            DnsQType::SSHFP => "SSHFP",
+            DnsQType::IPSECKEY => "IPSECKEY",
+            DnsQType::RRSIG => "RRSIG",
+            DnsQType::NSEC => "NSEC",
+            DnsQType::DNSKEY => "DNSKEY",
+            DnsQType::DHCID => "DHCID",
+            DnsQType::NSEC3 => "NSEC3",
+            DnsQType::NSEC3PARAM => "NSEC3PARAM",
+            DnsQType::TLSA => "TLSA",
+            DnsQType::SMIMEA => "SMIMEA",
+            DnsQType::HIP => "HIP",
+            DnsQType::NINFO => "NINFO",
+            DnsQType::RKEY => "RKEY",
+            DnsQType::TALINK => "TALINK",
+            DnsQType::CDS => "CDS",
+            DnsQType::CDNSKEY => "CDNSKEY",
+            DnsQType::OPENPGPKEY => "OPENPGPKEY",
+            DnsQType::CSYNC => "CSYNC",
+            DnsQType::ZONEMD => "ZONEMD",
+            DnsQType::SVCB => "SVCB",
+            DnsQType::HTTPS => "HTTPS",
+            DnsQType::SPF => "SPF",
+            DnsQType::UINFO => "UINFO",
+            DnsQType::UID => "UID",
+            DnsQType::GID => "GID",
+            DnsQType::UNSPEC => "UNSPEC",
+            DnsQType::NID => "NID",
+            DnsQType::L32 => "L32",
+            DnsQType::L64 => "L64",
+            DnsQType::LP => "LP",
+            DnsQType::EUI48 => "EUI48",
+            DnsQType::EUI64 => "EUI64",
+            DnsQType::TKEY => "TKEY",
+            DnsQType::TSIG => "TSIG",
+            DnsQType::IXFR => "IXFR",
+            DnsQType::AXFR => "AXFR",
+            DnsQType::MAILB => "MAILB",
+            DnsQType::MAILA => "MAILA",
+            DnsQType::ALL => "ANY",
+            DnsQType::URI => "URI",
+            DnsQType::CAA => "CAA",
+            DnsQType::AVC => "AVC",
+            DnsQType::DOA => "DOA",
+            DnsQType::AMTRELAY => "AMTRELAY",
+            DnsQType::TA => "TA",
+            DnsQType::DLV => "DLV",
+            DnsQType::Unassigned => "UNASSIGNED",
+        }
+    }
+}
+
+impl Display for DnsQType {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DnsQType {
+    type Err = anyhow::Error;
+
+    /// Parse a type mnemonic or the generic TYPEnnn notation of RFC 3597 section 5
+    fn from_str(name: &str) -> Result<DnsQType> {
+        let upper = name.to_ascii_uppercase();
+        let qtype = match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+            Some(Ok(code)) => DnsQType::from_u16(code),
+            _ => (1..=260)
+                .chain(32768..=32769)
+                .map(DnsQType::from_u16)
+                .find(|qtype| *qtype != DnsQType::Unassigned && qtype.name() == upper)
+                .unwrap_or(DnsQType::Unassigned),
+        };
+
+        match qtype {
+            DnsQType::Unassigned => Err(anyhow!("Unknown record type {}", name)),
+            qtype => Ok(qtype),
+        }
+    }
 }
 
 impl DnsPacketData for DnsQType {
@@ -1193,7 +1935,7 @@ impl DnsPacketData for DnsQType {
     }
 }
 
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum DnsClass {
     #[default]
     IN,
@@ -1237,6 +1979,36 @@ impl DnsClass {
     }
 }
 
+impl Display for DnsClass {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            DnsClass::IN => f.write_str("IN"),
+            DnsClass::CS => f.write_str("CS"),
+            DnsClass::CH => f.write_str("CH"),
+            DnsClass::HS => f.write_str("HS"),
+            DnsClass::NONE => f.write_str("NONE"),
+            DnsClass::ANY => f.write_str("ANY"),
+            DnsClass::Unassigned | DnsClass::Reserved => write!(f, "CLASS{}", self.to_u16()),
+        }
+    }
+}
+
+impl FromStr for DnsClass {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<DnsClass> {
+        match name.to_ascii_uppercase().as_str() {
+            "IN" => Ok(DnsClass::IN),
+            "CS" => Ok(DnsClass::CS),
+            "CH" => Ok(DnsClass::CH),
+            "HS" => Ok(DnsClass::HS),
+            "NONE" => Ok(DnsClass::NONE),
+            "ANY" => Ok(DnsClass::ANY),
+            _ => Err(anyhow!("Unknown record class {}", name)),
+        }
+    }
+}
+
 impl DnsPacketData for DnsClass {
     fn from_bytes(data: &[u8], offset: usize) -> Result<DnsClass> {
         let rclass = ((data[offset] as u16) << 8) | data[offset + 1] as u16;
@@ -1294,7 +2066,7 @@ mod tests {
         assert_eq!(request.header.arcount, 1);
         assert_eq!(
             request.question.qname.labels,
-            vec!["mycelnet".to_string(), "tech".to_string()]
+            vec![b"mycelnet".to_vec(), b"tech".to_vec()]
         );
         assert_eq!(request.question.qtype, DnsQType::A);
         assert_eq!(request.question.qclass, DnsClass::IN);
@@ -1312,6 +2084,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn decode_notify_request() -> Result<()> {
+        let data = vec![
+            0x12, 0x34, // ID
+            0x24, 0x00, // Flags
+            0x00, 0x01, // QDCOUNT
+            0x00, 0x01, // ANCOUNT
+            0x00, 0x00, // NSCOUNT
+            0x00, 0x01, // ARCOUNT
+            0x08, 0x6d, 0x79, 0x63, 0x65, 0x6c, 0x6e, 0x65, 0x74, 0x04, 0x74, 0x65, 0x63, 0x68,
+            0x00, 0x00, 0x06, 0x00, 0x01, // QNAME
+            0xc0, 0x0c, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x18, 0xc0, 0x0c,
+            0xc0, 0x0c, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x00, 0x03, 0x84,
+            0x00, 0x09, 0x3a, 0x80, 0x00, 0x00, 0x01, 0x2c, // ANs
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ARs
+        ];
+
+        let request = DnsRequest::from_bytes(&data, 0)?;
+
+        assert_eq!(request.header.flags.opcode, DnsOpcode::Notify);
+        assert_eq!(request.header.flags.aa, 1);
+        assert_eq!(request.question.qtype, DnsQType::SOA);
+
+        // The additional section follows the records of the answer section
+        let additional = request.additional.as_deref().unwrap();
+        assert_eq!(additional.len(), 1);
+        assert_eq!(additional[0].rtype, DnsQType::OPT);
+        assert!(request.edns()?.is_some());
+        let answers = request.answers.clone().unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rtype, DnsQType::SOA);
+
+        // Written again with the counts of the sections it holds
+        let mut request = request;
+        request.header.ancount = 0;
+        let written = DnsRequest::from_bytes(&request.to_bytes()?, 0)?;
+        assert_eq!(written.header.ancount, 1);
+        assert_eq!(written.header.nscount, 0);
+        assert_eq!(written.header.arcount, 1);
+        let answer = &written.answers.as_deref().unwrap()[0];
+        assert_eq!(answer.rtype, DnsQType::SOA);
+        assert_eq!(answer.rdata, answers[0].rdata);
+        assert!(written.edns()?.is_some());
+
+        Ok(())
+    }
+
     #[test]
     fn decode_response() -> Result<()> {
         let data = vec![
@@ -1328,9 +2147,8 @@ mod tests {
             0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x04, 0x68, 0x15,
             0x23, 0x92, // RRs
             0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x04, 0xac, 0x43,
-            0xb0,
-            0xb6, // RRs
-                  //0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ARs
+            0xb0, 0xb6, // RRs
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ARs
         ];
 
         let response = DnsResponse::from_bytes(&data, 0).with_context(|| {
@@ -1357,10 +2175,11 @@ mod tests {
         assert_eq!(response.header.arcount, 1);
         assert_eq!(response.question.qtype, DnsQType::A);
         assert_eq!(response.question.qclass, DnsClass::IN);
+        assert_eq!(response.edns()?.map(|opt| opt.udp_payload_size), Some(1232));
 
         assert_eq!(
             response.question.qname.labels,
-            vec!["mycelnet".to_string(), "tech".to_string()]
+            vec![b"mycelnet".to_vec(), b"tech".to_vec()]
         );
 
         assert_eq!(
@@ -1373,6 +2192,54 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn decode_compressed_names() -> Result<()> {
+        let data = vec![
+            0x44, 0x6f, // ID
+            0x81, 0x80, // Flags
+            0x00, 0x01, // QDCOUNT
+            0x00, 0x02, // ANCOUNT
+            0x00, 0x00, // NSCOUNT
+            0x00, 0x00, // ARCOUNT
+            0x03, 0x77, 0x77, 0x77, 0x08, 0x6d, 0x79, 0x63, 0x65, 0x6c, 0x6e, 0x65, 0x74, 0x04,
+            0x74, 0x65, 0x63, 0x68, 0x00, // QNAME
+            0x00, 0x01, // QTYPE
+            0x00, 0x01, // QCLASS
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x06, 0x03, 0x63,
+            0x64, 0x6e, 0xc0, 0x10, // CNAME to cdn.mycelnet.tech
+            0xc0, 0x2f, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 0x01, 0x02,
+            0x03, 0x04, // A record owned by the CNAME target
+        ];
+
+        let response = DnsResponse::from_bytes(&data, 0)
+            .with_context(|| "Failed to parse DNS response with compressed names".to_string())?;
+        let answers = response.answers.expect("Response has answers");
+
+        assert_eq!(answers[0].name.pointer, 12);
+        assert_eq!(answers[0].rtype, DnsQType::CNAME);
+        assert_eq!(
+            answers[0].rdata,
+            vec![
+                0x03, 0x63, 0x64, 0x6e, 0x08, 0x6d, 0x79, 0x63, 0x65, 0x6c, 0x6e, 0x65, 0x74, 0x04,
+                0x74, 0x65, 0x63, 0x68, 0x00
+            ]
+        );
+        assert_eq!(answers[0].rdlength, 19);
+
+        assert_eq!(answers[1].name.pointer, 0);
+        assert_eq!(
+            answers[1].name.labels,
+            vec![b"cdn".to_vec(), b"mycelnet".to_vec(), b"tech".to_vec()]
+        );
+        assert_eq!(answers[1].rdata, vec![0x01, 0x02, 0x03, 0x04]);
+
+        // Pointer loops and truncated messages are errors rather than panics
+        assert!(DnsName::from_bytes(&[0xc0, 0x00], 0).is_err());
+        assert!(DnsResponse::from_bytes(&data[..data.len() - 2], 0).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn decode_flags() -> Result<()> {
         let data = vec![0x81, 0x80];
@@ -1455,7 +2322,7 @@ mod tests {
 
         assert_eq!(
             question.qname.labels,
-            vec!["mycelnet".to_string(), "tech".to_string()]
+            vec![b"mycelnet".to_vec(), b"tech".to_vec()]
         );
         assert_eq!(question.qtype, DnsQType::A);
         assert_eq!(question.qclass, DnsClass::IN);
@@ -1484,10 +2351,7 @@ mod tests {
             )
         })?;
 
-        assert_eq!(
-            qname.labels,
-            vec!["mycelnet".to_string(), "tech".to_string()]
-        );
+        assert_eq!(qname.labels, vec![b"mycelnet".to_vec(), b"tech".to_vec()]);
 
         assert_eq!(
             qname
@@ -1498,4 +2362,266 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn decode_binary_label() -> Result<()> {
+        let data = [0x02, 0xff, 0x00, 0x04, 0x74, 0x65, 0x63, 0x68, 0x00];
+
+        let qname = DnsName::from_bytes(&data, 0)?;
+
+        assert_eq!(qname.labels, vec![vec![0xff, 0x00], b"tech".to_vec()]);
+        assert_eq!(qname.to_bytes()?, data.to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_text_names() -> Result<()> {
+        let name: DnsName = "www.mycelnet.tech.".parse()?;
+        assert_eq!(
+            name.labels,
+            vec![b"www".to_vec(), b"mycelnet".to_vec(), b"tech".to_vec()]
+        );
+
+        let origin: DnsName = "mycelnet.tech".parse()?;
+        let name = DnsName::parse("a\\.b.c", Some(&origin))?;
+        assert_eq!(
+            name.labels,
+            vec![
+                b"a.b".to_vec(),
+                b"c".to_vec(),
+                b"mycelnet".to_vec(),
+                b"tech".to_vec()
+            ]
+        );
+        assert_eq!(DnsName::parse("c.", Some(&origin))?.count(), 1);
+
+        let name: DnsName = "\\000\\255x\\ y".parse()?;
+        assert_eq!(name.labels, vec![vec![0x00, 0xff, b'x', b' ', b'y']]);
+
+        assert_eq!(".".parse::<DnsName>()?.count(), 0);
+
+        assert!("".parse::<DnsName>().is_err());
+        assert!("a..b".parse::<DnsName>().is_err());
+        assert!(".a".parse::<DnsName>().is_err());
+        assert!("a\\".parse::<DnsName>().is_err());
+        assert!("a\\25".parse::<DnsName>().is_err());
+        assert!("a\\256".parse::<DnsName>().is_err());
+        assert!("a".repeat(64).parse::<DnsName>().is_err());
+        assert!(vec!["a".repeat(63); 4]
+            .join(".")
+            .parse::<DnsName>()
+            .is_err());
+        assert!([
+            "a".repeat(63),
+            "a".repeat(63),
+            "a".repeat(63),
+            "a".repeat(61)
+        ]
+        .join(".")
+        .parse::<DnsName>()
+        .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn display_escaped_names() -> Result<()> {
+        let name = DnsName::from_labels([&b"a.b"[..], b"\\x", &[0x00, 0xff, b' '], b"tech"]);
+        let text = name.to_string();
+
+        assert_eq!(text, "a\\.b.\\\\x.\\000\\255\\032.tech");
+        assert_eq!(text.parse::<DnsName>()?.labels, name.labels);
+        assert_eq!(DnsName::default().to_string(), ".");
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_names_ignoring_case() -> Result<()> {
+        let lower: DnsName = "www.mycelnet.tech".parse()?;
+        let upper: DnsName = "WWW.MycelNet.TECH.".parse()?;
+        assert_eq!(lower, upper);
+        assert_ne!(lower, "www.mycelnet.tec".parse()?);
+
+        let mut zones = std::collections::HashMap::new();
+        zones.insert(lower, 1);
+        assert_eq!(zones.get(&upper), Some(&1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn walk_name_hierarchy() -> Result<()> {
+        let zone: DnsName = "mycelnet.tech".parse()?;
+        let name: DnsName = "a.B.MycelNet.tech".parse()?;
+
+        assert!(name.is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&zone));
+        assert!(name.is_subdomain_of(&DnsName::default()));
+        assert!(!zone.is_subdomain_of(&name));
+        assert!(!"net.tech".parse::<DnsName>()?.is_subdomain_of(&zone));
+
+        assert_eq!(name.label_count(), 4);
+        assert_eq!(name.parent(), Some("b.mycelnet.tech".parse()?));
+        assert_eq!(DnsName::default().parent(), None);
+
+        let ancestors: Vec<String> = name.ancestors().map(|name| name.to_string()).collect();
+        assert_eq!(
+            ancestors,
+            vec![
+                "a.B.MycelNet.tech",
+                "B.MycelNet.tech",
+                "MycelNet.tech",
+                "tech",
+                "."
+            ]
+        );
+
+        let other: DnsName = "c.d.mycelnet.tech".parse()?;
+        assert_eq!(name.common_ancestor(&other), zone);
+        assert!(name.common_ancestor(&"example".parse()?).is_root());
+
+        assert_eq!(zone.prepend_label("www")?, "www.mycelnet.tech".parse()?);
+        assert!(zone.prepend_label("").is_err());
+        assert_eq!(
+            "www".parse::<DnsName>()?.append(&zone)?,
+            "www.mycelnet.tech".parse()?
+        );
+        let long = DnsName::from_labels(vec!["a".repeat(63); 3]);
+        assert!(long.append(&long).is_err());
+
+        let wildcard = name.parent().unwrap().wildcard_of()?;
+        assert_eq!(wildcard, "*.b.mycelnet.tech".parse()?);
+        assert!(wildcard.is_wildcard());
+        assert!(!name.is_wildcard());
+
+        let target: DnsName = "mycelnet.net".parse()?;
+        assert_eq!(
+            name.substitute(&zone, &target)?,
+            "a.b.mycelnet.net".parse()?
+        );
+        assert_eq!(name.substitute(&zone, &".".parse()?)?, "a.b".parse()?);
+        assert!(zone.substitute(&zone, &target).is_err());
+        assert!(target.substitute(&zone, &target).is_err());
+        let longer = DnsName::from_labels(vec!["a".repeat(63); 4]);
+        assert!(name.substitute(&zone, &longer).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn convert_unicode_names() -> Result<()> {
+        let name = DnsName::from_unicode("Bücher.Example.")?;
+
+        assert_eq!(
+            name.labels,
+            vec![b"xn--bcher-kva".to_vec(), b"example".to_vec()]
+        );
+        assert_eq!(name.to_unicode(), "bücher.example");
+
+        assert_eq!(DnsName::from_unicode("_sip._udp.mycelnet.tech")?.count(), 4);
+        assert_eq!(DnsName::from_unicode(".")?.count(), 0);
+        assert!(DnsName::from_unicode("a..b").is_err());
+        assert!(DnsName::from_unicode(&"a".repeat(64)).is_err());
+
+        // Labels that are not valid punycode are left alone
+        assert_eq!(
+            DnsName::from_labels(["xn--", "tech"]).to_unicode(),
+            "xn--.tech"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_to_limit() -> Result<()> {
+        let mut request = DnsRequest::default();
+        request.question.qname = "mycelnet.tech".parse()?;
+        let mut response = DnsResponse::from_request(&request)?;
+
+        let record = DnsResourceRecord {
+            name: request.question.qname.clone(),
+            rdlength: 0,
+            rdata: vec![0; 100],
+            ..DnsResourceRecord::default()
+        };
+        response.answers = Some(vec![record.clone(); 4]);
+        response.set_edns(DnsOptRecord::new())?;
+
+        // Header, question, three answers and the OPT record
+        let limit = 12 + 19 + 3 * record.size() + 11;
+        let mut writer = DnsWriter::new(limit);
+        response.write_to(&mut writer)?;
+        assert_eq!(writer.len(), limit);
+
+        let truncated = DnsResponse::from_bytes(writer.as_bytes(), 0)?;
+        assert_eq!(truncated.header.flags.tc, 1);
+        assert_eq!(truncated.header.ancount, 3);
+        assert_eq!(truncated.answers.as_deref().unwrap()[2].rdlength, 100);
+        assert!(truncated.edns()?.is_some());
+
+        // Everything fits without the limit and the stale count in the header is replaced
+        response.header.ancount = 0;
+        let complete = DnsResponse::from_bytes(&response.to_bytes()?, 0)?;
+        assert_eq!(complete.header.flags.tc, 0);
+        assert_eq!(complete.header.ancount, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn order_names_canonically() -> Result<()> {
+        // The example of RFC 4034 section 6.1
+        let mut names: Vec<DnsName> = [
+            "z.example",
+            "a.example",
+            "*.z.example",
+            "yljkjljk.a.example",
+            "\\200.z.example",
+            "example",
+            "zABC.a.EXAMPLE",
+            "Z.a.example",
+            "\\001.z.example",
+        ]
+        .iter()
+        .map(|name| name.parse())
+        .collect::<Result<_>>()?;
+        names.sort();
+
+        let sorted: Vec<String> = names.iter().map(DnsName::to_string).collect();
+        assert_eq!(
+            sorted,
+            [
+                "example",
+                "a.example",
+                "yljkjljk.a.example",
+                "Z.a.example",
+                "zABC.a.EXAMPLE",
+                "z.example",
+                "\\001.z.example",
+                "*.z.example",
+                "\\200.z.example",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_type_mnemonics() -> Result<()> {
+        assert_eq!("aaaa".parse::<DnsQType>()?, DnsQType::AAAA);
+        assert_eq!("NSAP-PTR".parse::<DnsQType>()?, DnsQType::NsapPtr);
+        assert_eq!("TYPE65".parse::<DnsQType>()?, DnsQType::HTTPS);
+        assert_eq!("DLV".parse::<DnsQType>()?, DnsQType::DLV);
+        assert!("TYPE54".parse::<DnsQType>().is_err());
+        assert!("BOGUS".parse::<DnsQType>().is_err());
+        assert_eq!(DnsQType::ALL.to_string(), "ANY");
+
+        assert_eq!("in".parse::<DnsClass>()?, DnsClass::IN);
+        assert_eq!(DnsClass::CH.to_string(), "CH");
+        assert!("XX".parse::<DnsClass>().is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,301 @@
+//! Builder for responses that keeps the header consistent with the sections.
+//!
+//! [`DnsMessage::response_to`] copies what a response must echo from the query, records are
+//! added section by section and [`DnsMessage::build`] fills in the counts and record lengths.
+//! Combinations a client could not make sense of, such as answers to a failed query or an
+//! extended response code without EDNS, are rejected when the response is built.
+
+use anyhow::{anyhow, Result};
+
+use crate::edns::DnsOptRecord;
+use crate::{
+    DnsHeader, DnsQType, DnsQuestion, DnsRcode, DnsRequest, DnsResourceRecord, DnsResponse,
+};
+
+/// Highest response code that fits in the header without an OPT record
+const MAX_HEADER_RCODE: u16 = 15;
+
+#[derive(Debug, Clone)]
+pub struct DnsMessage {
+    header: DnsHeader,
+    question: DnsQuestion,
+    rcode: DnsRcode,
+    answers: Vec<DnsResourceRecord>,
+    authority: Vec<DnsResourceRecord>,
+    additional: Vec<DnsResourceRecord>,
+    edns: Option<DnsOptRecord>,
+}
+
+impl DnsMessage {
+    /// Start a response echoing the id, opcode, RD bit and question of a query
+    pub fn response_to(query: &DnsRequest) -> DnsMessage {
+        let mut header = DnsHeader {
+            id: query.header.id,
+            qdcount: 1,
+            ..DnsHeader::default()
+        };
+        header.flags.qr = 1;
+        header.flags.opcode = query.header.flags.opcode;
+        header.flags.rd = query.header.flags.rd;
+        header.flags.cd = query.header.flags.cd;
+
+        DnsMessage {
+            header,
+            question: query.question.clone(),
+            rcode: DnsRcode::NoError,
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
+        }
+    }
+
+    /// Start a response without question to a query that could not be read past its header,
+    /// echoing its id and opcode
+    pub fn error_to(header: &DnsHeader) -> DnsMessage {
+        let mut response = DnsMessage::response_to(&DnsRequest::default());
+        response.header.id = header.id;
+        response.header.qdcount = 0;
+        response.header.flags.opcode = header.flags.opcode;
+        response
+    }
+
+    pub fn rcode(mut self, rcode: DnsRcode) -> DnsMessage {
+        self.rcode = rcode;
+        self
+    }
+
+    /// Mark the response as coming from an authority for the zone of the question
+    pub fn authoritative(mut self, authoritative: bool) -> DnsMessage {
+        self.header.flags.aa = authoritative as u8;
+        self
+    }
+
+    pub fn recursion_available(mut self, available: bool) -> DnsMessage {
+        self.header.flags.ra = available as u8;
+        self
+    }
+
+    pub fn answer(mut self, record: DnsResourceRecord) -> DnsMessage {
+        self.answers.push(record);
+        self
+    }
+
+    pub fn answers(mut self, records: impl IntoIterator<Item = DnsResourceRecord>) -> DnsMessage {
+        self.answers.extend(records);
+        self
+    }
+
+    pub fn authority(mut self, record: DnsResourceRecord) -> DnsMessage {
+        self.authority.push(record);
+        self
+    }
+
+    pub fn authorities(
+        mut self,
+        records: impl IntoIterator<Item = DnsResourceRecord>,
+    ) -> DnsMessage {
+        self.authority.extend(records);
+        self
+    }
+
+    pub fn additional(mut self, record: DnsResourceRecord) -> DnsMessage {
+        self.additional.push(record);
+        self
+    }
+
+    pub fn additionals(
+        mut self,
+        records: impl IntoIterator<Item = DnsResourceRecord>,
+    ) -> DnsMessage {
+        self.additional.extend(records);
+        self
+    }
+
+    /// Add an OPT record, it is written last and carries the upper bits of the response code
+    pub fn edns(mut self, opt: DnsOptRecord) -> DnsMessage {
+        self.edns = Some(opt);
+        self
+    }
+
+    /// Build the response with counts and record lengths taken from the sections
+    pub fn build(self) -> Result<DnsResponse> {
+        if self.rcode.to_u16() > MAX_HEADER_RCODE && self.edns.is_none() {
+            Err(anyhow!(
+                "Response code {:?} requires an OPT record",
+                self.rcode
+            ))?;
+        }
+
+        // Only successful and negative answers carry records, RFC 1035 section 4.1.1
+        let failed = !matches!(self.rcode, DnsRcode::NoError | DnsRcode::NameError);
+        if failed && !self.answers.is_empty() {
+            Err(anyhow!(
+                "Response with response code {:?} cannot carry answers",
+                self.rcode
+            ))?;
+        }
+
+        let sections = [&self.answers, &self.authority, &self.additional];
+        for record in sections.into_iter().flatten() {
+            match record.rtype {
+                DnsQType::OPT => Err(anyhow!("OPT records must be added with edns"))?,
+                DnsQType::TSIG => Err(anyhow!("TSIG records are added when signing"))?,
+                _ => {}
+            }
+        }
+
+        let mut header = self.header;
+        header.flags.rcode = self.rcode;
+        header.ancount = count(&self.answers, "answer")?;
+        header.nscount = count(&self.authority, "authority")?;
+
+        let mut additional = with_rdlength(self.additional)?;
+        if let Some(mut opt) = self.edns {
+            opt.set_rcode(self.rcode);
+            additional.push(opt.to_record()?);
+        }
+        header.arcount = count(&additional, "additional")?;
+
+        let section = |records: Vec<DnsResourceRecord>| (!records.is_empty()).then_some(records);
+        Ok(DnsResponse {
+            header,
+            question: self.question,
+            answers: section(with_rdlength(self.answers)?),
+            authority: section(with_rdlength(self.authority)?),
+            additional: section(additional),
+        })
+    }
+}
+
+fn count(records: &[DnsResourceRecord], section: &str) -> Result<u16> {
+    u16::try_from(records.len())
+        .map_err(|_| anyhow!("Too many {section} records: {}", records.len()))
+}
+
+fn with_rdlength(mut records: Vec<DnsResourceRecord>) -> Result<Vec<DnsResourceRecord>> {
+    for record in &mut records {
+        record.rdlength = u16::try_from(record.rdata.len()).map_err(|_| {
+            anyhow!(
+                "DNS resource record data of {} bytes is too long",
+                record.rdata.len()
+            )
+        })?;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsPacketData;
+
+    fn query() -> DnsRequest {
+        let mut query = DnsRequest::default();
+        query.header.id = 0x4146;
+        query.question.qname = "mycelnet.tech".parse().unwrap();
+        query
+    }
+
+    fn record(rtype: DnsQType, rdata: &[u8]) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: query().question.qname,
+            rtype,
+            rdlength: 0,
+            rdata: rdata.to_vec(),
+            ..DnsResourceRecord::default()
+        }
+    }
+
+    #[test]
+    fn build_responses_without_question() -> Result<()> {
+        let mut header = query().header;
+        header.flags.opcode = crate::DnsOpcode::Notify;
+        let response = DnsMessage::error_to(&header)
+            .rcode(DnsRcode::FormatError)
+            .edns(DnsOptRecord::new())
+            .build()?;
+
+        let data = response.to_bytes()?;
+        // The header is followed by the OPT record, its empty owner name first
+        assert_eq!(data[12], 0);
+        let parsed = DnsResponse::from_bytes(&data, 0)?;
+        assert_eq!(parsed.header.id, 0x4146);
+        assert_eq!(parsed.header.flags.opcode, crate::DnsOpcode::Notify);
+        assert_eq!(parsed.header.flags.rcode, DnsRcode::FormatError);
+        assert_eq!((parsed.header.qdcount, parsed.header.arcount), (0, 1));
+        assert!(parsed.edns()?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_consistent_responses() -> Result<()> {
+        let response = DnsMessage::response_to(&query())
+            .authoritative(true)
+            .answer(record(DnsQType::A, &[127, 0, 0, 1]))
+            .authority(record(DnsQType::NS, &[0]))
+            .edns(DnsOptRecord::new())
+            .build()?;
+
+        assert_eq!(response.header.id, 0x4146);
+        assert_eq!(response.header.flags.qr, 1);
+        assert_eq!(response.header.flags.aa, 1);
+        assert_eq!(
+            (
+                response.header.ancount,
+                response.header.nscount,
+                response.header.arcount
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(response.answers.as_deref().unwrap()[0].rdlength, 4);
+
+        let parsed = DnsResponse::from_bytes(&response.to_bytes()?, 0)?;
+        assert_eq!(
+            parsed.answers.as_deref().unwrap()[0].rdata,
+            vec![127, 0, 0, 1]
+        );
+        assert!(parsed.edns()?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_inconsistent_responses() {
+        let answer = record(DnsQType::A, &[127, 0, 0, 1]);
+
+        assert!(DnsMessage::response_to(&query())
+            .rcode(DnsRcode::ServerFailure)
+            .answer(answer.clone())
+            .build()
+            .is_err());
+        assert!(DnsMessage::response_to(&query())
+            .rcode(DnsRcode::BadCookie)
+            .build()
+            .is_err());
+        assert!(DnsMessage::response_to(&query())
+            .additional(record(DnsQType::OPT, &[]))
+            .build()
+            .is_err());
+        assert!(DnsMessage::response_to(&query())
+            .answer(record(DnsQType::A, &[0; 65536]))
+            .build()
+            .is_err());
+
+        let response = DnsMessage::response_to(&query())
+            .rcode(DnsRcode::BadCookie)
+            .edns(DnsOptRecord::new())
+            .build()
+            .unwrap();
+        assert_eq!(
+            response
+                .edns()
+                .unwrap()
+                .unwrap()
+                .rcode(response.header.flags.rcode),
+            DnsRcode::BadCookie
+        );
+    }
+}
@@ -0,0 +1,896 @@
+//! Transaction signatures (TSIG) as specified in RFC 8945.
+//!
+//! Signing and verification operate on serialized messages since the MAC is computed over the
+//! exact bytes sent on the wire. A signed message is the unsigned message with a TSIG resource
+//! record appended to the additional section and ARCOUNT incremented.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::{DnsClass, DnsName, DnsPacketData, DnsQType, DnsRcode};
+
+/// Default permitted clock skew in seconds recommended by RFC 8945 section 10.
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// Maximum number of unsigned messages permitted between signed messages of a TCP stream.
+pub const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+/// Bytes of a TSIG record besides its names, MAC and other data: type, class, TTL and RDLENGTH
+/// followed by the time signed, fudge, MAC size, original ID, error and other length fields
+const FIXED_SIZE: usize = 10 + 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn from_name(name: &str) -> Option<TsigAlgorithm> {
+        match name.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Some(TsigAlgorithm::HmacSha256),
+            "hmac-sha384" => Some(TsigAlgorithm::HmacSha384),
+            "hmac-sha512" => Some(TsigAlgorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    /// Length in octets of an untruncated MAC
+    pub fn mac_length(&self) -> usize {
+        match self {
+            TsigAlgorithm::HmacSha256 => 32,
+            TsigAlgorithm::HmacSha384 => 48,
+            TsigAlgorithm::HmacSha512 => 64,
+        }
+    }
+
+    fn compute(&self, secret: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            TsigAlgorithm::HmacSha256 => compute_hmac::<Hmac<Sha256>>(secret, parts),
+            TsigAlgorithm::HmacSha384 => compute_hmac::<Hmac<Sha384>>(secret, parts),
+            TsigAlgorithm::HmacSha512 => compute_hmac::<Hmac<Sha512>>(secret, parts),
+        }
+    }
+}
+
+fn compute_hmac<M: Mac + hmac::digest::KeyInit>(secret: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compare two MACs without short circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    /// Name of the key, shared by both parties of the transaction.
+    pub name: DnsName,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: Vec<u8>) -> TsigKey {
+        TsigKey {
+            name: DnsName::from_labels(
+                name.trim_end_matches('.')
+                    .split('.')
+                    .filter(|label| !label.is_empty())
+                    .map(|label| label.to_ascii_lowercase()),
+            ),
+            algorithm,
+            secret,
+        }
+    }
+}
+
+/// Parse a key in the `[algorithm:]name:base64-secret` format accepted by `dig -y`.
+/// The algorithm defaults to hmac-sha256 when omitted.
+impl FromStr for TsigKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TsigKey> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts.as_slice() {
+            [name, secret] => (TsigAlgorithm::HmacSha256, *name, *secret),
+            [algorithm, name, secret] => (
+                TsigAlgorithm::from_name(algorithm)
+                    .ok_or_else(|| anyhow!("Unsupported TSIG algorithm {}", algorithm))?,
+                *name,
+                *secret,
+            ),
+            _ => Err(anyhow!(
+                "Invalid TSIG key {}, expected [algorithm:]name:secret",
+                s
+            ))?,
+        };
+
+        if name.trim_end_matches('.').is_empty() {
+            Err(anyhow!("TSIG key name must not be empty"))?;
+        }
+
+        let secret = BASE64
+            .decode(secret)
+            .with_context(|| format!("Failed to decode secret of TSIG key {}", name))?;
+
+        Ok(TsigKey::new(name, algorithm, secret))
+    }
+}
+
+/// A set of keys indexed by name used to authenticate incoming messages
+#[derive(Debug, Default, Clone)]
+pub struct TsigKeyRing {
+    keys: HashMap<DnsName, TsigKey>,
+}
+
+impl TsigKeyRing {
+    pub fn new(keys: impl IntoIterator<Item = TsigKey>) -> TsigKeyRing {
+        let mut ring = TsigKeyRing::default();
+        for key in keys {
+            ring.insert(key);
+        }
+
+        ring
+    }
+
+    pub fn insert(&mut self, key: TsigKey) {
+        self.keys.insert(key.name.clone(), key);
+    }
+
+    /// Look up a key by name, the algorithm must match the one the key was configured with
+    pub fn get(&self, name: &DnsName, algorithm: &DnsName) -> Option<&TsigKey> {
+        self.keys
+            .get(name)
+            .filter(|key| TsigAlgorithm::from_name(&algorithm.to_string()) == Some(key.algorithm))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TsigRecord {
+    /// Owner name of the record, the name of the key used.
+    pub key_name: DnsName,
+    /// Name of the MAC algorithm in domain name syntax.
+    pub algorithm: DnsName,
+    /// Seconds since the UNIX epoch when the message was signed, 48 bits on the wire.
+    pub time_signed: u64,
+    /// Permitted error in seconds of the time signed.
+    pub fudge: u16,
+    /// Message authentication code of the message.
+    pub mac: Vec<u8>,
+    /// ID of the message before any forwarder rewrote it.
+    pub original_id: u16,
+    /// Extended response code covering TSIG processing.
+    pub error: DnsRcode,
+    /// Empty unless error is BADTIME in which case it holds the server time.
+    pub other_data: Vec<u8>,
+}
+
+impl TsigRecord {
+    /// TSIG variables that follow the message in the MAC input, RFC 8945 section 4.3.3
+    fn variables(&self) -> Result<Vec<u8>> {
+        let mut data = canonical_name(&self.key_name)?;
+        data.extend(DnsClass::ANY.to_bytes()?);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend(canonical_name(&self.algorithm)?);
+        data.extend(self.timers());
+        data.extend_from_slice(&self.error.to_u16().to_be_bytes());
+        data.extend_from_slice(&(self.other_data.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.other_data);
+
+        Ok(data)
+    }
+
+    /// Time signed and fudge which make up the variables of subsequent messages of a stream
+    fn timers(&self) -> Vec<u8> {
+        let mut data = self.time_signed.to_be_bytes()[2..].to_vec();
+        data.extend_from_slice(&self.fudge.to_be_bytes());
+        data
+    }
+
+    fn rdata(&self) -> Result<Vec<u8>> {
+        let mut data = canonical_name(&self.algorithm)?;
+        data.extend(self.timers());
+        data.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.mac);
+        data.extend_from_slice(&self.original_id.to_be_bytes());
+        data.extend_from_slice(&self.error.to_u16().to_be_bytes());
+        data.extend_from_slice(&(self.other_data.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.other_data);
+
+        Ok(data)
+    }
+
+    /// Number of bytes the record occupies when appended to a message
+    pub fn size(&self) -> usize {
+        self.key_name.length()
+            + self.algorithm.length()
+            + FIXED_SIZE
+            + self.mac.len()
+            + self.other_data.len()
+    }
+
+    /// Serialize as a complete resource record ready to be appended to a message
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let rdata = self.rdata()?;
+
+        let mut data = canonical_name(&self.key_name)?;
+        data.extend(DnsQType::TSIG.to_bytes()?);
+        data.extend(DnsClass::ANY.to_bytes()?);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+
+        Ok(data)
+    }
+
+    /// Server time carried in the other data of a BADTIME response
+    pub fn server_time(&self) -> Option<u64> {
+        if self.other_data.len() != 6 {
+            return None;
+        }
+
+        let mut time = [0; 8];
+        time[2..].copy_from_slice(&self.other_data);
+        Some(u64::from_be_bytes(time))
+    }
+}
+
+fn canonical_name(name: &DnsName) -> Result<Vec<u8>> {
+    DnsName::from_labels(name.labels.iter().map(|label| label.to_ascii_lowercase())).to_bytes()
+}
+
+/// Failure to verify a TSIG record, each maps to the response the server must send
+#[derive(Debug, Clone, PartialEq)]
+pub enum TsigError {
+    /// The message or its TSIG record is malformed
+    FormatError(String),
+    /// The key is unknown or the algorithm does not match
+    BadKey,
+    /// The MAC did not verify
+    BadSignature,
+    /// The message was signed outside of the fudge window around the given server time
+    BadTime(u64),
+    /// The MAC was truncated below what local policy accepts
+    BadTruncation,
+}
+
+impl TsigError {
+    /// Value of the error field in the TSIG record of the response
+    pub fn error(&self) -> DnsRcode {
+        match self {
+            TsigError::FormatError(_) => DnsRcode::NoError,
+            TsigError::BadKey => DnsRcode::BadKey,
+            TsigError::BadSignature => DnsRcode::BadSignature,
+            TsigError::BadTime(_) => DnsRcode::BadTimestamp,
+            TsigError::BadTruncation => DnsRcode::BadTruncation,
+        }
+    }
+
+    /// Response code to set in the header of the response
+    pub fn rcode(&self) -> DnsRcode {
+        match self {
+            TsigError::FormatError(_) => DnsRcode::FormatError,
+            _ => DnsRcode::NotAuth,
+        }
+    }
+}
+
+impl Display for TsigError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            TsigError::FormatError(reason) => write!(f, "Malformed TSIG: {}", reason),
+            TsigError::BadKey => write!(f, "TSIG key not recognized"),
+            TsigError::BadSignature => write!(f, "TSIG signature failure"),
+            TsigError::BadTime(now) => write!(f, "TSIG signature out of time window at {}", now),
+            TsigError::BadTruncation => write!(f, "TSIG MAC truncated"),
+        }
+    }
+}
+
+impl std::error::Error for TsigError {}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("Unexpected end of message at offset {}", offset))
+}
+
+/// Locate the TSIG record of a message returning its offset and parsed contents.
+/// A TSIG record anywhere but last in the additional section is rejected.
+pub fn find_tsig(message: &[u8]) -> Result<Option<(usize, TsigRecord)>> {
+    if message.len() < 12 {
+        Err(anyhow!(
+            "Message of {} bytes is shorter than a header",
+            message.len()
+        ))?;
+    }
+
+    let qdcount = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)? as usize + read_u16(message, 8)? as usize;
+    let records = answers + read_u16(message, 10)? as usize;
+
+    let mut index = 12;
+    for _ in 0..qdcount {
+        index += DnsName::wire_length(message, index)? + 4;
+    }
+
+    for i in 0..records {
+        let start = index;
+        let name_end = index + DnsName::wire_length(message, index)?;
+        let rtype = read_u16(message, name_end)?;
+        let rdlength = read_u16(message, name_end + 8)? as usize;
+        index = name_end + 10 + rdlength;
+
+        if index > message.len() {
+            Err(anyhow!("Record at offset {} exceeds message", start))?;
+        }
+
+        if DnsQType::from_u16(rtype) != DnsQType::TSIG {
+            continue;
+        }
+
+        if i < answers {
+            Err(anyhow!(
+                "TSIG record at offset {} is not in the additional section",
+                start
+            ))?;
+        }
+        if i != records - 1 || index != message.len() {
+            Err(anyhow!(
+                "TSIG record at offset {} is not the last record",
+                start
+            ))?;
+        }
+
+        let key_name = DnsName::from_labels(DnsName::from_bytes(message, start)?.labels);
+        let record = parse_tsig_rdata(message, name_end + 10, rdlength, key_name)
+            .with_context(|| format!("Failed to parse TSIG record at offset {}", start))?;
+
+        return Ok(Some((start, record)));
+    }
+
+    Ok(None)
+}
+
+fn parse_tsig_rdata(
+    message: &[u8],
+    offset: usize,
+    rdlength: usize,
+    key_name: DnsName,
+) -> Result<TsigRecord> {
+    let rdata = &message[offset..offset + rdlength];
+    let algorithm = DnsName::from_labels(DnsName::from_bytes(message, offset)?.labels);
+    let mut index = DnsName::wire_length(message, offset)?;
+
+    let field = |at: usize, len: usize| {
+        rdata
+            .get(at..at + len)
+            .ok_or_else(|| anyhow!("TSIG record data truncated at offset {}", at))
+    };
+
+    let mut time = [0; 8];
+    time[2..].copy_from_slice(field(index, 6)?);
+    let fudge = read_u16(rdata, index + 6)?;
+    let mac_size = read_u16(rdata, index + 8)? as usize;
+    let mac = field(index + 10, mac_size)?.to_vec();
+    index += 10 + mac_size;
+    let original_id = read_u16(rdata, index)?;
+    let error = read_u16(rdata, index + 2)?;
+    let other_length = read_u16(rdata, index + 4)? as usize;
+    let other_data = field(index + 6, other_length)?.to_vec();
+
+    Ok(TsigRecord {
+        key_name,
+        algorithm,
+        time_signed: u64::from_be_bytes(time),
+        fudge,
+        mac,
+        original_id,
+        error: DnsRcode::from_u16(error),
+        other_data,
+    })
+}
+
+/// Remove the TSIG record at offset and restore the header the MAC was computed over
+fn strip_tsig(message: &[u8], offset: usize, original_id: u16) -> Result<Vec<u8>> {
+    let mut data = message[..offset].to_vec();
+    data[0..2].copy_from_slice(&original_id.to_be_bytes());
+    let arcount = u16::from_be_bytes([data[10], data[11]])
+        .checked_sub(1)
+        .ok_or_else(|| anyhow!("Additional section holding the TSIG record is empty"))?;
+    data[10..12].copy_from_slice(&arcount.to_be_bytes());
+    Ok(data)
+}
+
+/// Append a TSIG record to a message and increment ARCOUNT
+fn append_tsig(message: &[u8], record: &TsigRecord) -> Result<Vec<u8>> {
+    if message.len() < 12 {
+        Err(anyhow!(
+            "Message of {} bytes is shorter than a header",
+            message.len()
+        ))?;
+    }
+
+    let mut data = message.to_vec();
+    let arcount = u16::from_be_bytes([data[10], data[11]])
+        .checked_add(1)
+        .ok_or_else(|| anyhow!("Additional section is full"))?;
+    data[10..12].copy_from_slice(&arcount.to_be_bytes());
+    data.extend(record.to_bytes()?);
+
+    Ok(data)
+}
+
+fn prefixed_mac(mac: &[u8]) -> Vec<u8> {
+    let mut data = (mac.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(mac);
+    data
+}
+
+/// Signs a request, a response or every message of a multi-message response stream.
+/// The first message is signed with the full TSIG variables, subsequent messages are chained to
+/// the previous MAC and only cover the timers, RFC 8945 section 5.3.1.
+#[derive(Debug, Clone)]
+pub struct TsigSigner {
+    key: TsigKey,
+    fudge: u16,
+    prior_mac: Option<Vec<u8>>,
+    signed: usize,
+}
+
+impl TsigSigner {
+    /// Create a signer, responses must pass the MAC of the request they answer
+    pub fn new(key: TsigKey, request_mac: Option<Vec<u8>>) -> TsigSigner {
+        TsigSigner {
+            key,
+            fudge: DEFAULT_FUDGE,
+            prior_mac: request_mac,
+            signed: 0,
+        }
+    }
+
+    /// MAC of the most recently signed message
+    pub fn mac(&self) -> Option<&[u8]> {
+        self.prior_mac.as_deref()
+    }
+
+    /// Number of bytes the TSIG record appended by this signer occupies, space that must be
+    /// left free when a message is truncated to fit a size limit
+    pub fn record_size(&self, other_data_length: usize) -> usize {
+        self.key.name.length()
+            + self.key.algorithm.name().len()
+            + 2
+            + FIXED_SIZE
+            + self.key.algorithm.mac_length()
+            + other_data_length
+    }
+
+    pub fn sign(&mut self, message: &[u8], time_signed: u64) -> Result<Vec<u8>> {
+        self.sign_with_error(message, time_signed, DnsRcode::NoError, Vec::new())
+    }
+
+    /// Sign a message carrying a TSIG error, used for BADTIME responses
+    pub fn sign_with_error(
+        &mut self,
+        message: &[u8],
+        time_signed: u64,
+        error: DnsRcode,
+        other_data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if message.len() < 12 {
+            Err(anyhow!(
+                "Message of {} bytes is shorter than a header",
+                message.len()
+            ))?;
+        }
+
+        let mut record = TsigRecord {
+            key_name: self.key.name.clone(),
+            algorithm: DnsName::from_labels(vec![self.key.algorithm.name().to_string()]),
+            time_signed,
+            fudge: self.fudge,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error,
+            other_data,
+        };
+
+        let prior = self
+            .prior_mac
+            .as_deref()
+            .map(prefixed_mac)
+            .unwrap_or_default();
+        let variables = if self.signed == 0 {
+            record.variables()?
+        } else {
+            record.timers()
+        };
+
+        record.mac = self
+            .key
+            .algorithm
+            .compute(&self.key.secret, &[&prior, message, &variables]);
+        self.prior_mac = Some(record.mac.clone());
+        self.signed += 1;
+
+        append_tsig(message, &record)
+    }
+}
+
+/// Verifies a signed request, a response or every message of a multi-message response stream
+#[derive(Debug, Clone)]
+pub struct TsigVerifier {
+    key: TsigKey,
+    prior_mac: Option<Vec<u8>>,
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+    verified: usize,
+}
+
+impl TsigVerifier {
+    /// Create a verifier, responses must pass the MAC of the request they answer
+    pub fn new(key: TsigKey, request_mac: Option<Vec<u8>>) -> TsigVerifier {
+        TsigVerifier {
+            key,
+            prior_mac: request_mac,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+            verified: 0,
+        }
+    }
+
+    /// MAC of the most recently verified message
+    pub fn mac(&self) -> Option<&[u8]> {
+        self.prior_mac.as_deref()
+    }
+
+    /// Verify a message at the current time `now` returning its TSIG record.
+    /// Unsigned messages are accepted within a stream after the first message and are covered by
+    /// the MAC of the next signed message in which case `None` is returned.
+    pub fn verify(&mut self, message: &[u8], now: u64) -> Result<Option<TsigRecord>, TsigError> {
+        let (offset, record) = match find_tsig(message) {
+            Ok(Some(tsig)) => tsig,
+            Ok(None) if self.verified > 0 && self.unsigned_count < MAX_UNSIGNED_MESSAGES => {
+                self.unsigned.extend_from_slice(message);
+                self.unsigned_count += 1;
+                return Ok(None);
+            }
+            Ok(None) => return Err(TsigError::BadSignature),
+            Err(e) => return Err(TsigError::FormatError(format!("{:#}", e))),
+        };
+
+        if record.key_name != self.key.name
+            || TsigAlgorithm::from_name(&record.algorithm.to_string()) != Some(self.key.algorithm)
+        {
+            return Err(TsigError::BadKey);
+        }
+
+        let full_length = self.key.algorithm.mac_length();
+        if record.mac.len() > full_length || record.mac.len() < (full_length / 2).max(10) {
+            return Err(TsigError::FormatError(format!(
+                "MAC size {} is invalid for {}",
+                record.mac.len(),
+                self.key.algorithm.name()
+            )));
+        }
+
+        let stripped = strip_tsig(message, offset, record.original_id)
+            .map_err(|e| TsigError::FormatError(format!("{:#}", e)))?;
+        let prior = self
+            .prior_mac
+            .as_deref()
+            .map(prefixed_mac)
+            .unwrap_or_default();
+        let variables = if self.verified == 0 {
+            record
+                .variables()
+                .map_err(|e| TsigError::FormatError(format!("{:#}", e)))?
+        } else {
+            record.timers()
+        };
+
+        let mac = self.key.algorithm.compute(
+            &self.key.secret,
+            &[&prior, &self.unsigned, &stripped, &variables],
+        );
+
+        if !constant_time_eq(&mac[..record.mac.len()], &record.mac) {
+            return Err(TsigError::BadSignature);
+        }
+
+        if record.mac.len() < full_length {
+            return Err(TsigError::BadTruncation);
+        }
+
+        if now.abs_diff(record.time_signed) > record.fudge as u64 {
+            return Err(TsigError::BadTime(now));
+        }
+
+        self.prior_mac = Some(record.mac.clone());
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+        self.verified += 1;
+
+        Ok(Some(record))
+    }
+}
+
+/// Append an unsigned TSIG record carrying an error, used for BADKEY and BADSIG responses
+pub fn append_tsig_error(message: &[u8], request: &TsigRecord, error: DnsRcode) -> Result<Vec<u8>> {
+    let record = TsigRecord {
+        key_name: request.key_name.clone(),
+        algorithm: request.algorithm.clone(),
+        time_signed: request.time_signed,
+        fudge: request.fudge,
+        mac: Vec::new(),
+        original_id: u16::from_be_bytes([message[0], message[1]]),
+        error,
+        other_data: Vec::new(),
+    };
+
+    append_tsig(message, &record)
+}
+
+/// Encode a time as the six octets of other data used in BADTIME responses
+pub fn time_other_data(time: u64) -> Vec<u8> {
+    time.to_be_bytes()[2..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a2V5LWZvci10ZXN0aW5nLXRzaWctc2lnbmF0dXJlcw==";
+
+    fn key() -> TsigKey {
+        format!("hmac-sha256:transfer.mycelnet.tech:{}", SECRET)
+            .parse()
+            .unwrap()
+    }
+
+    fn query() -> Vec<u8> {
+        vec![
+            0x12, 0x34, // ID
+            0x01, 0x00, // Flags
+            0x00, 0x01, // QDCOUNT
+            0x00, 0x00, // ANCOUNT
+            0x00, 0x00, // NSCOUNT
+            0x00, 0x00, // ARCOUNT
+            0x08, 0x6d, 0x79, 0x63, 0x65, 0x6c, 0x6e, 0x65, 0x74, 0x04, 0x74, 0x65, 0x63, 0x68,
+            0x00, // QNAME
+            0x00, 0xfc, // QTYPE
+            0x00, 0x01, // QCLASS
+        ]
+    }
+
+    #[test]
+    fn parse_key() -> Result<()> {
+        let key = key();
+        assert_eq!(key.name.to_string(), "transfer.mycelnet.tech");
+        assert_eq!(key.algorithm, TsigAlgorithm::HmacSha256);
+        assert_eq!(key.secret, b"key-for-testing-tsig-signatures");
+
+        let key: TsigKey = format!("Other.Key.:{}", SECRET).parse()?;
+        assert_eq!(key.name.to_string(), "other.key");
+        assert!("hmac-md5:name:secret".parse::<TsigKey>().is_err());
+        assert!(format!("hmac-sha256::{}", SECRET)
+            .parse::<TsigKey>()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sign_and_verify_request() -> Result<()> {
+        let now = 1_700_000_000;
+        let signed = TsigSigner::new(key(), None).sign(&query(), now)?;
+
+        assert_eq!(&signed[10..12], &[0x00, 0x01]);
+        let (offset, record) = find_tsig(&signed)?.expect("TSIG record is appended");
+        assert_eq!(offset, query().len());
+        assert_eq!(record.time_signed, now);
+        assert_eq!(record.original_id, 0x1234);
+        assert_eq!(record.mac.len(), 32);
+
+        let verified = TsigVerifier::new(key(), None).verify(&signed, now + 10)?;
+        assert!(verified.is_some());
+
+        let mut tampered = signed.clone();
+        tampered[20] ^= 0x20;
+        assert_eq!(
+            TsigVerifier::new(key(), None)
+                .verify(&tampered, now)
+                .unwrap_err(),
+            TsigError::BadSignature
+        );
+
+        assert_eq!(
+            TsigVerifier::new(key(), None)
+                .verify(&signed, now + 301)
+                .unwrap_err(),
+            TsigError::BadTime(now + 301)
+        );
+
+        let other: TsigKey = format!("hmac-sha512:transfer.mycelnet.tech:{}", SECRET).parse()?;
+        assert_eq!(
+            TsigVerifier::new(other, None)
+                .verify(&signed, now)
+                .unwrap_err(),
+            TsigError::BadKey
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_rewritten_id() -> Result<()> {
+        let now = 1_700_000_000;
+        let mut signed = TsigSigner::new(key(), None).sign(&query(), now)?;
+        signed[0] = 0xab;
+
+        assert!(TsigVerifier::new(key(), None).verify(&signed, now).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sign_and_verify_stream() -> Result<()> {
+        let now = 1_700_000_000;
+        let request = TsigSigner::new(key(), None).sign(&query(), now)?;
+        let (_, request_tsig) = find_tsig(&request)?.unwrap();
+
+        let mut response = query();
+        response[2] |= 0x80;
+
+        let mut signer = TsigSigner::new(key(), Some(request_tsig.mac.clone()));
+        let first = signer.sign(&response, now)?;
+        let second = signer.sign(&response, now + 1)?;
+        let third = signer.sign(&response, now + 2)?;
+
+        let mut verifier = TsigVerifier::new(key(), Some(request_tsig.mac.clone()));
+        assert!(verifier.verify(&first, now)?.is_some());
+        assert!(verifier.verify(&second, now)?.is_some());
+        assert!(verifier.verify(&third, now)?.is_some());
+
+        // Subsequent messages are chained so they cannot be verified out of order
+        let mut verifier = TsigVerifier::new(key(), Some(request_tsig.mac.clone()));
+        assert!(verifier.verify(&first, now)?.is_some());
+        assert_eq!(
+            verifier.verify(&third, now).unwrap_err(),
+            TsigError::BadSignature
+        );
+
+        // The first message of a stream must be signed
+        let mut verifier = TsigVerifier::new(key(), Some(request_tsig.mac));
+        assert_eq!(
+            verifier.verify(&response, now).unwrap_err(),
+            TsigError::BadSignature
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_stream_with_unsigned_messages() -> Result<()> {
+        let now = 1_700_000_000;
+        let mut response = query();
+        response[2] |= 0x80;
+
+        let mut signer = TsigSigner::new(key(), None);
+        let first = signer.sign(&response, now)?;
+
+        // Sign the concatenation of two messages to emulate an unsigned message in between
+        let mut covered = response.clone();
+        covered.extend_from_slice(&response);
+        let prior = prefixed_mac(signer.mac().unwrap());
+        let mut record = TsigRecord {
+            key_name: key().name,
+            algorithm: DnsName::from_labels(["hmac-sha256"]),
+            time_signed: now,
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id: 0x1234,
+            error: DnsRcode::NoError,
+            other_data: Vec::new(),
+        };
+        record.mac = key()
+            .algorithm
+            .compute(&key().secret, &[&prior, &covered, &record.timers()]);
+        let third = append_tsig(&response, &record)?;
+
+        let mut verifier = TsigVerifier::new(key(), None);
+        assert!(verifier.verify(&first, now)?.is_some());
+        assert!(verifier.verify(&response, now)?.is_none());
+        assert!(verifier.verify(&third, now)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_misplaced_tsig() -> Result<()> {
+        let signed = TsigSigner::new(key(), None).sign(&query(), 1_700_000_000)?;
+
+        let mut extended = signed.clone();
+        extended[11] = 2;
+        extended.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+
+        assert!(find_tsig(&extended).is_err());
+        assert!(find_tsig(&signed[..signed.len() - 1]).is_err());
+
+        // Moved to the answer section with ARCOUNT 0
+        let mut answered = signed.clone();
+        answered[7] = 1;
+        answered[11] = 0;
+        assert!(find_tsig(&answered).is_err());
+        assert!(matches!(
+            TsigVerifier::new(key(), None).verify(&answered, 1_700_000_000),
+            Err(TsigError::FormatError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reserve_record_size() -> Result<()> {
+        let unsigned = query();
+        let mut signer = TsigSigner::new(key(), None);
+        let signed = signer.sign(&unsigned, 1_700_000_000)?;
+        assert_eq!(signed.len() - unsigned.len(), signer.record_size(0));
+
+        let (_, record) = find_tsig(&signed)?.unwrap();
+        assert_eq!(record.size(), record.to_bytes()?.len());
+
+        let other_data = time_other_data(1_700_000_000);
+        let signed = signer.sign_with_error(
+            &unsigned,
+            1_700_000_000,
+            DnsRcode::BadTimestamp,
+            other_data.clone(),
+        )?;
+        assert_eq!(
+            signed.len() - unsigned.len(),
+            signer.record_size(other_data.len())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_ring_lookup() {
+        let ring = TsigKeyRing::new([key()]);
+        let name = DnsName::from_labels(["Transfer", "MycelNet", "tech"]);
+        let algorithm = |name: &str| DnsName::from_labels([name]);
+
+        assert!(ring.get(&name, &algorithm("hmac-sha256")).is_some());
+        assert!(ring.get(&name, &algorithm("hmac-sha512")).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+//! Builder for dynamic update requests, RFC 2136.
+//!
+//! An update names the zone it changes, lists prerequisites the zone must meet and the records
+//! to add or delete. Both kinds of entries are resource records whose class and TTL say what
+//! they mean, RFC 2136 sections 2.4 and 2.5, so [`UpdateBuilder`] has one method per meaning
+//! instead of taking raw records. Requests can be signed with TSIG, RFC 3007.
+
+use anyhow::{anyhow, Result};
+
+use crate::tsig::{TsigKey, TsigSigner, TsigVerifier};
+use crate::writer::{DnsWriter, MAX_MESSAGE_SIZE};
+use crate::{
+    DnsClass, DnsHeader, DnsName, DnsOpcode, DnsPacketData, DnsQType, DnsQuestion,
+    DnsResourceRecord,
+};
+
+#[derive(Debug, Clone)]
+pub struct UpdateBuilder {
+    id: u16,
+    zone: DnsName,
+    class: DnsClass,
+    prerequisites: Vec<DnsResourceRecord>,
+    updates: Vec<DnsResourceRecord>,
+    additional: Vec<DnsResourceRecord>,
+}
+
+impl UpdateBuilder {
+    /// Start an update of a zone of class IN
+    pub fn new(zone: DnsName) -> UpdateBuilder {
+        UpdateBuilder {
+            id: 0,
+            zone,
+            class: DnsClass::IN,
+            prerequisites: Vec::new(),
+            updates: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> UpdateBuilder {
+        self.id = id;
+        self
+    }
+
+    pub fn class(mut self, class: DnsClass) -> UpdateBuilder {
+        self.class = class;
+        self
+    }
+
+    /// Require an RRset of the type to exist at the name, whatever its records
+    pub fn rrset_exists(mut self, name: DnsName, rtype: DnsQType) -> UpdateBuilder {
+        self.prerequisites
+            .push(meta_record(name, rtype, DnsClass::ANY));
+        self
+    }
+
+    /// Require the RRset of a record to exist with exactly the records given this way
+    pub fn rrset_exists_with(mut self, mut record: DnsResourceRecord) -> UpdateBuilder {
+        record.rclass = self.class;
+        record.ttl = 0;
+        self.prerequisites.push(record);
+        self
+    }
+
+    /// Require no RRset of the type to exist at the name
+    pub fn rrset_absent(mut self, name: DnsName, rtype: DnsQType) -> UpdateBuilder {
+        self.prerequisites
+            .push(meta_record(name, rtype, DnsClass::NONE));
+        self
+    }
+
+    /// Require the name to own at least one record
+    pub fn name_in_use(mut self, name: DnsName) -> UpdateBuilder {
+        self.prerequisites
+            .push(meta_record(name, DnsQType::ALL, DnsClass::ANY));
+        self
+    }
+
+    /// Require the name to own no records
+    pub fn name_absent(mut self, name: DnsName) -> UpdateBuilder {
+        self.prerequisites
+            .push(meta_record(name, DnsQType::ALL, DnsClass::NONE));
+        self
+    }
+
+    /// Add a record to its RRset, the TTL of the record becomes the TTL of the RRset
+    pub fn add_record(mut self, mut record: DnsResourceRecord) -> UpdateBuilder {
+        record.rclass = self.class;
+        self.updates.push(record);
+        self
+    }
+
+    /// Delete the RRset of the type at the name
+    pub fn delete_rrset(mut self, name: DnsName, rtype: DnsQType) -> UpdateBuilder {
+        self.updates.push(meta_record(name, rtype, DnsClass::ANY));
+        self
+    }
+
+    /// Delete every RRset at the name
+    pub fn delete_name(mut self, name: DnsName) -> UpdateBuilder {
+        self.updates
+            .push(meta_record(name, DnsQType::ALL, DnsClass::ANY));
+        self
+    }
+
+    /// Delete a record from its RRset, matching the name, type and data
+    pub fn delete_record(mut self, mut record: DnsResourceRecord) -> UpdateBuilder {
+        record.rclass = DnsClass::NONE;
+        record.ttl = 0;
+        self.updates.push(record);
+        self
+    }
+
+    /// Add a record to the additional section, for instance glue for added NS records
+    pub fn additional(mut self, record: DnsResourceRecord) -> UpdateBuilder {
+        self.additional.push(record);
+        self
+    }
+
+    /// Serialize the update with the zone, prerequisite, update and additional sections
+    pub fn build(self) -> Result<Vec<u8>> {
+        let sections = [&self.prerequisites, &self.updates];
+        for record in sections.into_iter().flatten() {
+            if !record.name.is_subdomain_of(&self.zone) {
+                Err(anyhow!(
+                    "{} is outside of the zone {}",
+                    record.name,
+                    self.zone
+                ))?;
+            }
+        }
+        for record in &self.additional {
+            if record.rtype == DnsQType::TSIG {
+                Err(anyhow!("TSIG records are added when signing"))?;
+            }
+        }
+
+        let mut header = DnsHeader {
+            id: self.id,
+            qdcount: 1,
+            ancount: count(&self.prerequisites, "prerequisite")?,
+            nscount: count(&self.updates, "update")?,
+            arcount: count(&self.additional, "additional")?,
+            ..DnsHeader::default()
+        };
+        header.flags.opcode = DnsOpcode::Update;
+        // The zone section has the format of a question, RFC 2136 section 2.3
+        let zone = DnsQuestion {
+            qname: self.zone,
+            qtype: DnsQType::SOA,
+            qclass: self.class,
+        };
+
+        let mut writer = DnsWriter::new(MAX_MESSAGE_SIZE);
+        header.write_to(&mut writer)?;
+        zone.write_to(&mut writer)?;
+        let sections = [&self.prerequisites, &self.updates, &self.additional];
+        for record in sections.into_iter().flatten() {
+            record.write_to(&mut writer)?;
+        }
+
+        Ok(writer.into_bytes())
+    }
+
+    /// Serialize and sign the update, returns the verifier for the response of the server
+    pub fn build_signed(self, key: TsigKey, time_signed: u64) -> Result<(Vec<u8>, TsigVerifier)> {
+        let data = self.build()?;
+
+        let mut signer = TsigSigner::new(key.clone(), None);
+        let data = signer.sign(&data, time_signed)?;
+        let verifier = TsigVerifier::new(key, signer.mac().map(<[u8]>::to_vec));
+
+        Ok((data, verifier))
+    }
+}
+
+/// An entry standing for an RRset or every RRset at a name rather than for a record
+fn meta_record(name: DnsName, rtype: DnsQType, rclass: DnsClass) -> DnsResourceRecord {
+    DnsResourceRecord {
+        name,
+        rtype,
+        rclass,
+        ttl: 0,
+        rdlength: 0,
+        rdata: Vec::new(),
+    }
+}
+
+fn count(records: &[DnsResourceRecord], section: &str) -> Result<u16> {
+    u16::try_from(records.len())
+        .map_err(|_| anyhow!("Too many {section} records: {}", records.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view::{DnsMessageRef, DnsSection};
+    use crate::DnsRequest;
+
+    const SECRET: &str = "a2V5LWZvci10ZXN0aW5nLXRzaWctc2lnbmF0dXJlcw==";
+
+    fn name(name: &str) -> DnsName {
+        name.parse().unwrap()
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: name(owner),
+            rdlength: 4,
+            rdata: address.to_vec(),
+            ..DnsResourceRecord::default()
+        }
+    }
+
+    #[test]
+    fn build_updates() -> Result<()> {
+        let data = UpdateBuilder::new(name("mycelnet.tech"))
+            .id(0x2136)
+            .name_in_use(name("mycelnet.tech"))
+            .rrset_absent(name("web.mycelnet.tech"), DnsQType::CNAME)
+            .rrset_exists_with(a("ns1.mycelnet.tech", [192, 0, 2, 53]))
+            .delete_rrset(name("web.mycelnet.tech"), DnsQType::AAAA)
+            .delete_record(a("web.mycelnet.tech", [192, 0, 2, 1]))
+            .add_record(a("web.mycelnet.tech", [192, 0, 2, 2]))
+            .build()?;
+
+        let request = DnsRequest::from_bytes(&data, 0)?;
+        assert_eq!(request.header.id, 0x2136);
+        assert_eq!(request.header.flags.opcode, DnsOpcode::Update);
+        assert_eq!(request.question.qname, name("mycelnet.tech"));
+        assert_eq!(request.question.qtype, DnsQType::SOA);
+
+        let message = DnsMessageRef::new(&data)?;
+        let records = message
+            .records()
+            .map(|record| {
+                let record = record?;
+                let section = record.section();
+                Ok((section, record.to_record()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let entries: Vec<_> = records
+            .iter()
+            .map(|(section, record)| (*section, record.rtype, record.rclass, record.ttl))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (DnsSection::Answer, DnsQType::ALL, DnsClass::ANY, 0),
+                (DnsSection::Answer, DnsQType::CNAME, DnsClass::NONE, 0),
+                (DnsSection::Answer, DnsQType::A, DnsClass::IN, 0),
+                (DnsSection::Authority, DnsQType::AAAA, DnsClass::ANY, 0),
+                (DnsSection::Authority, DnsQType::A, DnsClass::NONE, 0),
+                (DnsSection::Authority, DnsQType::A, DnsClass::IN, 300),
+            ]
+        );
+        assert_eq!(records[4].1.rdata, vec![192, 0, 2, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_updates() {
+        assert!(UpdateBuilder::new(name("mycelnet.tech"))
+            .add_record(a("www.mycelnet.net", [192, 0, 2, 1]))
+            .build()
+            .is_err());
+        assert!(UpdateBuilder::new(name("mycelnet.tech"))
+            .name_absent(name("mycelnet.net"))
+            .build()
+            .is_err());
+        assert!(UpdateBuilder::new(name("mycelnet.tech"))
+            .additional(DnsResourceRecord {
+                rtype: DnsQType::TSIG,
+                ..DnsResourceRecord::default()
+            })
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn sign_updates() -> Result<()> {
+        let key: TsigKey = format!("update:{SECRET}").parse()?;
+        let (data, _) = UpdateBuilder::new(name("mycelnet.tech"))
+            .add_record(a("web.mycelnet.tech", [192, 0, 2, 2]))
+            .build_signed(key.clone(), 1_700_000_000)?;
+
+        let message = DnsMessageRef::new(&data)?;
+        assert_eq!(message.header().arcount, 1);
+        let mut verifier = TsigVerifier::new(key, None);
+        assert!(verifier.verify(&data, 1_700_000_000)?.is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+//! Borrowed views of DNS messages that are parsed on demand.
+//!
+//! A [`DnsMessageRef`] only decodes the header up front. Questions and records are located by
+//! walking the datagram while they are iterated, and their names are [`DnsNameRef`]s that follow
+//! compression pointers as the labels are read. Nothing is copied until one of the `to_*`
+//! conversions into the owned types of this crate is called.
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Result};
+
+use crate::edns::DnsOptRecord;
+use crate::{
+    DnsClass, DnsHeader, DnsName, DnsPacketData, DnsQType, DnsQuestion, DnsRequest,
+    DnsResourceRecord, DnsResponse, MAX_POINTER_HOPS,
+};
+
+/// Section of a message a record was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsSection {
+    Answer,
+    Authority,
+    Additional,
+}
+
+const SECTIONS: [DnsSection; 3] = [
+    DnsSection::Answer,
+    DnsSection::Authority,
+    DnsSection::Additional,
+];
+
+/// A message borrowed from the buffer it was received in
+#[derive(Debug, Clone)]
+pub struct DnsMessageRef<'a> {
+    data: &'a [u8],
+    header: DnsHeader,
+}
+
+impl<'a> DnsMessageRef<'a> {
+    pub fn new(data: &'a [u8]) -> Result<DnsMessageRef<'a>> {
+        let header = DnsHeader::from_bytes(data, 0)?;
+        Ok(DnsMessageRef { data, header })
+    }
+
+    pub fn header(&self) -> &DnsHeader {
+        &self.header
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            data: self.data,
+            index: 12,
+            remaining: self.header.qdcount,
+            failed: false,
+        }
+    }
+
+    /// The first question, queries in practice carry exactly one
+    pub fn question(&self) -> Result<Option<QuestionRef<'a>>> {
+        self.questions().next().transpose()
+    }
+
+    /// Records of all sections in the order they appear in the message
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            data: self.data,
+            index: 12,
+            questions: self.header.qdcount,
+            remaining: [
+                self.header.ancount,
+                self.header.nscount,
+                self.header.arcount,
+            ],
+            section: 0,
+            failed: false,
+        }
+    }
+
+    /// Records of a single section
+    pub fn section(&self, section: DnsSection) -> impl Iterator<Item = Result<RecordRef<'a>>> {
+        self.records().filter(move |record| {
+            record
+                .as_ref()
+                .map_or(true, |record| record.section() == section)
+        })
+    }
+
+    /// Parse the OPT record of the additional section, more than one is an error
+    pub fn edns(&self) -> Result<Option<DnsOptRecord>> {
+        let mut opt = None;
+        for record in self.section(DnsSection::Additional) {
+            let record = record?;
+            if record.rtype() != DnsQType::OPT {
+                continue;
+            }
+
+            if opt.is_some() {
+                Err(anyhow!("Message contains more than one OPT record"))?;
+            }
+            opt = Some(record.to_opt()?);
+        }
+
+        Ok(opt)
+    }
+
+    pub fn to_request(&self) -> Result<DnsRequest> {
+        DnsRequest::from_bytes(self.data, 0)
+    }
+
+    pub fn to_response(&self) -> Result<DnsResponse> {
+        DnsResponse::from_bytes(self.data, 0)
+    }
+}
+
+/// A possibly compressed name inside a message
+#[derive(Debug, Clone, Copy)]
+pub struct DnsNameRef<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DnsNameRef<'a> {
+    pub fn new(data: &'a [u8], offset: usize) -> DnsNameRef<'a> {
+        DnsNameRef { data, offset }
+    }
+
+    /// Labels of the name following compression pointers
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            data: self.data,
+            index: self.offset,
+            hops: 0,
+            done: false,
+        }
+    }
+
+    /// Number of bytes the name occupies where it starts, a compression pointer ends it
+    pub fn wire_length(&self) -> Result<usize> {
+        DnsName::wire_length(self.data, self.offset)
+    }
+
+    pub fn to_name(&self) -> Result<DnsName> {
+        DnsName::from_bytes(self.data, self.offset)
+    }
+}
+
+/// Names are compared ignoring ASCII case, a malformed name equals no name
+impl PartialEq<DnsName> for DnsNameRef<'_> {
+    fn eq(&self, other: &DnsName) -> bool {
+        let mut labels = self.labels();
+        for expected in &other.labels {
+            match labels.next() {
+                Some(Ok(label)) if label.eq_ignore_ascii_case(expected) => {}
+                _ => return false,
+            }
+        }
+
+        labels.next().is_none()
+    }
+}
+
+impl Display for DnsNameRef<'_> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self.to_name() {
+            Ok(name) => write!(f, "{}", name),
+            Err(_) => write!(f, "<malformed name at offset {}>", self.offset),
+        }
+    }
+}
+
+pub struct Labels<'a> {
+    data: &'a [u8],
+    index: usize,
+    hops: usize,
+    done: bool,
+}
+
+impl<'a> Labels<'a> {
+    fn read(&mut self) -> Result<Option<&'a [u8]>> {
+        loop {
+            let label_length = *self
+                .data
+                .get(self.index)
+                .ok_or_else(|| anyhow!("DNS name at offset {} exceeds message", self.index))?;
+
+            if label_length == 0 {
+                return Ok(None);
+            }
+
+            if label_length & 0b11000000 == 0b11000000 {
+                let low = *self.data.get(self.index + 1).ok_or_else(|| {
+                    anyhow!("DNS name pointer at offset {} is truncated", self.index)
+                })?;
+
+                self.hops += 1;
+                if self.hops > MAX_POINTER_HOPS {
+                    Err(anyhow!(
+                        "Too many compression pointers in DNS name at offset {}",
+                        self.index
+                    ))?;
+                }
+
+                self.index = ((label_length & 0b00111111) as usize) << 8 | low as usize;
+                continue;
+            }
+
+            let start = self.index + 1;
+            let label = self
+                .data
+                .get(start..start + label_length as usize)
+                .ok_or_else(|| anyhow!("DNS label at offset {} exceeds message", self.index))?;
+            self.index = start + label_length as usize;
+
+            return Ok(Some(label));
+        }
+    }
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Result<&'a [u8]>> {
+        if self.done {
+            return None;
+        }
+
+        let label = self.read().transpose();
+        if !matches!(label, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        label
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    data: &'a [u8],
+    offset: usize,
+    qname: DnsNameRef<'a>,
+    qtype: u16,
+    qclass: u16,
+    end: usize,
+}
+
+impl<'a> QuestionRef<'a> {
+    fn parse(data: &'a [u8], offset: usize) -> Result<QuestionRef<'a>> {
+        let qname = DnsNameRef::new(data, offset);
+        let index = offset + qname.wire_length()?;
+        let fields = data
+            .get(index..index + 4)
+            .ok_or_else(|| anyhow!("DNS question at offset {} is truncated", offset))?;
+
+        Ok(QuestionRef {
+            data,
+            offset,
+            qname,
+            qtype: u16::from_be_bytes([fields[0], fields[1]]),
+            qclass: u16::from_be_bytes([fields[2], fields[3]]),
+            end: index + 4,
+        })
+    }
+
+    pub fn qname(&self) -> DnsNameRef<'a> {
+        self.qname
+    }
+
+    pub fn qtype(&self) -> DnsQType {
+        DnsQType::from_u16(self.qtype)
+    }
+
+    pub fn qclass(&self) -> DnsClass {
+        DnsClass::from_u16(self.qclass)
+    }
+
+    /// Whether this question asks the same as an owned question
+    pub fn matches(&self, question: &DnsQuestion) -> bool {
+        self.qtype == question.qtype.to_u16()
+            && self.qclass == question.qclass.to_u16()
+            && self.qname == question.qname
+    }
+
+    pub fn to_question(&self) -> Result<DnsQuestion> {
+        DnsQuestion::from_bytes(self.data, self.offset)
+    }
+}
+
+pub struct Questions<'a> {
+    data: &'a [u8],
+    index: usize,
+    remaining: u16,
+    failed: bool,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionRef<'a>>;
+
+    fn next(&mut self) -> Option<Result<QuestionRef<'a>>> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+
+        match QuestionRef::parse(self.data, self.index) {
+            Ok(question) => {
+                self.index = question.end;
+                self.remaining -= 1;
+                Some(Ok(question))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    data: &'a [u8],
+    offset: usize,
+    section: DnsSection,
+    name: DnsNameRef<'a>,
+    rtype: u16,
+    rclass: u16,
+    ttl: u32,
+    rdata_offset: usize,
+    rdlength: usize,
+}
+
+impl<'a> RecordRef<'a> {
+    fn parse(data: &'a [u8], offset: usize, section: DnsSection) -> Result<RecordRef<'a>> {
+        let name = DnsNameRef::new(data, offset);
+        let index = offset + name.wire_length()?;
+        let fields = data
+            .get(index..index + 10)
+            .ok_or_else(|| anyhow!("DNS resource record at offset {} is truncated", offset))?;
+        let rdlength = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+
+        if data.len() < index + 10 + rdlength {
+            Err(anyhow!(
+                "DNS resource record data at offset {} exceeds message",
+                offset
+            ))?;
+        }
+
+        Ok(RecordRef {
+            data,
+            offset,
+            section,
+            name,
+            rtype: u16::from_be_bytes([fields[0], fields[1]]),
+            rclass: u16::from_be_bytes([fields[2], fields[3]]),
+            ttl: u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]),
+            rdata_offset: index + 10,
+            rdlength,
+        })
+    }
+
+    fn end(&self) -> usize {
+        self.rdata_offset + self.rdlength
+    }
+
+    pub fn section(&self) -> DnsSection {
+        self.section
+    }
+
+    pub fn name(&self) -> DnsNameRef<'a> {
+        self.name
+    }
+
+    pub fn rtype(&self) -> DnsQType {
+        DnsQType::from_u16(self.rtype)
+    }
+
+    /// Class of the record, for OPT records this field holds the UDP payload size instead
+    pub fn rclass(&self) -> DnsClass {
+        DnsClass::from_u16(self.rclass)
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Record data as it is in the message, names in it may point elsewhere in the message
+    pub fn rdata(&self) -> &'a [u8] {
+        &self.data[self.rdata_offset..self.end()]
+    }
+
+    /// Owned record with the names in its data decompressed
+    pub fn to_record(&self) -> Result<DnsResourceRecord> {
+        DnsResourceRecord::from_bytes(self.data, self.offset)
+    }
+
+    pub fn to_opt(&self) -> Result<DnsOptRecord> {
+        DnsOptRecord::from_record(&self.to_record()?)
+    }
+}
+
+pub struct Records<'a> {
+    data: &'a [u8],
+    index: usize,
+    /// Questions still to be skipped before the first record
+    questions: u16,
+    remaining: [u16; 3],
+    section: usize,
+    failed: bool,
+}
+
+impl<'a> Records<'a> {
+    fn read(&mut self) -> Result<Option<RecordRef<'a>>> {
+        while self.questions > 0 {
+            self.index = QuestionRef::parse(self.data, self.index)?.end;
+            self.questions -= 1;
+        }
+
+        while self.section < SECTIONS.len() && self.remaining[self.section] == 0 {
+            self.section += 1;
+        }
+
+        if self.section == SECTIONS.len() {
+            return Ok(None);
+        }
+
+        let record = RecordRef::parse(self.data, self.index, SECTIONS[self.section])?;
+        self.index = record.end();
+        self.remaining[self.section] -= 1;
+
+        Ok(Some(record))
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordRef<'a>>;
+
+    fn next(&mut self) -> Option<Result<RecordRef<'a>>> {
+        if self.failed {
+            return None;
+        }
+
+        let record = self.read().transpose();
+        if matches!(record, Some(Err(_))) {
+            self.failed = true;
+        }
+
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Response for cdn.mycelnet.tech with a CNAME to edge.cdn.mycelnet.tech and an A record,
+    // both compressed against the question, followed by an OPT record
+    const RESPONSE: [u8; 89] = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, // Header
+        0x03, 0x63, 0x64, 0x6e, 0x08, 0x6d, 0x79, 0x63, 0x65, 0x6c, 0x6e, 0x65, 0x74, 0x04, 0x74,
+        0x65, 0x63, 0x68, 0x00, 0x00, 0x01, 0x00, 0x01, // Question
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x07, 0x04, 0x65, 0x64,
+        0x67, 0x65, 0xc0, 0x0c, // CNAME
+        0xc0, 0x2f, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 0xc6, 0x33, 0x64,
+        0x07, // A
+        0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0xff, 0x01, 0x00, 0x04,
+        0x01, 0x02, 0x03, 0x04, // OPT
+    ];
+
+    #[test]
+    fn iterate_borrowed_message() -> Result<()> {
+        let message = DnsMessageRef::new(&RESPONSE)?;
+        assert_eq!(message.header().id, 0x1234);
+
+        let question = message.question()?.unwrap();
+        let qname: DnsName = "CDN.mycelnet.tech".parse()?;
+        assert!(question.qname() == qname);
+        assert_eq!(question.qtype(), DnsQType::A);
+
+        let records = message.records().collect::<Result<Vec<_>>>()?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].section(), DnsSection::Answer);
+        assert_eq!(records[0].rtype(), DnsQType::CNAME);
+        assert_eq!(
+            records[0].rdata(),
+            &[0x04, 0x65, 0x64, 0x67, 0x65, 0xc0, 0x0c]
+        );
+        assert_eq!(records[1].name().to_string(), "edge.cdn.mycelnet.tech");
+        assert_eq!(records[1].ttl(), 60);
+        assert_eq!(records[1].rdata(), &[198, 51, 100, 7]);
+        assert_eq!(records[2].section(), DnsSection::Additional);
+
+        let labels = records[1].name().labels().collect::<Result<Vec<_>>>()?;
+        assert_eq!(labels, vec![&b"edge"[..], b"cdn", b"mycelnet", b"tech"]);
+
+        assert_eq!(message.section(DnsSection::Answer).count(), 2);
+        assert_eq!(message.edns()?.unwrap().udp_payload_size, 1232);
+
+        let cname = records[0].to_record()?;
+        assert_eq!(
+            DnsName::from_bytes(&cname.rdata, 0)?,
+            "edge.cdn.mycelnet.tech".parse()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stop_at_malformed_records() -> Result<()> {
+        let message = DnsMessageRef::new(&RESPONSE[..60])?;
+
+        let records: Vec<_> = message.records().collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_err());
+
+        // A name pointing at itself
+        let mut looped = RESPONSE;
+        looped[36] = 0x23;
+        let message = DnsMessageRef::new(&looped)?;
+        let record = message.records().next().unwrap()?;
+        assert!(record.name().labels().any(|label| label.is_err()));
+        assert!(!(record.name() == DnsName::default()));
+
+        Ok(())
+    }
+}
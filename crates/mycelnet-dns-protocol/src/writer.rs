@@ -0,0 +1,186 @@
+//! Serialization of messages into a single buffer with a size limit.
+//!
+//! Types write themselves with [`DnsPacketData::write_to`] instead of returning their own
+//! `Vec<u8>`, so a whole message is built in one allocation that can be reused between
+//! messages. Counts and lengths that are only known after their contents are written are
+//! back-patched, and a write that would exceed the limit fails with [`BufferFull`] so the
+//! caller can roll back and truncate the message.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use anyhow::Result;
+
+use crate::DnsPacketData;
+
+/// Largest message that can be sent, limited by the 16 bit length prefix used over TCP
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Smallest UDP payload every client must accept, RFC 1035 section 2.3.4
+pub const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// A write would have exceeded the size limit of a [`DnsWriter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferFull {
+    pub limit: usize,
+}
+
+impl Display for BufferFull {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "Message exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl Error for BufferFull {}
+
+#[derive(Debug, Clone)]
+pub struct DnsWriter {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl DnsWriter {
+    pub fn new(limit: usize) -> DnsWriter {
+        DnsWriter::with_buffer(Vec::new(), limit)
+    }
+
+    /// Write into an existing buffer, for instance one returned by [`DnsWriter::into_bytes`]
+    /// for an earlier message. The buffer is cleared but keeps its capacity.
+    pub fn with_buffer(mut buf: Vec<u8>, limit: usize) -> DnsWriter {
+        buf.clear();
+        DnsWriter {
+            buf,
+            limit: limit.min(MAX_MESSAGE_SIZE),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Change the limit, writes already made beyond it are kept
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_MESSAGE_SIZE);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Bytes that can still be written before reaching the limit
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.buf.len())
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), BufferFull> {
+        self.write_bytes(&[value])
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> Result<(), BufferFull> {
+        self.write_bytes(&value.to_be_bytes())
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), BufferFull> {
+        self.write_bytes(&value.to_be_bytes())
+    }
+
+    /// Append bytes, nothing is written if they do not all fit
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), BufferFull> {
+        if bytes.len() > self.remaining() {
+            return Err(BufferFull { limit: self.limit });
+        }
+
+        self.buf.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Overwrite a byte written earlier
+    ///
+    /// Panics if nothing was written at the position.
+    pub fn patch_u8(&mut self, position: usize, value: u8) {
+        self.buf[position] = value;
+    }
+
+    /// Overwrite a 16 bit value written earlier, typically a count or length placeholder
+    ///
+    /// Panics if nothing was written at the position.
+    pub fn patch_u16(&mut self, position: usize, value: u16) {
+        self.buf[position..position + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Discard everything written after a length returned by [`DnsWriter::len`]
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Serialize a value on its own, used by the `to_bytes` of types implementing `write_to`
+pub(crate) fn to_vec(value: &impl DnsPacketData) -> Result<Vec<u8>> {
+    let mut writer = DnsWriter::new(MAX_MESSAGE_SIZE);
+    value.write_to(&mut writer)?;
+    Ok(writer.into_bytes())
+}
+
+/// Write records until one does not fit, returning how many were written and whether any were
+/// left out. A record that does not fit is removed again so the message stays well formed.
+pub(crate) fn write_records<'a>(
+    writer: &mut DnsWriter,
+    records: impl IntoIterator<Item = &'a crate::DnsResourceRecord>,
+) -> Result<(u16, bool)> {
+    let mut count = 0;
+    for record in records {
+        let mark = writer.len();
+        match record.write_to(writer) {
+            Ok(()) => count += 1,
+            Err(e) if e.is::<BufferFull>() => {
+                writer.truncate(mark);
+                return Ok((count, true));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((count, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_within_limit() {
+        let mut writer = DnsWriter::new(4);
+        writer.write_u16(0x1234).unwrap();
+        assert_eq!(writer.write_u32(1), Err(BufferFull { limit: 4 }));
+        assert_eq!(writer.len(), 2);
+
+        writer.write_u8(0).unwrap();
+        writer.patch_u16(0, 0xabcd);
+        writer.patch_u8(2, 0xef);
+        assert_eq!(writer.as_bytes(), &[0xab, 0xcd, 0xef]);
+        assert_eq!(writer.remaining(), 1);
+
+        let buf = writer.into_bytes();
+        let capacity = buf.capacity();
+        let writer = DnsWriter::with_buffer(buf, 512);
+        assert!(writer.is_empty());
+        assert_eq!(writer.as_bytes().len(), 0);
+        assert!(writer.buf.capacity() >= capacity);
+    }
+}
@@ -0,0 +1,117 @@
+//! Storage of zone data behind the lookups answering queries.
+//!
+//! Answers only need the records at a name, whether a name exists, the NSEC record covering a
+//! name and a way to walk all records. [`ZoneBackend`] asks for just that, so a zone can be
+//! served from memory as a [`Zone`] or from a database too large to load, such as the SQLite
+//! backend of the `sqlite` feature.
+
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+use anyhow::{anyhow, Result};
+
+use mycelnet_dns_protocol::{DnsClass, DnsName, DnsQType, DnsResourceRecord};
+
+use crate::rdata::Soa;
+use crate::{Node, RRset, Zone};
+
+pub trait ZoneBackend: Debug + Send + Sync {
+    fn origin(&self) -> &DnsName;
+
+    fn class(&self) -> DnsClass;
+
+    /// RRsets owned by a name, None if it owns no records
+    fn node(&self, name: &DnsName) -> Result<Option<Cow<'_, Node>>>;
+
+    /// Whether the name owns records or is an empty non-terminal above names that do
+    fn contains_name(&self, name: &DnsName) -> Result<bool>;
+
+    /// The NSEC RRset matching a name or, for a name without one, the RRset of the closest
+    /// name before it in canonical order, with its owner
+    fn nsec(&self, name: &DnsName) -> Result<Option<(DnsName, Cow<'_, RRset>)>>;
+
+    /// All records of the zone
+    fn records(&self) -> Box<dyn Iterator<Item = Result<DnsResourceRecord>> + '_>;
+
+    fn get(&self, name: &DnsName, rtype: DnsQType) -> Result<Option<RRset>> {
+        Ok(self.node(name)?.and_then(|node| node.get(rtype).cloned()))
+    }
+
+    /// Longest name equal to or above a name in the zone that exists, RFC 4592 section 3.3.1
+    fn closest_encloser(&self, name: &DnsName) -> Result<Option<DnsName>> {
+        for ancestor in name.ancestors() {
+            if !ancestor.is_subdomain_of(self.origin()) {
+                break;
+            }
+            if self.contains_name(&ancestor)? {
+                return Ok(Some(ancestor));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether the zone is signed with DNSSEC and proves names do not exist with NSEC records
+    fn is_signed(&self) -> Result<bool> {
+        Ok(self.get(self.origin(), DnsQType::NSEC)?.is_some())
+    }
+
+    /// The SOA record of the zone as it is sent in answers
+    fn soa_record(&self) -> Result<DnsResourceRecord> {
+        let rrset = self
+            .get(self.origin(), DnsQType::SOA)?
+            .ok_or_else(|| anyhow!("Zone {} has no SOA record", self.origin()))?;
+        let mut records = rrset.records(self.origin(), self.class());
+
+        match (records.next(), records.next()) {
+            (Some(record), None) => Ok(record),
+            _ => Err(anyhow!(
+                "Zone {} has more than one SOA record",
+                self.origin()
+            )),
+        }
+    }
+
+    fn soa(&self) -> Result<Soa> {
+        Soa::from_rdata(&self.soa_record()?.rdata)
+    }
+
+    fn serial(&self) -> Result<u32> {
+        Ok(self.soa()?.serial)
+    }
+
+    /// The zone if it is held in memory, which dynamic updates and journals need to change it
+    fn zone(&self) -> Option<&Zone> {
+        None
+    }
+}
+
+impl ZoneBackend for Zone {
+    fn origin(&self) -> &DnsName {
+        Zone::origin(self)
+    }
+
+    fn class(&self) -> DnsClass {
+        Zone::class(self)
+    }
+
+    fn node(&self, name: &DnsName) -> Result<Option<Cow<'_, Node>>> {
+        Ok(Zone::node(self, name).map(Cow::Borrowed))
+    }
+
+    fn contains_name(&self, name: &DnsName) -> Result<bool> {
+        Ok(Zone::contains_name(self, name))
+    }
+
+    fn nsec(&self, name: &DnsName) -> Result<Option<(DnsName, Cow<'_, RRset>)>> {
+        Ok(Zone::nsec(self, name).map(|(owner, rrset)| (owner.clone(), Cow::Borrowed(rrset))))
+    }
+
+    fn records(&self) -> Box<dyn Iterator<Item = Result<DnsResourceRecord>> + '_> {
+        Box::new(Zone::records(self).map(Ok))
+    }
+
+    fn zone(&self) -> Option<&Zone> {
+        Some(self)
+    }
+}
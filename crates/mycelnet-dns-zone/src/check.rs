@@ -0,0 +1,325 @@
+//! Semantic checks of the records read from a master file.
+//!
+//! Parsing only makes sure each record is well formed on its own. The checks here look at the
+//! records together for the mistakes servers reject or that break resolution: the SOA record
+//! of the apex, aliases sharing their name with other data (RFC 1034 section 3.6.2), name
+//! servers without addresses or pointing at aliases (RFC 2181 section 10.3), data hidden below
+//! delegations and RRsets whose records disagree on the TTL (RFC 2181 section 5.2).
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use mycelnet_dns_protocol::{DnsName, DnsPacketData, DnsQType, DnsResourceRecord};
+
+use crate::master::{Location, MasterRecord};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The zone can be served but probably not as intended
+    Warning,
+    /// The zone must not be served
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// A problem found in a zone and the record it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.severity, self.message)
+    }
+}
+
+/// Check the records of the zone at an origin, returns the problems in the order of the file
+pub fn check(origin: &DnsName, records: &[MasterRecord]) -> Vec<Problem> {
+    let mut checker = Checker {
+        problems: Vec::new(),
+    };
+
+    let records: Vec<&MasterRecord> = records
+        .iter()
+        .filter(|entry| {
+            let inside = entry.record.name.is_subdomain_of(origin);
+            if !inside {
+                checker.error(
+                    entry,
+                    format!(
+                        "Record for {} is outside of zone {}",
+                        entry.record.name, origin
+                    ),
+                );
+            }
+            inside
+        })
+        .collect();
+
+    let mut names: HashMap<&DnsName, Vec<&MasterRecord>> = HashMap::new();
+    for entry in &records {
+        names.entry(&entry.record.name).or_default().push(entry);
+    }
+    let has = |name: &DnsName, rtype: DnsQType| {
+        names
+            .get(name)
+            .is_some_and(|entries| entries.iter().any(|entry| entry.record.rtype == rtype))
+    };
+
+    // Names below the apex with NS records delegate their subtree, RFC 1034 section 4.2.1
+    let cuts: HashSet<&DnsName> = records
+        .iter()
+        .filter(|entry| entry.record.rtype == DnsQType::NS && &entry.record.name != origin)
+        .map(|entry| &entry.record.name)
+        .collect();
+    // Addresses of name servers, the glue when they are at or below a delegation
+    let targets: HashSet<DnsName> = records
+        .iter()
+        .filter(|entry| entry.record.rtype == DnsQType::NS)
+        .filter_map(|entry| target(&entry.record, 0))
+        .collect();
+
+    if !has(origin, DnsQType::SOA) {
+        let location = records
+            .first()
+            .map(|entry| entry.location.clone())
+            .unwrap_or_default();
+        checker.problems.push(Problem {
+            severity: Severity::Error,
+            location,
+            message: format!("Zone {origin} has no SOA record at its apex"),
+        });
+    }
+
+    let mut ttls: HashMap<(&DnsName, u16), u32> = HashMap::new();
+    let mut soa_seen = false;
+    for entry in &records {
+        let record = &entry.record;
+        let name = &record.name;
+
+        match ttls.get(&(name, record.rtype.to_u16())) {
+            Some(&ttl) if ttl != record.ttl => checker.warning(
+                entry,
+                format!(
+                    "TTL {} of {} record for {} differs from the TTL {} of its RRset, {} is used",
+                    record.ttl, record.rtype, name, ttl, ttl
+                ),
+            ),
+            Some(_) => {}
+            None => {
+                ttls.insert((name, record.rtype.to_u16()), record.ttl);
+            }
+        }
+
+        if record.rtype == DnsQType::SOA {
+            if name != origin {
+                checker.error(entry, format!("SOA record for {name} is not at the apex"));
+            } else if soa_seen {
+                checker.error(entry, format!("Zone {origin} has more than one SOA record"));
+            }
+            soa_seen |= name == origin;
+        }
+
+        let glue = matches!(record.rtype, DnsQType::A | DnsQType::AAAA) && targets.contains(name);
+        if let Some(cut) = cuts
+            .iter()
+            .find(|cut| name != **cut && name.is_subdomain_of(cut))
+        {
+            if !glue {
+                checker.error(
+                    entry,
+                    format!(
+                        "{} record for {} is below the delegation at {}",
+                        record.rtype, name, cut
+                    ),
+                );
+            }
+            continue;
+        }
+        if cuts.contains(name)
+            && !glue
+            && !matches!(
+                record.rtype,
+                DnsQType::NS | DnsQType::DS | DnsQType::NSEC | DnsQType::RRSIG
+            )
+        {
+            checker.error(
+                entry,
+                format!(
+                    "{} record for {} is at the delegation point",
+                    record.rtype, name
+                ),
+            );
+        }
+
+        if record.rtype == DnsQType::CNAME {
+            let other = names[name].iter().find(|other| {
+                !matches!(
+                    other.record.rtype,
+                    DnsQType::CNAME | DnsQType::NSEC | DnsQType::RRSIG
+                )
+            });
+            if let Some(other) = other {
+                checker.error(
+                    entry,
+                    format!(
+                        "CNAME record for {} coexists with its {} record at {}",
+                        name, other.record.rtype, other.location
+                    ),
+                );
+            }
+            let first = names[name]
+                .iter()
+                .find(|other| other.record.rtype == DnsQType::CNAME);
+            if first.is_some_and(|first| !std::ptr::eq(*first, *entry)) {
+                checker.error(entry, format!("{name} has more than one CNAME record"));
+            }
+        }
+
+        let (offset, severity) = match record.rtype {
+            DnsQType::NS => (0, Severity::Error),
+            DnsQType::MX => (2, Severity::Warning),
+            _ => continue,
+        };
+        let Some(target) = target(record, offset) else {
+            continue;
+        };
+        if !target.is_subdomain_of(origin) {
+            continue;
+        }
+
+        if has(&target, DnsQType::CNAME) {
+            checker.push(
+                severity,
+                entry,
+                format!(
+                    "{} record for {} points at {} which is an alias",
+                    record.rtype, name, target
+                ),
+            );
+        } else if record.rtype == DnsQType::NS
+            && !has(&target, DnsQType::A)
+            && !has(&target, DnsQType::AAAA)
+        {
+            checker.error(
+                entry,
+                format!("Name server {target} of {name} has no address records in the zone"),
+            );
+        }
+    }
+
+    checker.problems
+}
+
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn push(&mut self, severity: Severity, entry: &MasterRecord, message: String) {
+        self.problems.push(Problem {
+            severity,
+            location: entry.location.clone(),
+            message,
+        });
+    }
+
+    fn error(&mut self, entry: &MasterRecord, message: String) {
+        self.push(Severity::Error, entry, message);
+    }
+
+    fn warning(&mut self, entry: &MasterRecord, message: String) {
+        self.push(Severity::Warning, entry, message);
+    }
+}
+
+/// Name in the record data of an NS or MX record
+fn target(record: &DnsResourceRecord, offset: usize) -> Option<DnsName> {
+    DnsName::from_bytes(&record.rdata, offset)
+        .ok()
+        .map(|name| DnsName::from_labels(name.labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::master;
+
+    use super::*;
+
+    fn problems(hosts: &str) -> Result<Vec<(Severity, usize)>> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = format!("$TTL 300\n@ SOA ns1 hostmaster 1 3600 900 604800 300\n@ NS ns1\nns1 A 192.0.2.53\n{hosts}");
+        let records = master::parse_str(&text, &origin)?;
+
+        Ok(check(&origin, &records)
+            .into_iter()
+            .map(|problem| (problem.severity, problem.location.line))
+            .collect())
+    }
+
+    #[test]
+    fn check_zones() -> Result<()> {
+        use Severity::{Error, Warning};
+
+        assert_eq!(
+            problems("www A 192.0.2.1\nsub NS ns.sub\nns.sub A 192.0.2.2\n")?,
+            vec![]
+        );
+
+        assert_eq!(
+            problems("@ SOA ns2 hostmaster 2 3600 900 604800 300\n")?,
+            vec![(Error, 5)]
+        );
+        assert_eq!(
+            problems("www SOA ns2 hostmaster 2 3600 900 604800 300\n")?,
+            vec![(Error, 5)]
+        );
+        assert_eq!(
+            problems("www CNAME @\nwww TXT \"alias\"\n")?,
+            vec![(Error, 5)]
+        );
+        assert_eq!(problems("www CNAME @\nwww CNAME ns1\n")?, vec![(Error, 6)]);
+
+        // Name servers need addresses and must not be aliases
+        assert_eq!(problems("sub NS ns.sub\n")?, vec![(Error, 5)]);
+        assert_eq!(problems("@ NS ns2\n")?, vec![(Error, 5)]);
+        assert_eq!(problems("@ NS ns2\nns2 CNAME ns1\n")?, vec![(Error, 5)]);
+        assert_eq!(problems("@ NS ns.mycelnet.net.\n")?, vec![]);
+        assert_eq!(
+            problems("@ MX 10 mail\nmail CNAME ns1\n")?,
+            vec![(Warning, 5)]
+        );
+
+        // Only glue may be below a delegation
+        assert_eq!(
+            problems("sub NS ns.mycelnet.net.\nwww.sub A 192.0.2.3\nsub TXT \"cut\"\n")?,
+            vec![(Error, 6), (Error, 7)]
+        );
+
+        assert_eq!(problems("ns1 600 A 192.0.2.54\n")?, vec![(Warning, 5)]);
+
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let records = master::parse_str("$TTL 300\nwww A 192.0.2.1\n", &origin)?;
+        let problems = check(&origin, &records);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].to_string(),
+            "line 2: error: Zone mycelnet.tech has no SOA record at its apex"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+//! Journal of the changes made to a zone, the source of incremental transfers (RFC 1995).
+//!
+//! Every change is a [`Diff`] tagged with the SOA records before and after it. The journal keeps
+//! a contiguous chain of diffs so the changes since any serial it covers can be replayed in
+//! order. A journal may be kept in a file next to the zone, each diff being stored as a 32 bit
+//! length followed by its records in uncompressed wire format, in the order of an IXFR answer.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use mycelnet_dns_protocol::{DnsName, DnsPacketData, DnsQType, DnsResourceRecord};
+
+use crate::rdata::Soa;
+use crate::Zone;
+
+/// Records removed from and added to a zone going from one SOA serial to the next
+#[derive(Debug, Clone)]
+pub struct Diff {
+    /// SOA record of the zone before the change
+    pub from: DnsResourceRecord,
+    /// SOA record of the zone after the change
+    pub to: DnsResourceRecord,
+    pub removed: Vec<DnsResourceRecord>,
+    pub added: Vec<DnsResourceRecord>,
+}
+
+impl Diff {
+    /// Changes turning one version of a zone into another. Records whose TTL changed are
+    /// removed and added again.
+    pub fn between(old: &Zone, new: &Zone) -> Result<Diff> {
+        let key = |record: &DnsResourceRecord| {
+            (
+                record.name.clone(),
+                record.rtype.to_u16(),
+                record.ttl,
+                record.rdata.clone(),
+            )
+        };
+        let old_records: HashSet<_> = old.records().map(|record| key(&record)).collect();
+        let new_records: HashSet<_> = new.records().map(|record| key(&record)).collect();
+
+        Ok(Diff {
+            from: old.soa_record()?,
+            to: new.soa_record()?,
+            removed: old
+                .records()
+                .filter(|record| record.rtype != DnsQType::SOA)
+                .filter(|record| !new_records.contains(&key(record)))
+                .collect(),
+            added: new
+                .records()
+                .filter(|record| record.rtype != DnsQType::SOA)
+                .filter(|record| !old_records.contains(&key(record)))
+                .collect(),
+        })
+    }
+
+    pub fn from_serial(&self) -> Result<u32> {
+        Ok(Soa::from_rdata(&self.from.rdata)?.serial)
+    }
+
+    pub fn to_serial(&self) -> Result<u32> {
+        Ok(Soa::from_rdata(&self.to.rdata)?.serial)
+    }
+
+    /// The records of the diff as they appear in an IXFR answer, RFC 1995 section 4
+    pub fn records(&self) -> impl Iterator<Item = DnsResourceRecord> + '_ {
+        std::iter::once(self.from.clone())
+            .chain(self.removed.iter().cloned())
+            .chain(std::iter::once(self.to.clone()))
+            .chain(self.added.iter().cloned())
+    }
+
+    /// Read a diff from its records, starting with the old SOA record and with the new one
+    /// separating the removed records from the added ones
+    pub fn from_records(records: impl IntoIterator<Item = DnsResourceRecord>) -> Result<Diff> {
+        let mut records = records.into_iter().map(|mut record| {
+            // Drop any compression pointer into the message the record was read from
+            record.name = DnsName::from_labels(record.name.labels);
+            record
+        });
+
+        let from = records
+            .next()
+            .filter(|record| record.rtype == DnsQType::SOA)
+            .ok_or_else(|| anyhow!("Diff does not start with an SOA record"))?;
+
+        let mut removed = Vec::new();
+        let to = loop {
+            match records.next() {
+                Some(record) if record.rtype == DnsQType::SOA => break record,
+                Some(record) => removed.push(record),
+                None => Err(anyhow!("Diff has no SOA record ending its removed records"))?,
+            }
+        };
+
+        let added: Vec<_> = records.collect();
+        if added.iter().any(|record| record.rtype == DnsQType::SOA) {
+            Err(anyhow!("Diff has more than two SOA records"))?;
+        }
+
+        Ok(Diff {
+            from,
+            to,
+            removed,
+            added,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for record in self.records() {
+            bytes.extend(record.to_bytes()?);
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Diff> {
+        let mut records = Vec::new();
+        let mut index = 0;
+        while index < data.len() {
+            records.push(DnsResourceRecord::from_bytes(data, index)?);
+            index += DnsResourceRecord::wire_length(data, index)?;
+        }
+
+        Diff::from_records(records)
+    }
+}
+
+/// Contiguous chain of the latest changes of a zone
+#[derive(Debug)]
+pub struct Journal {
+    /// File the journal is kept in, None for a journal only kept in memory
+    path: Option<PathBuf>,
+    /// Number of diffs kept, older ones are dropped
+    limit: usize,
+    diffs: VecDeque<Diff>,
+}
+
+impl Journal {
+    pub fn new(limit: usize) -> Journal {
+        Journal {
+            path: None,
+            limit,
+            diffs: VecDeque::new(),
+        }
+    }
+
+    /// Read the journal kept in a file, a missing file is an empty journal
+    pub fn open(path: &Path, limit: usize) -> Result<Journal> {
+        let mut journal = Journal::new(limit);
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let mut index = 0;
+        while index < data.len() {
+            let length = data
+                .get(index..index + 4)
+                .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+                .ok_or_else(|| anyhow!("{} is truncated at byte {}", path.display(), index))?;
+            let entry = data
+                .get(index + 4..index + 4 + length)
+                .ok_or_else(|| anyhow!("{} is truncated at byte {}", path.display(), index))?;
+
+            let diff = Diff::from_bytes(entry)
+                .with_context(|| format!("Invalid diff in {} at byte {}", path.display(), index))?;
+            journal.push(diff)?;
+            index += 4 + length;
+        }
+
+        journal.path = Some(path.to_path_buf());
+        Ok(journal)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Diffs from the oldest to the latest
+    pub fn diffs(&self) -> impl Iterator<Item = &Diff> {
+        self.diffs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+
+    /// Serial of the zone after the latest diff
+    pub fn last_serial(&self) -> Option<u32> {
+        self.diffs.back()?.to_serial().ok()
+    }
+
+    /// Record a change of the zone and write it to the journal file
+    pub fn append(&mut self, diff: Diff) -> Result<()> {
+        let rewrite = self.push(diff)?;
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if rewrite {
+            let mut data = Vec::new();
+            for diff in &self.diffs {
+                data.extend(entry(diff)?);
+            }
+            // Replace the file at once so a failure leaves the previous journal in place
+            let temporary = path.with_extension("jnl.tmp");
+            fs::write(&temporary, data)
+                .and_then(|_| fs::rename(&temporary, path))
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        } else {
+            let diff = self.diffs.back().unwrap();
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(&entry(diff)?))
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Diffs leading from a serial to the latest version, None if the journal does not go back
+    /// to that serial
+    pub fn since(&self, serial: u32) -> Option<impl Iterator<Item = &Diff>> {
+        if self.last_serial() == Some(serial) {
+            return Some(self.diffs.range(self.diffs.len()..));
+        }
+
+        let start = self
+            .diffs
+            .iter()
+            .position(|diff| diff.from_serial().ok() == Some(serial))?;
+        Some(self.diffs.range(start..))
+    }
+
+    /// Bring a zone up to date with the diffs following its serial, returns the number of
+    /// diffs applied
+    pub fn replay(&self, zone: &mut Zone) -> Result<usize> {
+        let Some(diffs) = self.since(zone.serial()?) else {
+            return Ok(0);
+        };
+
+        let mut count = 0;
+        for diff in diffs {
+            zone.apply(diff)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Add a diff in memory, returns whether the journal file has to be rewritten because older
+    /// diffs were dropped
+    fn push(&mut self, diff: Diff) -> Result<bool> {
+        let mut rewrite = false;
+
+        // A change that does not follow the latest one starts a new chain
+        if let Some(serial) = self.last_serial() {
+            if diff.from_serial()? != serial {
+                self.diffs.clear();
+                rewrite = true;
+            }
+        }
+
+        self.diffs.push_back(diff);
+        while self.diffs.len() > self.limit.max(1) {
+            self.diffs.pop_front();
+            rewrite = true;
+        }
+
+        Ok(rewrite)
+    }
+}
+
+/// A diff as it is stored in a journal file
+fn entry(diff: &Diff) -> std::io::Result<Vec<u8>> {
+    let data = diff
+        .to_bytes()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let mut entry = (data.len() as u32).to_be_bytes().to_vec();
+    entry.extend(data);
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use mycelnet_dns_protocol::DnsClass;
+
+    use crate::master;
+
+    use super::*;
+
+    fn zone(serial: u32, hosts: &str) -> Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = format!(
+            "$TTL 300\n@ SOA ns1 hostmaster {serial} 3600 900 604800 300\n@ NS ns1\n{hosts}"
+        );
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in master::parse_str(&text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+        Ok(zone)
+    }
+
+    #[test]
+    fn journal_zone_changes() -> Result<()> {
+        let versions = [
+            zone(1, "www A 192.0.2.1\n")?,
+            zone(2, "www A 192.0.2.2\nftp A 192.0.2.3\n")?,
+            zone(3, "www 600 A 192.0.2.2\nftp A 192.0.2.3\n")?,
+        ];
+
+        let diff = Diff::between(&versions[0], &versions[1])?;
+        assert_eq!((diff.from_serial()?, diff.to_serial()?), (1, 2));
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.added.len(), 2);
+
+        let path = std::env::temp_dir().join(format!("journal-{}.jnl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut journal = Journal::open(&path, 10)?;
+        journal.append(diff)?;
+        journal.append(Diff::between(&versions[1], &versions[2])?)?;
+
+        let journal = Journal::open(&path, 10)?;
+        fs::remove_file(&path)?;
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.last_serial(), Some(3));
+        assert_eq!(journal.since(2).unwrap().count(), 1);
+        assert_eq!(journal.since(3).unwrap().count(), 0);
+        assert!(journal.since(0).is_none());
+
+        let mut zone = versions[0].clone();
+        assert_eq!(journal.replay(&mut zone)?, 2);
+        assert_eq!(zone, versions[2]);
+
+        // Diffs must apply to the version they were made from
+        let mut zone = versions[0].clone();
+        assert!(zone.apply(journal.diffs().nth(1).unwrap()).is_err());
+        assert_eq!(zone, versions[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_old_diffs() -> Result<()> {
+        let mut journal = Journal::new(2);
+        for serial in 1..5 {
+            journal.append(Diff::between(&zone(serial, "")?, &zone(serial + 1, "")?)?)?;
+        }
+        assert_eq!(journal.len(), 2);
+        assert!(journal.since(2).is_none());
+        assert_eq!(journal.since(3).unwrap().count(), 2);
+
+        // A change not following the latest one breaks the chain
+        journal.append(Diff::between(&zone(7, "")?, &zone(8, "")?)?)?;
+        assert_eq!(journal.len(), 1);
+        assert!(journal.since(4).is_none());
+
+        Ok(())
+    }
+}
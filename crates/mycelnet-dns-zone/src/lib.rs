@@ -0,0 +1,412 @@
+//! Authoritative zone data for the mycelnet DNS server.
+//!
+//! A [`Zone`] holds the records below an origin grouped into RRsets per owner name, kept in
+//! canonical order so transfers and written files are stable. Zones are read from master files
+//! with the [`master`] module, validated with [`check`] and their changes are recorded in a
+//! [`journal`]. A server holds the zones it answers for in a [`store::ZoneStore`] and looks
+//! up their records through a [`backend::ZoneBackend`].
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use mycelnet_dns_protocol::{DnsClass, DnsName, DnsQType, DnsResourceRecord};
+
+use check::{Problem, Severity};
+use journal::Diff;
+use rdata::Soa;
+
+pub mod backend;
+pub mod check;
+pub mod journal;
+pub mod master;
+pub mod rdata;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+pub mod update;
+
+/// Records of the same owner, class and type, RFC 2181 section 5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRset {
+    pub rtype: DnsQType,
+    /// All records of an RRset share a TTL, RFC 2181 section 5.2
+    pub ttl: u32,
+    pub rdatas: Vec<Vec<u8>>,
+}
+
+impl RRset {
+    pub fn new(rtype: DnsQType, ttl: u32) -> RRset {
+        RRset {
+            rtype,
+            ttl,
+            rdatas: Vec::new(),
+        }
+    }
+
+    /// The records of the set for an owner name
+    pub fn records<'a>(
+        &'a self,
+        name: &'a DnsName,
+        rclass: DnsClass,
+    ) -> impl Iterator<Item = DnsResourceRecord> + 'a {
+        self.rdatas.iter().map(move |rdata| DnsResourceRecord {
+            name: name.clone(),
+            rtype: self.rtype,
+            rclass,
+            ttl: self.ttl,
+            rdlength: rdata.len() as u16,
+            rdata: rdata.clone(),
+        })
+    }
+}
+
+/// The RRsets of one owner name ordered by type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node {
+    rrsets: Vec<RRset>,
+}
+
+impl Node {
+    pub fn get(&self, rtype: DnsQType) -> Option<&RRset> {
+        self.rrsets.iter().find(|rrset| rrset.rtype == rtype)
+    }
+
+    pub fn rrsets(&self) -> &[RRset] {
+        &self.rrsets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rrsets.is_empty()
+    }
+
+    /// Data of the RRSIG records of the node covering a type, RFC 4034 section 3.1.1
+    pub fn signatures(&self, rtype: DnsQType) -> impl Iterator<Item = &Vec<u8>> {
+        self.get(DnsQType::RRSIG)
+            .into_iter()
+            .flat_map(|rrset| &rrset.rdatas)
+            .filter(move |rdata| {
+                rdata.len() >= 2 && u16::from_be_bytes([rdata[0], rdata[1]]) == rtype.to_u16()
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    origin: DnsName,
+    class: DnsClass,
+    nodes: BTreeMap<DnsName, Node>,
+}
+
+impl Zone {
+    pub fn new(origin: DnsName, class: DnsClass) -> Zone {
+        Zone {
+            origin,
+            class,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Read a zone from a master file and check it, returns the zone with the warnings found.
+    /// A zone with errors, for instance one without an SOA record at its origin, is not loaded.
+    pub fn load(path: &Path, origin: &DnsName) -> Result<(Zone, Vec<Problem>)> {
+        let records = master::read_file(path, origin)?;
+
+        let (errors, warnings): (Vec<_>, Vec<_>) = check::check(origin, &records)
+            .into_iter()
+            .partition(|problem| problem.severity == Severity::Error);
+        if !errors.is_empty() {
+            let errors: Vec<_> = errors.iter().map(Problem::to_string).collect();
+            Err(anyhow!(errors.join(", ")))
+                .with_context(|| format!("Failed to load zone {}", origin))?;
+        }
+
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in records {
+            zone.insert(entry.record)
+                .with_context(|| entry.location.to_string())?;
+        }
+
+        Ok((zone, warnings))
+    }
+
+    pub fn origin(&self) -> &DnsName {
+        &self.origin
+    }
+
+    pub fn class(&self) -> DnsClass {
+        self.class
+    }
+
+    pub fn soa(&self) -> Result<Soa> {
+        let rrset = self
+            .get(&self.origin, DnsQType::SOA)
+            .ok_or_else(|| anyhow!("Zone {} has no SOA record", self.origin))?;
+
+        match &rrset.rdatas[..] {
+            [rdata] => Soa::from_rdata(rdata),
+            _ => Err(anyhow!("Zone {} has more than one SOA record", self.origin)),
+        }
+    }
+
+    /// The SOA record of the zone as it is sent in answers and transfers
+    pub fn soa_record(&self) -> Result<DnsResourceRecord> {
+        self.soa()?;
+        let rrset = self.get(&self.origin, DnsQType::SOA).unwrap();
+        Ok(rrset.records(&self.origin, self.class).next().unwrap())
+    }
+
+    pub fn serial(&self) -> Result<u32> {
+        Ok(self.soa()?.serial)
+    }
+
+    pub fn node(&self, name: &DnsName) -> Option<&Node> {
+        self.nodes.get(name)
+    }
+
+    pub fn get(&self, name: &DnsName, rtype: DnsQType) -> Option<&RRset> {
+        self.nodes.get(name)?.get(rtype)
+    }
+
+    /// Whether the name owns records or is an empty non-terminal above names that do.
+    /// Descendants of a name directly follow it in canonical order.
+    pub fn contains_name(&self, name: &DnsName) -> bool {
+        self.nodes
+            .range(name..)
+            .next()
+            .is_some_and(|(next, _)| next.is_subdomain_of(name))
+    }
+
+    /// Longest name equal to or above a name in the zone that exists, RFC 4592 section 3.3.1
+    pub fn closest_encloser(&self, name: &DnsName) -> Option<DnsName> {
+        name.ancestors()
+            .take_while(|ancestor| ancestor.is_subdomain_of(&self.origin))
+            .find(|ancestor| self.contains_name(ancestor))
+    }
+
+    /// Whether the zone is signed with DNSSEC and proves names do not exist with NSEC records
+    pub fn is_signed(&self) -> bool {
+        self.get(&self.origin, DnsQType::NSEC).is_some()
+    }
+
+    /// The NSEC RRset matching a name or, for a name without one, the RRset covering it, which
+    /// is the one of the closest name before it in canonical order, RFC 4034 section 4.1
+    pub fn nsec(&self, name: &DnsName) -> Option<(&DnsName, &RRset)> {
+        self.nodes
+            .range(..=name)
+            .rev()
+            .find_map(|(owner, node)| Some((owner, node.get(DnsQType::NSEC)?)))
+    }
+
+    /// Owner names with their RRsets in canonical order
+    pub fn nodes(&self) -> impl Iterator<Item = (&DnsName, &Node)> {
+        self.nodes.iter()
+    }
+
+    /// All records of the zone in canonical order
+    pub fn records(&self) -> impl Iterator<Item = DnsResourceRecord> + '_ {
+        self.nodes.iter().flat_map(move |(name, node)| {
+            node.rrsets
+                .iter()
+                .flat_map(move |rrset| rrset.records(name, self.class))
+        })
+    }
+
+    /// Number of records in the zone
+    pub fn len(&self) -> usize {
+        self.nodes
+            .values()
+            .flat_map(|node| &node.rrsets)
+            .map(|rrset| rrset.rdatas.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Add a record, returns false if the zone already holds it. A record with a TTL different
+    /// from the rest of its RRset takes the TTL of the set.
+    pub fn insert(&mut self, record: DnsResourceRecord) -> Result<bool> {
+        if !record.name.is_subdomain_of(&self.origin) {
+            Err(anyhow!(
+                "Record for {} is outside of zone {}",
+                record.name,
+                self.origin
+            ))?;
+        }
+        if record.rclass != self.class {
+            Err(anyhow!(
+                "Record for {} has class {} instead of {}",
+                record.name,
+                record.rclass,
+                self.class
+            ))?;
+        }
+        if matches!(
+            record.rtype,
+            DnsQType::OPT | DnsQType::TSIG | DnsQType::AXFR | DnsQType::IXFR | DnsQType::ALL
+        ) {
+            Err(anyhow!(
+                "Record type {} cannot be stored in a zone",
+                record.rtype
+            ))?;
+        }
+
+        // Drop any compression pointer into the message the record was read from
+        let name = DnsName::from_labels(record.name.labels);
+        let node = self.nodes.entry(name).or_default();
+        let index = match node
+            .rrsets
+            .binary_search_by_key(&record.rtype.to_u16(), |rrset| rrset.rtype.to_u16())
+        {
+            Ok(index) => index,
+            Err(index) => {
+                node.rrsets
+                    .insert(index, RRset::new(record.rtype, record.ttl));
+                index
+            }
+        };
+
+        let rrset = &mut node.rrsets[index];
+        if rrset.rdatas.contains(&record.rdata) {
+            return Ok(false);
+        }
+        rrset.rdatas.push(record.rdata);
+
+        Ok(true)
+    }
+
+    /// Remove a single record, returns false if the zone does not hold it
+    pub fn remove(&mut self, name: &DnsName, rtype: DnsQType, rdata: &[u8]) -> bool {
+        let Some(node) = self.nodes.get_mut(name) else {
+            return false;
+        };
+        let Some(rrset) = node.rrsets.iter_mut().find(|rrset| rrset.rtype == rtype) else {
+            return false;
+        };
+
+        let count = rrset.rdatas.len();
+        rrset.rdatas.retain(|existing| existing != rdata);
+        let removed = rrset.rdatas.len() != count;
+
+        node.rrsets.retain(|rrset| !rrset.rdatas.is_empty());
+        if node.rrsets.is_empty() {
+            self.nodes.remove(name);
+        }
+
+        removed
+    }
+
+    pub fn remove_rrset(&mut self, name: &DnsName, rtype: DnsQType) -> Option<RRset> {
+        let node = self.nodes.get_mut(name)?;
+        let index = node.rrsets.iter().position(|rrset| rrset.rtype == rtype)?;
+        let rrset = node.rrsets.remove(index);
+
+        if node.rrsets.is_empty() {
+            self.nodes.remove(name);
+        }
+
+        Some(rrset)
+    }
+
+    pub fn remove_node(&mut self, name: &DnsName) -> Option<Node> {
+        self.nodes.remove(name)
+    }
+
+    /// Apply a diff made from the current version of the zone. The zone is left unchanged if
+    /// the diff does not match it.
+    pub fn apply(&mut self, diff: &Diff) -> Result<()> {
+        let serial = self.serial()?;
+        if diff.from_serial()? != serial {
+            Err(anyhow!(
+                "Diff from serial {} does not apply to zone {} with serial {}",
+                diff.from_serial()?,
+                self.origin,
+                serial
+            ))?;
+        }
+
+        let mut zone = self.clone();
+        zone.remove_rrset(&self.origin, DnsQType::SOA);
+        zone.insert(diff.to.clone())?;
+        for record in &diff.removed {
+            if !zone.remove(&record.name, record.rtype, &record.rdata) {
+                Err(anyhow!(
+                    "Zone {} has no {} record for {} to remove",
+                    self.origin,
+                    record.rtype,
+                    record.name
+                ))?;
+            }
+        }
+        for record in &diff.added {
+            zone.insert(record.clone())?;
+        }
+
+        *self = zone;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone() -> Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        let text = "$TTL 300\n\
+                    @ SOA ns1 hostmaster 1 3600 900 604800 300\n\
+                    @ NS ns1\n\
+                    www A 192.0.2.2\n\
+                    ns1 A 192.0.2.1\n\
+                    www A 192.0.2.3\n";
+        for entry in master::parse_str(text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+
+        Ok(zone)
+    }
+
+    #[test]
+    fn store_records_in_canonical_order() -> Result<()> {
+        let mut zone = zone()?;
+
+        assert_eq!(zone.serial()?, 1);
+        assert_eq!(zone.len(), 5);
+        let names: Vec<String> = zone.records().map(|r| r.name.to_string()).collect();
+        assert_eq!(
+            names,
+            [
+                "mycelnet.tech",
+                "mycelnet.tech",
+                "ns1.mycelnet.tech",
+                "www.mycelnet.tech",
+                "www.mycelnet.tech"
+            ]
+        );
+
+        let www: DnsName = "WWW.mycelnet.tech".parse()?;
+        assert!(zone.contains_name(&"mycelnet.tech".parse()?));
+        assert!(!zone.contains_name(&"ftp.mycelnet.tech".parse()?));
+        assert_eq!(zone.get(&www, DnsQType::A).unwrap().rdatas.len(), 2);
+
+        let duplicate = zone.records().nth(3).unwrap();
+        assert!(!zone.insert(duplicate)?);
+
+        assert!(zone.remove(&www, DnsQType::A, &[192, 0, 2, 2]));
+        assert!(!zone.remove(&www, DnsQType::A, &[192, 0, 2, 2]));
+        assert!(zone.remove_rrset(&www, DnsQType::A).is_some());
+        assert!(zone.node(&www).is_none());
+
+        let outside = DnsResourceRecord {
+            name: "mycelnet.net".parse()?,
+            ..DnsResourceRecord::default()
+        };
+        assert!(zone.insert(outside).is_err());
+
+        Ok(())
+    }
+}
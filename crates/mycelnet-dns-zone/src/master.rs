@@ -0,0 +1,635 @@
+//! Reader and writer for zones in the master file format of RFC 1035 section 5.
+//!
+//! Besides records the `$ORIGIN`, `$TTL` (RFC 2308) and `$INCLUDE` directives are understood.
+//! Entries may span lines within parentheses, owners may be left out to repeat the previous
+//! one, and the TTL and class may come in either order. Every record keeps the file and line
+//! it was read from so later checks can point at it, along with its comments.
+//!
+//! Zones are written in a canonical form: records sorted as in the zone with the SOA record
+//! first, names relative to the origin, each RRset under a single owner and the columns aligned.
+//! Comments of the records read from a previous version of the file are written along with
+//! the records still in the zone, so a file survives being read and written again.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use mycelnet_dns_protocol::{DnsClass, DnsName, DnsQType, DnsResourceRecord};
+
+use crate::rdata::{format_name, format_rdata, parse_name, parse_rdata, parse_ttl};
+use crate::Zone;
+
+/// Limit on nested `$INCLUDE` directives so a file including itself fails instead of looping
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Where a record was read from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// File the record was read from, None for text parsed directly
+    pub file: Option<PathBuf>,
+    pub line: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MasterRecord {
+    pub record: DnsResourceRecord,
+    pub location: Location,
+    /// Comment lines preceding the record
+    pub comments: Vec<String>,
+    /// Comment on the first line of the record
+    pub comment: Option<String>,
+}
+
+/// Read the records of a master file and the files it includes
+pub fn read_file(path: &Path, origin: &DnsName) -> Result<Vec<MasterRecord>> {
+    let mut reader = MasterReader::new(origin.clone());
+    reader.read_file(path, 0)?;
+    Ok(reader.records)
+}
+
+/// Parse master file text, `$INCLUDE` is not available without a file to resolve it against
+pub fn parse_str(text: &str, origin: &DnsName) -> Result<Vec<MasterRecord>> {
+    let mut reader = MasterReader::new(origin.clone());
+    reader.parse(text, None, 0)?;
+    Ok(reader.records)
+}
+
+/// Write a zone to a master file, keeping the comments of the records read from it before.
+/// The file is replaced at once so a failure leaves the previous version in place.
+pub fn write_file(path: &Path, zone: &Zone, previous: &[MasterRecord]) -> Result<()> {
+    let text = format_zone(zone, previous)?;
+
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, text)
+        .and_then(|_| fs::rename(&temporary, path))
+        .with_context(|| format!("Failed to write master file {}", path.display()))
+}
+
+/// Write a zone as master file text in canonical form, with the comments of the same records
+/// in a previous version of the file
+pub fn format_zone(zone: &Zone, previous: &[MasterRecord]) -> Result<String> {
+    let origin = zone.origin();
+    let soa = zone.soa_record()?;
+
+    // Comments before the first record belong to the file rather than to the record
+    let header = previous
+        .first()
+        .map(|entry| entry.comments.as_slice())
+        .unwrap_or_default();
+    let comments: HashMap<_, _> = previous
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let record = &entry.record;
+            let key = (
+                record.name.clone(),
+                record.rtype.to_u16(),
+                record.rdata.clone(),
+            );
+            let comments = if index == 0 {
+                &[][..]
+            } else {
+                &entry.comments[..]
+            };
+            (key, (comments, entry.comment.as_ref()))
+        })
+        .collect();
+
+    let mut lines: Vec<Line> = Vec::new();
+    for (name, node) in zone.nodes() {
+        let first = lines.len();
+        // The SOA record starts the zone, RFC 1035 section 5.2
+        let mut rrsets: Vec<_> = node.rrsets().iter().collect();
+        rrsets.sort_by_key(|rrset| (rrset.rtype != DnsQType::SOA, rrset.rtype.to_u16()));
+
+        let mut owner = Some(format_name(name, origin));
+        let mut node_comments = Vec::new();
+        for rrset in rrsets {
+            let mut rdatas = rrset.rdatas.clone();
+            rdatas.sort();
+
+            for rdata in rdatas {
+                let entry = comments.get(&(name.clone(), rrset.rtype.to_u16(), rdata.clone()));
+                // Comment lines go before the owner so its records stay together
+                if let Some((comments, _)) = entry {
+                    node_comments.extend(comments.iter().cloned());
+                }
+                lines.push(Line {
+                    comments: Vec::new(),
+                    owner: owner.take().unwrap_or_default(),
+                    ttl: (rrset.ttl != soa.ttl).then(|| rrset.ttl.to_string()),
+                    rtype: rrset.rtype.to_string(),
+                    rdata: format_rdata(rrset.rtype, &rdata, origin),
+                    comment: entry.and_then(|(_, comment)| comment.cloned()),
+                });
+            }
+        }
+        if let Some(line) = lines.get_mut(first) {
+            line.comments = node_comments;
+        }
+    }
+
+    let owner_width = lines.iter().map(|line| line.owner.len()).max().unwrap_or(0);
+    let ttl_width = lines
+        .iter()
+        .filter_map(|line| line.ttl.as_ref().map(String::len))
+        .max()
+        .unwrap_or(0);
+    let type_width = lines.iter().map(|line| line.rtype.len()).max().unwrap_or(0);
+
+    let mut text = String::new();
+    for comment in header {
+        text.push_str(&format_comment(comment));
+        text.push('\n');
+    }
+    text.push_str(&format!(
+        "$ORIGIN {}\n$TTL {}\n\n",
+        absolute(origin),
+        soa.ttl
+    ));
+    for line in lines {
+        for comment in &line.comments {
+            text.push_str(&format_comment(comment));
+            text.push('\n');
+        }
+
+        let ttl = line.ttl.unwrap_or_default();
+        let mut record = format!(
+            "{:owner_width$} {:>ttl_width$} {} {:type_width$} {}",
+            line.owner,
+            ttl,
+            zone.class(),
+            line.rtype,
+            line.rdata
+        );
+        if let Some(comment) = &line.comment {
+            record.push(' ');
+            record.push_str(&format_comment(comment));
+        }
+        text.push_str(record.trim_end());
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+/// A record as it is written, before the columns are aligned
+struct Line {
+    comments: Vec<String>,
+    /// Empty for records after the first of an owner
+    owner: String,
+    /// None for records with the TTL of the `$TTL` directive
+    ttl: Option<String>,
+    rtype: String,
+    rdata: String,
+    comment: Option<String>,
+}
+
+fn format_comment(comment: &str) -> String {
+    match comment {
+        "" => ";".to_string(),
+        comment => format!("; {comment}"),
+    }
+}
+
+fn absolute(name: &DnsName) -> String {
+    match name.is_root() {
+        true => ".".to_string(),
+        false => format!("{name}."),
+    }
+}
+
+/// One logical entry of a file, which may span several lines within parentheses
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// The entry started with white space, so it has no owner
+    indented: bool,
+    tokens: Vec<String>,
+    /// Comment lines between the previous entry and this one
+    comments: Vec<String>,
+    /// Comment on the first line of the entry, comments on further lines are dropped
+    comment: Option<String>,
+}
+
+struct MasterReader {
+    origin: DnsName,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsName>,
+    last_ttl: Option<u32>,
+    last_class: DnsClass,
+    /// Comments read since the last record, directives pass theirs on to the next record
+    comments: Vec<String>,
+    records: Vec<MasterRecord>,
+}
+
+impl MasterReader {
+    fn new(origin: DnsName) -> MasterReader {
+        MasterReader {
+            origin,
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            last_class: DnsClass::IN,
+            comments: Vec::new(),
+            records: Vec::new(),
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read master file {}", path.display()))?;
+        self.parse(&text, Some(path), depth)
+    }
+
+    fn parse(&mut self, text: &str, file: Option<&Path>, depth: usize) -> Result<()> {
+        for entry in tokenize(text).with_context(|| match file {
+            Some(file) => format!("Failed to read master file {}", file.display()),
+            None => "Failed to read master file".to_string(),
+        })? {
+            let location = Location {
+                file: file.map(Path::to_path_buf),
+                line: entry.line,
+            };
+            self.entry(&entry, &location, depth)
+                .with_context(|| location.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn entry(&mut self, entry: &Entry, location: &Location, depth: usize) -> Result<()> {
+        let tokens: Vec<&str> = entry.tokens.iter().map(String::as_str).collect();
+        self.comments.extend(entry.comments.iter().cloned());
+
+        match tokens[0].to_ascii_uppercase().as_str() {
+            "$ORIGIN" if !entry.indented => {
+                let [_, origin] = tokens[..] else {
+                    Err(anyhow!("$ORIGIN takes a single name"))?
+                };
+                self.origin = parse_name(origin, &self.origin)?;
+            }
+            "$TTL" if !entry.indented => {
+                let [_, ttl] = tokens[..] else {
+                    Err(anyhow!("$TTL takes a single TTL"))?
+                };
+                self.default_ttl = Some(parse_ttl(ttl)?);
+            }
+            "$INCLUDE" if !entry.indented => self.include(&tokens[1..], location, depth)?,
+            directive if directive.starts_with('$') && !entry.indented => {
+                Err(anyhow!("Unsupported directive {}", tokens[0]))?
+            }
+            _ => {
+                let record = self.record(entry.indented, &tokens)?;
+                self.records.push(MasterRecord {
+                    record,
+                    location: location.clone(),
+                    comments: std::mem::take(&mut self.comments),
+                    comment: entry.comment.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read an included file with its own origin, the origin of this file is kept afterwards
+    fn include(&mut self, arguments: &[&str], location: &Location, depth: usize) -> Result<()> {
+        let (path, origin) = match arguments {
+            [path] => (path, self.origin.clone()),
+            [path, origin] => (path, parse_name(origin, &self.origin)?),
+            _ => Err(anyhow!("$INCLUDE takes a file name and an optional origin"))?,
+        };
+        let Some(file) = &location.file else {
+            Err(anyhow!("$INCLUDE is only available in files"))?
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            Err(anyhow!(
+                "$INCLUDE is nested more than {} levels deep",
+                MAX_INCLUDE_DEPTH
+            ))?;
+        }
+
+        let path = file.parent().unwrap_or(Path::new("")).join(path);
+        let saved = std::mem::replace(&mut self.origin, origin);
+        let result = self.read_file(&path, depth + 1);
+        self.origin = saved;
+
+        result
+    }
+
+    fn record(&mut self, indented: bool, tokens: &[&str]) -> Result<DnsResourceRecord> {
+        let mut tokens = tokens.iter().copied();
+
+        let name = if indented {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| anyhow!("Record has no owner and there is no previous owner"))?
+        } else {
+            let owner = tokens.next().unwrap_or_default();
+            parse_name(owner, &self.origin)?
+        };
+
+        // The TTL and class are both optional and may come in either order
+        let mut ttl = None;
+        let mut rclass = None;
+        let rtype = loop {
+            let token = tokens
+                .next()
+                .ok_or_else(|| anyhow!("Record for {} has no type", name))?;
+
+            if ttl.is_none() && token.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(token)?);
+            } else if let (None, Ok(class)) = (rclass, DnsClass::from_str(token)) {
+                rclass = Some(class);
+            } else {
+                break DnsQType::from_str(token)?;
+            }
+        };
+
+        let fields: Vec<&str> = tokens.collect();
+        let rdata = parse_rdata(rtype, &fields, &self.origin)
+            .with_context(|| format!("Invalid {} record for {}", rtype, name))?;
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| anyhow!("Record for {} has no TTL and there is no $TTL", name))?;
+        let rclass = rclass.unwrap_or(self.last_class);
+
+        self.last_owner = Some(name.clone());
+        self.last_ttl = Some(ttl);
+        self.last_class = rclass;
+
+        Ok(DnsResourceRecord {
+            name,
+            rtype,
+            rclass,
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+        })
+    }
+}
+
+/// Split text into entries of tokens, joining lines within parentheses and keeping comments
+/// before and on the first line of each entry. Quotes are removed from quoted strings while
+/// escapes are kept for the fields to decode.
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut comments = Vec::new();
+    let mut token: Option<String> = None;
+    let mut depth = 0;
+    let mut line = 1;
+    let mut line_start = true;
+    let mut chars = text.chars().peekable();
+
+    fn push_token(
+        entry: &mut Option<Entry>,
+        token: &mut Option<String>,
+        line: usize,
+        indented: bool,
+    ) {
+        if let Some(token) = token.take() {
+            entry
+                .get_or_insert_with(|| Entry {
+                    line,
+                    indented,
+                    tokens: Vec::new(),
+                    comments: Vec::new(),
+                    comment: None,
+                })
+                .tokens
+                .push(token);
+        }
+    }
+
+    let mut indented = false;
+    while let Some(c) = chars.next() {
+        if line_start && depth == 0 {
+            indented = c == ' ' || c == '\t';
+        }
+        line_start = false;
+
+        match c {
+            '\\' => {
+                let current = token.get_or_insert_with(String::new);
+                current.push(c);
+                match chars.next() {
+                    Some('\n') | None => Err(anyhow!("Line {} ends with an escape", line))?,
+                    Some(escaped) => current.push(escaped),
+                }
+            }
+            '"' => {
+                let current = token.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            current.push('\\');
+                            match chars.next() {
+                                Some('\n') | None => {
+                                    Err(anyhow!("Unterminated string on line {}", line))?
+                                }
+                                Some(escaped) => current.push(escaped),
+                            }
+                        }
+                        Some('\n') | None => Err(anyhow!("Unterminated string on line {}", line))?,
+                        Some(c) => current.push(c),
+                    }
+                }
+            }
+            ';' => {
+                push_token(&mut entry, &mut token, line, indented);
+                let comment: String =
+                    std::iter::from_fn(|| chars.next_if(|c| *c != '\n')).collect();
+                let comment = comment.trim().to_string();
+                match &mut entry {
+                    Some(entry) if entry.line == line => entry.comment = Some(comment),
+                    Some(_) => {}
+                    None => comments.push(comment),
+                }
+            }
+            '(' => {
+                push_token(&mut entry, &mut token, line, indented);
+                depth += 1;
+            }
+            ')' => {
+                push_token(&mut entry, &mut token, line, indented);
+                if depth == 0 {
+                    Err(anyhow!("Unbalanced parenthesis on line {}", line))?;
+                }
+                depth -= 1;
+            }
+            '\n' => {
+                push_token(&mut entry, &mut token, line, indented);
+                if depth == 0 {
+                    if let Some(mut entry) = entry.take() {
+                        entry.comments = std::mem::take(&mut comments);
+                        entries.push(entry);
+                    }
+                }
+                line += 1;
+                line_start = true;
+            }
+            c if c.is_whitespace() => push_token(&mut entry, &mut token, line, indented),
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    push_token(&mut entry, &mut token, line, indented);
+    if depth != 0 {
+        Err(anyhow!("Unbalanced parenthesis at the end of the file"))?;
+    }
+    if let Some(mut entry) = entry {
+        entry.comments = comments;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"$ORIGIN mycelnet.tech.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            1h 15m 1w 300 )
+    IN  NS  ns1
+ns1 300 A   192.0.2.1
+    IN 600 AAAA 2001:db8::1
+www CNAME @
+txt TXT "v=spf1 -all" "quoted \"semi;colon\""
+
+$ORIGIN sub
+host A 192.0.2.2
+"#;
+
+    #[test]
+    fn parse_master_file() -> Result<()> {
+        let records = parse_str(ZONE, &"example.".parse()?)?;
+
+        let summary: Vec<(String, DnsQType, u32, usize)> = records
+            .iter()
+            .map(|entry| {
+                (
+                    entry.record.name.to_string(),
+                    entry.record.rtype,
+                    entry.record.ttl,
+                    entry.location.line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("mycelnet.tech".to_string(), DnsQType::SOA, 3600, 3),
+                ("mycelnet.tech".to_string(), DnsQType::NS, 3600, 6),
+                ("ns1.mycelnet.tech".to_string(), DnsQType::A, 300, 7),
+                ("ns1.mycelnet.tech".to_string(), DnsQType::AAAA, 600, 8),
+                ("www.mycelnet.tech".to_string(), DnsQType::CNAME, 3600, 9),
+                ("txt.mycelnet.tech".to_string(), DnsQType::TXT, 3600, 10),
+                ("host.sub.mycelnet.tech".to_string(), DnsQType::A, 3600, 13),
+            ]
+        );
+
+        assert_eq!(
+            records[5].record.rdata,
+            b"\x0bv=spf1 -all\x13quoted \"semi;colon\"".to_vec()
+        );
+        assert_eq!(
+            records[4].record.rdata,
+            b"\x08mycelnet\x04tech\x00".to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_canonical_zones() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = r#"; Zone of the mycelnet project
+$TTL 1h
+www   CNAME  web ; the main site
+@ IN SOA ns1 hostmaster (
+    2024010101 ; serial
+    1h 15m 1w 300 )
+; web servers
+web  300 A 192.0.2.2
+web A 192.0.2.1
+@ NS ns1.mycelnet.net.
+@ NS ns1
+ns1 A 192.0.2.53
+txt TXT "v=spf1 -all"
+"#;
+
+        let records = parse_str(text, &origin)?;
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in &records {
+            zone.insert(entry.record.clone())?;
+        }
+
+        let formatted = format_zone(&zone, &records)?;
+        assert_eq!(
+            formatted,
+            r#"; Zone of the mycelnet project
+$ORIGIN mycelnet.tech.
+$TTL 3600
+
+@       IN SOA   ns1 hostmaster 2024010101 3600 900 604800 300
+        IN NS    ns1.mycelnet.net.
+        IN NS    ns1
+ns1     IN A     192.0.2.53
+txt     IN TXT   "v=spf1 -all"
+; web servers
+web 300 IN A     192.0.2.1
+    300 IN A     192.0.2.2
+www     IN CNAME web ; the main site
+"#
+        );
+
+        // Writing the zone read back from the written file gives the same file
+        let reread = parse_str(&formatted, &origin)?;
+        let mut copy = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in &reread {
+            copy.insert(entry.record.clone())?;
+        }
+        let diff = crate::journal::Diff::between(&zone, &copy)?;
+        assert!(diff.removed.is_empty() && diff.added.is_empty());
+        assert_eq!(format_zone(&copy, &reread)?, formatted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_errors_with_line() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+
+        let error = parse_str("$TTL 60\nwww A 192.0.2.300\n", &origin).unwrap_err();
+        assert!(format!("{error:#}").starts_with("line 2: Invalid A record"));
+
+        assert!(parse_str("www A 192.0.2.1\n", &origin).is_err());
+        assert!(parse_str("$TTL 60\n  A 192.0.2.1\n", &origin).is_err());
+        assert!(parse_str("$TTL 60\nwww A ( 192.0.2.1\n", &origin).is_err());
+        assert!(parse_str("$INCLUDE other.zone\n", &origin).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,740 @@
+//! Conversion of record data between its master file text and wire format.
+//!
+//! Names in record data are stored uncompressed so records can be copied between messages and
+//! zones as they are. Types without a dedicated text format here can always be written in the
+//! generic `\# length hex` notation of RFC 3597 section 5.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use mycelnet_dns_protocol::{DnsName, DnsPacketData, DnsQType};
+
+/// Start of the SOA record data, the fields of RFC 1035 section 3.3.13
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    pub mname: DnsName,
+    pub rname: DnsName,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Soa {
+    pub fn from_rdata(rdata: &[u8]) -> Result<Soa> {
+        let mname = DnsName::from_bytes(rdata, 0)?;
+        let index = DnsName::wire_length(rdata, 0)?;
+        let rname = DnsName::from_bytes(rdata, index)?;
+        let index = index + DnsName::wire_length(rdata, index)?;
+
+        let timers = rdata
+            .get(index..index + 20)
+            .ok_or_else(|| anyhow!("SOA record data of {} bytes is truncated", rdata.len()))?;
+        let timer = |position: usize| {
+            u32::from_be_bytes([
+                timers[position],
+                timers[position + 1],
+                timers[position + 2],
+                timers[position + 3],
+            ])
+        };
+
+        Ok(Soa {
+            mname,
+            rname,
+            serial: timer(0),
+            refresh: timer(4),
+            retry: timer(8),
+            expire: timer(12),
+            minimum: timer(16),
+        })
+    }
+
+    pub fn to_rdata(&self) -> Result<Vec<u8>> {
+        let mut rdata = self.mname.to_bytes()?;
+        rdata.extend(self.rname.to_bytes()?);
+        for timer in [
+            self.serial,
+            self.refresh,
+            self.retry,
+            self.expire,
+            self.minimum,
+        ] {
+            rdata.extend_from_slice(&timer.to_be_bytes());
+        }
+
+        Ok(rdata)
+    }
+}
+
+/// Serial number comparison, RFC 1982. Returns whether `serial` is newer than `other`, serials
+/// exactly half the number space apart are unordered and never newer.
+pub fn serial_newer(serial: u32, other: u32) -> bool {
+    serial != other && serial.wrapping_sub(other) < 1 << 31
+}
+
+/// Parse a TTL or SOA timer given in seconds or with the `w`, `d`, `h`, `m` and `s` units
+/// accepted by most servers, for instance `1h30m`
+pub fn parse_ttl(text: &str) -> Result<u32> {
+    if let Ok(seconds) = text.parse::<u32>() {
+        return Ok(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut value: Option<u32> = None;
+    for c in text.chars() {
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            digit if digit.is_ascii_digit() => {
+                let digit = digit as u32 - '0' as u32;
+                value = value
+                    .unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|value| value.checked_add(digit));
+                if value.is_none() {
+                    Err(anyhow!("Time value {} is too large", text))?;
+                }
+                continue;
+            }
+            _ => Err(anyhow!("Invalid time value {}", text))?,
+        };
+
+        let seconds = value
+            .take()
+            .ok_or_else(|| anyhow!("Invalid time value {}", text))?
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds));
+        total = seconds.ok_or_else(|| anyhow!("Time value {} is too large", text))?;
+    }
+
+    if value.is_some() || text.is_empty() {
+        Err(anyhow!("Invalid time value {}", text))?;
+    }
+
+    Ok(total)
+}
+
+/// Parse a name in record data, `@` stands for the origin
+pub fn parse_name(text: &str, origin: &DnsName) -> Result<DnsName> {
+    match text {
+        "@" => Ok(origin.clone()),
+        _ => DnsName::parse(text, Some(origin)),
+    }
+}
+
+/// Parse the text fields of a record into its wire format record data
+pub fn parse_rdata(rtype: DnsQType, fields: &[&str], origin: &DnsName) -> Result<Vec<u8>> {
+    if fields.first() == Some(&"\\#") {
+        return parse_generic(fields);
+    }
+
+    let mut rdata = Vec::new();
+    let mut fields = Fields {
+        fields,
+        index: 0,
+        rtype,
+    };
+
+    match rtype {
+        DnsQType::A => {
+            let address: Ipv4Addr = fields.next()?.parse().context("Invalid IPv4 address")?;
+            rdata.extend_from_slice(&address.octets());
+        }
+        DnsQType::AAAA => {
+            let address: Ipv6Addr = fields.next()?.parse().context("Invalid IPv6 address")?;
+            rdata.extend_from_slice(&address.octets());
+        }
+        DnsQType::NS
+        | DnsQType::MD
+        | DnsQType::MF
+        | DnsQType::CNAME
+        | DnsQType::MB
+        | DnsQType::MG
+        | DnsQType::MR
+        | DnsQType::PTR
+        | DnsQType::DNAME => rdata.extend(fields.name(origin)?),
+        DnsQType::SOA => {
+            rdata.extend(fields.name(origin)?);
+            rdata.extend(fields.name(origin)?);
+            rdata.extend_from_slice(&fields.number::<u32>()?.to_be_bytes());
+            for _ in 0..4 {
+                rdata.extend_from_slice(&parse_ttl(fields.next()?)?.to_be_bytes());
+            }
+        }
+        DnsQType::MINFO | DnsQType::RP => {
+            rdata.extend(fields.name(origin)?);
+            rdata.extend(fields.name(origin)?);
+        }
+        DnsQType::MX | DnsQType::AFSDB | DnsQType::RT | DnsQType::KX => {
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            rdata.extend(fields.name(origin)?);
+        }
+        DnsQType::PX => {
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            rdata.extend(fields.name(origin)?);
+            rdata.extend(fields.name(origin)?);
+        }
+        DnsQType::SRV => {
+            for _ in 0..3 {
+                rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            }
+            rdata.extend(fields.name(origin)?);
+        }
+        DnsQType::TXT | DnsQType::SPF => {
+            rdata.extend(parse_character_string(fields.next()?)?);
+            while let Some(field) = fields.optional() {
+                rdata.extend(parse_character_string(field)?);
+            }
+        }
+        DnsQType::HINFO => {
+            rdata.extend(parse_character_string(fields.next()?)?);
+            rdata.extend(parse_character_string(fields.next()?)?);
+        }
+        DnsQType::NAPTR => {
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            for _ in 0..3 {
+                rdata.extend(parse_character_string(fields.next()?)?);
+            }
+            rdata.extend(fields.name(origin)?);
+        }
+        DnsQType::CAA => {
+            rdata.push(fields.number::<u8>()?);
+            let tag = parse_character_string(fields.next()?)?;
+            if tag.len() < 2 || !tag[1..].iter().all(u8::is_ascii_alphanumeric) {
+                Err(anyhow!("Invalid CAA tag"))?;
+            }
+            rdata.extend(tag);
+            // The value is not length prefixed, RFC 8659 section 4.1
+            rdata.extend_from_slice(&parse_character_string(fields.next()?)?[1..]);
+        }
+        DnsQType::DS | DnsQType::CDS => {
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            rdata.push(fields.number::<u8>()?);
+            rdata.push(fields.number::<u8>()?);
+            rdata.extend(decode_hex(&fields.rest()?.concat())?);
+        }
+        DnsQType::SSHFP => {
+            rdata.push(fields.number::<u8>()?);
+            rdata.push(fields.number::<u8>()?);
+            rdata.extend(decode_hex(&fields.rest()?.concat())?);
+        }
+        DnsQType::TLSA | DnsQType::SMIMEA => {
+            rdata.push(fields.number::<u8>()?);
+            rdata.push(fields.number::<u8>()?);
+            rdata.push(fields.number::<u8>()?);
+            rdata.extend(decode_hex(&fields.rest()?.concat())?);
+        }
+        DnsQType::DNSKEY | DnsQType::CDNSKEY => {
+            rdata.extend_from_slice(&fields.number::<u16>()?.to_be_bytes());
+            rdata.push(fields.number::<u8>()?);
+            rdata.push(fields.number::<u8>()?);
+            rdata.extend(
+                BASE64
+                    .decode(fields.rest()?.concat())
+                    .context("Invalid base64 key")?,
+            );
+        }
+        _ => Err(anyhow!(
+            "Record type {} must be written in the generic \\# format",
+            rtype
+        ))?,
+    }
+
+    fields.finish()?;
+    if rdata.len() > u16::MAX as usize {
+        Err(anyhow!("Record data of {} bytes is too long", rdata.len()))?;
+    }
+
+    Ok(rdata)
+}
+
+/// Record data in the `\# length hex` format of RFC 3597 section 5
+fn parse_generic(fields: &[&str]) -> Result<Vec<u8>> {
+    let length: usize = fields
+        .get(1)
+        .ok_or_else(|| anyhow!("Generic record data is missing its length"))?
+        .parse()
+        .context("Invalid generic record data length")?;
+    let rdata = decode_hex(&fields[2..].concat())?;
+
+    if rdata.len() != length {
+        Err(anyhow!(
+            "Generic record data of {} bytes does not match its length {}",
+            rdata.len(),
+            length
+        ))?;
+    }
+
+    Ok(rdata)
+}
+
+/// Decode a character string with `\X` and `\DDD` escapes and prefix it with its length
+pub fn parse_character_string(text: &str) -> Result<Vec<u8>> {
+    let mut data = vec![0];
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let digits: String = std::iter::from_fn(|| chars.next_if(char::is_ascii_digit))
+            .take(3)
+            .collect();
+        match digits.len() {
+            0 => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("Character string {} ends with an escape", text))?;
+                let mut buf = [0; 4];
+                data.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+            }
+            3 => data.push(
+                digits
+                    .parse()
+                    .map_err(|_| anyhow!("Escape \\{} exceeds 255", digits))?,
+            ),
+            _ => Err(anyhow!("Escape in {} needs three digits", text))?,
+        }
+    }
+
+    if data.len() > 256 {
+        Err(anyhow!(
+            "Character string of {} bytes exceeds 255 bytes",
+            data.len() - 1
+        ))?;
+    }
+    data[0] = (data.len() - 1) as u8;
+
+    Ok(data)
+}
+
+/// Write a name for a master file, `@` for the origin, relative below it and absolute elsewhere
+pub fn format_name(name: &DnsName, origin: &DnsName) -> String {
+    if name == origin {
+        "@".to_string()
+    } else if name.is_subdomain_of(origin) && !origin.is_root() {
+        let relative = &name.labels[..name.labels.len() - origin.labels.len()];
+        DnsName::from_labels(relative.to_vec()).to_string()
+    } else if name.is_root() {
+        ".".to_string()
+    } else {
+        format!("{name}.")
+    }
+}
+
+/// Write record data in its master file text, the inverse of [`parse_rdata`]. Data of types
+/// without a text format here, or that does not decode as its type, is written in the generic
+/// format.
+pub fn format_rdata(rtype: DnsQType, rdata: &[u8], origin: &DnsName) -> String {
+    match format_fields(rtype, rdata, origin) {
+        Ok(fields) => fields.join(" "),
+        Err(_) => format_generic(rdata),
+    }
+}
+
+fn format_fields(rtype: DnsQType, rdata: &[u8], origin: &DnsName) -> Result<Vec<String>> {
+    let mut data = Data { rdata, index: 0 };
+    let mut fields = Vec::new();
+
+    match rtype {
+        DnsQType::A => {
+            let octets: [u8; 4] = data.bytes(4)?.try_into()?;
+            fields.push(Ipv4Addr::from(octets).to_string());
+        }
+        DnsQType::AAAA => {
+            let octets: [u8; 16] = data.bytes(16)?.try_into()?;
+            fields.push(Ipv6Addr::from(octets).to_string());
+        }
+        DnsQType::NS
+        | DnsQType::MD
+        | DnsQType::MF
+        | DnsQType::CNAME
+        | DnsQType::MB
+        | DnsQType::MG
+        | DnsQType::MR
+        | DnsQType::PTR
+        | DnsQType::DNAME => fields.push(data.name(origin)?),
+        DnsQType::SOA => {
+            fields.push(data.name(origin)?);
+            fields.push(data.name(origin)?);
+            for _ in 0..5 {
+                fields.push(data.u32()?.to_string());
+            }
+        }
+        DnsQType::MINFO | DnsQType::RP => {
+            fields.push(data.name(origin)?);
+            fields.push(data.name(origin)?);
+        }
+        DnsQType::MX | DnsQType::AFSDB | DnsQType::RT | DnsQType::KX => {
+            fields.push(data.u16()?.to_string());
+            fields.push(data.name(origin)?);
+        }
+        DnsQType::PX => {
+            fields.push(data.u16()?.to_string());
+            fields.push(data.name(origin)?);
+            fields.push(data.name(origin)?);
+        }
+        DnsQType::SRV => {
+            for _ in 0..3 {
+                fields.push(data.u16()?.to_string());
+            }
+            fields.push(data.name(origin)?);
+        }
+        DnsQType::TXT | DnsQType::SPF => {
+            fields.push(data.character_string()?);
+            while !data.is_empty() {
+                fields.push(data.character_string()?);
+            }
+        }
+        DnsQType::HINFO => {
+            fields.push(data.character_string()?);
+            fields.push(data.character_string()?);
+        }
+        DnsQType::NAPTR => {
+            fields.push(data.u16()?.to_string());
+            fields.push(data.u16()?.to_string());
+            for _ in 0..3 {
+                fields.push(data.character_string()?);
+            }
+            fields.push(data.name(origin)?);
+        }
+        DnsQType::CAA => {
+            fields.push(data.u8()?.to_string());
+            let length = data.u8()? as usize;
+            let tag = data.bytes(length)?;
+            if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                Err(anyhow!("Invalid CAA tag"))?;
+            }
+            fields.push(String::from_utf8_lossy(tag).into_owned());
+            let value = data.rest();
+            if value.len() > 255 {
+                Err(anyhow!("CAA value of {} bytes is too long", value.len()))?;
+            }
+            fields.push(quote(value));
+        }
+        DnsQType::DS | DnsQType::CDS => {
+            fields.push(data.u16()?.to_string());
+            fields.push(data.u8()?.to_string());
+            fields.push(data.u8()?.to_string());
+            fields.push(encode_hex(data.rest_nonempty()?));
+        }
+        DnsQType::SSHFP => {
+            fields.push(data.u8()?.to_string());
+            fields.push(data.u8()?.to_string());
+            fields.push(encode_hex(data.rest_nonempty()?));
+        }
+        DnsQType::TLSA | DnsQType::SMIMEA => {
+            for _ in 0..3 {
+                fields.push(data.u8()?.to_string());
+            }
+            fields.push(encode_hex(data.rest_nonempty()?));
+        }
+        DnsQType::DNSKEY | DnsQType::CDNSKEY => {
+            fields.push(data.u16()?.to_string());
+            fields.push(data.u8()?.to_string());
+            fields.push(data.u8()?.to_string());
+            fields.push(BASE64.encode(data.rest_nonempty()?));
+        }
+        _ => Err(anyhow!("Record type {} has no text format", rtype))?,
+    }
+
+    data.finish()?;
+    Ok(fields)
+}
+
+fn format_generic(rdata: &[u8]) -> String {
+    if rdata.is_empty() {
+        return "\\# 0".to_string();
+    }
+
+    format!("\\# {} {}", rdata.len(), encode_hex(rdata))
+}
+
+/// Quote a character string, escaping quotes, backslashes and bytes that are not printable
+pub fn quote(data: &[u8]) -> String {
+    let mut text = String::from('"');
+    for &byte in data {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x20..=0x7e => text.push(byte as char),
+            _ => text.push_str(&format!("\\{byte:03}")),
+        }
+    }
+    text.push('"');
+
+    text
+}
+
+pub fn decode_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        Err(anyhow!("Invalid hexadecimal data {}", text))?;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&text[index..index + 2], 16)
+                .map_err(|_| anyhow!("Invalid hexadecimal data {}", text))
+        })
+        .collect()
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+/// Fields of a record read one at a time
+struct Fields<'a> {
+    fields: &'a [&'a str],
+    index: usize,
+    rtype: DnsQType,
+}
+
+impl<'a> Fields<'a> {
+    fn optional(&mut self) -> Option<&'a str> {
+        let field = self.fields.get(self.index)?;
+        self.index += 1;
+        Some(field)
+    }
+
+    fn next(&mut self) -> Result<&'a str> {
+        self.optional()
+            .ok_or_else(|| anyhow!("Record data of type {} is incomplete", self.rtype))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T> {
+        let field = self.next()?;
+        field
+            .parse()
+            .map_err(|_| anyhow!("Invalid number {} in {} record", field, self.rtype))
+    }
+
+    fn name(&mut self, origin: &DnsName) -> Result<Vec<u8>> {
+        parse_name(self.next()?, origin)?.to_bytes()
+    }
+
+    /// All remaining fields, at least one
+    fn rest(&mut self) -> Result<&'a [&'a str]> {
+        self.next()?;
+        let rest = &self.fields[self.index - 1..];
+        self.index = self.fields.len();
+        Ok(rest)
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.index < self.fields.len() {
+            Err(anyhow!(
+                "Unexpected {} after the data of a {} record",
+                self.fields[self.index],
+                self.rtype
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Record data read one field at a time
+struct Data<'a> {
+    rdata: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Data<'a> {
+    fn is_empty(&self) -> bool {
+        self.index == self.rdata.len()
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .rdata
+            .get(self.index..self.index + length)
+            .ok_or_else(|| anyhow!("Record data of {} bytes is truncated", self.rdata.len()))?;
+        self.index += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self, origin: &DnsName) -> Result<String> {
+        let length = DnsName::wire_length(self.rdata, self.index)?;
+        let name = DnsName::from_bytes(self.bytes(length)?, 0)?;
+        Ok(format_name(&name, origin))
+    }
+
+    fn character_string(&mut self) -> Result<String> {
+        let length = self.u8()? as usize;
+        Ok(quote(self.bytes(length)?))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.rdata[self.index..];
+        self.index = self.rdata.len();
+        rest
+    }
+
+    /// All remaining bytes, at least one
+    fn rest_nonempty(&mut self) -> Result<&'a [u8]> {
+        match self.rest() {
+            [] => Err(anyhow!(
+                "Record data of {} bytes is truncated",
+                self.rdata.len()
+            )),
+            rest => Ok(rest),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.is_empty() {
+            Err(anyhow!(
+                "{} bytes left after the end of the record data",
+                self.rdata.len() - self.index
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_record_data() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+
+        assert_eq!(
+            parse_rdata(DnsQType::A, &["192.0.2.1"], &origin)?,
+            vec![192, 0, 2, 1]
+        );
+        assert_eq!(
+            parse_rdata(DnsQType::MX, &["10", "mail"], &origin)?,
+            [&[0, 10][..], b"\x04mail\x08mycelnet\x04tech\x00"].concat()
+        );
+        assert_eq!(
+            parse_rdata(DnsQType::TXT, &["hello world", "a\\\"b\\065"], &origin)?,
+            b"\x0bhello world\x04a\"bA".to_vec()
+        );
+        assert_eq!(
+            parse_rdata(DnsQType::CAA, &["0", "issue", "ca.example"], &origin)?,
+            b"\x00\x05issueca.example".to_vec()
+        );
+        assert_eq!(
+            parse_rdata(DnsQType::HIP, &["\\#", "3", "0a", "0B0c"], &origin)?,
+            vec![10, 11, 12]
+        );
+
+        let soa = parse_rdata(
+            DnsQType::SOA,
+            &["ns1", "hostmaster", "2024010101", "1h", "15m", "1w", "300"],
+            &origin,
+        )?;
+        let soa = Soa::from_rdata(&soa)?;
+        assert_eq!(soa.mname, "ns1.mycelnet.tech".parse()?);
+        assert_eq!(
+            (soa.serial, soa.refresh, soa.retry),
+            (2024010101, 3600, 900)
+        );
+        assert_eq!((soa.expire, soa.minimum), (604800, 300));
+        assert_eq!(Soa::from_rdata(&soa.to_rdata()?)?, soa);
+
+        assert!(parse_rdata(DnsQType::A, &["192.0.2.1", "extra"], &origin).is_err());
+        assert!(parse_rdata(DnsQType::MX, &["mail"], &origin).is_err());
+        assert!(parse_rdata(DnsQType::HIP, &["\\#", "2", "0a"], &origin).is_err());
+        assert!(parse_rdata(DnsQType::HIP, &["00"], &origin).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn format_record_data() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let records: &[(DnsQType, &[&str], &str)] = &[
+            (DnsQType::A, &["192.0.2.1"], "192.0.2.1"),
+            (DnsQType::AAAA, &["2001:db8::1"], "2001:db8::1"),
+            (DnsQType::CNAME, &["@"], "@"),
+            (DnsQType::NS, &["ns1.mycelnet.net."], "ns1.mycelnet.net."),
+            (DnsQType::MX, &["10", "mail"], "10 mail"),
+            (
+                DnsQType::SOA,
+                &["ns1", "hostmaster", "1", "1h", "15m", "1w", "300"],
+                "ns1 hostmaster 1 3600 900 604800 300",
+            ),
+            (
+                DnsQType::TXT,
+                &["hello world", "a\\\"b\\007"],
+                "\"hello world\" \"a\\\"b\\007\"",
+            ),
+            (
+                DnsQType::CAA,
+                &["0", "issue", "ca.example"],
+                "0 issue \"ca.example\"",
+            ),
+            (DnsQType::DS, &["1", "13", "2", "ab", "CD"], "1 13 2 ABCD"),
+            (DnsQType::HIP, &["\\#", "2", "0a0b"], "\\# 2 0A0B"),
+        ];
+
+        for (rtype, fields, text) in records {
+            let rdata = parse_rdata(*rtype, fields, &origin)?;
+            let formatted = format_rdata(*rtype, &rdata, &origin);
+            assert_eq!(&formatted, text);
+
+            let fields: Vec<&str> = formatted
+                .split(' ')
+                .map(|field| field.trim_matches('"'))
+                .collect();
+            if *rtype != DnsQType::TXT {
+                assert_eq!(parse_rdata(*rtype, &fields, &origin)?, rdata);
+            }
+        }
+
+        // Data that does not decode as its type is written in the generic format
+        assert_eq!(format_rdata(DnsQType::A, &[1, 2], &origin), "\\# 2 0102");
+        assert_eq!(format_rdata(DnsQType::NS, &[], &origin), "\\# 0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_serials() -> Result<()> {
+        assert!(serial_newer(2, 1));
+        assert!(serial_newer(0, u32::MAX));
+        assert!(!serial_newer(1, 1));
+        assert!(!serial_newer(1, 2));
+        assert!(!serial_newer(1 << 31, 0));
+
+        assert_eq!(parse_ttl("1h30m")?, 5400);
+        assert_eq!(parse_ttl("86400")?, 86400);
+        assert!(parse_ttl("1x").is_err());
+        assert!(parse_ttl("h").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+//! Zone data served from an SQLite database, with the `sqlite` feature.
+//!
+//! Every row of the `records` table is one record: the owner name, the TTL, the type and the
+//! data in master file format, where names may be relative to the origin of the zone. Rows are
+//! read when queries need them, so other tools can change the records in the database while
+//! the zone is served.
+//!
+//! Lookups go through the `key` column, the owner name in canonical order (RFC 4034 section
+//! 6.1) as bytes. Tools adding rows leave it empty, the server fills it in when opening the
+//! database and before the next lookup once another connection committed changes, and clears it
+//! again when the owner name of a row changes.
+//!
+//! Queries run on the calling thread and wait for the connection of the zone, callers in async
+//! code run them on blocking threads.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use mycelnet_dns_protocol::{DnsClass, DnsName, DnsQType, DnsResourceRecord};
+
+use crate::backend::ZoneBackend;
+use crate::rdata::{format_rdata, parse_name};
+use crate::{master, Node, RRset, Zone};
+
+/// Rows read at a time when walking all records
+const BATCH_SIZE: i64 = 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS records (
+    name TEXT NOT NULL,
+    ttl INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    data TEXT NOT NULL,
+    key BLOB
+);
+";
+
+/// Indexes of the key column, created once tables made by other tools have the column too
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS records_key ON records (key);
+CREATE INDEX IF NOT EXISTS records_nsec ON records (upper(type), key);
+CREATE TRIGGER IF NOT EXISTS records_rename AFTER UPDATE OF name ON records BEGIN
+    UPDATE records SET key = NULL WHERE rowid = NEW.rowid;
+END;
+";
+
+/// A row of the records table, the row ID with the owner name, TTL, type and data
+type Row = (i64, String, u32, String, String);
+
+#[derive(Debug)]
+pub struct SqliteBackend {
+    origin: DnsName,
+    connection: Mutex<Connection>,
+    /// `PRAGMA data_version` when keys were last filled in, it changes with commits of others
+    version: AtomicI64,
+}
+
+impl SqliteBackend {
+    /// Open the database of a zone, creating the records table if it does not exist
+    pub fn open(path: &Path, origin: DnsName) -> Result<SqliteBackend> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .and_then(|_| {
+                let keyed: bool = connection.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM pragma_table_info('records') WHERE name = 'key')",
+                    [],
+                    |row| row.get(0),
+                )?;
+                if !keyed {
+                    connection.execute("ALTER TABLE records ADD COLUMN key BLOB", [])?;
+                }
+                connection.execute_batch(INDEXES)
+            })
+            .with_context(|| format!("Failed to create the tables of {}", path.display()))?;
+
+        let backend = SqliteBackend {
+            origin,
+            connection: Mutex::new(connection),
+            version: AtomicI64::new(-1),
+        };
+        drop(
+            backend
+                .keyed()
+                .with_context(|| format!("Failed to fill in the keys of {}", path.display()))?,
+        );
+        Ok(backend)
+    }
+
+    /// Add records in one transaction, for instance those read from a master file
+    pub fn import(&self, records: impl IntoIterator<Item = DnsResourceRecord>) -> Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut count = 0;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO records (name, ttl, type, data, key) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for record in records {
+                if !record.name.is_subdomain_of(&self.origin) {
+                    Err(anyhow!(
+                        "Record for {} is outside of zone {}",
+                        record.name,
+                        self.origin
+                    ))?;
+                }
+                insert.execute(params![
+                    absolute(&record.name),
+                    record.ttl,
+                    record.rtype.to_string(),
+                    format_rdata(record.rtype, &record.rdata, &self.origin),
+                    key(&record.name),
+                ])?;
+                count += 1;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(count)
+    }
+
+    /// The connection once every row has its key, looking for rows without one only after
+    /// other connections committed. Rows with owner names that do not parse keep an empty key,
+    /// lookups do not find them but walking all records reports them.
+    fn keyed(&self) -> Result<MutexGuard<'_, Connection>> {
+        let mut connection = self.connection.lock().unwrap();
+        let version: i64 = connection
+            .prepare_cached("PRAGMA data_version")?
+            .query_row([], |row| row.get(0))?;
+        if self.version.swap(version, Ordering::Relaxed) == version {
+            return Ok(connection);
+        }
+
+        let unkeyed: Vec<(i64, String)> = connection
+            .prepare_cached("SELECT rowid, name FROM records WHERE key IS NULL")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        if unkeyed.is_empty() {
+            return Ok(connection);
+        }
+
+        let transaction = connection.transaction()?;
+        {
+            let mut update = transaction.prepare("UPDATE records SET key = ?1 WHERE rowid = ?2")?;
+            for (rowid, name) in unkeyed {
+                if let Ok(name) = parse_name(&name, &self.origin) {
+                    update.execute(params![key(&name), rowid])?;
+                }
+            }
+        }
+        transaction.commit()?;
+
+        Ok(connection)
+    }
+
+    /// Rows owned by a name
+    fn rows(&self, name: &DnsName) -> Result<Vec<Row>> {
+        let connection = self.keyed()?;
+        let mut select = connection
+            .prepare_cached("SELECT rowid, name, ttl, type, data FROM records WHERE key = ?1")?;
+        let rows = select
+            .query_map([key(name)], row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    /// Rows following a row ID, in the order they were added
+    fn batch(&self, after: i64) -> Result<Vec<Row>> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare_cached(
+            "SELECT rowid, name, ttl, type, data FROM records WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+        )?;
+        let rows = select
+            .query_map([after, BATCH_SIZE], row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    /// Parse a row as a line of a master file
+    fn record(&self, (rowid, name, ttl, rtype, data): Row) -> Result<DnsResourceRecord> {
+        let line = format!("{name} {ttl} {rtype} {data}");
+        let mut records = master::parse_str(&line, &self.origin)
+            .with_context(|| format!("Invalid record in row {rowid}: {line}"))?;
+
+        match (records.pop(), records.is_empty()) {
+            (Some(entry), true) => Ok(entry.record),
+            _ => Err(anyhow!("Row {rowid} does not hold one record: {line}")),
+        }
+    }
+}
+
+impl ZoneBackend for SqliteBackend {
+    fn origin(&self) -> &DnsName {
+        &self.origin
+    }
+
+    fn class(&self) -> DnsClass {
+        DnsClass::IN
+    }
+
+    fn node(&self, name: &DnsName) -> Result<Option<Cow<'_, Node>>> {
+        // The records are grouped into RRsets the same way as in a zone loaded into memory
+        let mut zone = Zone::new(self.origin.clone(), DnsClass::IN);
+        for row in self.rows(name)? {
+            zone.insert(self.record(row)?)?;
+        }
+
+        Ok(zone.remove_node(name).map(Cow::Owned))
+    }
+
+    fn contains_name(&self, name: &DnsName) -> Result<bool> {
+        let connection = self.keyed()?;
+        let exists = if name.is_root() {
+            connection.query_row("SELECT EXISTS (SELECT 1 FROM records)", [], |row| {
+                row.get(0)
+            })?
+        } else {
+            // The keys of the name and its descendants start with its key, they sort before the
+            // key with the last byte raised
+            let start = key(name);
+            let mut end = start.clone();
+            if let Some(last) = end.last_mut() {
+                *last += 1;
+            }
+            connection.query_row(
+                "SELECT EXISTS (SELECT 1 FROM records WHERE key >= ?1 AND key < ?2)",
+                [start, end],
+                |row| row.get(0),
+            )?
+        };
+
+        Ok(exists)
+    }
+
+    fn nsec(&self, name: &DnsName) -> Result<Option<(DnsName, Cow<'_, RRset>)>> {
+        let owner: Option<String> = self
+            .keyed()?
+            .prepare_cached(
+                "SELECT name FROM records WHERE upper(type) = 'NSEC' AND key <= ?1
+                    ORDER BY key DESC LIMIT 1",
+            )?
+            .query_row([key(name)], |row| row.get(0))
+            .optional()?;
+        let Some(owner) = owner else {
+            return Ok(None);
+        };
+        let owner = parse_name(&owner, &self.origin)?;
+
+        let nsec = self
+            .node(&owner)?
+            .and_then(|node| node.get(DnsQType::NSEC).cloned());
+        Ok(nsec.map(|nsec| (owner, Cow::Owned(nsec))))
+    }
+
+    fn records(&self) -> Box<dyn Iterator<Item = Result<DnsResourceRecord>> + '_> {
+        let mut rows = Vec::new().into_iter();
+        let mut last = 0;
+        let mut done = false;
+
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some(row) = rows.next() {
+                return Some(self.record(row));
+            }
+            if done {
+                return None;
+            }
+
+            match self.batch(last) {
+                Ok(batch) => {
+                    done = (batch.len() as i64) < BATCH_SIZE;
+                    last = batch.last().map_or(last, |row| row.0);
+                    rows = batch.into_iter();
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            }
+        }))
+    }
+}
+
+fn row(row: &rusqlite::Row) -> rusqlite::Result<Row> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+/// Owner name as bytes that compare in canonical order, RFC 4034 section 6.1: the labels from the
+/// root down in lower case, each ended by two zero bytes and with zero bytes in them escaped as a
+/// zero and a one byte, so that a label sorts before longer labels starting with it
+fn key(name: &DnsName) -> Vec<u8> {
+    let mut key = Vec::new();
+    for label in name.labels.iter().rev() {
+        for byte in label {
+            match byte.to_ascii_lowercase() {
+                0 => key.extend([0, 1]),
+                byte => key.push(byte),
+            }
+        }
+        key.extend([0, 0]);
+    }
+    key
+}
+
+/// Owner name as stored in the database, with the trailing dot of an absolute name
+fn absolute(name: &DnsName) -> String {
+    match name.to_string() {
+        name if name.ends_with('.') => name,
+        name => format!("{name}."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use mycelnet_dns_protocol::DnsPacketData;
+
+    use super::*;
+
+    const ZONE: &str = r#"$TTL 300
+@ SOA ns1 hostmaster 1 3600 900 604800 60
+@ NS ns1
+@ NSEC \# 1 00
+ns1 A 192.0.2.53
+ns1 AAAA 2001:db8::53
+_sip._udp SRV 10 5 5060 sip
+a.b.deep TXT "hello world"
+"#;
+
+    /// A database file for tests sharing it with other connections
+    fn database(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn backends() -> Result<(Zone, SqliteBackend)> {
+        backends_in(Path::new(":memory:"))
+    }
+
+    fn backends_in(path: &Path) -> Result<(Zone, SqliteBackend)> {
+        let origin: DnsName = "mycelnet.tech".parse()?;
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        let records: Vec<_> = master::parse_str(ZONE, &origin)?
+            .into_iter()
+            .map(|entry| entry.record)
+            .collect();
+        for record in &records {
+            zone.insert(record.clone())?;
+        }
+
+        let backend = SqliteBackend::open(path, origin)?;
+        assert_eq!(backend.import(records)?, 7);
+        Ok((zone, backend))
+    }
+
+    #[test]
+    fn serve_from_sqlite() -> Result<()> {
+        let (zone, backend) = backends()?;
+
+        for name in [
+            "mycelnet.tech",
+            "NS1.mycelnet.tech",
+            "_sip._udp.mycelnet.tech",
+            "_udp.mycelnet.tech",
+            "deep.mycelnet.tech",
+            "x.b.deep.mycelnet.tech",
+            "www.mycelnet.tech",
+        ] {
+            let name: DnsName = name.parse()?;
+            assert_eq!(
+                backend.node(&name)?.as_deref(),
+                Zone::node(&zone, &name),
+                "{name}"
+            );
+            assert_eq!(
+                backend.contains_name(&name)?,
+                zone.contains_name(&name),
+                "{name}"
+            );
+            assert_eq!(
+                ZoneBackend::closest_encloser(&backend, &name)?,
+                zone.closest_encloser(&name),
+                "{name}"
+            );
+            let nsec = backend.nsec(&name)?;
+            assert_eq!(
+                nsec.as_ref().map(|(owner, rrset)| (owner, rrset.as_ref())),
+                zone.nsec(&name),
+                "{name}"
+            );
+        }
+
+        assert!(ZoneBackend::is_signed(&backend)?);
+        assert_eq!(ZoneBackend::soa(&backend)?.serial, 1);
+        let records = ZoneBackend::records(&backend).collect::<Result<Vec<_>>>()?;
+        assert_eq!(records.len(), zone.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_records_changed_by_others() -> Result<()> {
+        let path = database("read-records-changed-by-others");
+        let (_, backend) = backends_in(&path)?;
+        let www: DnsName = "www.mycelnet.tech".parse()?;
+
+        let connection = Connection::open(&path)?;
+        connection.execute(
+            "INSERT INTO records (name, ttl, type, data) VALUES ('www.mycelnet.tech.', 60, 'CNAME', 'ns1')",
+            [],
+        )?;
+        connection.execute(
+            "INSERT INTO records (name, ttl, type, data) VALUES ('bad.mycelnet.tech.', 60, 'A', 'not-an-address')",
+            [],
+        )?;
+        drop(connection);
+
+        let cname = backend.get(&www, DnsQType::CNAME)?.unwrap();
+        assert_eq!(cname.ttl, 60);
+        assert_eq!(
+            DnsName::from_bytes(&cname.rdatas[0], 0)?,
+            "ns1.mycelnet.tech".parse()?
+        );
+
+        assert!(backend.node(&"bad.mycelnet.tech".parse()?).is_err());
+        let records: Vec<_> = ZoneBackend::records(&backend).collect();
+        assert_eq!(records.len(), 9);
+        assert!(records[8].is_err());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keep_canonical_order() -> Result<()> {
+        // RFC 4034 section 6.1 with names that compare differently as text
+        let mut names = [
+            "z.a.example",
+            "a.example",
+            "\\000.a.example",
+            "example",
+            "Z.a.example",
+            "a\\.b.example",
+            "*.z.example",
+            "\\200.z.example",
+            "yljkjljk.a.example",
+            "zABC.a.EXAMPLE",
+            "\\001.z.example",
+            "b.example",
+            "a\\000.example",
+        ]
+        .map(|name| name.parse::<DnsName>())
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        let mut keys: Vec<_> = names.iter().map(key).collect();
+        keys.sort();
+        assert_eq!(keys, names.iter().map(key).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_rows_without_keys() -> Result<()> {
+        let path = database("find-rows-without-keys");
+        let (_, backend) = backends_in(&path)?;
+
+        let connection = Connection::open(&path)?;
+        connection.execute(
+            "INSERT INTO records (name, ttl, type, data) VALUES ('www', 60, 'A', '192.0.2.80')",
+            [],
+        )?;
+        // The escaped dot is part of the label, the name is not below b.mycelnet.tech
+        connection.execute(
+            "INSERT INTO records (name, ttl, type, data) VALUES ('a\\.b', 60, 'NSEC', '\\# 1 00')",
+            [],
+        )?;
+
+        assert!(backend
+            .get(&"WWW.mycelnet.tech".parse()?, DnsQType::A)?
+            .is_some());
+        assert!(backend.contains_name(&"a\\.b.mycelnet.tech".parse()?)?);
+        assert!(!backend.contains_name(&"b.mycelnet.tech".parse()?)?);
+        let (owner, _) = backend.nsec(&"a\\.c.mycelnet.tech".parse()?)?.unwrap();
+        assert_eq!(owner, "a\\.b.mycelnet.tech".parse()?);
+        let (owner, _) = backend.nsec(&"b.mycelnet.tech".parse()?)?.unwrap();
+        assert_eq!(owner, "a\\.b.mycelnet.tech".parse()?);
+        let (owner, _) = backend.nsec(&"a.mycelnet.tech".parse()?)?.unwrap();
+        assert_eq!(owner, "mycelnet.tech".parse()?);
+
+        // Renamed rows are found under their new name
+        connection.execute(
+            "UPDATE records SET name = 'web.mycelnet.tech.' WHERE name = 'www'",
+            [],
+        )?;
+        assert!(backend.node(&"www.mycelnet.tech".parse()?)?.is_none());
+        assert!(backend
+            .get(&"web.mycelnet.tech".parse()?, DnsQType::A)?
+            .is_some());
+
+        // Rows added before the database is opened get their keys at once
+        connection.execute(
+            "INSERT INTO records (name, ttl, type, data) VALUES ('ftp', 60, 'A', '192.0.2.21')",
+            [],
+        )?;
+        drop(backend);
+        let backend = SqliteBackend::open(&path, "mycelnet.tech".parse()?)?;
+        let unkeyed: i64 = connection.query_row(
+            "SELECT count(*) FROM records WHERE key IS NULL AND name = 'ftp'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(unkeyed, 0);
+        assert!(backend
+            .get(&"ftp.mycelnet.tech".parse()?, DnsQType::A)?
+            .is_some());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
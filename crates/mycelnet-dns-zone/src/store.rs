@@ -0,0 +1,157 @@
+//! The set of zones a server answers for.
+//!
+//! Queries take a snapshot of the zones and never wait on changes: the set is copied on write
+//! and swapped in whole, so a query sees either the zones before or after a change, never a
+//! mix of both.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use mycelnet_dns_protocol::DnsName;
+
+use crate::backend::ZoneBackend;
+
+type Zones = HashMap<DnsName, Arc<dyn ZoneBackend>>;
+
+#[derive(Debug, Default)]
+pub struct ZoneStore {
+    zones: RwLock<Arc<Zones>>,
+}
+
+impl ZoneStore {
+    pub fn new(zones: impl IntoIterator<Item = Arc<dyn ZoneBackend>>) -> ZoneStore {
+        let zones = zones
+            .into_iter()
+            .map(|zone| (zone.origin().clone(), zone))
+            .collect();
+
+        ZoneStore {
+            zones: RwLock::new(Arc::new(zones)),
+        }
+    }
+
+    /// Zone with the longest origin containing a name, the zone authoritative for it
+    pub fn find(&self, name: &DnsName) -> Option<Arc<dyn ZoneBackend>> {
+        let zones = self.snapshot();
+        name.ancestors()
+            .find_map(|ancestor| zones.get(&ancestor).cloned())
+    }
+
+    /// Zone with exactly the origin
+    pub fn get(&self, origin: &DnsName) -> Option<Arc<dyn ZoneBackend>> {
+        self.snapshot().get(origin).cloned()
+    }
+
+    /// All zones in no particular order
+    pub fn zones(&self) -> Vec<Arc<dyn ZoneBackend>> {
+        self.snapshot().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+
+    /// Add a zone or replace the one with its origin, returns the replaced zone
+    pub fn insert(&self, zone: Arc<dyn ZoneBackend>) -> Option<Arc<dyn ZoneBackend>> {
+        let mut zones = self.zones.write().unwrap();
+        Arc::make_mut(&mut zones).insert(zone.origin().clone(), zone)
+    }
+
+    pub fn remove(&self, origin: &DnsName) -> Option<Arc<dyn ZoneBackend>> {
+        let mut zones = self.zones.write().unwrap();
+        Arc::make_mut(&mut zones).remove(origin)
+    }
+
+    /// Replace the zones at once except those to keep, for instance the ones maintained by
+    /// other means than the replacement. Returns the zones that were replaced.
+    pub fn replace(
+        &self,
+        zones: impl IntoIterator<Item = Arc<dyn ZoneBackend>>,
+        keep: impl Fn(&dyn ZoneBackend) -> bool,
+    ) -> Vec<Arc<dyn ZoneBackend>> {
+        let mut current = self.zones.write().unwrap();
+        let (kept, replaced): (Zones, Zones) = current
+            .iter()
+            .map(|(origin, zone)| (origin.clone(), zone.clone()))
+            .partition(|(_, zone)| keep(zone.as_ref()));
+
+        let mut next = kept;
+        next.extend(zones.into_iter().map(|zone| (zone.origin().clone(), zone)));
+        *current = Arc::new(next);
+
+        replaced.into_values().collect()
+    }
+
+    /// The zones as they are now, unaffected by later changes
+    fn snapshot(&self) -> Arc<Zones> {
+        self.zones.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use mycelnet_dns_protocol::DnsClass;
+
+    use crate::Zone;
+
+    use super::*;
+
+    fn zone(origin: &str) -> Arc<dyn ZoneBackend> {
+        Arc::new(Zone::new(origin.parse().unwrap(), DnsClass::IN))
+    }
+
+    fn origin(zone: Option<Arc<dyn ZoneBackend>>) -> Option<String> {
+        zone.map(|zone| zone.origin().to_string())
+    }
+
+    #[test]
+    fn select_enclosing_zones() -> Result<()> {
+        let store = ZoneStore::new([zone("mycelnet.tech"), zone("lab.mycelnet.tech")]);
+
+        assert_eq!(
+            origin(store.find(&"www.mycelnet.tech".parse()?)),
+            Some("mycelnet.tech".to_string())
+        );
+        assert_eq!(
+            origin(store.find(&"host.Lab.mycelnet.tech".parse()?)),
+            Some("lab.mycelnet.tech".to_string())
+        );
+        assert_eq!(
+            origin(store.find(&"lab.mycelnet.tech".parse()?)),
+            Some("lab.mycelnet.tech".to_string())
+        );
+        assert_eq!(origin(store.find(&"mycelnet.net".parse()?)), None);
+        assert_eq!(origin(store.get(&"www.mycelnet.tech".parse()?)), None);
+
+        // Snapshots taken before a change keep the zones they saw
+        let before = store.zones();
+        assert!(store.remove(&"lab.mycelnet.tech".parse()?).is_some());
+        assert_eq!(before.len(), 2);
+        assert_eq!(
+            origin(store.find(&"host.lab.mycelnet.tech".parse()?)),
+            Some("mycelnet.tech".to_string())
+        );
+
+        assert!(store.insert(zone("mycelnet.net")).is_none());
+        assert!(store.insert(zone("mycelnet.net")).is_some());
+        assert_eq!(store.len(), 2);
+
+        let kept: DnsName = "mycelnet.net".parse()?;
+        let previous = store.replace([zone("example")], |zone| zone.origin() == &kept);
+        assert_eq!(previous.len(), 1);
+        assert_eq!(store.len(), 2);
+        assert_eq!(origin(store.find(&"www.mycelnet.tech".parse()?)), None);
+        assert_eq!(
+            origin(store.find(&"www.example".parse()?)),
+            Some("example".to_string())
+        );
+
+        Ok(())
+    }
+}
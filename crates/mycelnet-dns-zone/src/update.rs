@@ -0,0 +1,424 @@
+//! Dynamic updates of a zone, RFC 2136 section 3.
+//!
+//! Prerequisites are checked against the current zone and the updates are applied to a copy,
+//! so an update either changes the zone as a whole or not at all. The SOA serial of the new
+//! version is incremented unless the update sets a newer one itself.
+
+use std::fmt::{Display, Formatter};
+
+use mycelnet_dns_protocol::{DnsClass, DnsName, DnsQType, DnsRcode, DnsResourceRecord};
+
+use crate::rdata::{serial_newer, Soa};
+use crate::Zone;
+
+/// Reason an update was rejected and the rcode answering it
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateError {
+    pub rcode: DnsRcode,
+    pub reason: String,
+}
+
+impl UpdateError {
+    pub fn new(rcode: DnsRcode, reason: impl Into<String>) -> UpdateError {
+        UpdateError {
+            rcode,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for UpdateError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.rcode, self.reason)
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// Types that only exist in queries and can neither be required nor added
+fn is_meta(rtype: DnsQType) -> bool {
+    matches!(
+        rtype,
+        DnsQType::OPT
+            | DnsQType::TSIG
+            | DnsQType::AXFR
+            | DnsQType::IXFR
+            | DnsQType::MAILA
+            | DnsQType::MAILB
+            | DnsQType::ALL
+    )
+}
+
+/// Check the prerequisites and apply the updates to a copy of the zone. Returns the new version
+/// of the zone, or None if the updates left it unchanged.
+pub fn update(
+    zone: &Zone,
+    prerequisites: &[DnsResourceRecord],
+    updates: &[DnsResourceRecord],
+) -> Result<Option<Zone>, UpdateError> {
+    check_prerequisites(zone, prerequisites)?;
+    prescan(zone, updates)?;
+
+    let mut updated = zone.clone();
+    let serial = zone
+        .serial()
+        .map_err(|e| UpdateError::new(DnsRcode::ServerFailure, format!("{e:#}")))?;
+    for record in updates {
+        apply(&mut updated, record)?;
+    }
+
+    if &updated == zone {
+        return Ok(None);
+    }
+
+    // Changes without a newer SOA record of their own get the next serial, RFC 2136 section 3.6
+    let new_serial = updated.serial().unwrap_or(serial);
+    if !serial_newer(new_serial, serial) {
+        set_serial(&mut updated, serial.wrapping_add(1))?;
+    }
+
+    Ok(Some(updated))
+}
+
+/// RFC 2136 section 3.2
+fn check_prerequisites(
+    zone: &Zone,
+    prerequisites: &[DnsResourceRecord],
+) -> Result<(), UpdateError> {
+    // RRsets that must exist with exactly the given records
+    let mut required = Zone::new(zone.origin().clone(), zone.class());
+
+    for record in prerequisites {
+        if record.ttl != 0 {
+            Err(UpdateError::new(
+                DnsRcode::FormatError,
+                format!("Prerequisite for {} has a TTL", record.name),
+            ))?;
+        }
+        check_in_zone(zone, &record.name)?;
+
+        let node = zone.node(&record.name);
+        match record.rclass {
+            DnsClass::ANY | DnsClass::NONE if !record.rdata.is_empty() => Err(UpdateError::new(
+                DnsRcode::FormatError,
+                format!("Prerequisite for {} has record data", record.name),
+            ))?,
+            DnsClass::ANY if record.rtype == DnsQType::ALL => {
+                if node.is_none() {
+                    Err(UpdateError::new(
+                        DnsRcode::NameError,
+                        format!("Name {} is not in use", record.name),
+                    ))?;
+                }
+            }
+            DnsClass::ANY => {
+                if node.and_then(|node| node.get(record.rtype)).is_none() {
+                    Err(UpdateError::new(
+                        DnsRcode::NXRRSet,
+                        format!("{} has no {} records", record.name, record.rtype),
+                    ))?;
+                }
+            }
+            DnsClass::NONE if record.rtype == DnsQType::ALL => {
+                if node.is_some() {
+                    Err(UpdateError::new(
+                        DnsRcode::YXDomain,
+                        format!("Name {} is in use", record.name),
+                    ))?;
+                }
+            }
+            DnsClass::NONE => {
+                if node.and_then(|node| node.get(record.rtype)).is_some() {
+                    Err(UpdateError::new(
+                        DnsRcode::YXRRSet,
+                        format!("{} has {} records", record.name, record.rtype),
+                    ))?;
+                }
+            }
+            rclass if rclass == zone.class() && !is_meta(record.rtype) => {
+                required
+                    .insert(record.clone())
+                    .map_err(|e| UpdateError::new(DnsRcode::FormatError, format!("{e:#}")))?;
+            }
+            _ => Err(UpdateError::new(
+                DnsRcode::FormatError,
+                format!("Prerequisite for {} has an invalid class", record.name),
+            ))?,
+        }
+    }
+
+    // Compare record data as sets, TTLs do not take part
+    for (name, node) in required.nodes() {
+        for rrset in node.rrsets() {
+            let matches = zone.get(name, rrset.rtype).is_some_and(|existing| {
+                existing.rdatas.len() == rrset.rdatas.len()
+                    && rrset
+                        .rdatas
+                        .iter()
+                        .all(|rdata| existing.rdatas.contains(rdata))
+            });
+            if !matches {
+                Err(UpdateError::new(
+                    DnsRcode::NXRRSet,
+                    format!("{} records of {} differ", rrset.rtype, name),
+                ))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject malformed updates before any is applied, RFC 2136 section 3.4.1
+fn prescan(zone: &Zone, updates: &[DnsResourceRecord]) -> Result<(), UpdateError> {
+    for record in updates {
+        check_in_zone(zone, &record.name)?;
+
+        let valid = match record.rclass {
+            rclass if rclass == zone.class() => !is_meta(record.rtype),
+            DnsClass::ANY => {
+                record.ttl == 0
+                    && record.rdata.is_empty()
+                    && (record.rtype == DnsQType::ALL || !is_meta(record.rtype))
+            }
+            DnsClass::NONE => record.ttl == 0 && !is_meta(record.rtype),
+            _ => false,
+        };
+
+        if !valid {
+            Err(UpdateError::new(
+                DnsRcode::FormatError,
+                format!(
+                    "Invalid update of {} records of {}",
+                    record.rtype, record.name
+                ),
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply a single update, RFC 2136 section 3.4.2
+fn apply(zone: &mut Zone, record: &DnsResourceRecord) -> Result<(), UpdateError> {
+    let apex = &record.name == zone.origin();
+
+    match record.rclass {
+        DnsClass::ANY if record.rtype == DnsQType::ALL => {
+            let rtypes: Vec<DnsQType> = zone
+                .node(&record.name)
+                .map(|node| node.rrsets().iter().map(|rrset| rrset.rtype).collect())
+                .unwrap_or_default();
+            for rtype in rtypes {
+                // The SOA and NS records of the apex survive deleting every RRset
+                if !(apex && matches!(rtype, DnsQType::SOA | DnsQType::NS)) {
+                    zone.remove_rrset(&record.name, rtype);
+                }
+            }
+        }
+        DnsClass::ANY => {
+            if !(apex && matches!(record.rtype, DnsQType::SOA | DnsQType::NS)) {
+                zone.remove_rrset(&record.name, record.rtype);
+            }
+        }
+        DnsClass::NONE => {
+            let last_ns = apex
+                && record.rtype == DnsQType::NS
+                && zone
+                    .get(&record.name, DnsQType::NS)
+                    .is_some_and(|rrset| rrset.rdatas.len() == 1);
+            if record.rtype != DnsQType::SOA && !last_ns {
+                zone.remove(&record.name, record.rtype, &record.rdata);
+            }
+        }
+        _ => add(zone, record)?,
+    }
+
+    Ok(())
+}
+
+fn add(zone: &mut Zone, record: &DnsResourceRecord) -> Result<(), UpdateError> {
+    let node = zone.node(&record.name);
+    let has = |rtype: DnsQType| node.is_some_and(|node| node.get(rtype).is_some());
+    let has_other = node.is_some_and(|node| {
+        node.rrsets()
+            .iter()
+            .any(|rrset| rrset.rtype != DnsQType::CNAME)
+    });
+
+    match record.rtype {
+        // Aliases cannot share their name with other data
+        DnsQType::CNAME if has_other => return Ok(()),
+        DnsQType::CNAME => {
+            zone.remove_rrset(&record.name, DnsQType::CNAME);
+        }
+        _ if has(DnsQType::CNAME) => return Ok(()),
+        DnsQType::SOA => {
+            let soa = Soa::from_rdata(&record.rdata)
+                .map_err(|e| UpdateError::new(DnsRcode::FormatError, format!("{e:#}")))?;
+            let current = zone
+                .serial()
+                .map_err(|e| UpdateError::new(DnsRcode::ServerFailure, format!("{e:#}")))?;
+            if record.name != *zone.origin() || !serial_newer(soa.serial, current) {
+                return Ok(());
+            }
+            zone.remove_rrset(&record.name, DnsQType::SOA);
+        }
+        _ => {}
+    }
+
+    zone.insert(record.clone())
+        .map_err(|e| UpdateError::new(DnsRcode::FormatError, format!("{e:#}")))?;
+
+    Ok(())
+}
+
+fn set_serial(zone: &mut Zone, serial: u32) -> Result<(), UpdateError> {
+    let failure = |e: anyhow::Error| UpdateError::new(DnsRcode::ServerFailure, format!("{e:#}"));
+
+    let mut soa = zone.soa().map_err(failure)?;
+    soa.serial = serial;
+    let mut record = zone.soa_record().map_err(failure)?;
+    record.rdata = soa.to_rdata().map_err(failure)?;
+    record.rdlength = record.rdata.len() as u16;
+
+    let origin = zone.origin().clone();
+    zone.remove_rrset(&origin, DnsQType::SOA);
+    zone.insert(record).map_err(failure)?;
+
+    Ok(())
+}
+
+fn check_in_zone(zone: &Zone, name: &DnsName) -> Result<(), UpdateError> {
+    if name.is_subdomain_of(zone.origin()) {
+        Ok(())
+    } else {
+        Err(UpdateError::new(
+            DnsRcode::NotZone,
+            format!("{} is outside of zone {}", name, zone.origin()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::master;
+
+    use super::*;
+
+    fn zone() -> anyhow::Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = "$TTL 300\n\
+                    @ SOA ns1 hostmaster 7 3600 900 604800 300\n\
+                    @ NS ns1\n\
+                    ns1 A 192.0.2.1\n\
+                    www A 192.0.2.2\n\
+                    www A 192.0.2.3\n\
+                    ftp CNAME www\n";
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in master::parse_str(text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+        Ok(zone)
+    }
+
+    fn record(text: &str, rclass: DnsClass) -> anyhow::Result<DnsResourceRecord> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let mut record = master::parse_str(text, &origin)?.remove(0).record;
+        record.rclass = rclass;
+        Ok(record)
+    }
+
+    fn rcode(result: Result<Option<Zone>, UpdateError>) -> DnsRcode {
+        result.err().map(|e| e.rcode).unwrap_or(DnsRcode::NoError)
+    }
+
+    #[test]
+    fn check_update_prerequisites() -> anyhow::Result<()> {
+        let zone = zone()?;
+        let check = |text: &str, rclass| -> anyhow::Result<DnsRcode> {
+            let mut prerequisite = record(text, rclass)?;
+            if rclass != DnsClass::IN {
+                prerequisite.rdata.clear();
+            }
+            Ok(rcode(update(&zone, &[prerequisite], &[])))
+        };
+
+        assert_eq!(check("www 0 A 0.0.0.0", DnsClass::ANY)?, DnsRcode::NoError);
+        assert_eq!(check("www 0 MX 0 .", DnsClass::ANY)?, DnsRcode::NXRRSet);
+        assert_eq!(
+            check("mail 0 A 0.0.0.0", DnsClass::NONE)?,
+            DnsRcode::NoError
+        );
+        assert_eq!(check("www 0 A 0.0.0.0", DnsClass::NONE)?, DnsRcode::YXRRSet);
+        assert_eq!(
+            check("www 300 A 0.0.0.0", DnsClass::ANY)?,
+            DnsRcode::FormatError
+        );
+        assert_eq!(
+            check("www.mycelnet.net. 0 A 0.0.0.0", DnsClass::ANY)?,
+            DnsRcode::NotZone
+        );
+
+        let mut in_use = record("www 0 A 0.0.0.0", DnsClass::NONE)?;
+        in_use.rtype = DnsQType::ALL;
+        in_use.rdata.clear();
+        assert_eq!(
+            rcode(update(&zone, &[in_use.clone()], &[])),
+            DnsRcode::YXDomain
+        );
+        in_use.rclass = DnsClass::ANY;
+        in_use.name = "mail.mycelnet.tech".parse()?;
+        assert_eq!(rcode(update(&zone, &[in_use], &[])), DnsRcode::NameError);
+
+        // RRsets must match exactly
+        let both = [
+            record("www 0 A 192.0.2.3", DnsClass::IN)?,
+            record("www 0 A 192.0.2.2", DnsClass::IN)?,
+        ];
+        assert_eq!(rcode(update(&zone, &both, &[])), DnsRcode::NoError);
+        assert_eq!(rcode(update(&zone, &both[..1], &[])), DnsRcode::NXRRSet);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_updates_atomically() -> anyhow::Result<()> {
+        let zone = zone()?;
+        let www: DnsName = "www.mycelnet.tech".parse()?;
+
+        let mut delete_rrset = record("www 0 A 0.0.0.0", DnsClass::ANY)?;
+        delete_rrset.rdata.clear();
+        let updates = [
+            delete_rrset,
+            record("www 300 A 192.0.2.9", DnsClass::IN)?,
+            record("www 0 A 192.0.2.9", DnsClass::NONE)?,
+            record("www 300 AAAA 2001:db8::9", DnsClass::IN)?,
+            record("ftp 300 A 192.0.2.9", DnsClass::IN)?,
+            record("@ 0 NS ns1", DnsClass::NONE)?,
+        ];
+        let updated = update(&zone, &[], &updates)?.unwrap();
+
+        assert_eq!(updated.serial()?, 8);
+        assert!(updated.get(&www, DnsQType::A).is_none());
+        assert!(updated.get(&www, DnsQType::AAAA).is_some());
+        // Data next to an alias and the last NS record of the apex are left alone
+        assert!(updated
+            .get(&"ftp.mycelnet.tech".parse()?, DnsQType::A)
+            .is_none());
+        assert!(updated.get(zone.origin(), DnsQType::NS).is_some());
+
+        // A failing update leaves no change behind
+        let failing = [
+            record("mail 300 A 192.0.2.9", DnsClass::IN)?,
+            record("mail.mycelnet.net. 300 A 192.0.2.9", DnsClass::IN)?,
+        ];
+        assert_eq!(rcode(update(&zone, &[], &failing)), DnsRcode::NotZone);
+
+        // Updates that change nothing keep the serial
+        let existing = record("www 300 A 192.0.2.2", DnsClass::IN)?;
+        assert!(update(&zone, &[], &[existing])?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use mycelnet_dns_protocol::tsig::{
+    append_tsig_error, find_tsig, time_other_data, TsigError, TsigKey, TsigKeyRing, TsigRecord,
+    TsigSigner, TsigVerifier,
+};
+use mycelnet_dns_protocol::DnsRcode;
+
+/// Outcome of authenticating a request against the key ring
+#[derive(Debug)]
+pub enum Authentication {
+    /// The request carried no TSIG record
+    Unsigned,
+    /// The request was signed with a known key, responses are signed with the same key
+    Signed { key: TsigKey, request: TsigRecord },
+    /// The request failed verification and must be answered with the error
+    Failed {
+        error: TsigError,
+        key: Option<TsigKey>,
+        request: TsigRecord,
+    },
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Verify the TSIG record of a request if it has one
+pub fn authenticate(keyring: &TsigKeyRing, data: &[u8], now: u64) -> Result<Authentication> {
+    let request = match find_tsig(data)? {
+        Some((_, request)) => request,
+        None => return Ok(Authentication::Unsigned),
+    };
+
+    let key = match keyring.get(&request.key_name, &request.algorithm) {
+        Some(key) => key.clone(),
+        None => {
+            return Ok(Authentication::Failed {
+                error: TsigError::BadKey,
+                key: None,
+                request,
+            })
+        }
+    };
+
+    match TsigVerifier::new(key.clone(), None).verify(data, now) {
+        Ok(_) => Ok(Authentication::Signed { key, request }),
+        Err(TsigError::FormatError(reason)) => Err(TsigError::FormatError(reason).into()),
+        Err(error) => Ok(Authentication::Failed {
+            error,
+            key: Some(key),
+            request,
+        }),
+    }
+}
+
+/// Signs a stream of response messages according to how the request was authenticated
+pub enum ResponseSigner {
+    Unsigned,
+    Signed(TsigSigner),
+    /// BADTIME responses are signed at the time of the request and carry the server time
+    BadTime(TsigSigner, u64),
+    /// Other failures are answered with an unsigned TSIG record carrying the error
+    Error(TsigRecord, DnsRcode),
+}
+
+impl ResponseSigner {
+    pub fn new(authentication: &Authentication) -> ResponseSigner {
+        match authentication {
+            Authentication::Unsigned => ResponseSigner::Unsigned,
+            Authentication::Signed { key, request } => {
+                ResponseSigner::Signed(TsigSigner::new(key.clone(), Some(request.mac.clone())))
+            }
+            Authentication::Failed {
+                error: TsigError::BadTime(_),
+                key: Some(key),
+                request,
+            } => ResponseSigner::BadTime(
+                TsigSigner::new(key.clone(), Some(request.mac.clone())),
+                request.time_signed,
+            ),
+            Authentication::Failed { error, request, .. } => {
+                ResponseSigner::Error(request.clone(), error.error())
+            }
+        }
+    }
+
+    /// Number of bytes the TSIG record appended to the response occupies
+    pub fn size(&self) -> usize {
+        match self {
+            ResponseSigner::Unsigned => 0,
+            ResponseSigner::Signed(signer) => signer.record_size(0),
+            ResponseSigner::BadTime(signer, _) => signer.record_size(6),
+            // Error records carry neither a MAC nor other data
+            ResponseSigner::Error(request, _) => {
+                request.size() - request.mac.len() - request.other_data.len()
+            }
+        }
+    }
+
+    pub fn sign(&mut self, response: &[u8], now: u64) -> Result<Vec<u8>> {
+        match self {
+            ResponseSigner::Unsigned => Ok(response.to_vec()),
+            ResponseSigner::Signed(signer) => signer.sign(response, now),
+            ResponseSigner::BadTime(signer, time_signed) => signer.sign_with_error(
+                response,
+                *time_signed,
+                DnsRcode::BadTimestamp,
+                time_other_data(now),
+            ),
+            ResponseSigner::Error(request, error) => append_tsig_error(response, request, *error),
+        }
+    }
+}
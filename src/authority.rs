@@ -0,0 +1,782 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use mycelnet_dns_protocol::{DnsName, DnsPacketData, DnsQType, DnsRcode, DnsResourceRecord};
+use mycelnet_dns_zone::{backend::ZoneBackend, store::ZoneStore, Node};
+
+/// Answer to a question from the data of a zone the server is authoritative for
+#[derive(Debug)]
+pub struct Lookup {
+    pub rcode: DnsRcode,
+    /// Cleared for referrals to the servers of a delegated zone
+    pub authoritative: bool,
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+    pub additional: Vec<DnsResourceRecord>,
+}
+
+/// Where the answer for a name comes from when not from the node of the name
+enum Redirection<'a> {
+    /// The name is at or below the NS records of a delegation
+    Referral(DnsName, Cow<'a, Node>),
+    /// The name is below the owner of a DNAME record
+    Dname(DnsName, Cow<'a, Node>),
+}
+
+/// Zone answering a question, the one with the longest origin containing the name. DS records
+/// belong to the parent side of a zone cut, RFC 4035 section 3.1.4.1, so they are answered from
+/// the zone above the apex of a child zone when the server has that one as well.
+pub fn zone_for(
+    zones: &ZoneStore,
+    qname: &DnsName,
+    qtype: DnsQType,
+) -> Option<Arc<dyn ZoneBackend>> {
+    let zone = zones.find(qname)?;
+    if qtype != DnsQType::DS || zone.origin() != qname {
+        return Some(zone);
+    }
+
+    qname
+        .parent()
+        .and_then(|parent| zones.find(&parent))
+        .or(Some(zone))
+}
+
+/// Look up a name in a zone containing it, RFC 1034 section 4.3.2. Names that do not exist
+/// are answered from the wildcard below their closest encloser, RFC 4592 section 3.3. Clients
+/// setting the DO bit get the signatures and NSEC proofs of signed zones, RFC 4035 section 3.1.
+/// Names below a DNAME record are redirected to the same names below its target, RFC 6672, and
+/// names at or below a delegation are referred to its name servers, RFC 1034 section 4.3.2.
+pub fn lookup<Z: ZoneBackend + ?Sized>(
+    zone: &Z,
+    qname: &DnsName,
+    qtype: DnsQType,
+    dnssec_ok: bool,
+) -> Result<Lookup> {
+    let mut lookup = Lookup {
+        rcode: DnsRcode::NoError,
+        authoritative: true,
+        answers: Vec::new(),
+        authority: Vec::new(),
+        additional: Vec::new(),
+    };
+    let dnssec = dnssec_ok && zone.is_signed()?;
+
+    match find_redirection(zone, qname, qtype)? {
+        Some(Redirection::Referral(cut, node)) => {
+            refer(&mut lookup, zone, &cut, &node, dnssec)?;
+            return Ok(lookup);
+        }
+        Some(Redirection::Dname(owner, node)) => {
+            redirect(&mut lookup, zone, &owner, &node, qname, dnssec)?;
+            return Ok(lookup);
+        }
+        None => {}
+    }
+
+    match zone.node(qname)? {
+        Some(node) => {
+            answer(&mut lookup, zone, &node, qname, qtype, dnssec);
+            if dnssec && lookup.answers.is_empty() {
+                add_nsec(&mut lookup, zone, qname)?;
+            }
+        }
+        // Names without records that have descendants exist, RFC 8020 section 2, and block
+        // wildcards above them from matching
+        None if zone.contains_name(qname)? => {
+            if dnssec {
+                add_nsec(&mut lookup, zone, qname)?;
+            }
+        }
+        None => {
+            let wildcard = zone
+                .closest_encloser(qname)?
+                .and_then(|encloser| encloser.wildcard_of().ok());
+            let source = match &wildcard {
+                Some(wildcard) => zone.node(wildcard)?.map(|node| (wildcard, node)),
+                None => None,
+            };
+
+            match source {
+                // Records of the wildcard are synthesized with the name of the question
+                Some((wildcard, node)) => {
+                    answer(&mut lookup, zone, &node, qname, qtype, dnssec);
+                    if dnssec {
+                        // Prove no closer name matched, RFC 4035 section 3.1.3.3
+                        add_nsec(&mut lookup, zone, qname)?;
+                        if lookup.answers.is_empty() {
+                            add_nsec(&mut lookup, zone, wildcard)?;
+                        }
+                    }
+                }
+                None => {
+                    lookup.rcode = DnsRcode::NameError;
+                    if dnssec {
+                        // Prove neither the name nor a wildcard exists, RFC 4035 section 3.1.3.2
+                        add_nsec(&mut lookup, zone, qname)?;
+                        if let Some(wildcard) = &wildcard {
+                            add_nsec(&mut lookup, zone, wildcard)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if lookup.answers.is_empty() {
+        if let Some(soa) = negative_soa(zone) {
+            if dnssec {
+                let signatures = signatures(zone, zone.origin(), DnsQType::SOA, soa.ttl)?;
+                lookup.authority.splice(0..0, signatures);
+            }
+            lookup.authority.insert(0, soa);
+        }
+    }
+
+    Ok(lookup)
+}
+
+/// Delegation or DNAME record above the name closest to the apex, the data below either is not
+/// part of the zone, RFC 2181 section 6 and RFC 6672 section 2.4. The DS records of a delegation
+/// belong to the parent side and are answered from the zone, RFC 4035 section 3.1.4.1.
+fn find_redirection<'a, Z: ZoneBackend + ?Sized>(
+    zone: &'a Z,
+    qname: &DnsName,
+    qtype: DnsQType,
+) -> Result<Option<Redirection<'a>>> {
+    let mut redirection = None;
+
+    for ancestor in qname.ancestors() {
+        if !ancestor.is_subdomain_of(zone.origin()) {
+            break;
+        }
+        let Some(node) = zone.node(&ancestor)? else {
+            continue;
+        };
+
+        let at_qname = &ancestor == qname;
+        if &ancestor != zone.origin()
+            && node.get(DnsQType::NS).is_some()
+            && !(at_qname && qtype == DnsQType::DS)
+        {
+            redirection = Some(Redirection::Referral(ancestor, node));
+        } else if node.get(DnsQType::DNAME).is_some() && !at_qname {
+            redirection = Some(Redirection::Dname(ancestor, node));
+        }
+    }
+
+    Ok(redirection)
+}
+
+/// Refer to the name servers of a delegation with their addresses as glue, RFC 1034 section
+/// 4.3.2. Signed zones also prove whether the delegated zone is signed, RFC 4035 section 3.1.4.
+fn refer<Z: ZoneBackend + ?Sized>(
+    lookup: &mut Lookup,
+    zone: &Z,
+    cut: &DnsName,
+    node: &Node,
+    dnssec: bool,
+) -> Result<()> {
+    lookup.authoritative = false;
+
+    let Some(ns) = node.get(DnsQType::NS) else {
+        return Ok(());
+    };
+    lookup.authority.extend(ns.records(cut, zone.class()));
+    if dnssec {
+        match node.get(DnsQType::DS) {
+            Some(ds) => {
+                lookup.authority.extend(ds.records(cut, zone.class()));
+                lookup.authority.extend(
+                    node.signatures(DnsQType::DS)
+                        .map(|rdata| signature(zone, cut, rdata, ds.ttl)),
+                );
+            }
+            None => add_nsec(lookup, zone, cut)?,
+        }
+    }
+
+    for rdata in &ns.rdatas {
+        let Ok(target) = DnsName::from_bytes(rdata, 0) else {
+            continue;
+        };
+        let target = DnsName::from_labels(target.labels);
+        if !target.is_subdomain_of(zone.origin()) {
+            continue;
+        }
+        let Some(glue) = zone.node(&target)? else {
+            continue;
+        };
+        for rtype in [DnsQType::A, DnsQType::AAAA] {
+            if let Some(addresses) = glue.get(rtype) {
+                lookup
+                    .additional
+                    .extend(addresses.records(&target, zone.class()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer with the DNAME record and a CNAME record synthesized from it, which points from the
+/// name of the question to the same name below the target, RFC 6672 section 3.2
+fn redirect<Z: ZoneBackend + ?Sized>(
+    lookup: &mut Lookup,
+    zone: &Z,
+    owner: &DnsName,
+    node: &Node,
+    qname: &DnsName,
+    dnssec: bool,
+) -> Result<()> {
+    let Some(dname) = node.get(DnsQType::DNAME) else {
+        return Ok(());
+    };
+    let records: Vec<_> = dname.records(owner, zone.class()).collect();
+    lookup.answers.extend(records.iter().cloned());
+    if dnssec {
+        lookup.answers.extend(
+            node.signatures(DnsQType::DNAME)
+                .map(|rdata| signature(zone, owner, rdata, dname.ttl)),
+        );
+    }
+
+    let Some(target) = records
+        .first()
+        .and_then(|record| DnsName::from_bytes(&record.rdata, 0).ok())
+    else {
+        return Ok(());
+    };
+    let target = DnsName::from_labels(target.labels);
+    // Names becoming too long by the substitution can not exist, RFC 6672 section 2.2
+    let Ok(rdata) = qname
+        .substitute(owner, &target)
+        .and_then(|alias| alias.to_bytes())
+    else {
+        lookup.rcode = DnsRcode::YXDomain;
+        return Ok(());
+    };
+
+    // The synthesized CNAME record is not signed, validators derive it from the DNAME record
+    lookup.answers.push(DnsResourceRecord {
+        name: qname.clone(),
+        rtype: DnsQType::CNAME,
+        rclass: zone.class(),
+        ttl: dname.ttl,
+        rdlength: rdata.len() as u16,
+        rdata,
+    });
+
+    Ok(())
+}
+
+/// Answer from the records of a node, owned by the name of the question
+fn answer<Z: ZoneBackend + ?Sized>(
+    lookup: &mut Lookup,
+    zone: &Z,
+    node: &Node,
+    owner: &DnsName,
+    qtype: DnsQType,
+    dnssec: bool,
+) {
+    let mut rrsets: Vec<_> = node
+        .rrsets()
+        .iter()
+        .filter(|rrset| match qtype {
+            DnsQType::ALL => true,
+            _ => rrset.rtype == qtype,
+        })
+        .collect();
+
+    // Aliases answer every type but their own targets are left to the client to resolve
+    if rrsets.is_empty() {
+        rrsets.extend(node.get(DnsQType::CNAME));
+    }
+
+    for rrset in rrsets {
+        lookup.answers.extend(rrset.records(owner, zone.class()));
+        if dnssec && rrset.rtype != DnsQType::RRSIG {
+            lookup.answers.extend(
+                node.signatures(rrset.rtype)
+                    .map(|rdata| signature(zone, owner, rdata, rrset.ttl)),
+            );
+        }
+    }
+}
+
+/// Add the NSEC record matching or covering a name with its signatures, once
+fn add_nsec<Z: ZoneBackend + ?Sized>(lookup: &mut Lookup, zone: &Z, name: &DnsName) -> Result<()> {
+    let Some((owner, nsec)) = zone.nsec(name)? else {
+        return Ok(());
+    };
+    if lookup
+        .authority
+        .iter()
+        .any(|record| record.rtype == DnsQType::NSEC && record.name == owner)
+    {
+        return Ok(());
+    }
+
+    lookup.authority.extend(nsec.records(&owner, zone.class()));
+    let signatures = signatures(zone, &owner, DnsQType::NSEC, nsec.ttl)?;
+    lookup.authority.extend(signatures);
+
+    Ok(())
+}
+
+/// RRSIG records of an RRset in the zone
+fn signatures<Z: ZoneBackend + ?Sized>(
+    zone: &Z,
+    owner: &DnsName,
+    rtype: DnsQType,
+    ttl: u32,
+) -> Result<Vec<DnsResourceRecord>> {
+    Ok(zone
+        .node(owner)?
+        .iter()
+        .flat_map(|node| node.signatures(rtype))
+        .map(|rdata| signature(zone, owner, rdata, ttl))
+        .collect())
+}
+
+fn signature<Z: ZoneBackend + ?Sized>(
+    zone: &Z,
+    owner: &DnsName,
+    rdata: &[u8],
+    ttl: u32,
+) -> DnsResourceRecord {
+    DnsResourceRecord {
+        name: owner.clone(),
+        rtype: DnsQType::RRSIG,
+        rclass: zone.class(),
+        ttl,
+        rdlength: rdata.len() as u16,
+        rdata: rdata.to_vec(),
+    }
+}
+
+/// SOA record proving a negative answer, its TTL limits how long the answer is cached,
+/// RFC 2308 section 3
+fn negative_soa<Z: ZoneBackend + ?Sized>(zone: &Z) -> Option<DnsResourceRecord> {
+    let soa = zone.soa().ok()?;
+    let mut record = zone.soa_record().ok()?;
+    record.ttl = record.ttl.min(soa.minimum);
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use mycelnet_dns_protocol::DnsClass;
+    use mycelnet_dns_zone::{master, Zone};
+
+    use super::*;
+
+    fn zone(records: &str) -> Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = format!(
+            "$TTL 300\n@ SOA ns1 hostmaster 1 3600 900 604800 60\n@ NS ns1\nns1 A 192.0.2.53\n{records}"
+        );
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in master::parse_str(&text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+        Ok(zone)
+    }
+
+    type Records = Vec<(String, DnsQType)>;
+
+    fn summary(lookup: &Lookup) -> (DnsRcode, Records, Records) {
+        let records = |records: &[DnsResourceRecord]| {
+            records
+                .iter()
+                .map(|record| (record.name.to_string(), record.rtype))
+                .collect()
+        };
+        (
+            lookup.rcode,
+            records(&lookup.answers),
+            records(&lookup.authority),
+        )
+    }
+
+    #[test]
+    fn synthesize_from_wildcards() -> Result<()> {
+        let zone = zone(
+            "*.preview A 192.0.2.80\n*.preview TXT \"preview\"\nhost.ent.preview A 192.0.2.81\n",
+        )?;
+        let soa = ("mycelnet.tech".to_string(), DnsQType::SOA);
+
+        let found = lookup(
+            &zone,
+            &"pr-42.preview.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NoError,
+                vec![("pr-42.preview.mycelnet.tech".to_string(), DnsQType::A)],
+                vec![]
+            )
+        );
+        assert_eq!(found.answers[0].rdata, vec![192, 0, 2, 80]);
+
+        // Wildcards match any number of labels below the closest encloser
+        let found = lookup(
+            &zone,
+            &"a.b.preview.mycelnet.tech".parse()?,
+            DnsQType::TXT,
+            false,
+        )?;
+        assert_eq!(found.answers.len(), 1);
+        let found = lookup(
+            &zone,
+            &"pr-42.preview.mycelnet.tech".parse()?,
+            DnsQType::MX,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (DnsRcode::NoError, vec![], vec![soa.clone()])
+        );
+
+        // Empty non-terminals exist, so the wildcard above them does not match them or below
+        let found = lookup(
+            &zone,
+            &"ent.preview.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (DnsRcode::NoError, vec![], vec![soa.clone()])
+        );
+        let found = lookup(
+            &zone,
+            &"x.ent.preview.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (DnsRcode::NameError, vec![], vec![soa.clone()])
+        );
+
+        let found = lookup(&zone, &"www.mycelnet.tech".parse()?, DnsQType::A, false)?;
+        assert_eq!(summary(&found), (DnsRcode::NameError, vec![], vec![soa]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_wildcard_answers() -> Result<()> {
+        // Signatures cover A (1), SOA (6) and NSEC (47), their remaining data does not matter
+        let zone = zone(
+            r#"@ NSEC \# 1 00
+@ RRSIG \# 2 0006
+@ RRSIG \# 2 002f
+*.preview A 192.0.2.80
+*.preview NSEC \# 1 01
+*.preview RRSIG \# 2 0001
+*.preview RRSIG \# 2 002f
+ns1 NSEC \# 1 02
+ns1 RRSIG \# 2 002f
+"#,
+        )?;
+        let origin = "mycelnet.tech".to_string();
+        let wildcard = "*.preview.mycelnet.tech".to_string();
+        let qname = "pr-42.preview.mycelnet.tech".to_string();
+
+        // Without the DO bit the answer is the same as for an unsigned zone
+        let found = lookup(&zone, &qname.parse()?, DnsQType::A, false)?;
+        assert_eq!(found.answers.len(), 1);
+        assert!(found.authority.is_empty());
+
+        let found = lookup(&zone, &qname.parse()?, DnsQType::A, true)?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NoError,
+                vec![
+                    (qname.clone(), DnsQType::A),
+                    (qname.clone(), DnsQType::RRSIG)
+                ],
+                vec![
+                    (wildcard.clone(), DnsQType::NSEC),
+                    (wildcard.clone(), DnsQType::RRSIG)
+                ]
+            )
+        );
+
+        let found = lookup(&zone, &"www.mycelnet.tech".parse()?, DnsQType::A, true)?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NameError,
+                vec![],
+                vec![
+                    (origin.clone(), DnsQType::SOA),
+                    (origin.clone(), DnsQType::RRSIG),
+                    (wildcard.clone(), DnsQType::NSEC),
+                    (wildcard, DnsQType::RRSIG),
+                    (origin.clone(), DnsQType::NSEC),
+                    (origin, DnsQType::RRSIG),
+                ]
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_below_dnames() -> Result<()> {
+        let long_target = zone(&format!("old DNAME {}.mycelnet.net.\n", "b".repeat(63)))?;
+        let zone = zone("old DNAME mycelnet.net.\nold TXT \"moved\"\nx.old A 192.0.2.1\n")?;
+        let dname = ("old.mycelnet.tech".to_string(), DnsQType::DNAME);
+
+        let found = lookup(&zone, &"www.old.mycelnet.tech".parse()?, DnsQType::A, false)?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NoError,
+                vec![
+                    dname.clone(),
+                    ("www.old.mycelnet.tech".to_string(), DnsQType::CNAME)
+                ],
+                vec![]
+            )
+        );
+        assert_eq!(
+            DnsName::from_bytes(&found.answers[1].rdata, 0)?,
+            "www.mycelnet.net".parse()?
+        );
+        assert_eq!(found.answers[1].ttl, 300);
+
+        // Names below the DNAME are occluded, the owner itself keeps its data
+        let found = lookup(&zone, &"x.old.mycelnet.tech".parse()?, DnsQType::A, false)?;
+        assert_eq!(found.answers[1].rtype, DnsQType::CNAME);
+        let found = lookup(&zone, &"old.mycelnet.tech".parse()?, DnsQType::TXT, false)?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NoError,
+                vec![("old.mycelnet.tech".to_string(), DnsQType::TXT)],
+                vec![]
+            )
+        );
+
+        let long = format!("{0}.{0}.{0}.old.mycelnet.tech", "a".repeat(63));
+        let found = lookup(&long_target, &long.parse()?, DnsQType::A, false)?;
+        assert_eq!(summary(&found), (DnsRcode::YXDomain, vec![dname], vec![]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refer_below_delegations() -> Result<()> {
+        let zone = zone(
+            r#"* A 192.0.2.80
+sub NS ns.sub
+sub NS ns1
+sub DS \# 4 00010203
+ns.sub A 192.0.2.2
+www.sub A 192.0.2.3
+"#,
+        )?;
+        let sub = "sub.mycelnet.tech".to_string();
+        let referral = (
+            DnsRcode::NoError,
+            vec![],
+            vec![(sub.clone(), DnsQType::NS), (sub.clone(), DnsQType::NS)],
+        );
+
+        for qname in ["sub", "www.sub", "ns.sub", "other.sub"] {
+            let qname: DnsName = format!("{qname}.mycelnet.tech").parse()?;
+            let found = lookup(&zone, &qname, DnsQType::A, false)?;
+            assert_eq!(summary(&found), referral, "{qname}");
+            assert!(!found.authoritative);
+            let mut glue: Vec<_> = found
+                .additional
+                .iter()
+                .map(|record| record.name.to_string())
+                .collect();
+            glue.sort();
+            assert_eq!(glue, vec!["ns.sub.mycelnet.tech", "ns1.mycelnet.tech"]);
+        }
+
+        // DS records of the delegation are answered by the parent
+        let found = lookup(&zone, &sub.parse()?, DnsQType::DS, false)?;
+        assert_eq!(
+            summary(&found),
+            (DnsRcode::NoError, vec![(sub.clone(), DnsQType::DS)], vec![])
+        );
+        assert!(found.authoritative);
+        let found = lookup(
+            &zone,
+            &"www.sub.mycelnet.tech".parse()?,
+            DnsQType::DS,
+            false,
+        )?;
+        assert_eq!(summary(&found), referral);
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_ds_from_parent_zones() -> Result<()> {
+        let parent = zone("sub NS ns.sub\nsub DS \\# 4 00010203\nns.sub A 192.0.2.54\n")?;
+        let origin: DnsName = "sub.mycelnet.tech".parse()?;
+        let mut child = Zone::new(origin.clone(), DnsClass::IN);
+        let text = "$TTL 300\n@ SOA ns hostmaster 1 3600 900 604800 60\n@ NS ns\nns A 192.0.2.54\n";
+        for entry in master::parse_str(text, &origin)? {
+            child.insert(entry.record)?;
+        }
+        let child: Arc<dyn ZoneBackend> = Arc::new(child);
+        let zones = ZoneStore::new([child.clone()]);
+
+        // Without the parent the child zone answers, with NODATA
+        let found = zone_for(&zones, &origin, DnsQType::DS).unwrap();
+        assert_eq!(found.origin(), &origin);
+
+        zones.insert(Arc::new(parent));
+        let found = zone_for(&zones, &origin, DnsQType::DS).unwrap();
+        let lookup = lookup(found.as_ref(), &origin, DnsQType::DS, false)?;
+        assert_eq!(
+            summary(&lookup),
+            (
+                DnsRcode::NoError,
+                vec![("sub.mycelnet.tech".to_string(), DnsQType::DS)],
+                vec![]
+            )
+        );
+
+        // Other types at the apex and DS below it stay with the child
+        for (name, qtype) in [
+            ("sub.mycelnet.tech", DnsQType::NS),
+            ("sub.mycelnet.tech", DnsQType::SOA),
+            ("www.sub.mycelnet.tech", DnsQType::DS),
+        ] {
+            let found = zone_for(&zones, &name.parse()?, qtype).unwrap();
+            assert_eq!(found.origin(), &origin, "{name} {qtype:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_delegations() -> Result<()> {
+        // Signatures cover DS (43) and NSEC (47), their remaining data does not matter
+        let zone = zone(
+            r#"@ NSEC \# 1 00
+signed NS ns1
+signed DS \# 4 00010203
+signed RRSIG \# 2 002b
+unsigned NS ns1
+unsigned NSEC \# 1 01
+unsigned RRSIG \# 2 002f
+"#,
+        )?;
+        let signed = "signed.mycelnet.tech".to_string();
+        let unsigned = "unsigned.mycelnet.tech".to_string();
+
+        let found = lookup(
+            &zone,
+            &"www.signed.mycelnet.tech".parse()?,
+            DnsQType::A,
+            true,
+        )?;
+        assert_eq!(
+            summary(&found).2,
+            vec![
+                (signed.clone(), DnsQType::NS),
+                (signed.clone(), DnsQType::DS),
+                (signed, DnsQType::RRSIG),
+            ]
+        );
+
+        // Delegations to unsigned zones prove there are no DS records
+        let found = lookup(&zone, &unsigned.parse()?, DnsQType::A, true)?;
+        assert_eq!(
+            summary(&found).2,
+            vec![
+                (unsigned.clone(), DnsQType::NS),
+                (unsigned.clone(), DnsQType::NSEC),
+                (unsigned, DnsQType::RRSIG),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stop_redirections_at_delegations() -> Result<()> {
+        // The DNAME below the delegation is not part of the zone
+        let zone = zone("sub NS ns1\nold.sub DNAME mycelnet.net.\nold DNAME mycelnet.net.\n")?;
+
+        let found = lookup(
+            &zone,
+            &"x.old.sub.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert!(!found.authoritative);
+        assert_eq!(found.authority[0].rtype, DnsQType::NS);
+
+        let found = lookup(&zone, &"ns.old.mycelnet.tech".parse()?, DnsQType::NS, false)?;
+        assert!(found.authoritative);
+        assert_eq!(found.answers[0].rtype, DnsQType::DNAME);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn answer_from_databases() -> Result<()> {
+        use std::path::Path;
+        use std::sync::Arc;
+
+        use mycelnet_dns_zone::sqlite::SqliteBackend;
+
+        let records = zone("www A 192.0.2.80\nalias CNAME www\n")?;
+        let database = SqliteBackend::open(Path::new(":memory:"), records.origin().clone())?;
+        database.import(records.records())?;
+        let zone: Arc<dyn ZoneBackend> = Arc::new(database);
+
+        let found = lookup(
+            zone.as_ref(),
+            &"alias.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NoError,
+                vec![("alias.mycelnet.tech".to_string(), DnsQType::CNAME)],
+                vec![]
+            )
+        );
+
+        let found = lookup(
+            zone.as_ref(),
+            &"nope.mycelnet.tech".parse()?,
+            DnsQType::A,
+            false,
+        )?;
+        assert_eq!(
+            summary(&found),
+            (
+                DnsRcode::NameError,
+                vec![],
+                vec![("mycelnet.tech".to_string(), DnsQType::SOA)]
+            )
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Check zones in master file format for the errors the server refuses to load them with
+
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use cli::{ZoneFile, ZoneFormat};
+use mycelnet_dns_zone::{
+    check::{self, Severity},
+    master,
+};
+
+#[derive(Parser)]
+#[command(version, author, about = "Check zones in master file format")]
+struct Args {
+    /// Zones to check, in ORIGIN=FILE format
+    #[arg(value_name = "ORIGIN=FILE", required = true)]
+    zones: Vec<ZoneFile>,
+
+    /// Fail on warnings as well as on errors
+    #[arg(long)]
+    strict: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut failed = false;
+    for zone in &args.zones {
+        // Databases are changed by other tools while served, rows are checked as they are read
+        if zone.format == ZoneFormat::Sqlite {
+            println!(
+                "{}: {} is an SQLite database, only master files can be checked",
+                zone.origin,
+                zone.path.display()
+            );
+            failed = true;
+            continue;
+        }
+
+        let records = match master::read_file(&zone.path, &zone.origin) {
+            Ok(records) => records,
+            Err(e) => {
+                println!("{}: {e:#}", zone.origin);
+                failed = true;
+                continue;
+            }
+        };
+
+        let problems = check::check(&zone.origin, &records);
+        for problem in &problems {
+            println!("{problem}");
+        }
+
+        let errors = problems
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .count();
+        let warnings = problems.len() - errors;
+        if errors == 0 && warnings == 0 {
+            println!("{}: {} records OK", zone.origin, records.len());
+        } else {
+            println!("{}: {errors} errors, {warnings} warnings", zone.origin);
+        }
+        failed |= errors > 0 || (args.strict && warnings > 0);
+    }
+
+    match failed {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use mycelnet_dns_protocol::edns::{truncate_address, ExtendedErrorCode};
+use mycelnet_dns_protocol::{DnsName, DnsQType, DnsQuestion, DnsRcode, DnsResourceRecord};
+
+/// Upper bound on how long an answer is cached regardless of its TTL
+const MAX_TTL: u32 = 86400;
+
+/// TTL of expired answers served because the upstream failed, RFC 8767 section 4
+const STALE_TTL: u32 = 30;
+
+/// An upstream answer without the per message OPT and TSIG records
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub rcode: DnsRcode,
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+    pub additional: Vec<DnsResourceRecord>,
+    /// Scope prefix length of the client subnet the answer was tailored to, 0 if it applies to all
+    pub scope_prefix: u8,
+    /// Extended DNS Errors explaining how the answer was obtained
+    pub extended_errors: Vec<(ExtendedErrorCode, String)>,
+}
+
+impl Answer {
+    /// Seconds the answer may be cached, the lowest TTL of its records. Negative answers are
+    /// limited by the SOA minimum, RFC 2308 section 5.
+    fn ttl(&self) -> u32 {
+        let records = self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional);
+
+        records
+            .map(|record| match record.rtype {
+                DnsQType::SOA if self.answers.is_empty() && record.rdata.len() >= 4 => {
+                    let minimum = &record.rdata[record.rdata.len() - 4..];
+                    record.ttl.min(u32::from_be_bytes([
+                        minimum[0], minimum[1], minimum[2], minimum[3],
+                    ]))
+                }
+                _ => record.ttl,
+            })
+            .min()
+            .unwrap_or(0)
+            .min(MAX_TTL)
+    }
+
+    /// Copy of the answer with all TTLs set to the same value
+    fn with_ttl(&self, ttl: u32) -> Answer {
+        let set_ttl = |records: &[DnsResourceRecord]| {
+            records
+                .iter()
+                .map(|record| DnsResourceRecord {
+                    ttl,
+                    ..record.clone()
+                })
+                .collect()
+        };
+
+        Answer {
+            answers: set_ttl(&self.answers),
+            authority: set_ttl(&self.authority),
+            additional: set_ttl(&self.additional),
+            ..self.clone()
+        }
+    }
+
+    /// Copy of the answer with TTLs reduced by the time spent in the cache
+    fn aged(&self, age: Duration) -> Answer {
+        let age = age.as_secs() as u32;
+        let age_records = |records: &[DnsResourceRecord]| {
+            records
+                .iter()
+                .map(|record| DnsResourceRecord {
+                    ttl: record.ttl.saturating_sub(age),
+                    ..record.clone()
+                })
+                .collect()
+        };
+
+        Answer {
+            answers: age_records(&self.answers),
+            authority: age_records(&self.authority),
+            additional: age_records(&self.additional),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: DnsName,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion) -> CacheKey {
+        CacheKey {
+            name: question.qname.clone(),
+            qtype: question.qtype.to_u16(),
+            qclass: question.qclass.to_u16(),
+        }
+    }
+}
+
+struct CacheEntry {
+    /// Network and prefix length the answer is valid for, None if it is valid for all clients
+    network: Option<(IpAddr, u8)>,
+    inserted: Instant,
+    expires: Instant,
+    answer: Answer,
+}
+
+impl CacheEntry {
+    /// Whether a query for the client subnet may be answered with this entry, RFC 7871 7.3.2
+    fn matches(&self, subnet: Option<(IpAddr, u8)>) -> bool {
+        match (self.network, subnet) {
+            (None, _) => true,
+            (Some((network, prefix)), Some((address, source_prefix))) => {
+                prefix <= source_prefix
+                    && network.is_ipv4() == address.is_ipv4()
+                    && truncate_address(address, prefix) == network
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    fn prefix(&self) -> u8 {
+        self.network.map(|(_, prefix)| prefix).unwrap_or(0)
+    }
+}
+
+/// Answer cache scoped by client subnet so answers tailored to one network are not served to
+/// clients of another
+pub struct Cache {
+    entries: HashMap<CacheKey, Vec<CacheEntry>>,
+    capacity: usize,
+    /// How long answers are kept after they expire to be served when the upstream fails
+    max_stale: Duration,
+}
+
+impl Cache {
+    /// Create a cache holding answers for up to `capacity` questions
+    pub fn new(capacity: usize, max_stale: Duration) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+            max_stale,
+        }
+    }
+
+    /// Most specific unexpired answer for the question and client subnet
+    pub fn get(
+        &self,
+        question: &DnsQuestion,
+        subnet: Option<(IpAddr, u8)>,
+        now: Instant,
+    ) -> Option<Answer> {
+        self.entries
+            .get(&CacheKey::new(question))?
+            .iter()
+            .filter(|entry| entry.expires > now && entry.matches(subnet))
+            .max_by_key(|entry| entry.prefix())
+            .map(|entry| entry.answer.aged(now.duration_since(entry.inserted)))
+    }
+
+    /// Most specific answer for the question that expired less than `max_stale` ago
+    pub fn get_stale(
+        &self,
+        question: &DnsQuestion,
+        subnet: Option<(IpAddr, u8)>,
+        now: Instant,
+    ) -> Option<Answer> {
+        self.entries
+            .get(&CacheKey::new(question))?
+            .iter()
+            .filter(|entry| entry.expires + self.max_stale > now && entry.matches(subnet))
+            .max_by_key(|entry| entry.prefix())
+            .map(|entry| entry.answer.with_ttl(STALE_TTL))
+    }
+
+    /// Cache an answer received for a query sent with the client subnet
+    pub fn insert(
+        &mut self,
+        question: &DnsQuestion,
+        subnet: Option<(IpAddr, u8)>,
+        answer: &Answer,
+        now: Instant,
+    ) {
+        let ttl = answer.ttl();
+        if ttl == 0
+            || self.capacity == 0
+            || !matches!(answer.rcode, DnsRcode::NoError | DnsRcode::NameError)
+        {
+            return;
+        }
+
+        // Answers with a scope longer than the subnet sent are only valid for that subnet
+        let network = subnet
+            .filter(|_| answer.scope_prefix > 0)
+            .map(|(address, source_prefix)| {
+                let prefix = answer.scope_prefix.min(source_prefix);
+                (truncate_address(address, prefix), prefix)
+            });
+
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entries| {
+                entries.retain(|entry| entry.expires + self.max_stale > now);
+                !entries.is_empty()
+            });
+        }
+
+        if self.entries.len() >= self.capacity {
+            if let Some(key) = self.entries.keys().next().cloned() {
+                self.entries.remove(&key);
+            }
+        }
+
+        let entries = self.entries.entry(CacheKey::new(question)).or_default();
+        entries.retain(|entry| entry.network != network && entry.expires + self.max_stale > now);
+        entries.push(CacheEntry {
+            network,
+            inserted: now,
+            expires: now + Duration::from_secs(ttl as u64),
+            answer: answer.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question() -> DnsQuestion {
+        DnsQuestion {
+            qname: DnsName::from_labels(["geo", "mycelnet"]),
+            ..DnsQuestion::default()
+        }
+    }
+
+    fn answer(address: [u8; 4], scope_prefix: u8) -> Answer {
+        Answer {
+            rcode: DnsRcode::NoError,
+            answers: vec![DnsResourceRecord {
+                name: question().qname,
+                ttl: 60,
+                rdlength: 4,
+                rdata: address.to_vec(),
+                ..DnsResourceRecord::default()
+            }],
+            authority: Vec::new(),
+            additional: Vec::new(),
+            scope_prefix,
+            extended_errors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn scope_answers_by_subnet() {
+        let mut cache = Cache::new(16, Duration::ZERO);
+        let now = Instant::now();
+        let europe = Some(("198.51.100.0".parse().unwrap(), 24));
+        let asia = Some(("203.0.113.0".parse().unwrap(), 24));
+
+        cache.insert(&question(), europe, &answer([10, 0, 0, 1], 16), now);
+        cache.insert(&question(), asia, &answer([10, 0, 0, 2], 24), now);
+
+        let nearby = Some(("198.51.7.0".parse().unwrap(), 24));
+        let hit = cache.get(&question(), nearby, now).unwrap();
+        assert_eq!(hit.answers[0].rdata, vec![10, 0, 0, 1]);
+
+        let hit = cache.get(&question(), asia, now).unwrap();
+        assert_eq!(hit.answers[0].rdata, vec![10, 0, 0, 2]);
+
+        let elsewhere = Some(("192.0.2.0".parse().unwrap(), 24));
+        assert!(cache.get(&question(), elsewhere, now).is_none());
+        assert!(cache.get(&question(), None, now).is_none());
+    }
+
+    #[test]
+    fn share_unscoped_answers() {
+        let mut cache = Cache::new(16, Duration::ZERO);
+        let now = Instant::now();
+        let subnet = Some(("198.51.100.0".parse().unwrap(), 24));
+
+        cache.insert(&question(), subnet, &answer([10, 0, 0, 1], 0), now);
+
+        let other = Some(("203.0.113.0".parse().unwrap(), 24));
+        assert!(cache.get(&question(), other, now).is_some());
+        assert!(cache.get(&question(), None, now).is_some());
+    }
+
+    #[test]
+    fn expire_and_age_answers() {
+        let mut cache = Cache::new(16, Duration::ZERO);
+        let now = Instant::now();
+
+        cache.insert(&question(), None, &answer([10, 0, 0, 1], 0), now);
+
+        let hit = cache
+            .get(&question(), None, now + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(hit.answers[0].ttl, 40);
+        assert!(cache
+            .get(&question(), None, now + Duration::from_secs(60))
+            .is_none());
+    }
+
+    #[test]
+    fn serve_stale_answers() {
+        let mut cache = Cache::new(16, Duration::from_secs(600));
+        let now = Instant::now();
+
+        cache.insert(&question(), None, &answer([10, 0, 0, 1], 0), now);
+
+        let later = now + Duration::from_secs(300);
+        assert!(cache.get(&question(), None, later).is_none());
+        let stale = cache.get_stale(&question(), None, later).unwrap();
+        assert_eq!(stale.answers[0].ttl, STALE_TTL);
+
+        assert!(cache
+            .get_stale(&question(), None, now + Duration::from_secs(660))
+            .is_none());
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+use mycelnet_dns_protocol::cookie::ServerCookieSecrets;
+use mycelnet_dns_protocol::edns::{DnsOptRecord, EdnsOption};
+
+use crate::ratelimit::RateLimiter;
+
+/// Outcome of checking the cookie option of a request
+#[derive(Debug, PartialEq)]
+pub enum CookieCheck {
+    /// The request carried no cookie option
+    Missing,
+    /// The request carried a client cookie but no server cookie
+    ClientOnly([u8; 8]),
+    /// The server cookie was issued by this server to the client
+    Valid([u8; 8]),
+    /// The server cookie is expired, forged or was issued to another client
+    Invalid([u8; 8]),
+}
+
+impl CookieCheck {
+    pub fn client(&self) -> Option<&[u8; 8]> {
+        match self {
+            CookieCheck::Missing => None,
+            CookieCheck::ClientOnly(client)
+            | CookieCheck::Valid(client)
+            | CookieCheck::Invalid(client) => Some(client),
+        }
+    }
+}
+
+/// Issues and validates server cookies and rate limits clients that cannot present one
+pub struct CookieGuard {
+    secrets: RwLock<ServerCookieSecrets>,
+    limiter: Option<Mutex<RateLimiter>>,
+}
+
+impl CookieGuard {
+    /// Create a guard with a random secret, a rate limit of zero disables rate limiting
+    pub fn new(rate_limit: u32) -> CookieGuard {
+        CookieGuard {
+            secrets: RwLock::new(ServerCookieSecrets::new(rand::random())),
+            limiter: (rate_limit > 0).then(|| Mutex::new(RateLimiter::new(rate_limit))),
+        }
+    }
+
+    /// Switch to a new random secret, cookies issued with the previous one remain valid
+    pub fn rotate(&self) {
+        self.secrets.write().unwrap().rotate(rand::random());
+    }
+
+    pub fn check(&self, opt: Option<&DnsOptRecord>, addr: IpAddr, now: u32) -> CookieCheck {
+        match opt.and_then(|opt| opt.cookie()) {
+            None => CookieCheck::Missing,
+            Some((client, [])) => CookieCheck::ClientOnly(*client),
+            Some((client, server)) => {
+                if self
+                    .secrets
+                    .read()
+                    .unwrap()
+                    .validate(client, server, addr, now)
+                {
+                    CookieCheck::Valid(*client)
+                } else {
+                    CookieCheck::Invalid(*client)
+                }
+            }
+        }
+    }
+
+    /// Whether to process a request, only requests without a valid cookie are rate limited
+    pub fn allow(&self, check: &CookieCheck, addr: IpAddr, now: Instant) -> bool {
+        match (check, &self.limiter) {
+            (CookieCheck::Valid(_), _) | (_, None) => true,
+            (_, Some(limiter)) => limiter.lock().unwrap().allow(addr, now),
+        }
+    }
+
+    /// Cookie option for a response carrying a freshly issued server cookie
+    pub fn option(&self, client: &[u8; 8], addr: IpAddr, now: u32) -> EdnsOption {
+        EdnsOption::Cookie {
+            client: *client,
+            server: self.secrets.read().unwrap().generate(client, addr, now),
+        }
+    }
+}
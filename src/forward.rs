@@ -0,0 +1,546 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::{error::Elapsed, timeout},
+};
+
+use mycelnet_dns_protocol::{
+    cookie::ClientCookieJar,
+    edns::{max_prefix, truncate_address, DnsOptRecord, EdnsOption, ExtendedErrorCode},
+    view::{DnsMessageRef, DnsSection},
+    DnsHeader, DnsName, DnsPacketData, DnsQType, DnsQuestion, DnsRcode, DnsRequest,
+    DnsResourceRecord,
+};
+
+use crate::cache::{Answer, Cache};
+
+/// Most aliases followed for one question before giving up on it
+const MAX_ALIASES: usize = 16;
+
+/// Prefix lengths client addresses are truncated to before being sent upstream
+#[derive(Debug, Clone, Copy)]
+pub struct EcsPolicy {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+/// Forwards queries to an upstream recursive resolver and caches the answers
+pub struct Forwarder {
+    upstream: SocketAddr,
+    timeout: Duration,
+    ecs: Option<EcsPolicy>,
+    cache: Mutex<Cache>,
+    cookies: Mutex<ClientCookieJar>,
+}
+
+impl Forwarder {
+    pub fn new(
+        upstream: SocketAddr,
+        timeout: Duration,
+        ecs: Option<EcsPolicy>,
+        cache_size: usize,
+        max_stale: Duration,
+    ) -> Forwarder {
+        Forwarder {
+            upstream,
+            timeout,
+            ecs,
+            cache: Mutex::new(Cache::new(cache_size, max_stale)),
+            cookies: Mutex::new(ClientCookieJar::new(rand::random())),
+        }
+    }
+
+    /// Subnet to send upstream for a client. A subnet sent by the client itself takes precedence
+    /// over its address but is never passed on with more bits than the policy allows.
+    pub fn client_subnet(
+        &self,
+        client: IpAddr,
+        requested: Option<(IpAddr, u8, u8)>,
+    ) -> Option<(IpAddr, u8)> {
+        let policy = self.ecs?;
+        let (address, prefix) = match requested {
+            Some((address, source_prefix, _)) => (address, source_prefix),
+            None => (client, u8::MAX),
+        };
+
+        let limit = match address {
+            IpAddr::V4(_) => policy.ipv4_prefix,
+            IpAddr::V6(_) => policy.ipv6_prefix,
+        };
+        let prefix = prefix.min(limit).min(max_prefix(&address));
+
+        Some((truncate_address(address, prefix), prefix))
+    }
+
+    /// Answer a question, following the CNAME and DNAME records of the answer to names the
+    /// upstream left unresolved, RFC 1034 section 3.6.2 and RFC 6672 section 3.4
+    pub async fn resolve(
+        &self,
+        question: &DnsQuestion,
+        subnet: Option<(IpAddr, u8)>,
+    ) -> Result<Answer> {
+        let mut answer = self.lookup(question, subnet).await?;
+        let mut queried = vec![question.qname.clone()];
+
+        while answer.rcode == DnsRcode::NoError {
+            let Some(target) =
+                follow_aliases(&question.qname, question.qtype, &mut answer.answers)?
+            else {
+                break;
+            };
+            // The end of the chain was already asked about and has no data of the type
+            if queried.contains(&target) {
+                break;
+            }
+
+            log::trace!("Following alias of {} to {}", question.qname, target);
+            let next = self
+                .lookup(
+                    &DnsQuestion {
+                        qname: target.clone(),
+                        ..question.clone()
+                    },
+                    subnet,
+                )
+                .await?;
+            queried.push(target);
+
+            answer.rcode = next.rcode;
+            answer.answers.extend(detach(next.answers));
+            answer.authority = detach(next.authority);
+            answer.additional = detach(next.additional);
+            answer.scope_prefix = answer.scope_prefix.max(next.scope_prefix);
+            answer.extended_errors.extend(next.extended_errors);
+        }
+
+        Ok(answer)
+    }
+
+    /// Answer a question from the cache or by querying upstream. If the upstream fails an
+    /// expired answer is served when the cache still holds one.
+    async fn lookup(&self, question: &DnsQuestion, subnet: Option<(IpAddr, u8)>) -> Result<Answer> {
+        if let Some(answer) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(question, subnet, Instant::now())
+        {
+            log::trace!("Answering {} from cache", question.qname);
+            return Ok(answer);
+        }
+
+        let answer = match self.query(question, subnet).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                let stale = self
+                    .cache
+                    .lock()
+                    .unwrap()
+                    .get_stale(question, subnet, Instant::now());
+
+                return match stale {
+                    Some(mut answer) => {
+                        log::debug!("Serving stale answer for {}: {e:#}", question.qname);
+                        let code = match answer.rcode {
+                            DnsRcode::NameError => ExtendedErrorCode::StaleNxdomainAnswer,
+                            _ => ExtendedErrorCode::StaleAnswer,
+                        };
+                        answer.extended_errors.push((code, e.to_string()));
+                        Ok(answer)
+                    }
+                    None => Err(e),
+                };
+            }
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(question, subnet, &answer, Instant::now());
+
+        Ok(answer)
+    }
+
+    async fn query(&self, question: &DnsQuestion, subnet: Option<(IpAddr, u8)>) -> Result<Answer> {
+        // Retry once when the upstream rejects a stale server cookie with a fresh one
+        for _ in 0..2 {
+            let (data, opt) = self.exchange(question, subnet).await?;
+            let response = DnsMessageRef::new(&data)?;
+            let rcode = match &opt {
+                Some(opt) => opt.rcode(response.header().flags.rcode),
+                None => response.header().flags.rcode,
+            };
+
+            if rcode == DnsRcode::BadCookie {
+                log::debug!("Upstream {} rejected our cookie, retrying", self.upstream);
+                continue;
+            }
+
+            // Only trust the scope of an answer to the subnet we asked about
+            let scope_prefix = opt
+                .as_ref()
+                .and_then(|opt| opt.client_subnet())
+                .filter(|(address, source_prefix, _)| subnet == Some((*address, *source_prefix)))
+                .map(|(_, _, scope_prefix)| scope_prefix)
+                .unwrap_or(0);
+
+            // Pass on why the upstream failed or altered the answer
+            let extended_errors = opt
+                .as_ref()
+                .map(|opt| {
+                    opt.extended_errors()
+                        .into_iter()
+                        .map(|(code, text)| (code, text.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let mut answer = Answer {
+                rcode,
+                answers: Vec::new(),
+                authority: Vec::new(),
+                additional: Vec::new(),
+                scope_prefix,
+                extended_errors,
+            };
+
+            // Only copy the records that are passed on, OPT and TSIG belong to this message
+            for record in response.records() {
+                let record = record
+                    .with_context(|| format!("Failed to parse response from {}", self.upstream))?;
+                let section = match record.section() {
+                    DnsSection::Answer => &mut answer.answers,
+                    DnsSection::Authority => &mut answer.authority,
+                    DnsSection::Additional => &mut answer.additional,
+                };
+
+                if !matches!(record.rtype(), DnsQType::OPT | DnsQType::TSIG) {
+                    section.push(record.to_record()?);
+                }
+            }
+
+            return Ok(answer);
+        }
+
+        Err(anyhow!(
+            "Upstream {} repeatedly rejected our cookie",
+            self.upstream
+        ))
+    }
+
+    /// Send a query upstream over UDP, retrying over TCP if the answer is truncated
+    async fn exchange(
+        &self,
+        question: &DnsQuestion,
+        subnet: Option<(IpAddr, u8)>,
+    ) -> Result<(Vec<u8>, Option<DnsOptRecord>)> {
+        let id = rand::random::<u16>();
+
+        let mut opt = DnsOptRecord::new();
+        opt.set_option(self.cookies.lock().unwrap().option(self.upstream));
+        if let Some((address, prefix)) = subnet {
+            opt.set_option(EdnsOption::client_subnet(address, prefix));
+        }
+
+        let mut request = DnsRequest {
+            header: DnsHeader {
+                id,
+                qdcount: 1,
+                arcount: 1,
+                ..DnsHeader::default()
+            },
+            question: DnsQuestion {
+                qname: DnsName::from_labels(question.qname.labels.clone()),
+                ..question.clone()
+            },
+            additional: Some(vec![opt.to_record()?]),
+            ..DnsRequest::default()
+        };
+        request.header.flags.rd = 1;
+        let request_bytes = request.to_bytes()?;
+
+        let mut data = timeout(self.timeout, self.exchange_udp(&request_bytes, id))
+            .await
+            .with_context(|| format!("Timed out waiting for upstream {}", self.upstream))??;
+
+        if DnsMessageRef::new(&data)?.header().flags.tc == 1 {
+            log::debug!(
+                "Response from {} truncated, retrying over TCP",
+                self.upstream
+            );
+            data = timeout(self.timeout, self.exchange_tcp(&request_bytes))
+                .await
+                .with_context(|| format!("Timed out waiting for upstream {}", self.upstream))??;
+        }
+
+        let response = DnsMessageRef::new(&data)
+            .with_context(|| format!("Failed to parse response from {}", self.upstream))?;
+        let matches = response
+            .question()?
+            .is_some_and(|answered| answered.matches(question));
+        if response.header().id != id || response.header().flags.qr != 1 || !matches {
+            Err(anyhow!(
+                "Response from {} does not match the query",
+                self.upstream
+            ))?;
+        }
+
+        let opt = response
+            .edns()
+            .with_context(|| format!("Failed to parse response from {}", self.upstream))?;
+        if let Some(opt) = &opt {
+            if opt.cookie().is_some() && !self.cookies.lock().unwrap().update(self.upstream, opt) {
+                Err(anyhow!(
+                    "Response from {} carries a foreign client cookie",
+                    self.upstream
+                ))?;
+            }
+        }
+
+        Ok((data, opt))
+    }
+
+    async fn exchange_udp(&self, request: &[u8], id: u16) -> Result<Vec<u8>> {
+        let local: SocketAddr = match self.upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.upstream).await?;
+        socket.send(request).await?;
+
+        // Ignore datagrams that do not answer our query
+        let mut buf = vec![0; 65535];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    }
+
+    async fn exchange_tcp(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(self.upstream).await?;
+        stream
+            .write_all(&(request.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(request).await?;
+
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await?;
+
+        Ok(buf)
+    }
+}
+
+/// Follow the aliases of a name through the records of an answer, synthesizing the CNAME
+/// records of DNAME records the upstream sent without them. Returns the name the chain ends at
+/// when it has no records of the type in the answer, fails on loops and overly long chains.
+fn follow_aliases(
+    qname: &DnsName,
+    qtype: DnsQType,
+    records: &mut Vec<DnsResourceRecord>,
+) -> Result<Option<DnsName>> {
+    let mut name = qname.clone();
+    let mut seen = Vec::new();
+
+    loop {
+        if records
+            .iter()
+            .any(|record| record.name == name && (qtype == DnsQType::ALL || record.rtype == qtype))
+        {
+            return Ok(None);
+        }
+
+        let cname = records
+            .iter()
+            .find(|record| record.name == name && record.rtype == DnsQType::CNAME);
+        let target = match cname {
+            Some(cname) => target(cname)?,
+            None => {
+                let dname = records.iter().find(|record| {
+                    record.rtype == DnsQType::DNAME
+                        && record.name.label_count() < name.label_count()
+                        && name.is_subdomain_of(&record.name)
+                });
+                let Some(dname) = dname else {
+                    return Ok((name != *qname).then_some(name));
+                };
+
+                let target = name.substitute(&dname.name, &target(dname)?)?;
+                let rdata = target.to_bytes()?;
+                let cname = DnsResourceRecord {
+                    name: name.clone(),
+                    rtype: DnsQType::CNAME,
+                    rclass: dname.rclass,
+                    ttl: dname.ttl,
+                    rdlength: rdata.len() as u16,
+                    rdata,
+                };
+                records.push(cname);
+                target
+            }
+        };
+
+        seen.push(name);
+        if seen.contains(&target) {
+            Err(anyhow!("Aliases of {} loop at {}", qname, target))?;
+        }
+        if seen.len() > MAX_ALIASES {
+            Err(anyhow!("{} has more than {} aliases", qname, MAX_ALIASES))?;
+        }
+        name = target;
+    }
+}
+
+/// Records answering another question than the client's, with owner names no longer
+/// compressed to the question name of the upstream response they were read from
+fn detach(records: Vec<DnsResourceRecord>) -> Vec<DnsResourceRecord> {
+    records
+        .into_iter()
+        .map(|record| DnsResourceRecord {
+            name: DnsName::from_labels(record.name.labels),
+            ..record
+        })
+        .collect()
+}
+
+/// Name in the record data of a CNAME or DNAME record
+fn target(record: &DnsResourceRecord) -> Result<DnsName> {
+    let name = DnsName::from_bytes(&record.rdata, 0)
+        .with_context(|| format!("Invalid {} record for {}", record.rtype, record.name))?;
+    Ok(DnsName::from_labels(name.labels))
+}
+
+/// Extended DNS Error reported to clients when forwarding failed
+pub fn extended_error(error: &anyhow::Error) -> ExtendedErrorCode {
+    if error.downcast_ref::<Elapsed>().is_some() {
+        ExtendedErrorCode::NoReachableAuthority
+    } else if error.downcast_ref::<std::io::Error>().is_some() {
+        ExtendedErrorCode::NetworkError
+    } else {
+        ExtendedErrorCode::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mycelnet_dns_protocol::DnsResponse;
+
+    use super::*;
+
+    fn alias(owner: &str, rtype: DnsQType, target: &str) -> DnsResourceRecord {
+        let rdata = target.parse::<DnsName>().unwrap().to_bytes().unwrap();
+        DnsResourceRecord {
+            name: owner.parse().unwrap(),
+            rtype,
+            rdlength: rdata.len() as u16,
+            rdata,
+            ..DnsResourceRecord::default()
+        }
+    }
+
+    fn address(owner: &str) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: owner.parse().unwrap(),
+            rdlength: 4,
+            rdata: vec![192, 0, 2, 1],
+            ..DnsResourceRecord::default()
+        }
+    }
+
+    #[test]
+    fn follow_alias_chains() -> Result<()> {
+        let qname: DnsName = "www.mycelnet.tech".parse()?;
+
+        let mut records = vec![address("www.mycelnet.tech")];
+        assert_eq!(follow_aliases(&qname, DnsQType::A, &mut records)?, None);
+
+        // The chain ends at a name the upstream did not resolve
+        let mut records = vec![
+            alias("www.mycelnet.tech", DnsQType::CNAME, "web.mycelnet.tech"),
+            alias("mycelnet.tech", DnsQType::DNAME, "mycelnet.net"),
+        ];
+        assert_eq!(
+            follow_aliases(&qname, DnsQType::A, &mut records)?,
+            Some("web.mycelnet.net".parse()?)
+        );
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].name, "web.mycelnet.tech".parse()?);
+        assert_eq!(records[2].rtype, DnsQType::CNAME);
+        assert_eq!(target(&records[2])?, "web.mycelnet.net".parse()?);
+
+        records.push(address("web.mycelnet.net"));
+        assert_eq!(follow_aliases(&qname, DnsQType::A, &mut records)?, None);
+        assert_eq!(records.len(), 4);
+
+        // Aliases are the answer when asked for
+        let mut records = vec![alias("www.mycelnet.tech", DnsQType::CNAME, "web")];
+        assert_eq!(follow_aliases(&qname, DnsQType::CNAME, &mut records)?, None);
+
+        let mut records = vec![
+            alias("www.mycelnet.tech", DnsQType::CNAME, "www.mycelnet.net"),
+            alias("mycelnet.net", DnsQType::DNAME, "mycelnet.tech"),
+        ];
+        assert!(follow_aliases(&qname, DnsQType::A, &mut records).is_err());
+
+        let mut records: Vec<_> = (0..=MAX_ALIASES)
+            .map(|index| {
+                let owner = match index {
+                    0 => "www.mycelnet.tech".to_string(),
+                    _ => format!("alias{index}.mycelnet.tech"),
+                };
+                alias(
+                    &owner,
+                    DnsQType::CNAME,
+                    &format!("alias{}.mycelnet.tech", index + 1),
+                )
+            })
+            .collect();
+        assert!(follow_aliases(&qname, DnsQType::A, &mut records).is_err());
+        records.pop();
+        assert!(follow_aliases(&qname, DnsQType::A, &mut records)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_chased_owners_in_full() -> Result<()> {
+        // Upstream answer for the alias target as read with its owner compressed to the
+        // question name
+        let mut chased = address("cdn.other.net");
+        chased.name.pointer = 12;
+
+        let mut answers = vec![alias("www.example.com", DnsQType::CNAME, "cdn.other.net")];
+        answers.extend(detach(vec![chased]));
+        let response = DnsResponse {
+            header: DnsHeader {
+                qdcount: 1,
+                ..DnsHeader::default()
+            },
+            question: DnsQuestion {
+                qname: "www.example.com".parse()?,
+                qtype: DnsQType::A,
+                ..DnsQuestion::default()
+            },
+            answers: Some(answers),
+            ..DnsResponse::default()
+        };
+
+        let response = DnsResponse::from_bytes(&response.to_bytes()?, 0)?;
+        let answers = response.answers.unwrap_or_default();
+        assert_eq!(answers[0].name, "www.example.com".parse()?);
+        assert_eq!(answers[0].rtype, DnsQType::CNAME);
+        assert_eq!(answers[1].name, "cdn.other.net".parse()?);
+        assert_eq!(answers[1].rtype, DnsQType::A);
+
+        Ok(())
+    }
+}
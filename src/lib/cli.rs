@@ -1,5 +1,13 @@
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use std::net::Ipv4Addr;
+use mycelnet_dns_protocol::edns::{max_prefix, truncate_address};
+use mycelnet_dns_protocol::tsig::TsigKey;
+use mycelnet_dns_protocol::{DnsName, DnsQType};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Parser)]
 #[command(version, author, about)]
@@ -33,4 +41,449 @@ pub struct Args {
         default_value = "5300"
     )]
     pub port: u16,
+
+    /// TSIG keys used to authenticate requests, in [ALGORITHM:]NAME:SECRET format with a base64 secret
+    #[arg(
+        long = "tsig-key",
+        env = "MY_DNS_TSIG_KEYS",
+        value_name = "KEY",
+        value_delimiter = ','
+    )]
+    pub tsig_keys: Vec<TsigKey>,
+
+    /// Seconds between rotations of the secret used to issue DNS cookies
+    #[arg(
+        long,
+        env = "MY_DNS_COOKIE_SECRET_ROTATION",
+        value_name = "SECONDS",
+        default_value = "3600"
+    )]
+    pub cookie_secret_rotation: u64,
+
+    /// Queries per second accepted from each client without a valid DNS cookie, 0 disables the limit
+    #[arg(
+        long,
+        env = "MY_DNS_COOKIELESS_RATE_LIMIT",
+        value_name = "QPS",
+        default_value = "0"
+    )]
+    pub cookieless_rate_limit: u32,
+
+    /// Upstream resolver queries are forwarded to, by default queries are answered locally
+    #[arg(long, env = "MY_DNS_FORWARDER", value_name = "ADDR:PORT")]
+    pub forwarder: Option<SocketAddr>,
+
+    /// Milliseconds to wait for an answer from the upstream resolver
+    #[arg(
+        long,
+        env = "MY_DNS_FORWARDER_TIMEOUT",
+        value_name = "MILLISECONDS",
+        default_value = "2000"
+    )]
+    pub forwarder_timeout: u64,
+
+    /// Pass the subnet of clients to the upstream resolver with EDNS Client Subnet
+    #[arg(long, env = "MY_DNS_ECS")]
+    pub ecs: bool,
+
+    /// Prefix length IPv4 client addresses are truncated to before being sent upstream
+    #[arg(
+        long,
+        env = "MY_DNS_ECS_IPV4_PREFIX",
+        value_name = "BITS",
+        default_value = "24",
+        value_parser = clap::value_parser!(u8).range(0..=32)
+    )]
+    pub ecs_ipv4_prefix: u8,
+
+    /// Prefix length IPv6 client addresses are truncated to before being sent upstream
+    #[arg(
+        long,
+        env = "MY_DNS_ECS_IPV6_PREFIX",
+        value_name = "BITS",
+        default_value = "56",
+        value_parser = clap::value_parser!(u8).range(0..=128)
+    )]
+    pub ecs_ipv6_prefix: u8,
+
+    /// Number of questions the answer cache of the forwarder holds
+    #[arg(
+        long,
+        env = "MY_DNS_CACHE_SIZE",
+        value_name = "ENTRIES",
+        default_value = "10000"
+    )]
+    pub cache_size: usize,
+
+    /// Seconds expired answers are kept to be served when the upstream resolver fails, 0 disables serving stale answers
+    #[arg(
+        long,
+        env = "MY_DNS_SERVE_STALE",
+        value_name = "SECONDS",
+        default_value = "0"
+    )]
+    pub serve_stale: u64,
+
+    /// Zones served authoritatively, in ORIGIN=FILE format with the zone in master file format or ORIGIN=sqlite:FILE for a zone served from an SQLite database
+    #[arg(
+        long = "zone",
+        env = "MY_DNS_ZONES",
+        value_name = "ORIGIN=FILE",
+        value_delimiter = ','
+    )]
+    pub zones: Vec<ZoneFile>,
+
+    /// Clients allowed to transfer zones, an address, a network in ADDR/BITS format or key:NAME for requests signed with a TSIG key
+    #[arg(
+        long = "allow-transfer",
+        env = "MY_DNS_ALLOW_TRANSFER",
+        value_name = "RULE",
+        value_delimiter = ','
+    )]
+    pub allow_transfer: Vec<AclRule>,
+
+    /// Number of changes kept in the journal of each zone for incremental transfers
+    #[arg(
+        long,
+        env = "MY_DNS_JOURNAL_SIZE",
+        value_name = "CHANGES",
+        default_value = "100"
+    )]
+    pub journal_size: usize,
+
+    /// Zones transferred from a primary and served as a secondary, in ORIGIN=ADDR:PORT[@KEY] format with the name of the TSIG key transfers are signed with. Set up at startup only, reloading keeps them and their keys as they are
+    #[arg(
+        long = "secondary",
+        env = "MY_DNS_SECONDARIES",
+        value_name = "ORIGIN=PRIMARY",
+        value_delimiter = ','
+    )]
+    pub secondaries: Vec<SecondaryZone>,
+
+    /// Secondaries sent a NOTIFY when the serial of a zone changes
+    #[arg(
+        long = "notify",
+        env = "MY_DNS_NOTIFY",
+        value_name = "ADDR:PORT",
+        value_delimiter = ','
+    )]
+    pub notify: Vec<SocketAddr>,
+
+    /// Clients besides the primaries allowed to send NOTIFY for secondary zones, an address, a network in ADDR/BITS format or key:NAME for requests signed with a TSIG key
+    #[arg(
+        long = "allow-notify",
+        env = "MY_DNS_ALLOW_NOTIFY",
+        value_name = "RULE",
+        value_delimiter = ','
+    )]
+    pub allow_notify: Vec<AclRule>,
+
+    /// Dynamic updates allowed, in KEY:NAME[:TYPE/TYPE...] format where a NAME starting with *. covers every name below it
+    #[arg(
+        long = "allow-update",
+        env = "MY_DNS_ALLOW_UPDATE",
+        value_name = "RULE",
+        value_delimiter = ','
+    )]
+    pub allow_update: Vec<UpdateRule>,
+
+    /// Configuration file in TOML format adding zones, TSIG keys, access rules and NOTIFY targets to those given as options, read again on SIGHUP. Secondary zones are only given as options and keep the TSIG keys they started with until a restart
+    #[arg(long, env = "MY_DNS_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Watch the zone files and the configuration file and reload what changed without SIGHUP
+    #[arg(long, env = "MY_DNS_WATCH")]
+    pub watch: bool,
+
+    /// Time without further changes to watched files before they are reloaded
+    #[arg(
+        long,
+        env = "MY_DNS_WATCH_DELAY",
+        value_name = "MILLISECONDS",
+        default_value = "500"
+    )]
+    pub watch_delay: u64,
+}
+
+impl Args {
+    /// Settings given as options together with those of the configuration file, which is read
+    /// again on every call
+    pub fn settings(&self) -> Result<Settings> {
+        let mut settings = Settings {
+            zones: self.zones.clone(),
+            tsig_keys: self.tsig_keys.clone(),
+            allow_transfer: self.allow_transfer.clone(),
+            allow_notify: self.allow_notify.clone(),
+            allow_update: self.allow_update.clone(),
+            notify: self.notify.clone(),
+        };
+
+        if let Some(path) = &self.config {
+            let config = Config::read(path)?;
+            settings.zones.extend(config.zone);
+            settings.tsig_keys.extend(config.tsig_key);
+            settings.allow_transfer.extend(config.allow_transfer);
+            settings.allow_notify.extend(config.allow_notify);
+            settings.allow_update.extend(config.allow_update);
+            settings.notify.extend(config.notify);
+        }
+
+        let mut origins = HashSet::new();
+        for zone in &settings.zones {
+            if !origins.insert(&zone.origin) {
+                Err(anyhow!("Zone {} is configured more than once", zone.origin))?;
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/// The settings that can change while the server runs
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub zones: Vec<ZoneFile>,
+    pub tsig_keys: Vec<TsigKey>,
+    pub allow_transfer: Vec<AclRule>,
+    pub allow_notify: Vec<AclRule>,
+    pub allow_update: Vec<UpdateRule>,
+    pub notify: Vec<SocketAddr>,
+}
+
+/// Configuration file, its keys are named and formatted like the options they add to
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    #[serde(deserialize_with = "parse_all")]
+    zone: Vec<ZoneFile>,
+    #[serde(deserialize_with = "parse_all")]
+    tsig_key: Vec<TsigKey>,
+    #[serde(deserialize_with = "parse_all")]
+    allow_transfer: Vec<AclRule>,
+    #[serde(deserialize_with = "parse_all")]
+    allow_notify: Vec<AclRule>,
+    #[serde(deserialize_with = "parse_all")]
+    allow_update: Vec<UpdateRule>,
+    #[serde(deserialize_with = "parse_all")]
+    notify: Vec<SocketAddr>,
+}
+
+impl Config {
+    /// Read a configuration file, zone files are relative to the directory of the file
+    fn read(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut config: Config = toml::from_str(&text)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for zone in &mut config.zone {
+            zone.path = directory.join(&zone.path);
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse a list of strings in the format of an option
+fn parse_all<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|text| {
+            text.parse()
+                .map_err(|e| D::Error::custom(format!("{text}: {e:#}")))
+        })
+        .collect()
+}
+
+/// A zone and the file it is read from
+#[derive(Debug, Clone)]
+pub struct ZoneFile {
+    pub origin: DnsName,
+    pub path: PathBuf,
+    pub format: ZoneFormat,
+}
+
+/// How the records of a zone are stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneFormat {
+    /// A master file loaded into memory, RFC 1035 section 5
+    Master,
+    /// An SQLite database read as queries need the records
+    Sqlite,
+}
+
+impl ZoneFile {
+    /// File the changes of the zone are journaled in, next to the master file
+    pub fn journal_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".jnl");
+        PathBuf::from(path)
+    }
+}
+
+impl FromStr for ZoneFile {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<ZoneFile> {
+        let (origin, path) = text
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Zone {} is not in ORIGIN=FILE format", text))?;
+        let (path, format) = match path.strip_prefix("sqlite:") {
+            Some(path) => (path, ZoneFormat::Sqlite),
+            None => (path, ZoneFormat::Master),
+        };
+
+        Ok(ZoneFile {
+            origin: origin.parse()?,
+            path: PathBuf::from(path),
+            format,
+        })
+    }
+}
+
+/// A zone served as a secondary and the primary it is transferred from
+#[derive(Debug, Clone)]
+pub struct SecondaryZone {
+    pub origin: DnsName,
+    pub primary: SocketAddr,
+    /// Name of the TSIG key requests to the primary are signed with
+    pub key: Option<DnsName>,
+}
+
+impl FromStr for SecondaryZone {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<SecondaryZone> {
+        let (origin, primary) = text
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Secondary zone {} is not in ORIGIN=PRIMARY format", text))?;
+        let (primary, key) = match primary.split_once('@') {
+            Some((primary, key)) => (primary, Some(key.parse()?)),
+            None => (primary, None),
+        };
+
+        Ok(SecondaryZone {
+            origin: origin.parse()?,
+            primary: primary
+                .parse()
+                .with_context(|| format!("Invalid primary address in secondary zone {}", text))?,
+            key,
+        })
+    }
+}
+
+/// Entry of an access control list, a client matching any entry of a list is allowed
+#[derive(Debug, Clone, PartialEq)]
+pub enum AclRule {
+    /// Clients in a network given by an address and prefix length
+    Network(IpAddr, u8),
+    /// Requests signed with a TSIG key
+    Key(DnsName),
+}
+
+impl AclRule {
+    pub fn matches(&self, addr: IpAddr, key: Option<&DnsName>) -> bool {
+        match self {
+            AclRule::Network(network, prefix) => {
+                network.is_ipv4() == addr.is_ipv4() && truncate_address(addr, *prefix) == *network
+            }
+            AclRule::Key(name) => key == Some(name),
+        }
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<AclRule> {
+        if let Some(name) = text.strip_prefix("key:") {
+            return Ok(AclRule::Key(name.parse()?));
+        }
+
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("Invalid address in access rule {}", text))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix(&address))
+                .ok_or_else(|| anyhow!("Invalid prefix length in access rule {}", text))?,
+            None => max_prefix(&address),
+        };
+
+        Ok(AclRule::Network(truncate_address(address, prefix), prefix))
+    }
+}
+
+/// Names and types the holder of a TSIG key may change with dynamic updates
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateRule {
+    pub key: DnsName,
+    pub name: DnsName,
+    /// The rule covers the names below `name` instead of the name itself
+    pub subdomains: bool,
+    /// Types that may be changed, any type if empty
+    pub types: Vec<DnsQType>,
+}
+
+impl UpdateRule {
+    pub fn matches(&self, key: &DnsName, name: &DnsName, rtype: DnsQType) -> bool {
+        let name_matches = match self.subdomains {
+            true => name != &self.name && name.is_subdomain_of(&self.name),
+            false => name == &self.name,
+        };
+
+        key == &self.key && name_matches && (self.types.is_empty() || self.types.contains(&rtype))
+    }
+}
+
+impl FromStr for UpdateRule {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<UpdateRule> {
+        let mut parts = text.split(':');
+        let (Some(key), Some(name)) = (parts.next(), parts.next()) else {
+            return Err(anyhow!(
+                "Update rule {} is not in KEY:NAME[:TYPES] format",
+                text
+            ));
+        };
+        let types = match parts.next() {
+            Some(types) => types
+                .split('/')
+                .map(DnsQType::from_str)
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Invalid type in update rule {}", text))?,
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            Err(anyhow!(
+                "Update rule {} is not in KEY:NAME[:TYPES] format",
+                text
+            ))?;
+        }
+
+        let (name, subdomains) = match name.strip_prefix("*.") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+
+        Ok(UpdateRule {
+            key: key.parse()?,
+            name: name.parse()?,
+            subdomains,
+            types,
+        })
+    }
 }
@@ -1,20 +1,173 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use structured_logger::async_json::new_writer;
 use tokio::{
-    net::UdpSocket,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
     select,
     signal::unix::{signal, SignalKind},
     sync::watch,
+    time::{interval, timeout},
+};
+
+use mycelnet_dns_protocol::{
+    edns::{DnsOptRecord, EdnsOption, ExtendedErrorCode},
+    message::DnsMessage,
+    tsig::TsigKeyRing,
+    view::{DnsMessageRef, DnsSection},
+    writer::{DnsWriter, MAX_MESSAGE_SIZE, MIN_UDP_PAYLOAD_SIZE},
+    DnsHeader, DnsName, DnsOpcode, DnsPacketData, DnsQType, DnsQuestion, DnsRcode, DnsRequest,
+    DnsResourceRecord,
 };
 
-use mycelnet_dns_protocol::{DnsPacketData, DnsRequest, DnsResponse};
+use mycelnet_dns_zone::{
+    backend::ZoneBackend,
+    journal::{Diff, Journal},
+    store::ZoneStore,
+    Zone,
+};
+
+use auth::{Authentication, ResponseSigner};
+use cli::{AclRule, Args, Settings, UpdateRule};
+use cookie::{CookieCheck, CookieGuard};
+use forward::{EcsPolicy, Forwarder};
+use secondary::Secondary;
+
+mod auth;
+mod authority;
+mod cache;
+mod cookie;
+mod forward;
+mod notify;
+mod ratelimit;
+mod reload;
+mod secondary;
+mod transfer;
+mod update;
+mod watcher;
+
+/// Time a TCP connection may stay idle between requests, RFC 7766 section 6.2.3
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State shared by all requests
+struct Context {
+    cookies: CookieGuard,
+    forwarder: Option<Forwarder>,
+    zones: ZoneStore,
+    /// Changes of the zones by origin, for incremental transfers
+    journals: Mutex<HashMap<DnsName, Journal>>,
+    /// Zones transferred from primaries
+    secondaries: Vec<Arc<Secondary>>,
+    policy: RwLock<Arc<Policy>>,
+    /// Serializes dynamic updates so each applies to the latest version of its zone
+    update_lock: Mutex<()>,
+}
+
+/// Keys and access rules, replaced as a whole when the configuration is reloaded
+struct Policy {
+    keyring: TsigKeyRing,
+    /// Secondaries notified when the serial of a zone changes
+    notify_targets: Vec<SocketAddr>,
+    /// Clients besides the primaries allowed to send NOTIFY for secondary zones
+    notify_acl: Vec<AclRule>,
+    /// Names and types TSIG keys may change with dynamic updates
+    update_policy: Vec<UpdateRule>,
+    transfer_acl: Vec<AclRule>,
+}
+
+impl Policy {
+    fn new(settings: &Settings) -> Policy {
+        Policy {
+            keyring: TsigKeyRing::new(settings.tsig_keys.clone()),
+            notify_targets: settings.notify.clone(),
+            notify_acl: settings.allow_notify.clone(),
+            update_policy: settings.allow_update.clone(),
+            transfer_acl: settings.allow_transfer.clone(),
+        }
+    }
+}
+
+impl Context {
+    /// The keys and access rules as they are now, unaffected by later reloads
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    fn set_policy(&self, policy: Policy) {
+        *self.policy.write().unwrap() = Arc::new(policy);
+    }
+
+    /// Zone with the longest origin containing a name
+    fn zone(&self, name: &DnsName) -> Option<Arc<dyn ZoneBackend>> {
+        self.zones.find(name)
+    }
 
-use cli::Args;
+    /// Serve a new version of a zone, record its changes for incremental transfers and notify
+    /// secondaries if its serial changed
+    fn update_zone(&self, zone: Zone, diffs: Vec<Diff>) -> Arc<Zone> {
+        let origin = zone.origin().clone();
+        let zone = Arc::new(zone);
+
+        let previous = self.zones.insert(zone.clone());
+
+        if previous.and_then(|previous| previous.serial().ok()) != zone.serial().ok() {
+            notify::notify_all(&self.policy().notify_targets, zone.as_ref());
+        }
+
+        if let Some(journal) = self.journals.lock().unwrap().get_mut(&origin) {
+            for diff in diffs {
+                if let Err(e) = journal.append(diff) {
+                    log::error!("Failed to journal change of zone {origin}: {e:#}");
+                }
+            }
+        }
+
+        zone
+    }
+
+    /// Wait until no other update or reload changes the zones. The lock guards no data, an
+    /// update that panicked left nothing half done.
+    fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.update_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn remove_zone(&self, origin: &DnsName) {
+        self.zones.remove(origin);
+    }
+}
+
+#[cfg(test)]
+impl Context {
+    /// Context of a server without forwarder or zones, tests add the zones they need
+    fn for_tests(settings: &Settings, secondaries: Vec<Arc<Secondary>>) -> Context {
+        Context {
+            cookies: CookieGuard::new(0),
+            forwarder: None,
+            zones: ZoneStore::default(),
+            journals: Mutex::new(HashMap::new()),
+            secondaries,
+            policy: RwLock::new(Arc::new(Policy::new(settings))),
+            update_lock: Mutex::new(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let args = Arc::new(Args::parse());
 
     structured_logger::Builder::with_level(args.log_level.as_str())
         .with_target_writer("*", new_writer(tokio::io::stdout()))
@@ -22,15 +175,22 @@ async fn main() -> Result<()> {
 
     log::info!("Logger initialized");
 
-    // Setup interrupt channel and spawn interrupt handler
+    // Setup interrupt and reload channels and spawn signal handler
     let (stop_tx, mut stop_rx) = watch::channel(());
+    let (reload_tx, mut reload_rx) = watch::channel(());
     tokio::spawn(async move {
         let mut sigterm = signal(SignalKind::terminate()).unwrap();
         let mut sigint = signal(SignalKind::interrupt()).unwrap();
+        let mut sighup = signal(SignalKind::hangup()).unwrap();
         loop {
             select! {
                 _ = sigterm.recv() => log::info!("Recieved SIGTERM"),
                 _ = sigint.recv() => log::info!("Recieved SIGINT"),
+                _ = sighup.recv() => {
+                    log::info!("Received SIGHUP");
+                    reload_tx.send(()).unwrap();
+                    continue;
+                }
             };
 
             log::debug!("Sending interrupt message to worker");
@@ -38,19 +198,163 @@ async fn main() -> Result<()> {
         }
     });
 
+    let settings = match args.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Failed to read the configuration: {e:#}");
+            return Err(e);
+        }
+    };
+
+    let mut zones = Vec::new();
+    let mut journals = HashMap::new();
+    for zone_file in &settings.zones {
+        match reload::load_zone(zone_file, args.journal_size) {
+            Ok((zone, journal)) => {
+                if let Some(journal) = journal {
+                    journals.insert(zone.origin().clone(), journal);
+                }
+                zones.push(zone);
+            }
+            Err(e) => {
+                log::error!("Failed to load zone {}: {e:#}", zone_file.origin);
+                return Err(e);
+            }
+        }
+    }
+
+    let mut secondaries = Vec::new();
+    for zone in &args.secondaries {
+        let key = match &zone.key {
+            Some(name) => match settings.tsig_keys.iter().find(|key| &key.name == name) {
+                Some(key) => Some(key.clone()),
+                None => {
+                    log::error!("Unknown TSIG key {name} for secondary zone {}", zone.origin);
+                    return Err(anyhow!("Unknown TSIG key {name}"));
+                }
+            },
+            None => None,
+        };
+        // Changes transferred incrementally are passed on to secondaries of this server
+        journals.insert(zone.origin.clone(), Journal::new(args.journal_size));
+        secondaries.push(Arc::new(Secondary::new(
+            zone.origin.clone(),
+            zone.primary,
+            key,
+        )));
+    }
+
+    let context = Arc::new(Context {
+        cookies: CookieGuard::new(args.cookieless_rate_limit),
+        forwarder: args.forwarder.map(|upstream| {
+            let ecs = args.ecs.then_some(EcsPolicy {
+                ipv4_prefix: args.ecs_ipv4_prefix,
+                ipv6_prefix: args.ecs_ipv6_prefix,
+            });
+            Forwarder::new(
+                upstream,
+                Duration::from_millis(args.forwarder_timeout),
+                ecs,
+                args.cache_size,
+                Duration::from_secs(args.serve_stale),
+            )
+        }),
+        zones: ZoneStore::new(zones),
+        journals: Mutex::new(journals),
+        secondaries: secondaries.clone(),
+        policy: RwLock::new(Arc::new(Policy::new(&settings))),
+        update_lock: Mutex::new(()),
+    });
+    log::info!("Loaded {} TSIG keys", context.policy().keyring.len());
+    if let Some(forwarder) = &args.forwarder {
+        log::info!("Forwarding queries to {forwarder}");
+    }
+
+    for zone in context.zones.zones() {
+        notify::notify_all(&context.policy().notify_targets, zone.as_ref());
+    }
+
+    for secondary in secondaries {
+        log::info!(
+            "Serving zone {} as a secondary of {}",
+            secondary.origin,
+            secondary.primary
+        );
+        tokio::spawn(secondary::maintain(context.clone(), secondary));
+    }
+
+    // Periodically rotate the secret used to issue server cookies
+    let rotation_context = context.clone();
+    let rotation_period = Duration::from_secs(args.cookie_secret_rotation.max(1));
+    tokio::spawn(async move {
+        let mut ticker = interval(rotation_period);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            log::debug!("Rotating server cookie secret");
+            rotation_context.cookies.rotate();
+        }
+    });
+
+    let watch = if args.watch {
+        match watcher::spawn(context.clone(), args.clone(), &settings) {
+            Ok(watch) => Some(watch),
+            Err(e) => {
+                log::error!("Failed to watch files for changes: {e:#}");
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Serve the configuration and zones anew when told to reload them
+    let reload_context = context.clone();
+    let reload_args = args.clone();
+    tokio::spawn(async move {
+        while reload_rx.changed().await.is_ok() {
+            let context = reload_context.clone();
+            let args = reload_args.clone();
+            let reloaded =
+                tokio::task::spawn_blocking(move || reload::reload(&context, &args)).await;
+            match reloaded {
+                Ok(Ok(settings)) => {
+                    // Zones may have been added or removed
+                    if let Some(watch) = &watch {
+                        watch.reloaded(settings);
+                    }
+                }
+                Ok(Err(e)) => log::error!(
+                    "Failed to reload, keeping the current configuration and zones: {e:#}"
+                ),
+                Err(e) => log::error!("Failed to reload: {e}"),
+            }
+        }
+    });
+
     log::info!("Starting server");
     let worker = tokio::spawn(async move {
         let server_addr = format!("{}:{}", args.server_addr, args.port);
         let socket = match UdpSocket::bind(&server_addr).await {
             Ok(socket) => {
                 log::info!("Listening on {server_addr}");
-                socket
+                Arc::new(socket)
             }
             Err(e) => {
                 log::error!("Failed to bind socket to {server_addr}: {e}");
                 return Err::<(), anyhow::Error>(e.into());
             }
         };
+        let listener = match TcpListener::bind(&server_addr).await {
+            Ok(listener) => {
+                log::info!("Listening on {server_addr} over TCP");
+                listener
+            }
+            Err(e) => {
+                log::error!("Failed to bind TCP listener to {server_addr}: {e}");
+                return Err(e.into());
+            }
+        };
 
         loop {
             select! {
@@ -59,7 +363,20 @@ async fn main() -> Result<()> {
                     log::info!("Interrupt received stopping server");
                     break Ok(());
                 }
-                _ = handle_request(&socket) => {}
+                received = receive_request(&socket) => {
+                    // Handle requests concurrently so slow upstream queries do not hold up others
+                    if let Ok((data, addr)) = received {
+                        tokio::spawn(handle_request(socket.clone(), context.clone(), data, addr));
+                    }
+                }
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, addr)) => {
+                            tokio::spawn(handle_connection(stream, context.clone(), addr));
+                        }
+                        Err(e) => log::error!("Failed to accept TCP connection: {e}"),
+                    }
+                }
             }
         }
     });
@@ -72,7 +389,7 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn handle_request(socket: &UdpSocket) -> Result<()> {
+async fn receive_request(socket: &UdpSocket) -> Result<(Vec<u8>, SocketAddr)> {
     let mut buf = [0; 1024];
 
     let (len, addr) = match socket.recv_from(&mut buf).await {
@@ -83,48 +400,576 @@ async fn handle_request(socket: &UdpSocket) -> Result<()> {
         }
     };
 
-    let data = &buf[..len];
     println!("Received {len} bytes from {addr}");
 
-    let request = match DnsRequest::from_bytes(data, 0) {
+    Ok((buf[..len].to_vec(), addr))
+}
+
+async fn handle_request(
+    socket: Arc<UdpSocket>,
+    context: Arc<Context>,
+    data: Vec<u8>,
+    addr: SocketAddr,
+) -> Result<()> {
+    for response_bytes in create_response(&context, &data, addr, Transport::Udp).await? {
+        match socket.send_to(response_bytes.as_slice(), addr).await {
+            Ok(_) => {
+                log::trace!("Sent {} byte response to {addr}", response_bytes.len());
+            }
+            Err(e) => {
+                log::error!("Failed to send response: {e}");
+                return Err(e.into());
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Answer requests on a TCP connection, each message is prefixed with its length,
+/// RFC 1035 section 4.2.2
+async fn handle_connection(
+    mut stream: TcpStream,
+    context: Arc<Context>,
+    addr: SocketAddr,
+) -> Result<()> {
+    log::trace!("Accepted TCP connection from {addr}");
+
+    loop {
+        let length = match timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(length)) => length,
+            // The client closed the connection or stayed idle for too long
+            Ok(Err(_)) | Err(_) => return Ok(()),
+        };
+
+        let mut data = vec![0; length as usize];
+        if let Err(e) = stream.read_exact(&mut data).await {
+            log::debug!("Failed to read request from {addr}: {e}");
+            return Err(e.into());
+        }
+
+        for response_bytes in create_response(&context, &data, addr, Transport::Tcp).await? {
+            let mut message = (response_bytes.len() as u16).to_be_bytes().to_vec();
+            message.extend(response_bytes);
+            if let Err(e) = stream.write_all(&message).await {
+                log::error!("Failed to send response to {addr}: {e}");
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// Read zone data on a blocking thread, queries of backends like databases wait for the disk
+async fn blocking<T: Send + 'static>(
+    read: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(read).await?
+}
+
+/// Build the serialized messages answering a request, several for zone transfers and none if
+/// the request is dropped
+async fn create_response(
+    context: &Context,
+    data: &[u8],
+    addr: SocketAddr,
+    transport: Transport,
+) -> Result<Vec<Vec<u8>>> {
+    let view = DnsMessageRef::new(data)?;
+    let request = match read_request(&view) {
         Ok(request) => {
             log::trace!("Received request: {request:?}");
             request
         }
         Err(e) => {
-            log::error!("Failed to parse request: {e}");
-            return Err(e);
+            log::warn!("Rejecting malformed request from {addr}: {e:#}");
+            return format_error(data, &e);
         }
     };
 
-    let response = match DnsResponse::from_request(&request) {
-        Ok(response) => {
-            log::trace!("Created response: {response:?}");
-            response
-        }
+    let mut rcode = DnsRcode::NoError;
+    // Reasons for failures sent back as Extended DNS Errors to clients supporting EDNS
+    let mut extended_errors = Vec::new();
+
+    let now = auth::unix_time();
+    let authentication = match auth::authenticate(&context.policy().keyring, data, now) {
+        Ok(authentication) => authentication,
+        // Answered unsigned, RFC 8945 section 5.2
         Err(e) => {
-            log::error!("Failed to create response: {e}");
-            return Err(e);
+            log::warn!("Rejecting request from {addr} with malformed TSIG record: {e:#}");
+            rcode = DnsRcode::FormatError;
+            extended_errors.push((
+                ExtendedErrorCode::Other,
+                format!("Malformed TSIG record: {e:#}"),
+            ));
+            Authentication::Unsigned
         }
     };
 
-    let response_bytes = match response.to_bytes() {
-        Ok(response_bytes) => response_bytes,
+    let edns = match view.edns() {
+        Ok(edns) => edns,
         Err(e) => {
-            log::error!("Failed to serialize response: {e}");
-            return Err(e);
+            log::warn!("Rejecting request from {addr} with malformed EDNS: {e:#}");
+            rcode = DnsRcode::FormatError;
+            extended_errors.push((ExtendedErrorCode::Other, format!("Malformed EDNS: {e:#}")));
+            None
         }
     };
 
-    match socket.send_to(response_bytes.as_slice(), addr).await {
-        Ok(_) => {
-            log::trace!("Sent response to {addr}: {response:?}");
+    let cookie = context.cookies.check(edns.as_ref(), addr.ip(), now as u32);
+    // TCP clients proved their address with the handshake, only UDP sources can be spoofed
+    if transport == Transport::Udp && !context.cookies.allow(&cookie, addr.ip(), Instant::now()) {
+        log::debug!("Dropping request from {addr} without valid cookie over rate limit");
+        return Ok(Vec::new());
+    }
+
+    if let CookieCheck::Invalid(_) = cookie {
+        log::debug!("Rejecting request from {addr} with invalid server cookie");
+        rcode = DnsRcode::BadCookie;
+        extended_errors.push((
+            ExtendedErrorCode::Other,
+            "Invalid server cookie".to_string(),
+        ));
+    }
+
+    if let Authentication::Signed { key, .. } = &authentication {
+        log::debug!("Request from {addr} authenticated with key {}", key.name);
+    }
+
+    if let Authentication::Failed { error, request, .. } = &authentication {
+        log::warn!(
+            "Rejecting request from {addr} signed with key {}: {error}",
+            request.key_name
+        );
+        rcode = error.rcode();
+        extended_errors.push((
+            ExtendedErrorCode::Prohibited,
+            format!("TSIG verification failed: {error}"),
+        ));
+    }
+
+    let requested_subnet = edns.as_ref().and_then(|opt| opt.client_subnet());
+    let dnssec_ok = edns.as_ref().is_some_and(|opt| opt.dnssec_ok);
+    let mut scope_prefix = 0;
+    let mut message = DnsMessage::response_to(&request).recursion_available(true);
+    // Messages following the first one of a zone transfer
+    let mut continuation = Vec::new();
+    let zone = authority::zone_for(
+        &context.zones,
+        &request.question.qname,
+        request.question.qtype,
+    );
+    match (context.forwarder.as_ref(), zone.as_ref()) {
+        _ if rcode != DnsRcode::NoError => {}
+        _ if request.header.flags.opcode == DnsOpcode::Notify => {
+            rcode = notify::receive(context, &request, addr, &authentication);
+            message = message.authoritative(true);
+        }
+        _ if request.header.flags.opcode == DnsOpcode::Update => {
+            if let Err(e) = update::receive(context, data, &request, addr, &authentication) {
+                log::info!("Rejecting update from {addr}: {e}");
+                rcode = e.rcode;
+                let code = match e.rcode {
+                    DnsRcode::Refused => ExtendedErrorCode::Prohibited,
+                    _ => ExtendedErrorCode::Other,
+                };
+                extended_errors.push((code, e.reason));
+            }
+        }
+        _ if request.header.flags.opcode != DnsOpcode::Query => {
+            log::debug!(
+                "Rejecting {:?} request from {addr}",
+                request.header.flags.opcode
+            );
+            rcode = DnsRcode::NotImplemented;
+            extended_errors.push((
+                ExtendedErrorCode::NotSupported,
+                format!("Opcode {:?} is not supported", request.header.flags.opcode),
+            ));
+        }
+        (_, zone) if matches!(request.question.qtype, DnsQType::AXFR | DnsQType::IXFR) => {
+            let zone = zone.filter(|zone| zone.origin() == &request.question.qname);
+            let serial = match request.question.qtype {
+                DnsQType::IXFR => Some(transfer::requested_serial(data)),
+                _ => None,
+            };
+            match (zone, serial) {
+                // Full transfers do not fit in datagrams, RFC 5936 section 4.2
+                (_, None) if transport == Transport::Udp => {
+                    log::debug!("Rejecting zone transfer from {addr} over UDP");
+                    rcode = DnsRcode::NotImplemented;
+                    extended_errors.push((
+                        ExtendedErrorCode::NotSupported,
+                        "Zone transfers require TCP".to_string(),
+                    ));
+                }
+                (None, _) => {
+                    log::debug!(
+                        "Rejecting transfer of unknown zone {} from {addr}",
+                        request.question.qname
+                    );
+                    rcode = DnsRcode::NotAuth;
+                    extended_errors.push((
+                        ExtendedErrorCode::NotAuthoritative,
+                        format!("Not authoritative for {}", request.question.qname),
+                    ));
+                }
+                (Some(zone), _)
+                    if !transfer::allowed(
+                        &context.policy().transfer_acl,
+                        addr.ip(),
+                        &authentication,
+                    ) =>
+                {
+                    log::warn!("Refusing transfer of zone {} to {addr}", zone.origin());
+                    rcode = DnsRcode::Refused;
+                    extended_errors.push((
+                        ExtendedErrorCode::Prohibited,
+                        "Zone transfer not allowed".to_string(),
+                    ));
+                }
+                (Some(_), Some(Err(e))) => {
+                    log::debug!("Rejecting malformed IXFR request from {addr}: {e:#}");
+                    rcode = DnsRcode::FormatError;
+                    extended_errors.push((ExtendedErrorCode::Other, format!("{e:#}")));
+                }
+                (Some(zone), serial) => {
+                    let serial = serial.and_then(Result::ok);
+                    let udp = transport == Transport::Udp;
+                    let transfer = match zone.zone() {
+                        Some(_) => {
+                            let journals = context.journals.lock().unwrap();
+                            let journal = journals.get(zone.origin());
+                            transfer::respond(zone.as_ref(), journal, &request, serial, udp)
+                        }
+                        // Zones in databases have no journals
+                        None => {
+                            let (zone, request) = (zone.clone(), request.clone());
+                            blocking(move || {
+                                transfer::respond(zone.as_ref(), None, &request, serial, udp)
+                            })
+                            .await
+                        }
+                    };
+                    match transfer {
+                        Ok(mut messages) => {
+                            log::info!(
+                                "Transferring zone {} with serial {} to {addr}",
+                                zone.origin(),
+                                zone.serial().unwrap_or_default()
+                            );
+                            message = messages.remove(0);
+                            continuation = messages;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to transfer zone {}: {e:#}", zone.origin());
+                            rcode = DnsRcode::ServerFailure;
+                        }
+                    }
+                }
+            }
+        }
+        (_, Some(zone)) => {
+            let (qname, qtype) = (&request.question.qname, request.question.qtype);
+            let lookup = match zone.zone() {
+                Some(zone) => authority::lookup(zone, qname, qtype, dnssec_ok),
+                None => {
+                    let (zone, qname) = (zone.clone(), qname.clone());
+                    blocking(move || authority::lookup(zone.as_ref(), &qname, qtype, dnssec_ok))
+                        .await
+                }
+            };
+            match lookup {
+                Ok(lookup) => {
+                    rcode = lookup.rcode;
+                    message = message
+                        .authoritative(lookup.authoritative)
+                        .answers(lookup.answers)
+                        .authorities(lookup.authority)
+                        .additionals(lookup.additional);
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to look up {} in zone {}: {e:#}",
+                        request.question.qname,
+                        zone.origin()
+                    );
+                    rcode = DnsRcode::ServerFailure;
+                    extended_errors.push((
+                        ExtendedErrorCode::Other,
+                        "Failed to read zone data".to_string(),
+                    ));
+                }
+            }
+        }
+        // Secondaries without a current copy of their zone cannot answer for it
+        (_, None)
+            if context
+                .secondaries
+                .iter()
+                .any(|secondary| request.question.qname.is_subdomain_of(&secondary.origin)) =>
+        {
+            log::debug!(
+                "No current copy of the zone of {} to answer {addr}",
+                request.question.qname
+            );
+            rcode = DnsRcode::ServerFailure;
+            extended_errors.push((
+                ExtendedErrorCode::NoReachableAuthority,
+                "Zone has not been transferred from its primary or has expired".to_string(),
+            ));
+        }
+        (Some(forwarder), None) => {
+            let subnet = forwarder.client_subnet(addr.ip(), requested_subnet);
+            match forwarder.resolve(&request.question, subnet).await {
+                Ok(answer) => {
+                    rcode = answer.rcode;
+                    scope_prefix = answer.scope_prefix;
+                    extended_errors.extend(answer.extended_errors);
+                    message = message
+                        .answers(answer.answers)
+                        .authorities(answer.authority)
+                        .additionals(answer.additional);
+                }
+                Err(e) => {
+                    log::warn!("Failed to forward request from {addr}: {e:#}");
+                    rcode = DnsRcode::ServerFailure;
+                    extended_errors.push((forward::extended_error(&e), e.to_string()));
+                }
+            }
+        }
+        // Servers with zones and no forwarder only answer for their zones
+        (None, None) if !context.zones.is_empty() || !context.secondaries.is_empty() => {
+            log::debug!(
+                "Refusing query for {} outside of all zones from {addr}",
+                request.question.qname
+            );
+            rcode = DnsRcode::Refused;
+            extended_errors.push((
+                ExtendedErrorCode::NotAuthoritative,
+                format!("Not authoritative for {}", request.question.qname),
+            ));
+        }
+        // Without zones or a forwarder every name resolves to the loopback address
+        (None, None) => {
+            message = message.answer(loopback_answer(&request.question));
+        }
+    }
+    message = message.rcode(rcode);
+
+    // A malformed OPT record still shows the client understands extended errors
+    let client_edns = view
+        .section(DnsSection::Additional)
+        .flatten()
+        .any(|record| record.rtype() == DnsQType::OPT);
+
+    if client_edns {
+        let mut opt = DnsOptRecord::new();
+        // The DO bit is copied to the response, RFC 3225 section 3
+        opt.dnssec_ok = dnssec_ok;
+        if let Some(client) = cookie.client() {
+            opt.set_option(context.cookies.option(client, addr.ip(), now as u32));
+        }
+        // Echo the client subnet with the scope the answer applies to, RFC 7871 section 7.2.1
+        if let Some((address, source_prefix, _)) = requested_subnet {
+            opt.set_option(EdnsOption::ClientSubnet {
+                source_prefix,
+                scope_prefix,
+                address,
+            });
+        }
+        for (code, text) in &extended_errors {
+            opt.add_extended_error(*code, text);
+        }
+        message = message.edns(opt);
+    }
+
+    let responses = match std::iter::once(message)
+        .chain(continuation)
+        .map(DnsMessage::build)
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(responses) => {
+            log::trace!("Created response: {responses:?}");
+            responses
         }
         Err(e) => {
-            log::error!("Failed to send response: {e}");
-            return Err(e.into());
+            log::error!("Failed to create response: {e}");
+            return Err(e);
         }
     };
 
-    Ok(())
+    // Truncate to what the client accepts over UDP, leaving room for the TSIG record
+    let limit = match transport {
+        Transport::Udp => edns
+            .as_ref()
+            .map(|opt| opt.udp_payload_size as usize)
+            .unwrap_or_default()
+            .max(MIN_UDP_PAYLOAD_SIZE),
+        Transport::Tcp => MAX_MESSAGE_SIZE,
+    };
+    let mut signer = ResponseSigner::new(&authentication);
+    let mut writer = DnsWriter::with_buffer(
+        Vec::with_capacity(limit),
+        limit.saturating_sub(signer.size()),
+    );
+
+    // Every message of a transfer is signed, each covering the MAC of the one before
+    let mut responses_bytes = Vec::with_capacity(responses.len());
+    for response in responses {
+        writer.clear();
+        let response_bytes = match response
+            .write_to(&mut writer)
+            .and_then(|_| signer.sign(writer.as_bytes(), now))
+        {
+            Ok(response_bytes) => response_bytes,
+            Err(e) => {
+                log::error!("Failed to serialize response: {e}");
+                return Err(e);
+            }
+        };
+        responses_bytes.push(response_bytes);
+    }
+
+    Ok(responses_bytes)
+}
+
+/// The header and the question of a request. Its records stay in the message, only the OPT
+/// record is read from it for every request, transfers, NOTIFY and UPDATE read what they need.
+fn read_request(view: &DnsMessageRef) -> Result<DnsRequest> {
+    let question = view
+        .question()?
+        .ok_or_else(|| anyhow!("Request has no question"))?;
+    Ok(DnsRequest {
+        header: view.header().clone(),
+        question: question.to_question()?,
+        ..Default::default()
+    })
+}
+
+/// FORMERR answer to a request that could not be parsed, echoing what its header tells. The
+/// reason goes in an Extended DNS Error if the request has additional records, which likely
+/// include an OPT record though the malformed request does not tell for sure.
+fn format_error(data: &[u8], error: &anyhow::Error) -> Result<Vec<Vec<u8>>> {
+    let header = DnsHeader::from_bytes(data, 0)?;
+    // Responses are never answered, two servers would otherwise keep answering each other
+    if header.flags.qr == 1 {
+        return Ok(Vec::new());
+    }
+
+    let mut message = DnsMessage::error_to(&header).rcode(DnsRcode::FormatError);
+    if header.arcount > 0 {
+        let mut opt = DnsOptRecord::new();
+        opt.add_extended_error(
+            ExtendedErrorCode::Other,
+            &format!("Malformed request: {error}"),
+        );
+        message = message.edns(opt);
+    }
+
+    Ok(vec![message.build()?.to_bytes()?])
+}
+
+/// Answer to a question resolving its name to 127.0.0.1
+fn loopback_answer(question: &DnsQuestion) -> DnsResourceRecord {
+    DnsResourceRecord {
+        name: question.qname.clone(),
+        rtype: question.qtype,
+        rclass: question.qclass,
+        ttl: 300,
+        rdlength: 4,
+        rdata: vec![127, 0, 0, 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mycelnet_dns_protocol::{
+        tsig::{TsigKey, TsigSigner},
+        DnsResponse,
+    };
+
+    use super::*;
+
+    async fn answer(context: &Context, data: &[u8]) -> Result<Option<DnsResponse>> {
+        let addr = "192.0.2.1:5353".parse()?;
+        let responses = create_response(context, data, addr, Transport::Udp).await?;
+        responses
+            .first()
+            .map(|response| DnsResponse::from_bytes(response, 0))
+            .transpose()
+    }
+
+    fn extended_errors(response: &DnsResponse) -> Result<Vec<ExtendedErrorCode>> {
+        Ok(response
+            .edns()?
+            .map(|opt| {
+                opt.extended_errors()
+                    .iter()
+                    .map(|(code, _)| *code)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn answer_malformed_requests() -> Result<()> {
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+
+        // The question name claims five bytes of which two follow, then an OPT record
+        let mut data = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        data.extend([5, b'w', b'w']);
+        let response = answer(&context, &data).await?.unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.header.flags.qr, 1);
+        assert_eq!(response.header.flags.rcode, DnsRcode::FormatError);
+        assert_eq!(response.header.qdcount, 0);
+        assert_eq!(extended_errors(&response)?, vec![ExtendedErrorCode::Other]);
+
+        // Clients without additional records get no OPT record
+        data[11] = 0;
+        let response = answer(&context, &data).await?.unwrap();
+        assert_eq!(response.header.flags.rcode, DnsRcode::FormatError);
+        assert!(response.edns()?.is_none());
+
+        // Malformed responses are dropped
+        data[2] |= 0x80;
+        assert!(answer(&context, &data).await?.is_none());
+        assert!(answer(&context, &data[..8]).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answer_malformed_tsig_records() -> Result<()> {
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let key: TsigKey =
+            "hmac-sha256:transfer.mycelnet.tech:c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0".parse()?;
+
+        let mut request = DnsRequest::default();
+        request.header.id = 0x4321;
+        request.header.qdcount = 1;
+        request.question.qname = "mycelnet.tech".parse()?;
+        request.question.qtype = DnsQType::A;
+        request.additional = Some(vec![DnsOptRecord::new().to_record()?]);
+        let signed = TsigSigner::new(key, None).sign(&request.to_bytes()?, auth::unix_time())?;
+
+        // A TSIG record moved to the answer section
+        let mut request = DnsRequest::from_bytes(&signed, 0)?;
+        let additional = request.additional.as_mut().unwrap();
+        let tsig = additional.pop().unwrap();
+        assert_eq!(tsig.rtype, DnsQType::TSIG);
+        request.answers = Some(vec![tsig]);
+
+        let response = answer(&context, &request.to_bytes()?).await?.unwrap();
+        assert_eq!(response.header.id, 0x4321);
+        assert_eq!(response.header.flags.rcode, DnsRcode::FormatError);
+        assert_eq!(extended_errors(&response)?, vec![ExtendedErrorCode::Other]);
+        // Not signed, the TSIG record could not be read
+        let additional = response.additional.unwrap_or_default();
+        assert!(additional
+            .iter()
+            .all(|record| record.rtype != DnsQType::TSIG));
+
+        Ok(())
+    }
 }
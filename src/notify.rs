@@ -0,0 +1,262 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::{net::UdpSocket, time::timeout};
+
+use mycelnet_dns_protocol::{
+    view::DnsMessageRef, DnsClass, DnsHeader, DnsName, DnsOpcode, DnsPacketData, DnsQType,
+    DnsQuestion, DnsRcode, DnsRequest, DnsResourceRecord,
+};
+use mycelnet_dns_zone::backend::ZoneBackend;
+
+use crate::{auth::Authentication, transfer, Context};
+
+/// Time to wait for a secondary to acknowledge a NOTIFY, doubled after every attempt,
+/// RFC 1996 section 3.6
+const NOTIFY_RETRY: Duration = Duration::from_secs(2);
+
+/// Attempts made to notify a secondary before giving up
+const NOTIFY_ATTEMPTS: usize = 5;
+
+/// Handle a NOTIFY for a zone this server is a secondary of by refreshing the zone right away,
+/// RFC 1996 section 3.11. Returns the rcode of the answer.
+pub fn receive(
+    context: &Context,
+    request: &DnsRequest,
+    addr: SocketAddr,
+    authentication: &Authentication,
+) -> DnsRcode {
+    let origin = &request.question.qname;
+    if request.question.qtype != DnsQType::SOA {
+        log::debug!(
+            "Rejecting NOTIFY for {origin} of type {} from {addr}",
+            request.question.qtype
+        );
+        return DnsRcode::NotImplemented;
+    }
+
+    let Some(secondary) = context
+        .secondaries
+        .iter()
+        .find(|secondary| &secondary.origin == origin)
+    else {
+        log::debug!("Rejecting NOTIFY for {origin} from {addr}, not a secondary of the zone");
+        return DnsRcode::NotAuth;
+    };
+
+    // Only the primary of the zone and allowed clients may trigger a refresh, RFC 1996
+    // section 3.10
+    if addr.ip() != secondary.primary.ip()
+        && !transfer::allowed(&context.policy().notify_acl, addr.ip(), authentication)
+    {
+        log::warn!("Refusing NOTIFY for {origin} from {addr}");
+        return DnsRcode::Refused;
+    }
+
+    log::info!("Received NOTIFY for {origin} from {addr}");
+    secondary.refresh.notify_one();
+
+    DnsRcode::NoError
+}
+
+/// Tell secondaries a zone changed, each in its own task
+pub fn notify_all(targets: &[SocketAddr], zone: &dyn ZoneBackend) {
+    let soa = match zone.soa_record() {
+        Ok(soa) => soa,
+        Err(e) => {
+            log::error!("Failed to notify secondaries of {}: {e:#}", zone.origin());
+            return;
+        }
+    };
+
+    for target in targets.iter().copied() {
+        let origin = zone.origin().clone();
+        let soa = soa.clone();
+        tokio::spawn(async move {
+            match send(&origin, soa, target).await {
+                Ok(()) => log::debug!("Secondary {target} acknowledged NOTIFY for {origin}"),
+                Err(e) => log::warn!("Failed to notify {target} of changes to {origin}: {e:#}"),
+            }
+        });
+    }
+}
+
+/// Send a NOTIFY carrying the current SOA record of a zone until the secondary answers it,
+/// RFC 1996 section 3
+pub async fn send(origin: &DnsName, soa: DnsResourceRecord, target: SocketAddr) -> Result<()> {
+    let id = rand::random::<u16>();
+    let mut request = DnsRequest {
+        header: DnsHeader {
+            id,
+            qdcount: 1,
+            ..DnsHeader::default()
+        },
+        question: DnsQuestion {
+            qname: origin.clone(),
+            qtype: DnsQType::SOA,
+            qclass: DnsClass::IN,
+        },
+        answers: Some(vec![soa]),
+        ..DnsRequest::default()
+    };
+    request.header.flags.opcode = DnsOpcode::Notify;
+    request.header.flags.aa = 1;
+    let data = request.to_bytes()?;
+
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+
+    let mut wait = NOTIFY_RETRY;
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(&data).await?;
+        match timeout(wait, receive_answer(&socket, id)).await {
+            Ok(Ok(DnsRcode::NoError)) => return Ok(()),
+            Ok(Ok(rcode)) => Err(anyhow!("{target} answered NOTIFY with {rcode:?}"))?,
+            Ok(Err(e)) => {
+                log::debug!("Failed to receive answer to NOTIFY from {target}: {e}");
+                tokio::time::sleep(wait).await;
+            }
+            Err(_) => log::debug!("No answer to NOTIFY from {target}, retrying"),
+        }
+        wait *= 2;
+    }
+
+    Err(anyhow!("No answer after {NOTIFY_ATTEMPTS} attempts"))
+}
+
+/// Wait for the answer to a NOTIFY and return its rcode, other datagrams are ignored
+async fn receive_answer(socket: &UdpSocket, id: u16) -> Result<DnsRcode> {
+    let mut buf = vec![0; 65535];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let Ok(message) = DnsMessageRef::new(&buf[..len]) else {
+            continue;
+        };
+
+        let header = message.header();
+        if header.id == id && header.flags.qr == 1 && header.flags.opcode == DnsOpcode::Notify {
+            return Ok(header.flags.rcode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cli::Settings;
+    use mycelnet_dns_zone::{master, Zone};
+
+    use crate::secondary::Secondary;
+
+    use super::*;
+
+    fn soa(origin: &DnsName, serial: u32) -> Result<DnsResourceRecord> {
+        let text = format!("@ 300 SOA ns1 hostmaster {serial} 3600 900 604800 300\n");
+        let mut entries = master::parse_str(&text, origin)?;
+        Ok(entries.remove(0).record)
+    }
+
+    fn notify(origin: &DnsName, serial: u32) -> Result<DnsRequest> {
+        let mut request = DnsRequest::default();
+        request.header.flags.opcode = DnsOpcode::Notify;
+        request.question.qname = origin.clone();
+        request.question.qtype = DnsQType::SOA;
+        request.answers = Some(vec![soa(origin, serial)?]);
+        Ok(request)
+    }
+
+    #[tokio::test]
+    async fn refresh_on_notify_from_primaries() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech".parse()?;
+        let secondary = Arc::new(Secondary::new(
+            origin.clone(),
+            "192.0.2.53:53".parse()?,
+            None,
+        ));
+        let settings = Settings {
+            allow_notify: vec!["198.51.100.0/24".parse()?],
+            ..Settings::default()
+        };
+        let context = Context::for_tests(&settings, vec![secondary.clone()]);
+        let refreshed = || async {
+            timeout(Duration::from_millis(50), secondary.refresh.notified())
+                .await
+                .is_ok()
+        };
+
+        let request = notify(&origin, 2)?;
+        let unknown = notify(&"other.tech".parse()?, 2)?;
+        for (request, addr, rcode) in [
+            (&unknown, "192.0.2.53:53", DnsRcode::NotAuth),
+            (&request, "203.0.113.1:53", DnsRcode::Refused),
+            (&request, "192.0.2.54:53", DnsRcode::Refused),
+        ] {
+            let answer = receive(&context, request, addr.parse()?, &Authentication::Unsigned);
+            assert_eq!(answer, rcode, "{addr}");
+            assert!(!refreshed().await, "{addr}");
+        }
+
+        // The primary and clients the ACL allows trigger a refresh
+        for addr in ["192.0.2.53:5353", "198.51.100.7:53"] {
+            let answer = receive(&context, &request, addr.parse()?, &Authentication::Unsigned);
+            assert_eq!(answer, DnsRcode::NoError, "{addr}");
+            assert!(refreshed().await, "{addr}");
+        }
+
+        // Types other than SOA are not part of RFC 1996
+        let mut other = notify(&origin, 2)?;
+        other.question.qtype = DnsQType::A;
+        let answer = receive(
+            &context,
+            &other,
+            "192.0.2.53:53".parse()?,
+            &Authentication::Unsigned,
+        );
+        assert_eq!(answer, DnsRcode::NotImplemented);
+        assert!(!refreshed().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_until_answered() -> Result<()> {
+        let origin: DnsName = "mycelnet.tech".parse()?;
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        zone.insert(soa(&origin, 3)?)?;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let target = socket.local_addr()?;
+        let sending = tokio::spawn(async move { send(&origin, zone.soa_record()?, target).await });
+
+        // The first attempt goes unanswered
+        let mut buf = vec![0; 512];
+        let (len, _) = socket.recv_from(&mut buf).await?;
+        let first = buf[..len].to_vec();
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        assert_eq!(buf[..len], first[..], "retries repeat the NOTIFY");
+
+        let request = DnsRequest::from_bytes(&buf[..len], 0)?;
+        assert_eq!(request.header.flags.opcode, DnsOpcode::Notify);
+        assert_eq!(request.answers.as_deref().map(<[_]>::len), Some(1));
+
+        // Answers with another ID are ignored
+        let mut answer = buf[..len].to_vec();
+        answer[2] |= 0x80;
+        answer[0] ^= 0xff;
+        socket.send_to(&answer, addr).await?;
+        answer[0] ^= 0xff;
+        socket.send_to(&answer, addr).await?;
+
+        sending.await??;
+        let more = timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await;
+        assert!(more.is_err(), "NOTIFY sent again after the answer");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Buckets idle for longer than this are full again and can be dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client address allowing a burst of up to one second worth of queries
+pub struct RateLimiter {
+    rate: u32,
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` queries per second from each address
+    pub fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    pub fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.pruned) > IDLE_TIMEOUT {
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) <= IDLE_TIMEOUT);
+            self.pruned = now;
+        }
+
+        let rate = self.rate as f64;
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_per_address() {
+        let mut limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.allow(client, now));
+        assert!(limiter.allow(client, now));
+        assert!(!limiter.allow(client, now));
+        assert!(limiter.allow(other, now));
+
+        assert!(limiter.allow(client, now + Duration::from_millis(500)));
+        assert!(!limiter.allow(client, now + Duration::from_millis(500)));
+    }
+}
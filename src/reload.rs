@@ -0,0 +1,499 @@
+//! Reloading the configuration and zones while the server runs.
+//!
+//! Everything is read and checked before any of it replaces what is served, so a mistake in
+//! one file leaves the server as it was. The zones are then swapped in at once: queries in
+//! flight finish with the zones they started with and sockets, caches and secondary zones are
+//! kept as they are. Secondary zones only come from options, which a reload does not read
+//! again, so they keep their primaries and TSIG keys until the server restarts.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context as _, Result};
+
+use mycelnet_dns_protocol::DnsName;
+#[cfg(feature = "sqlite")]
+use mycelnet_dns_zone::sqlite::SqliteBackend;
+use mycelnet_dns_zone::{
+    backend::ZoneBackend,
+    journal::{Diff, Journal},
+    rdata::serial_newer,
+    Zone,
+};
+
+use cli::{Args, Settings, ZoneFile, ZoneFormat};
+
+use crate::{notify, Context, Policy};
+
+/// Read and check a zone. Zones in master files are loaded into memory with the changes
+/// journaled since the file was written, zones in databases are opened and read as needed.
+pub fn load_zone(
+    zone_file: &ZoneFile,
+    journal_size: usize,
+) -> Result<(Arc<dyn ZoneBackend>, Option<Journal>)> {
+    if zone_file.format == ZoneFormat::Sqlite {
+        return Ok((open_database(zone_file)?, None));
+    }
+
+    let (mut zone, warnings) = Zone::load(&zone_file.path, &zone_file.origin)?;
+    for warning in warnings {
+        log::warn!("Zone {}: {warning}", zone_file.origin);
+    }
+
+    let journal = Journal::open(&zone_file.journal_path(), journal_size)?;
+    let replayed = journal.replay(&mut zone)?;
+    log::info!(
+        "Loaded zone {} with {} records and serial {}, {replayed} changes replayed from the journal",
+        zone.origin(),
+        zone.len(),
+        zone.serial()?
+    );
+
+    Ok((Arc::new(zone), Some(journal)))
+}
+
+#[cfg(feature = "sqlite")]
+fn open_database(zone_file: &ZoneFile) -> Result<Arc<dyn ZoneBackend>> {
+    let backend = SqliteBackend::open(&zone_file.path, zone_file.origin.clone())?;
+    // Every answer needs the SOA record, a database without one cannot serve the zone
+    let serial = backend.serial()?;
+    log::info!(
+        "Opened zone {} with serial {serial} from database {}",
+        zone_file.origin,
+        zone_file.path.display()
+    );
+
+    Ok(Arc::new(backend))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_database(zone_file: &ZoneFile) -> Result<Arc<dyn ZoneBackend>> {
+    Err(anyhow!(
+        "Zone {} is stored in an SQLite database but the server was built without the sqlite feature",
+        zone_file.origin
+    ))
+}
+
+/// A zone read again with the changes to journal and whether to notify secondaries of it
+struct Reloaded {
+    zone: Arc<dyn ZoneBackend>,
+    journal: Option<Journal>,
+    diff: Option<Diff>,
+    notify: bool,
+}
+
+/// Read the configuration and the zones it lists again and serve them in place of the current
+/// ones. Zones whose serial increased are journaled for incremental transfers and secondaries
+/// are notified of them. Returns the settings now in use.
+pub fn reload(context: &Context, args: &Args) -> Result<Settings> {
+    let settings = args.settings()?;
+    let secondaries: HashSet<&DnsName> = context
+        .secondaries
+        .iter()
+        .map(|secondary| &secondary.origin)
+        .collect();
+    if let Some(zone) = settings
+        .zones
+        .iter()
+        .find(|zone| secondaries.contains(&zone.origin))
+    {
+        Err(anyhow!(
+            "Zone {} is already served as a secondary",
+            zone.origin
+        ))?;
+    }
+
+    let served: Vec<_> = settings
+        .zones
+        .iter()
+        .map(|zone_file| context.zones.get(&zone_file.origin))
+        .collect();
+    let mut loaded = settings
+        .zones
+        .iter()
+        .map(|zone_file| load(zone_file, args.journal_size))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Updates wait until the zones are replaced so none applies to a zone about to be dropped.
+    // Zones updated while loading are loaded again, the journals read before miss the changes.
+    let _guard = context.lock_updates();
+    for ((zone_file, served), loaded) in settings.zones.iter().zip(&served).zip(&mut loaded) {
+        if !same(&context.zones.get(&zone_file.origin), served) {
+            *loaded = load(zone_file, args.journal_size)?;
+        }
+    }
+
+    let reloaded = loaded
+        .into_iter()
+        .map(|(zone, journal)| prepare(context, zone, journal))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Nothing fails from here on, the new state replaces the old one as a whole
+    let previous = context.zones.replace(
+        reloaded.iter().map(|reloaded| reloaded.zone.clone()),
+        |zone| secondaries.contains(zone.origin()),
+    );
+    context.set_policy(Policy::new(&settings));
+    let policy = context.policy();
+
+    let mut journals = context.journals.lock().unwrap();
+    journals.retain(|origin, _| secondaries.contains(origin));
+    for reloaded in reloaded {
+        apply(&policy, reloaded, &mut journals);
+    }
+
+    for zone in previous {
+        if context.zones.get(zone.origin()).is_none() {
+            log::info!("Zone {} is no longer served", zone.origin());
+        }
+    }
+    log::info!(
+        "Reloaded {} zones and {} TSIG keys",
+        settings.zones.len(),
+        policy.keyring.len()
+    );
+
+    Ok(settings)
+}
+
+/// Read one zone again and serve it in place of the current version, leaving the other zones
+/// and the configuration as they are
+pub fn reload_zone(context: &Context, zone_file: &ZoneFile, journal_size: usize) -> Result<()> {
+    if context
+        .secondaries
+        .iter()
+        .any(|secondary| secondary.origin == zone_file.origin)
+    {
+        Err(anyhow!(
+            "Zone {} is already served as a secondary",
+            zone_file.origin
+        ))?;
+    }
+
+    let served = context.zones.get(&zone_file.origin);
+    let mut loaded = load(zone_file, journal_size)?;
+
+    // Zones are loaded before waiting for updates, as in reload
+    let _guard = context.lock_updates();
+    if !same(&context.zones.get(&zone_file.origin), &served) {
+        loaded = load(zone_file, journal_size)?;
+    }
+    let (zone, journal) = loaded;
+    let reloaded = prepare(context, zone, journal)?;
+
+    context.zones.insert(reloaded.zone.clone());
+    let mut journals = context.journals.lock().unwrap();
+    apply(&context.policy(), reloaded, &mut journals);
+    log::info!("Reloaded zone {}", zone_file.origin);
+
+    Ok(())
+}
+
+fn load(
+    zone_file: &ZoneFile,
+    journal_size: usize,
+) -> Result<(Arc<dyn ZoneBackend>, Option<Journal>)> {
+    load_zone(zone_file, journal_size)
+        .with_context(|| format!("Failed to load zone {}", zone_file.origin))
+}
+
+/// Whether the same version of a zone is served, or none both times
+fn same(zone: &Option<Arc<dyn ZoneBackend>>, other: &Option<Arc<dyn ZoneBackend>>) -> bool {
+    match (zone, other) {
+        (Some(zone), Some(other)) => Arc::ptr_eq(zone, other),
+        (zone, other) => zone.is_none() && other.is_none(),
+    }
+}
+
+/// Compare a loaded zone with the version served now, which is left untouched
+fn prepare(
+    context: &Context,
+    zone: Arc<dyn ZoneBackend>,
+    journal: Option<Journal>,
+) -> Result<Reloaded> {
+    let origin = zone.origin();
+    let serial = zone.serial()?;
+
+    let (diff, notify) = match context.zones.get(origin) {
+        Some(old) => {
+            let old_serial = old.serial()?;
+            // Only zones in memory can be compared, databases change without the server
+            let diff = match (old.zone(), zone.zone()) {
+                (Some(old), Some(new)) => Some(Diff::between(old, new)?),
+                _ => None,
+            };
+            let changed = diff
+                .as_ref()
+                .is_some_and(|diff| !diff.added.is_empty() || !diff.removed.is_empty());
+            if serial == old_serial && changed {
+                log::warn!(
+                    "Zone {origin} changed without a new serial, secondaries keep the old version"
+                );
+            } else if serial_newer(old_serial, serial) {
+                log::warn!(
+                    "Serial {serial} of zone {origin} is older than the serial {old_serial} served before"
+                );
+            }
+
+            let newer = serial_newer(serial, old_serial);
+            // Changes of the master file itself are not in the journal yet
+            let unjournaled = newer
+                && journal
+                    .as_ref()
+                    .is_some_and(|journal| journal.last_serial() != Some(serial));
+            (diff.filter(|_| unjournaled), newer)
+        }
+        None => (None, true),
+    };
+
+    Ok(Reloaded {
+        zone,
+        journal,
+        diff,
+        notify,
+    })
+}
+
+/// Journal and announce a zone once it is served
+fn apply(policy: &Policy, reloaded: Reloaded, journals: &mut HashMap<DnsName, Journal>) {
+    let origin = reloaded.zone.origin().clone();
+    if reloaded.notify {
+        notify::notify_all(&policy.notify_targets, reloaded.zone.as_ref());
+    }
+
+    let Some(mut journal) = reloaded.journal else {
+        journals.remove(&origin);
+        return;
+    };
+    if let Some(diff) = reloaded.diff {
+        if let Err(e) = journal.append(diff) {
+            log::error!("Failed to journal change of zone {origin}: {e:#}");
+        }
+    }
+    journals.insert(origin, journal);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use clap::Parser;
+
+    use cli::AclRule;
+    use mycelnet_dns_protocol::DnsClass;
+    use mycelnet_dns_zone::master;
+
+    use crate::secondary::Secondary;
+
+    use super::*;
+
+    /// Directory of the configuration and zone files of a test, removed when dropped
+    struct Files(PathBuf);
+
+    impl Files {
+        fn new(test: &str) -> Result<Files> {
+            let directory =
+                std::env::temp_dir().join(format!("reload-{}-{test}", std::process::id()));
+            std::fs::create_dir_all(&directory)?;
+            Ok(Files(directory))
+        }
+
+        /// Write the configuration file serving the zones, each given by its origin and the
+        /// serial and hosts of its master file, and return the options to read it
+        fn configure(&self, zones: &[(&str, u32, &str)], allow_transfer: &str) -> Result<Args> {
+            let mut entries = Vec::new();
+            for (origin, serial, hosts) in zones {
+                let text = format!(
+                    "$TTL 300\n@ SOA ns1 hostmaster {serial} 3600 900 604800 300\n@ NS ns1\nns1 A 192.0.2.53\n{hosts}"
+                );
+                std::fs::write(self.0.join(format!("{origin}.zone")), text)?;
+                entries.push(format!("\"{origin}={origin}.zone\""));
+            }
+            let config = self.0.join("config.toml");
+            std::fs::write(
+                &config,
+                format!(
+                    "zone = [{}]\nallow-transfer = [\"{allow_transfer}\"]\n",
+                    entries.join(", ")
+                ),
+            )?;
+
+            Ok(Args::parse_from([
+                Path::new("dns-iterate"),
+                Path::new("--config"),
+                &config,
+            ]))
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn serial(context: &Context, origin: &str) -> Result<Option<u32>> {
+        context
+            .zones
+            .get(&origin.parse()?)
+            .map(|zone| zone.serial())
+            .transpose()
+    }
+
+    fn last_serial(context: &Context, origin: &str) -> Result<Option<Option<u32>>> {
+        let journals = context.journals.lock().unwrap();
+        Ok(journals.get(&origin.parse()?).map(Journal::last_serial))
+    }
+
+    #[test]
+    fn keep_everything_on_failures() -> Result<()> {
+        let files = Files::new("failures")?;
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let args = files.configure(
+            &[("a.test", 1, "www A 192.0.2.1\n"), ("b.test", 1, "")],
+            "127.0.0.1",
+        )?;
+        reload(&context, &args)?;
+
+        let args = files.configure(
+            &[
+                ("a.test", 2, "www A 192.0.2.2\n"),
+                ("b.test", 2, "www A not-an-address\n"),
+            ],
+            "192.0.2.0/24",
+        )?;
+        assert!(reload(&context, &args).is_err());
+
+        assert_eq!(serial(&context, "a.test")?, Some(1));
+        assert_eq!(serial(&context, "b.test")?, Some(1));
+        assert_eq!(last_serial(&context, "a.test")?, Some(None));
+        assert_eq!(
+            context.policy().transfer_acl,
+            vec!["127.0.0.1".parse::<AclRule>()?]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_secondary_zones() -> Result<()> {
+        let files = Files::new("secondaries")?;
+        let origin: DnsName = "secondary.test".parse()?;
+        let secondary = Secondary::new(origin.clone(), "192.0.2.53:53".parse()?, None);
+        let context = Context::for_tests(&Settings::default(), vec![Arc::new(secondary)]);
+        let mut transferred = Zone::new(origin.clone(), DnsClass::IN);
+        let text = "$TTL 300\n@ SOA ns1 hostmaster 7 3600 900 604800 300\n";
+        for entry in master::parse_str(text, &origin)? {
+            transferred.insert(entry.record)?;
+        }
+        context.zones.insert(Arc::new(transferred));
+        context
+            .journals
+            .lock()
+            .unwrap()
+            .insert(origin.clone(), Journal::new(10));
+
+        let args = files.configure(&[("a.test", 1, "")], "127.0.0.1")?;
+        reload(&context, &args)?;
+        assert_eq!(serial(&context, "secondary.test")?, Some(7));
+        assert_eq!(last_serial(&context, "secondary.test")?, Some(None));
+        assert_eq!(serial(&context, "a.test")?, Some(1));
+
+        // A master file cannot take over a secondary zone
+        let args = files.configure(&[("secondary.test", 8, "")], "127.0.0.1")?;
+        assert!(reload(&context, &args).is_err());
+        assert_eq!(serial(&context, "secondary.test")?, Some(7));
+        assert_eq!(serial(&context, "a.test")?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_new_serials() -> Result<()> {
+        let files = Files::new("serials")?;
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let args = files.configure(&[("a.test", 1, "www A 192.0.2.1\n")], "127.0.0.1")?;
+        reload(&context, &args)?;
+
+        let args = files.configure(&[("a.test", 2, "www A 192.0.2.2\n")], "127.0.0.1")?;
+        let zone_file = &args.settings()?.zones[0];
+        let reloaded = {
+            let (zone, journal) = load(zone_file, args.journal_size)?;
+            prepare(&context, zone, journal)?
+        };
+        assert!(reloaded.notify);
+        let diff = reloaded.diff.as_ref().unwrap();
+        assert_eq!((diff.from_serial()?, diff.to_serial()?), (1, 2));
+
+        reload(&context, &args)?;
+        assert_eq!(serial(&context, "a.test")?, Some(2));
+        assert_eq!(last_serial(&context, "a.test")?, Some(Some(2)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_changes_without_new_serials() -> Result<()> {
+        let files = Files::new("unchanged-serials")?;
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let args = files.configure(&[("a.test", 1, "www A 192.0.2.1\n")], "127.0.0.1")?;
+        reload(&context, &args)?;
+
+        let args = files.configure(&[("a.test", 1, "www A 192.0.2.2\n")], "127.0.0.1")?;
+        let zone_file = &args.settings()?.zones[0];
+        let reloaded = {
+            let (zone, journal) = load(zone_file, args.journal_size)?;
+            prepare(&context, zone, journal)?
+        };
+        assert!(!reloaded.notify);
+        assert!(reloaded.diff.is_none());
+
+        reload(&context, &args)?;
+        assert_eq!(last_serial(&context, "a.test")?, Some(None));
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_removed_zones() -> Result<()> {
+        let files = Files::new("removed")?;
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let args = files.configure(&[("a.test", 1, ""), ("b.test", 1, "")], "127.0.0.1")?;
+        reload(&context, &args)?;
+        assert_eq!(last_serial(&context, "b.test")?, Some(None));
+
+        let args = files.configure(&[("a.test", 1, "")], "127.0.0.1")?;
+        reload(&context, &args)?;
+        assert_eq!(serial(&context, "b.test")?, None);
+        assert_eq!(last_serial(&context, "b.test")?, None);
+        assert_eq!(serial(&context, "a.test")?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_after_failed_updates() -> Result<()> {
+        let files = Files::new("poisoned")?;
+        let context = Context::for_tests(&Settings::default(), Vec::new());
+        let args = files.configure(&[("a.test", 1, "")], "127.0.0.1")?;
+        reload(&context, &args)?;
+
+        let failed = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = context.lock_updates();
+                    panic!("update failed");
+                })
+                .join()
+        });
+        assert!(failed.is_err());
+        assert!(context.update_lock.is_poisoned());
+
+        let args = files.configure(&[("a.test", 2, "")], "127.0.0.1")?;
+        reload(&context, &args)?;
+        assert_eq!(serial(&context, "a.test")?, Some(2));
+        reload_zone(&context, &args.settings()?.zones[0], args.journal_size)?;
+
+        Ok(())
+    }
+}
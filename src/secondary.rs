@@ -0,0 +1,731 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context as _, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    select,
+    sync::Notify,
+    time::{sleep, timeout},
+};
+
+use mycelnet_dns_protocol::{
+    tsig::{TsigKey, TsigSigner, TsigVerifier},
+    view::{DnsMessageRef, DnsSection},
+    DnsClass, DnsHeader, DnsName, DnsPacketData, DnsQType, DnsQuestion, DnsRcode, DnsRequest,
+    DnsResourceRecord,
+};
+use mycelnet_dns_zone::{
+    journal::Diff,
+    rdata::{serial_newer, Soa},
+    Zone,
+};
+
+use crate::{auth, Context};
+
+/// Time between attempts to transfer a zone until its SOA record gives the retry interval
+const INITIAL_RETRY: Duration = Duration::from_secs(30);
+
+/// Time to wait for an answer to a SOA query and for each message of a transfer
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A zone transferred from a primary
+#[derive(Debug)]
+pub struct Secondary {
+    pub origin: DnsName,
+    pub primary: SocketAddr,
+    /// Key requests to the primary are signed with and its answers verified with
+    pub key: Option<TsigKey>,
+    /// Wakes the secondary to refresh the zone before its refresh timer runs out
+    pub refresh: Notify,
+}
+
+impl Secondary {
+    pub fn new(origin: DnsName, primary: SocketAddr, key: Option<TsigKey>) -> Secondary {
+        Secondary {
+            origin,
+            primary,
+            key,
+            refresh: Notify::new(),
+        }
+    }
+}
+
+/// Keep a secondary zone in sync with its primary following the timers of its SOA record,
+/// RFC 1034 section 4.3.5. The last transferred copy is served until it expires.
+pub async fn maintain(context: Arc<Context>, secondary: Arc<Secondary>) {
+    let mut zone: Option<Arc<Zone>> = None;
+    let mut refreshed = Instant::now();
+
+    loop {
+        let wait = match refresh(&secondary, zone.as_deref()).await {
+            Ok(update) => {
+                refreshed = Instant::now();
+                if let Some((updated, diffs)) = update {
+                    log::info!(
+                        "Transferred zone {} with serial {} from {}",
+                        secondary.origin,
+                        updated.serial().unwrap_or_default(),
+                        secondary.primary
+                    );
+                    zone = Some(context.update_zone(updated, diffs));
+                }
+                zone.as_ref()
+                    .and_then(|zone| zone.soa().ok())
+                    .map(|soa| Duration::from_secs(soa.refresh as u64))
+                    .unwrap_or(INITIAL_RETRY)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to refresh zone {} from {}: {e:#}",
+                    secondary.origin,
+                    secondary.primary
+                );
+                let soa = zone.as_ref().and_then(|zone| zone.soa().ok());
+                match failed(soa.as_ref(), refreshed.elapsed()) {
+                    Failure::Retry(wait) => wait,
+                    Failure::Expire => {
+                        log::error!(
+                            "Zone {} expired without reaching {}",
+                            secondary.origin,
+                            secondary.primary
+                        );
+                        context.remove_zone(&secondary.origin);
+                        zone = None;
+                        INITIAL_RETRY
+                    }
+                }
+            }
+        };
+
+        select! {
+            _ = sleep(wait.max(Duration::from_secs(1))) => {}
+            _ = secondary.refresh.notified() => {
+                log::debug!("Refreshing zone {} on NOTIFY", secondary.origin);
+            }
+        }
+    }
+}
+
+/// What a secondary does after failing to refresh its zone
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// Try again after the time
+    Retry(Duration),
+    /// Stop serving the zone, the primary was out of reach for longer than it may be served
+    Expire,
+}
+
+/// Follow the retry and expire timers of the SOA record of the zone served, if any, after a
+/// failed refresh with the time elapsed since the last successful one
+fn failed(soa: Option<&Soa>, elapsed: Duration) -> Failure {
+    let Some(soa) = soa else {
+        return Failure::Retry(INITIAL_RETRY);
+    };
+    let expire = Duration::from_secs(soa.expire as u64);
+    if elapsed >= expire {
+        return Failure::Expire;
+    }
+
+    // Check again in time to stop serving the zone when it expires
+    Failure::Retry(Duration::from_secs(soa.retry as u64).min(expire.saturating_sub(elapsed)))
+}
+
+/// Transfer a new version of the zone if the primary has one, along with the changes made to
+/// the current version
+async fn refresh(
+    secondary: &Secondary,
+    current: Option<&Zone>,
+) -> Result<Option<(Zone, Vec<Diff>)>> {
+    let Some(current) = current else {
+        let zone = match transfer(secondary, None).await? {
+            Transfer::Full(records) => full_zone(secondary, records)?,
+            _ => Err(anyhow!(
+                "Primary answered AXFR with an incremental transfer"
+            ))?,
+        };
+        return Ok(Some((zone, Vec::new())));
+    };
+
+    let serial = query_serial(secondary).await?;
+    let current_serial = current.serial()?;
+    if !serial_newer(serial, current_serial) {
+        log::debug!(
+            "Zone {} with serial {current_serial} is up to date",
+            secondary.origin
+        );
+        return Ok(None);
+    }
+
+    match transfer(secondary, Some(current)).await? {
+        Transfer::UpToDate => Ok(None),
+        Transfer::Full(records) => {
+            let zone = full_zone(secondary, records)?;
+            let diff = Diff::between(current, &zone)?;
+            Ok(Some((zone, vec![diff])))
+        }
+        Transfer::Incremental(diffs) => {
+            let mut zone = current.clone();
+            for diff in &diffs {
+                zone.apply(diff)?;
+            }
+            Ok(Some((zone, diffs)))
+        }
+    }
+}
+
+fn full_zone(secondary: &Secondary, records: Vec<DnsResourceRecord>) -> Result<Zone> {
+    let mut zone = Zone::new(secondary.origin.clone(), DnsClass::IN);
+    for record in records {
+        zone.insert(record)?;
+    }
+    zone.soa()?;
+
+    Ok(zone)
+}
+
+/// Request to send to the primary, signed if the secondary has a key, and the verifier for the
+/// answer
+fn request(
+    secondary: &Secondary,
+    qtype: DnsQType,
+    authority: Option<DnsResourceRecord>,
+) -> Result<(u16, Vec<u8>, Option<TsigVerifier>)> {
+    let id = rand::random::<u16>();
+    let request = DnsRequest {
+        header: DnsHeader {
+            id,
+            qdcount: 1,
+            ..DnsHeader::default()
+        },
+        question: DnsQuestion {
+            qname: secondary.origin.clone(),
+            qtype,
+            qclass: DnsClass::IN,
+        },
+        authority: authority.map(|record| vec![record]),
+        ..DnsRequest::default()
+    };
+
+    let data = request.to_bytes()?;
+
+    match &secondary.key {
+        Some(key) => {
+            let mut signer = TsigSigner::new(key.clone(), None);
+            let data = signer.sign(&data, auth::unix_time())?;
+            let verifier = TsigVerifier::new(key.clone(), signer.mac().map(<[u8]>::to_vec));
+            Ok((id, data, Some(verifier)))
+        }
+        None => Ok((id, data, None)),
+    }
+}
+
+/// Check a message from the primary answers the request and carries no error
+fn check_response(
+    secondary: &Secondary,
+    data: &[u8],
+    id: u16,
+    verifier: Option<&mut TsigVerifier>,
+) -> Result<bool> {
+    let signed = match verifier {
+        Some(verifier) => verifier
+            .verify(data, auth::unix_time())
+            .map_err(|e| anyhow!("Failed to verify answer of {}: {e}", secondary.primary))?
+            .is_some(),
+        None => false,
+    };
+
+    let header = DnsMessageRef::new(data)?.header().clone();
+    if header.id != id || header.flags.qr != 1 {
+        Err(anyhow!(
+            "Answer of {} does not match the request",
+            secondary.primary
+        ))?;
+    }
+    if header.flags.rcode != DnsRcode::NoError {
+        Err(anyhow!(
+            "Primary {} answered with {:?}",
+            secondary.primary,
+            header.flags.rcode
+        ))?;
+    }
+
+    Ok(signed)
+}
+
+/// Serial of the zone at the primary
+async fn query_serial(secondary: &Secondary) -> Result<u32> {
+    let (id, request, mut verifier) = request(secondary, DnsQType::SOA, None)?;
+
+    let local: SocketAddr = match secondary.primary {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(secondary.primary).await?;
+    socket.send(&request).await?;
+
+    // Ignore datagrams that do not answer our query
+    let mut buf = vec![0; 65535];
+    let data = timeout(PRIMARY_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return Ok::<_, anyhow::Error>(&buf[..len]);
+            }
+        }
+    })
+    .await
+    .with_context(|| format!("Timed out waiting for primary {}", secondary.primary))??;
+
+    check_response(secondary, data, id, verifier.as_mut())?;
+
+    for record in DnsMessageRef::new(data)?.records() {
+        let record = record?;
+        if record.rtype() == DnsQType::SOA && record.name().to_name()? == secondary.origin {
+            return Ok(Soa::from_rdata(&record.to_record()?.rdata)?.serial);
+        }
+    }
+
+    Err(anyhow!(
+        "Primary {} did not answer the SOA query for {}",
+        secondary.primary,
+        secondary.origin
+    ))
+}
+
+/// Request the zone over TCP, incrementally from the current version if there is one
+async fn transfer(secondary: &Secondary, current: Option<&Zone>) -> Result<Transfer> {
+    let (qtype, authority, serial) = match current {
+        Some(zone) => (
+            DnsQType::IXFR,
+            Some(zone.soa_record()?),
+            Some(zone.serial()?),
+        ),
+        None => (DnsQType::AXFR, None, None),
+    };
+    let (id, request, mut verifier) = request(secondary, qtype, authority)?;
+
+    let mut stream = timeout(PRIMARY_TIMEOUT, TcpStream::connect(secondary.primary))
+        .await
+        .with_context(|| format!("Timed out connecting to primary {}", secondary.primary))??;
+    stream
+        .write_all(&(request.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&request).await?;
+
+    let mut reader = TransferReader::new(serial);
+    loop {
+        let data = timeout(PRIMARY_TIMEOUT, async {
+            let len = stream.read_u16().await?;
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data).await?;
+            Ok::<_, anyhow::Error>(data)
+        })
+        .await
+        .with_context(|| format!("Timed out waiting for primary {}", secondary.primary))??;
+
+        let signed = check_response(secondary, &data, id, verifier.as_mut())?;
+
+        for record in DnsMessageRef::new(&data)?.records() {
+            let record = record?;
+            if record.section() == DnsSection::Answer {
+                reader.push(record.to_record()?)?;
+            }
+        }
+
+        if reader.is_done() {
+            // Intermediate messages may be unsigned but the last one must be signed,
+            // RFC 8945 section 5.3.1
+            if verifier.is_some() && !signed {
+                Err(anyhow!(
+                    "Last message of the transfer from {} is not signed",
+                    secondary.primary
+                ))?;
+            }
+            return reader.finish();
+        }
+    }
+}
+
+/// What a primary sent in answer to a transfer request
+#[derive(Debug)]
+enum Transfer {
+    /// The secondary already has the latest version
+    UpToDate,
+    /// Every record of the zone starting with its SOA record
+    Full(Vec<DnsResourceRecord>),
+    /// Changes from the version of the secondary to the latest one
+    Incremental(Vec<Diff>),
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum ReaderState {
+    #[default]
+    Start,
+    /// The SOA record of the version the secondary has, alone as it ends the transfer
+    UpToDate,
+    /// Only the SOA record of the new version has been read, the next record tells full
+    /// transfers from incremental ones
+    First,
+    Full,
+    /// Reading records a diff removes, up to the SOA record of the version it leads to
+    Removing,
+    /// Reading records a diff adds, up to the SOA record starting the next diff
+    Adding,
+    Done,
+}
+
+/// Collects the records of a transfer stream and tells full transfers from incremental ones,
+/// RFC 1995 section 4
+#[derive(Debug, Default)]
+struct TransferReader {
+    state: ReaderState,
+    /// Serial of the version an incremental transfer was requested from
+    current: Option<u32>,
+    serial: u32,
+    records: Vec<DnsResourceRecord>,
+    diffs: Vec<Diff>,
+    /// Records of the diff being read and the serial it leads to
+    diff: Vec<DnsResourceRecord>,
+    diff_serial: u32,
+}
+
+impl TransferReader {
+    fn new(current: Option<u32>) -> TransferReader {
+        TransferReader {
+            current,
+            ..TransferReader::default()
+        }
+    }
+
+    fn push(&mut self, record: DnsResourceRecord) -> Result<()> {
+        let serial = match record.rtype {
+            DnsQType::SOA => Some(Soa::from_rdata(&record.rdata)?.serial),
+            _ => None,
+        };
+
+        match (&self.state, serial) {
+            // A single SOA record no newer than the version of the secondary, RFC 1995 section 2
+            (ReaderState::Start, Some(serial))
+                if self
+                    .current
+                    .is_some_and(|current| !serial_newer(serial, current)) =>
+            {
+                self.state = ReaderState::UpToDate;
+            }
+            (ReaderState::Start, Some(serial)) => {
+                self.serial = serial;
+                self.records.push(record);
+                self.state = ReaderState::First;
+            }
+            (ReaderState::Start, None) => Err(anyhow!("Transfer does not start with an SOA"))?,
+            // A zone made of its SOA record alone
+            (ReaderState::First, Some(serial)) if serial == self.serial => {
+                self.state = ReaderState::Done;
+            }
+            (ReaderState::First, Some(_)) => {
+                self.diff.push(record);
+                self.state = ReaderState::Removing;
+            }
+            (ReaderState::First | ReaderState::Full, None) => {
+                self.records.push(record);
+                self.state = ReaderState::Full;
+            }
+            (ReaderState::Full, Some(_)) => self.state = ReaderState::Done,
+            (ReaderState::Removing, Some(serial)) => {
+                self.diff.push(record);
+                self.diff_serial = serial;
+                self.state = ReaderState::Adding;
+            }
+            (ReaderState::Adding, Some(serial)) => {
+                self.diffs
+                    .push(Diff::from_records(std::mem::take(&mut self.diff))?);
+                if self.diff_serial == self.serial {
+                    self.state = ReaderState::Done;
+                } else if serial != self.diff_serial {
+                    Err(anyhow!(
+                        "Diff from serial {serial} does not follow serial {}",
+                        self.diff_serial
+                    ))?;
+                } else {
+                    self.diff.push(record);
+                    self.state = ReaderState::Removing;
+                }
+            }
+            (ReaderState::Removing | ReaderState::Adding, None) => self.diff.push(record),
+            (ReaderState::UpToDate | ReaderState::Done, _) => {
+                Err(anyhow!("Records follow the end of the transfer"))?
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, ReaderState::UpToDate | ReaderState::Done)
+    }
+
+    fn finish(self) -> Result<Transfer> {
+        match self.state {
+            ReaderState::UpToDate => Ok(Transfer::UpToDate),
+            ReaderState::Done if self.diffs.is_empty() => Ok(Transfer::Full(self.records)),
+            ReaderState::Done => Ok(Transfer::Incremental(self.diffs)),
+            _ => Err(anyhow!("Transfer ended before its final SOA record")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use cli::Settings;
+    use mycelnet_dns_protocol::message::DnsMessage;
+    use mycelnet_dns_zone::{journal::Journal, master};
+
+    use crate::{create_response, transfer, Transport};
+
+    use super::*;
+
+    fn zone(serial: u32, hosts: &str) -> Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let text = format!(
+            "$TTL 300\n@ SOA ns1 hostmaster {serial} 3600 900 604800 300\n@ NS ns1\n{hosts}"
+        );
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in master::parse_str(&text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+        Ok(zone)
+    }
+
+    fn read(messages: Vec<DnsMessage>) -> Result<Transfer> {
+        let mut reader = TransferReader::default();
+        for message in messages {
+            for record in message.build()?.answers.unwrap_or_default() {
+                reader.push(record)?;
+            }
+        }
+        reader.finish()
+    }
+
+    #[test]
+    fn read_transfers() -> Result<()> {
+        let versions = [
+            zone(1, "www A 192.0.2.1\n")?,
+            zone(2, "www A 192.0.2.2\n")?,
+            zone(3, "www A 192.0.2.2\nftp A 192.0.2.3\n")?,
+        ];
+        let mut journal = Journal::new(10);
+        journal.append(Diff::between(&versions[0], &versions[1])?)?;
+        journal.append(Diff::between(&versions[1], &versions[2])?)?;
+
+        let mut request = DnsRequest::default();
+        request.question.qname = versions[2].origin().clone();
+
+        let Transfer::Full(records) = read(transfer::axfr(&versions[2], &request)?)? else {
+            panic!("AXFR not read as a full transfer");
+        };
+        assert_eq!(records.len(), versions[2].len());
+
+        let incremental = transfer::ixfr(&versions[2], Some(&journal), &request, 1)?;
+        let Transfer::Incremental(diffs) = read(incremental)? else {
+            panic!("IXFR not read as an incremental transfer");
+        };
+        assert_eq!(diffs.len(), 2);
+        let mut zone = versions[0].clone();
+        for diff in &diffs {
+            zone.apply(diff)?;
+        }
+        assert_eq!(zone, versions[2]);
+
+        // Transfers cut short are incomplete
+        let mut reader = TransferReader::new(Some(1));
+        reader.push(versions[2].soa_record()?)?;
+        assert!(!reader.is_done());
+        reader.push(versions[0].soa_record()?)?;
+        assert!(reader.finish().is_err());
+
+        Ok(())
+    }
+
+    /// Primary answering each connection with the next of the streams, one message per list of
+    /// records
+    async fn primary(streams: Vec<Vec<Vec<DnsResourceRecord>>>) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            for messages in streams {
+                let (mut stream, _) = listener.accept().await?;
+                let len = stream.read_u16().await?;
+                let mut data = vec![0; len as usize];
+                stream.read_exact(&mut data).await?;
+                let request = DnsRequest::from_bytes(&data, 0)?;
+                for records in messages {
+                    let response = DnsMessage::response_to(&request)
+                        .authoritative(true)
+                        .answers(records)
+                        .build()?
+                        .to_bytes()?;
+                    stream
+                        .write_all(&(response.len() as u16).to_be_bytes())
+                        .await?;
+                    stream.write_all(&response).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn read_transfers_across_messages() -> Result<()> {
+        let versions = [zone(1, "www A 192.0.2.1\n")?, zone(2, "www A 192.0.2.2\n")?];
+        let mut journal = Journal::new(10);
+        journal.append(Diff::between(&versions[0], &versions[1])?)?;
+        let mut request = DnsRequest::default();
+        request.question.qname = versions[1].origin().clone();
+        let records = |messages: Vec<DnsMessage>| -> Result<Vec<DnsResourceRecord>> {
+            let mut records = Vec::new();
+            for message in messages {
+                records.extend(message.build()?.answers.unwrap_or_default());
+            }
+            Ok(records)
+        };
+
+        // The first message of each stream holds only the SOA record of the new version
+        let mut incremental = records(transfer::ixfr(&versions[1], Some(&journal), &request, 1)?)?;
+        let mut full = records(transfer::axfr(&versions[1], &request)?)?;
+        let streams = vec![
+            vec![vec![incremental.remove(0)], incremental],
+            vec![vec![full.remove(0)], full],
+            vec![vec![versions[1].soa_record()?]],
+        ];
+        let secondary = Secondary::new(versions[1].origin().clone(), primary(streams).await?, None);
+
+        let Transfer::Incremental(diffs) = transfer(&secondary, Some(&versions[0])).await? else {
+            panic!("IXFR not read as an incremental transfer");
+        };
+        let mut zone = versions[0].clone();
+        zone.apply(&diffs[0])?;
+        assert_eq!(zone, versions[1]);
+
+        let Transfer::Full(records) = transfer(&secondary, Some(&versions[0])).await? else {
+            panic!("IXFR answered with the whole zone not read as a full transfer");
+        };
+        assert_eq!(records.len(), versions[1].len());
+
+        let transferred = transfer(&secondary, Some(&versions[1])).await?;
+        assert!(matches!(transferred, Transfer::UpToDate));
+
+        Ok(())
+    }
+
+    #[test]
+    fn follow_soa_timers() -> Result<()> {
+        let soa = zone(1, "")?.soa()?;
+        let retry = Duration::from_secs(soa.retry as u64);
+        let expire = Duration::from_secs(soa.expire as u64);
+
+        // Zones never transferred are requested again soon
+        assert_eq!(failed(None, Duration::ZERO), Failure::Retry(INITIAL_RETRY));
+
+        assert_eq!(failed(Some(&soa), Duration::ZERO), Failure::Retry(retry));
+        // The last retry comes when the zone expires
+        let elapsed = expire - Duration::from_secs(10);
+        assert_eq!(
+            failed(Some(&soa), elapsed),
+            Failure::Retry(Duration::from_secs(10))
+        );
+        assert_eq!(failed(Some(&soa), expire), Failure::Expire);
+        assert_eq!(
+            failed(Some(&soa), expire + Duration::from_secs(1)),
+            Failure::Expire
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expire_zones_out_of_reach() -> Result<()> {
+        // Refreshed and retried every second, served for three seconds without the primary
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let mut served = Zone::new(origin.clone(), DnsClass::IN);
+        let text = "$TTL 300\n@ SOA ns1 hostmaster 1 1 1 3 300\n@ NS ns1\nns1 A 192.0.2.53\n";
+        for entry in master::parse_str(text, &origin)? {
+            served.insert(entry.record)?;
+        }
+
+        let settings = Settings {
+            allow_transfer: vec!["127.0.0.1".parse()?],
+            ..Settings::default()
+        };
+        let primary_context = Context::for_tests(&settings, Vec::new());
+        primary_context.zones.insert(Arc::new(served));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let primary = listener.local_addr()?;
+        let primary_task = tokio::spawn(async move {
+            while let Ok((mut stream, addr)) = listener.accept().await {
+                let len = stream.read_u16().await?;
+                let mut data = vec![0; len as usize];
+                stream.read_exact(&mut data).await?;
+                for response in
+                    create_response(&primary_context, &data, addr, Transport::Tcp).await?
+                {
+                    stream
+                        .write_all(&(response.len() as u16).to_be_bytes())
+                        .await?;
+                    stream.write_all(&response).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        // The primary refuses SOA queries once it stops serving transfers
+        let socket = UdpSocket::bind(primary).await?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            while let Ok((len, addr)) = socket.recv_from(&mut buf).await {
+                buf[2] |= 0x80;
+                buf[3] = (buf[3] & 0xf0) | DnsRcode::Refused.to_u8();
+                socket.send_to(&buf[..len], addr).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let secondary = Arc::new(Secondary::new(origin.clone(), primary, None));
+        let context = Arc::new(Context::for_tests(
+            &Settings::default(),
+            vec![secondary.clone()],
+        ));
+        let maintain_task = tokio::spawn(maintain(context.clone(), secondary));
+
+        timeout(Duration::from_secs(5), async {
+            while context.zones.get(&origin).is_none() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let transferred = Instant::now();
+        primary_task.abort();
+
+        // Refreshes fail from now on but the zone is served until it expires
+        sleep(Duration::from_millis(1500)).await;
+        assert!(context.zones.get(&origin).is_some());
+
+        timeout(Duration::from_secs(5), async {
+            while context.zones.get(&origin).is_some() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        // Counted from the transfer, which the loop above saw up to a poll late
+        assert!(transferred.elapsed() >= Duration::from_millis(2900));
+        maintain_task.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+
+use cli::AclRule;
+use mycelnet_dns_protocol::{
+    message::DnsMessage,
+    view::{DnsMessageRef, DnsSection},
+    DnsQType, DnsRequest, DnsResourceRecord,
+};
+use mycelnet_dns_zone::{
+    backend::ZoneBackend,
+    journal::{Diff, Journal},
+    rdata::{serial_newer, Soa},
+};
+
+use crate::auth::Authentication;
+
+/// Size the messages of a zone transfer are filled to, well below the TCP limit so the OPT and
+/// TSIG records added afterwards always fit
+const TRANSFER_MESSAGE_SIZE: usize = 16384;
+
+/// Whether a client matches an access control list, by address or by the key it signed the
+/// request with
+pub fn allowed(acl: &[AclRule], addr: IpAddr, authentication: &Authentication) -> bool {
+    let key = match authentication {
+        Authentication::Signed { key, .. } => Some(&key.name),
+        _ => None,
+    };
+
+    acl.iter().any(|rule| rule.matches(addr, key))
+}
+
+/// Messages of a full zone transfer, RFC 5936 section 2.2. The SOA record starts and ends the
+/// transfer and the other records are split across as many messages as needed.
+pub fn axfr(zone: &dyn ZoneBackend, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+    let soa = zone.soa_record()?;
+    let mut records = vec![soa.clone()];
+    for record in zone.records() {
+        let record = record?;
+        if record.rtype != DnsQType::SOA {
+            records.push(record);
+        }
+    }
+    records.push(soa);
+
+    Ok(split(request, records))
+}
+
+/// Serial of the zone version a client has, from the SOA record in the authority section of an
+/// IXFR request, RFC 1995 section 3
+pub fn requested_serial(data: &[u8]) -> Result<u32> {
+    let message = DnsMessageRef::new(data)?;
+    for record in message.section(DnsSection::Authority) {
+        let record = record?;
+        if record.rtype() == DnsQType::SOA {
+            return Ok(Soa::from_rdata(&record.to_record()?.rdata)?.serial);
+        }
+    }
+
+    Err(anyhow!("IXFR request has no SOA record"))
+}
+
+/// Messages of an incremental zone transfer, RFC 1995 section 4. The changes since the serial
+/// of the client are sent between two copies of the current SOA record when the journal covers
+/// that serial, the whole zone otherwise.
+pub fn ixfr(
+    zone: &dyn ZoneBackend,
+    journal: Option<&Journal>,
+    request: &DnsRequest,
+    serial: u32,
+) -> Result<Vec<DnsMessage>> {
+    let current = zone.serial()?;
+    if !serial_newer(current, serial) {
+        return soa(zone, request);
+    }
+
+    let diffs: Option<Vec<&Diff>> = journal
+        .and_then(|journal| journal.since(serial))
+        .map(Iterator::collect);
+    match diffs {
+        Some(diffs) if diffs.last().and_then(|diff| diff.to_serial().ok()) == Some(current) => {
+            let soa = zone.soa_record()?;
+            let records = std::iter::once(soa.clone())
+                .chain(diffs.into_iter().flat_map(Diff::records))
+                .chain(std::iter::once(soa));
+
+            Ok(split(request, records))
+        }
+        _ => {
+            log::debug!(
+                "Journal of zone {} does not cover serial {serial}, transferring the whole zone",
+                zone.origin()
+            );
+            axfr(zone, request)
+        }
+    }
+}
+
+/// Messages answering a transfer request, the whole zone without a serial from the client.
+/// Datagrams only tell the client the current version, RFC 1995 section 2.
+pub fn respond(
+    zone: &dyn ZoneBackend,
+    journal: Option<&Journal>,
+    request: &DnsRequest,
+    serial: Option<u32>,
+    udp: bool,
+) -> Result<Vec<DnsMessage>> {
+    match serial {
+        Some(_) if udp => soa(zone, request),
+        Some(serial) => ixfr(zone, journal, request, serial),
+        None => axfr(zone, request),
+    }
+}
+
+/// Single message with the SOA record of the zone, the answer to IXFR requests from clients
+/// that are up to date or that have to retry over TCP, RFC 1995 section 2
+pub fn soa(zone: &dyn ZoneBackend, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+    Ok(split(request, [zone.soa_record()?]))
+}
+
+/// Fill messages answering a transfer request with records in order
+pub fn split(
+    request: &DnsRequest,
+    records: impl IntoIterator<Item = DnsResourceRecord>,
+) -> Vec<DnsMessage> {
+    let header_size = 12 + request.question.qname.length() + 4;
+
+    let mut messages = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = header_size;
+    for record in records {
+        if !chunk.is_empty() && size + record.size() > TRANSFER_MESSAGE_SIZE {
+            messages.push(std::mem::take(&mut chunk));
+            size = header_size;
+        }
+        size += record.size();
+        chunk.push(record);
+    }
+    messages.push(chunk);
+
+    messages
+        .into_iter()
+        .map(|answers| {
+            DnsMessage::response_to(request)
+                .authoritative(true)
+                .answers(answers)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mycelnet_dns_protocol::{
+        tsig::{TsigKey, TsigRecord},
+        DnsClass, DnsName, DnsPacketData, DnsRcode,
+    };
+    use mycelnet_dns_zone::{master, Zone};
+
+    use super::*;
+
+    fn zone(hosts: usize) -> Result<Zone> {
+        let origin: DnsName = "mycelnet.tech.".parse()?;
+        let mut text =
+            "$TTL 300\n@ SOA ns1 hostmaster 7 3600 900 604800 300\n@ NS ns1\n".to_string();
+        for host in 0..hosts {
+            text.push_str(&format!("host{host} TXT \"{}\"\n", "x".repeat(200)));
+        }
+
+        let mut zone = Zone::new(origin.clone(), DnsClass::IN);
+        for entry in master::parse_str(&text, &origin)? {
+            zone.insert(entry.record)?;
+        }
+        Ok(zone)
+    }
+
+    fn request(zone: &Zone) -> DnsRequest {
+        let mut request = DnsRequest::default();
+        request.question.qname = zone.origin().clone();
+        request.question.qtype = DnsQType::AXFR;
+        request
+    }
+
+    #[test]
+    fn transfer_zone_in_messages() -> Result<()> {
+        let zone = zone(200)?;
+        let messages = axfr(&zone, &request(&zone))?
+            .into_iter()
+            .map(DnsMessage::build)
+            .collect::<Result<Vec<_>>>()?;
+        assert!(messages.len() > 1);
+
+        let records: Vec<DnsResourceRecord> = messages
+            .iter()
+            .flat_map(|message| message.answers.clone().unwrap_or_default())
+            .collect();
+        assert_eq!(records.len(), zone.len() + 1);
+        assert_eq!(records.first().unwrap().rtype, DnsQType::SOA);
+        assert_eq!(records.last().unwrap().rtype, DnsQType::SOA);
+        for message in &messages {
+            assert_eq!(message.header.flags.aa, 1);
+            assert!(message.to_bytes()?.len() <= TRANSFER_MESSAGE_SIZE);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_zone_changes() -> Result<()> {
+        let old = zone(2)?;
+        let mut new = old.clone();
+        new.remove_node(&"host0.mycelnet.tech".parse()?);
+        let mut soa = new.soa()?;
+        soa.serial = 8;
+        let mut soa_record = new.soa_record()?;
+        soa_record.rdata = soa.to_rdata()?;
+        new.remove_rrset(&soa_record.name.clone(), DnsQType::SOA);
+        new.insert(soa_record)?;
+
+        let mut journal = Journal::new(10);
+        journal.append(Diff::between(&old, &new)?)?;
+
+        let mut request = request(&new);
+        request.question.qtype = DnsQType::IXFR;
+        let data = DnsMessage::response_to(&request)
+            .authority(old.soa_record()?)
+            .build()?
+            .to_bytes()?;
+        assert_eq!(requested_serial(&data)?, 7);
+
+        let serials = |messages: Vec<DnsMessage>| -> Result<Vec<Option<u32>>> {
+            let mut serials = Vec::new();
+            for message in messages {
+                for record in message.build()?.answers.unwrap_or_default() {
+                    serials.push(match record.rtype {
+                        DnsQType::SOA => Some(Soa::from_rdata(&record.rdata)?.serial),
+                        _ => None,
+                    });
+                }
+            }
+            Ok(serials)
+        };
+
+        let incremental = ixfr(&new, Some(&journal), &request, 7)?;
+        assert_eq!(
+            serials(incremental)?,
+            [Some(8), Some(7), None, Some(8), Some(8)]
+        );
+        assert_eq!(
+            serials(ixfr(&new, Some(&journal), &request, 8)?)?,
+            [Some(8)]
+        );
+
+        // Serials the journal does not reach back to get the whole zone
+        let full = serials(ixfr(&new, Some(&journal), &request, 3)?)?;
+        assert_eq!(full.len(), new.len() + 1);
+        assert_eq!(
+            serials(ixfr(&new, None, &request, 7)?)?.len(),
+            new.len() + 1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn restrict_transfers() -> Result<()> {
+        let acl: Vec<AclRule> = vec!["192.0.2.0/24".parse()?, "key:transfer".parse()?];
+        let key: TsigKey = "transfer:a2V5".parse()?;
+
+        assert!(allowed(
+            &acl,
+            "192.0.2.53".parse()?,
+            &Authentication::Unsigned
+        ));
+        assert!(!allowed(
+            &acl,
+            "198.51.100.1".parse()?,
+            &Authentication::Unsigned
+        ));
+        assert!(!allowed(
+            &acl,
+            "2001:db8::1".parse()?,
+            &Authentication::Unsigned
+        ));
+
+        let signed = Authentication::Signed {
+            request: TsigRecord {
+                key_name: key.name.clone(),
+                algorithm: DnsName::from_labels([key.algorithm.name()]),
+                time_signed: 0,
+                fudge: 300,
+                mac: Vec::new(),
+                original_id: 0,
+                error: DnsRcode::NoError,
+                other_data: Vec::new(),
+            },
+            key,
+        };
+        assert!(allowed(&acl, "198.51.100.1".parse()?, &signed));
+        assert!(!allowed(&[], "192.0.2.53".parse()?, &signed));
+
+        assert!("192.0.2.0/33".parse::<AclRule>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use mycelnet_dns_protocol::{
+    view::{DnsMessageRef, DnsSection},
+    DnsQType, DnsRcode, DnsRequest,
+};
+use mycelnet_dns_zone::{
+    journal::Diff,
+    update::{self, UpdateError},
+};
+
+use crate::{auth::Authentication, Context};
+
+/// Apply a dynamic update to a zone served as a primary, RFC 2136 section 3. Updates must be
+/// signed with a TSIG key allowed to change every name and type they touch, RFC 3007.
+pub fn receive(
+    context: &Context,
+    data: &[u8],
+    request: &DnsRequest,
+    addr: SocketAddr,
+    authentication: &Authentication,
+) -> Result<(), UpdateError> {
+    let origin = &request.question.qname;
+    if request.header.qdcount != 1 || request.question.qtype != DnsQType::SOA {
+        Err(UpdateError::new(
+            DnsRcode::FormatError,
+            "The zone section must hold a single SOA question",
+        ))?;
+    }
+
+    context
+        .zone(origin)
+        .filter(|zone| zone.origin() == origin)
+        .ok_or_else(|| {
+            UpdateError::new(DnsRcode::NotAuth, format!("Not authoritative for {origin}"))
+        })?;
+    if context
+        .secondaries
+        .iter()
+        .any(|secondary| &secondary.origin == origin)
+    {
+        Err(UpdateError::new(
+            DnsRcode::Refused,
+            format!("Zone {origin} is a secondary, updates go to its primary"),
+        ))?;
+    }
+
+    let format_error = |e: anyhow::Error| UpdateError::new(DnsRcode::FormatError, format!("{e:#}"));
+    let mut prerequisites = Vec::new();
+    let mut updates = Vec::new();
+    for record in DnsMessageRef::new(data).map_err(format_error)?.records() {
+        let record = record.map_err(format_error)?;
+        match record.section() {
+            DnsSection::Answer => prerequisites.push(record.to_record().map_err(format_error)?),
+            DnsSection::Authority => updates.push(record.to_record().map_err(format_error)?),
+            DnsSection::Additional => {}
+        }
+    }
+
+    let Authentication::Signed { key, .. } = authentication else {
+        Err(UpdateError::new(
+            DnsRcode::Refused,
+            "Updates must be signed with a TSIG key",
+        ))?
+    };
+    for record in &updates {
+        let allowed = context
+            .policy()
+            .update_policy
+            .iter()
+            .any(|rule| rule.matches(&key.name, &record.name, record.rtype));
+        if !allowed {
+            Err(UpdateError::new(
+                DnsRcode::Refused,
+                format!(
+                    "Key {} may not update {} records of {}",
+                    key.name, record.rtype, record.name
+                ),
+            ))?;
+        }
+    }
+
+    // Updates of all zones are applied one at a time, each to the latest version
+    let _guard = context.lock_updates();
+    let served = context
+        .zone(origin)
+        .filter(|zone| zone.origin() == origin)
+        .ok_or_else(|| {
+            UpdateError::new(DnsRcode::NotAuth, format!("Not authoritative for {origin}"))
+        })?;
+    let zone = served.zone().ok_or_else(|| {
+        UpdateError::new(
+            DnsRcode::Refused,
+            format!("Zone {origin} is served from a database, updates go to the database"),
+        )
+    })?;
+
+    match update::update(zone, &prerequisites, &updates)? {
+        Some(updated) => {
+            let diff = Diff::between(zone, &updated)
+                .map_err(|e| UpdateError::new(DnsRcode::ServerFailure, format!("{e:#}")))?;
+            log::info!(
+                "Updated zone {origin} to serial {} for {addr} with key {}, {} records removed and {} added",
+                diff.to_serial().unwrap_or_default(),
+                key.name,
+                diff.removed.len(),
+                diff.added.len()
+            );
+            context.update_zone(updated, vec![diff]);
+        }
+        None => log::debug!("Update of zone {origin} from {addr} changed nothing"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use cli::UpdateRule;
+    use mycelnet_dns_protocol::DnsName;
+
+    use super::*;
+
+    #[test]
+    fn restrict_updates() -> Result<()> {
+        let hosts: UpdateRule = "dhcp:*.hosts.mycelnet.tech.:A/AAAA".parse()?;
+        let apex: UpdateRule = "admin:mycelnet.tech.".parse()?;
+        let dhcp: DnsName = "dhcp".parse()?;
+        let admin: DnsName = "admin".parse()?;
+
+        let host: DnsName = "web.hosts.mycelnet.tech".parse()?;
+        assert!(hosts.matches(&dhcp, &host, DnsQType::A));
+        assert!(!hosts.matches(&dhcp, &host, DnsQType::MX));
+        assert!(!hosts.matches(&admin, &host, DnsQType::A));
+        assert!(!hosts.matches(&dhcp, &"hosts.mycelnet.tech".parse()?, DnsQType::A));
+
+        assert!(apex.matches(&admin, &"mycelnet.tech".parse()?, DnsQType::MX));
+        assert!(!apex.matches(&admin, &host, DnsQType::A));
+
+        assert!("dhcp".parse::<UpdateRule>().is_err());
+        assert!("dhcp:mycelnet.tech.:BOGUS".parse::<UpdateRule>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Reloading zones and the configuration when their files change, with `--watch`.
+//!
+//! The directories holding the files are watched rather than the files themselves, so files
+//! replaced at once by editors or by moving a new version in place are seen as well. Changes
+//! are collected until the files are left alone for the watch delay, then only the zones whose
+//! master files changed are read again. A change of the configuration file reloads everything
+//! the way SIGHUP does, and reloads on SIGHUP change the watched files the same way. Files read
+//! with `$INCLUDE` are not watched.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _, Result};
+use notify_debouncer_full::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, RecommendedCache,
+};
+use tokio::runtime::Handle;
+
+use cli::{Args, Settings, ZoneFile};
+
+use crate::{reload, Context};
+
+type Debouncer = notify_debouncer_full::Debouncer<RecommendedWatcher, RecommendedCache>;
+
+/// What the watch thread handles, one at a time
+enum Change {
+    Files(DebounceEventResult),
+    Reloaded(Settings),
+}
+
+/// Handle of the watch thread for telling it about reloads it did not start
+#[derive(Debug, Clone)]
+pub struct Watch {
+    changes: mpsc::Sender<Change>,
+}
+
+impl Watch {
+    /// Watch the files of settings reloaded by other means, such as SIGHUP
+    pub fn reloaded(&self, settings: Settings) {
+        // The thread only ends with the process
+        let _ = self.changes.send(Change::Reloaded(settings));
+    }
+}
+
+/// Files watched for changes by their path in a canonical directory, the form events name them
+#[derive(Debug, Default)]
+struct Watched {
+    directories: HashSet<PathBuf>,
+    config: Option<PathBuf>,
+    zones: HashMap<PathBuf, Vec<ZoneFile>>,
+}
+
+impl Watched {
+    /// Watch the configuration file and the master files of the zones in the settings, and
+    /// stop watching directories no longer holding any of them
+    fn update(
+        &mut self,
+        debouncer: &mut Debouncer,
+        args: &Args,
+        settings: &Settings,
+    ) -> Result<()> {
+        let mut directories = HashSet::new();
+        let config = match &args.config {
+            Some(path) => {
+                let (directory, file) = canonical(path)?;
+                directories.insert(directory);
+                Some(file)
+            }
+            None => None,
+        };
+        let mut zones: HashMap<PathBuf, Vec<ZoneFile>> = HashMap::new();
+        for zone_file in &settings.zones {
+            let (directory, file) = canonical(&zone_file.path)?;
+            directories.insert(directory);
+            zones.entry(file).or_default().push(zone_file.clone());
+        }
+
+        for directory in directories.difference(&self.directories) {
+            debouncer
+                .watch(directory, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {}", directory.display()))?;
+            log::debug!("Watching {} for changes", directory.display());
+        }
+        for directory in self.directories.difference(&directories) {
+            // The directory may be gone already, which ends the watch as well
+            if let Err(e) = debouncer.unwatch(directory) {
+                log::debug!("Failed to stop watching {}: {e}", directory.display());
+            }
+        }
+
+        *self = Watched {
+            directories,
+            config,
+            zones,
+        };
+        Ok(())
+    }
+
+    /// Reload what changed among the files at the paths
+    fn changed(
+        &mut self,
+        debouncer: &mut Debouncer,
+        context: &Context,
+        args: &Args,
+        paths: &HashSet<PathBuf>,
+    ) {
+        if let Some(config) = self
+            .config
+            .as_ref()
+            .filter(|config| paths.contains(*config))
+        {
+            log::info!("Configuration file {} changed", config.display());
+            match reload::reload(context, args) {
+                // Zones may have been added or removed
+                Ok(settings) => {
+                    if let Err(e) = self.update(debouncer, args, &settings) {
+                        log::error!("Failed to watch the zone files: {e:#}");
+                    }
+                }
+                Err(e) => log::error!(
+                    "Failed to reload, keeping the current configuration and zones: {e:#}"
+                ),
+            }
+            return;
+        }
+
+        for zone_file in paths
+            .iter()
+            .filter_map(|path| self.zones.get(path))
+            .flatten()
+        {
+            log::info!(
+                "Master file {} of zone {} changed",
+                zone_file.path.display(),
+                zone_file.origin
+            );
+            if let Err(e) = reload::reload_zone(context, zone_file, args.journal_size) {
+                log::error!(
+                    "Failed to reload zone {}, keeping the current version: {e:#}",
+                    zone_file.origin
+                );
+            }
+        }
+    }
+}
+
+/// Start watching the files of the settings in use and reload them on changes until the
+/// process ends
+pub fn spawn(context: Arc<Context>, args: Arc<Args>, settings: &Settings) -> Result<Watch> {
+    let (changes_tx, changes_rx) = mpsc::channel();
+    let events_tx = changes_tx.clone();
+    let mut debouncer = new_debouncer(
+        Duration::from_millis(args.watch_delay),
+        None,
+        move |events| {
+            let _ = events_tx.send(Change::Files(events));
+        },
+    )
+    .context("Failed to start watching files")?;
+    let mut watched = Watched::default();
+    watched.update(&mut debouncer, &args, settings)?;
+    log::info!(
+        "Watching {} directories for changes to the configuration and zones",
+        watched.directories.len()
+    );
+
+    // Reloading blocks on reading files, so changes are handled on a thread of their own which
+    // also keeps the watcher alive. Logs and NOTIFY messages are sent by tasks of the runtime.
+    let runtime = Handle::current();
+    thread::Builder::new()
+        .name("watch".to_string())
+        .spawn(move || {
+            let _runtime = runtime.enter();
+            for change in changes_rx {
+                match change {
+                    Change::Files(Ok(events)) => {
+                        // Reading the files when reloading them must not count as a change
+                        let paths: HashSet<_> = events
+                            .into_iter()
+                            .filter(|event| !event.event.kind.is_access())
+                            .flat_map(|event| event.event.paths)
+                            .collect();
+                        if !paths.is_empty() {
+                            watched.changed(&mut debouncer, &context, &args, &paths);
+                        }
+                    }
+                    Change::Files(Err(errors)) => {
+                        for e in errors {
+                            log::error!("Failed to watch files: {e}");
+                        }
+                    }
+                    Change::Reloaded(settings) => {
+                        if let Err(e) = watched.update(&mut debouncer, &args, &settings) {
+                            log::error!("Failed to watch the zone files: {e:#}");
+                        }
+                    }
+                }
+            }
+        })
+        .context("Failed to start the watch thread")?;
+
+    Ok(Watch {
+        changes: changes_tx,
+    })
+}
+
+/// Canonical directory of a file and the path of the file in it
+fn canonical(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} does not name a file", path.display()))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = directory
+        .canonicalize()
+        .with_context(|| format!("Failed to find directory {}", directory.display()))?;
+
+    let file = directory.join(name);
+    Ok((directory, file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_files_as_events_do() -> Result<()> {
+        let current = std::env::current_dir()?.canonicalize()?;
+
+        let (directory, file) = canonical(Path::new("mycelnet.zone"))?;
+        assert_eq!(directory, current);
+        assert_eq!(file, current.join("mycelnet.zone"));
+
+        let (directory, file) = canonical(Path::new("./src/../src/main.rs"))?;
+        assert_eq!(directory, current.join("src"));
+        assert_eq!(file, current.join("src").join("main.rs"));
+
+        assert!(canonical(Path::new("missing/mycelnet.zone")).is_err());
+        assert!(canonical(Path::new("/")).is_err());
+
+        Ok(())
+    }
+}